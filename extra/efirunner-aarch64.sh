@@ -0,0 +1,23 @@
+#!/usr/bin/env bash
+set -e
+
+TARGET_EFI="$1"
+# Remaining arguments go to QEMU, e.g. -kernel Image -initrd initrd.img -append "..."
+shift
+ESP_DIR="esp/EFI/BOOT"
+# AAVMF from edk2 (e.g. /usr/share/AAVMF/AAVMF_CODE.fd), padded to 64MB
+AAVMF="${AAVMF:-extra/AAVMF_CODE.fd}"
+
+mkdir -p "$ESP_DIR"
+rm -f "$ESP_DIR/BOOTAA64.EFI"
+cp "$TARGET_EFI" "$ESP_DIR/BOOTAA64.EFI"
+
+qemu-system-aarch64 \
+  -M virt \
+  -cpu cortex-a72 \
+  -m 1G \
+  -smp 2 \
+  -drive if=pflash,format=raw,readonly=on,file="$AAVMF" \
+  -drive file=fat:rw:esp,format=raw,if=virtio \
+  -nographic \
+  "$@"
@@ -0,0 +1,22 @@
+#!/usr/bin/env bash
+set -e
+
+TARGET_EFI="$1"
+# Remaining arguments go to QEMU, e.g. -kernel Image -initrd initrd.img -append "..."
+shift
+ESP_DIR="esp/EFI/BOOT"
+# edk2 RiscVVirtQemu build (e.g. /usr/share/qemu-efi-riscv64/RISCV_VIRT_CODE.fd), padded to 32MB
+RISCV_VIRT="${RISCV_VIRT:-extra/RISCV_VIRT_CODE.fd}"
+
+mkdir -p "$ESP_DIR"
+rm -f "$ESP_DIR/BOOTRISCV64.EFI"
+cp "$TARGET_EFI" "$ESP_DIR/BOOTRISCV64.EFI"
+
+qemu-system-riscv64 \
+  -M virt,pflash0=pflash0 \
+  -m 1G \
+  -smp 2 \
+  -blockdev node-name=pflash0,driver=file,read-only=on,filename="$RISCV_VIRT" \
+  -drive file=fat:rw:esp,format=raw,if=virtio \
+  -nographic \
+  "$@"
@@ -2,6 +2,8 @@
 set -e
 
 TARGET_EFI="$1"
+# Remaining arguments go to QEMU, e.g. -kernel vmlinuz -initrd initrd.img -append "..."
+shift
 ESP_DIR="esp/EFI/BOOT"
 #ESP_IMG="esp.img"
 
@@ -36,7 +38,8 @@ qemu-system-x86_64 \
   -cpu host \
   -drive if=pflash,format=raw,file=extra/OVMF.fd \
   -drive if=virtio,format=qcow2,file=/var/lib/libvirt/images/archlinux-2.qcow2 \
-  -drive file=fat:rw:esp,format=raw,index=0,media=disk
+  -drive file=fat:rw:esp,format=raw,index=0,media=disk \
+  "$@"
 # Expose the host directory as a FAT drive
 #  -no-reboot \                                  # Prevents QEMU from rebooting on exit
 #  -nographic   
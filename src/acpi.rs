@@ -0,0 +1,267 @@
+// acpi.rs
+// Installs replacement ACPI tables from the ESP and drops firmware tables before boot
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use uefi::boot;
+use uefi::proto::unsafe_protocol;
+use uefi::table::cfg::{ACPI_GUID, ACPI2_GUID};
+use uefi::{Result, Status, println, system};
+
+use crate::entries_parse::BootEntry;
+use crate::loader_conf::LoaderConf;
+use crate::secureboot;
+use crate::volume::{self, Volume, open_shared};
+
+const ACPI_DIR: &str = "loader\\acpi";
+
+/// Size of the common table header (signature up to creator revision).
+const HEADER_SIZE: usize = 36;
+
+/// `EFI_ACPI_TABLE_PROTOCOL`: the firmware copies the table, links it into
+/// the RSDT/XSDT and fixes the checksum.
+#[repr(C)]
+#[unsafe_protocol("ffe06bdd-6107-46a6-7bb2-5a9c7ec5275c")]
+struct AcpiTable {
+    install: unsafe extern "efiapi" fn(
+        this: *const AcpiTable,
+        buffer: *const c_void,
+        size: usize,
+        key: *mut usize,
+    ) -> Status,
+    uninstall: unsafe extern "efiapi" fn(this: *const AcpiTable, key: usize) -> Status,
+}
+
+/// `acpi-drop <signature> [oem-id]`: firmware tables to hide from the OS.
+struct DropRule<'a> {
+    signature: &'a str,
+    oem_id: Option<&'a str>,
+}
+
+impl<'a> DropRule<'a> {
+    fn parse(spec: &'a str) -> Option<Self> {
+        let mut words = spec.split_whitespace();
+        let signature = words.next().filter(|s| s.len() == 4)?;
+        Some(DropRule { signature, oem_id: words.next() })
+    }
+
+    /// The OEM ID is compared with both the OEM ID and OEM table ID, as
+    /// SSDTs of one firmware usually only differ in the latter.
+    fn matches(&self, header: &[u8]) -> bool {
+        let field = |range: core::ops::Range<usize>| {
+            core::str::from_utf8(&header[range]).unwrap_or("").trim_end_matches([' ', '\0'])
+        };
+        header[0..4] == *self.signature.as_bytes()
+            && self.oem_id.is_none_or(|id| field(10..16) == id || field(16..24) == id)
+    }
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Header of the table at `address`.
+unsafe fn header_at(address: u64) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(address as usize as *const u8, HEADER_SIZE) }
+}
+
+/// The firmware's RSDP: the ACPI 1.0 structure (20 bytes), or with
+/// `acpi2` the ACPI 2.0+ one, including the XSDT address.
+pub fn rsdp(acpi2: bool) -> Option<&'static [u8]> {
+    let address = system::with_config_table(|tables| {
+        let guid = if acpi2 { ACPI2_GUID } else { ACPI_GUID };
+        tables.iter().find(|table| table.guid == guid).map(|table| table.address as *const u8)
+    })?;
+    let rsdp = unsafe { core::slice::from_raw_parts(address, 24) };
+    if !rsdp.starts_with(b"RSD PTR ") {
+        return None;
+    }
+    let length = if acpi2 && rsdp[15] >= 2 { (le32(rsdp, 20) as usize).max(36) } else { 20 };
+    Some(unsafe { core::slice::from_raw_parts(address, length) })
+}
+
+/// Addresses of the XSDT and RSDT the RSDP points to, preferring the ACPI 2
+/// RSDP. Either may be missing.
+fn root_tables() -> (Option<u64>, Option<u64>) {
+    let Some(rsdp) = rsdp(true).or_else(|| rsdp(false)) else {
+        return (None, None);
+    };
+    let rsdt = le32(rsdp, 16) as u64;
+    let xsdt = if rsdp.len() >= 36 {
+        u64::from_le_bytes(rsdp[24..32].try_into().unwrap())
+    } else {
+        0
+    };
+    ((xsdt != 0).then_some(xsdt), (rsdt != 0).then_some(rsdt))
+}
+
+/// Entries of a root table: 8 bytes wide in the XSDT, 4 in the RSDT.
+fn root_entries(root: u64, width: usize) -> &'static mut [u8] {
+    let length = le32(unsafe { header_at(root) }, 4) as usize;
+    let table = unsafe { core::slice::from_raw_parts_mut(root as usize as *mut u8, length) };
+    let count = (length - HEADER_SIZE) / width;
+    &mut table[HEADER_SIZE..HEADER_SIZE + count * width]
+}
+
+fn entry_address(entry: &[u8]) -> u64 {
+    match entry.len() {
+        8 => u64::from_le_bytes(entry.try_into().unwrap()),
+        _ => le32(entry, 0) as u64,
+    }
+}
+
+/// Addresses of all tables the firmware lists.
+fn firmware_tables() -> Vec<u64> {
+    match root_tables() {
+        (Some(xsdt), _) => root_entries(xsdt, 8).chunks(8).map(entry_address).collect(),
+        (None, Some(rsdt)) => root_entries(rsdt, 4).chunks(4).map(entry_address).collect(),
+        (None, None) => Vec::new(),
+    }
+}
+
+/// The first firmware table with `signature`, e.g. the MADT (`APIC`). Only
+/// the x86_64 Limine code looks tables up itself.
+#[cfg(target_arch = "x86_64")]
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    firmware_tables().into_iter().find_map(|address| {
+        let header = unsafe { header_at(address) };
+        (header[0..4] == *signature).then(|| {
+            let length = le32(header, 4) as usize;
+            unsafe { core::slice::from_raw_parts(address as usize as *const u8, length) }
+        })
+    })
+}
+
+/// Removes the entries of `root` that point at one of `tables`, shrinking it
+/// and fixing its checksum.
+fn unlink(root: u64, width: usize, tables: &[u64]) {
+    let entries = root_entries(root, width);
+    let mut kept = 0;
+    for i in 0..entries.len() / width {
+        if tables.contains(&entry_address(&entries[i * width..(i + 1) * width])) {
+            continue;
+        }
+        entries.copy_within(i * width..(i + 1) * width, kept * width);
+        kept += 1;
+    }
+    let length = HEADER_SIZE + kept * width;
+    let table = unsafe { core::slice::from_raw_parts_mut(root as usize as *mut u8, length) };
+    table[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+    table[9] = 0;
+    table[9] = table.iter().fold(0u8, |sum, &b| sum.wrapping_sub(b));
+}
+
+/// Hides the tables among `candidates` that match one of `drops` from the
+/// RSDT and XSDT.
+fn drop_tables(candidates: &[u64], drops: &[DropRule]) {
+    let matching: Vec<u64> = candidates
+        .iter()
+        .copied()
+        .filter(|&address| drops.iter().any(|drop| drop.matches(unsafe { header_at(address) })))
+        .collect();
+    for &address in &matching {
+        let header = unsafe { header_at(address) };
+        println!(
+            "Dropping ACPI table {} ({})",
+            String::from_utf8_lossy(&header[0..4]),
+            String::from_utf8_lossy(&header[16..24]).trim_end()
+        );
+    }
+    if matching.is_empty() {
+        return;
+    }
+    let (xsdt, rsdt) = root_tables();
+    if let Some(xsdt) = xsdt {
+        unlink(xsdt, 8, &matching);
+    }
+    if let Some(rsdt) = rsdt {
+        unlink(rsdt, 4, &matching);
+    }
+}
+
+/// Files in `loader\acpi` named by `acpi-table` lines; `*` stands for every
+/// `.aml` file there.
+fn table_files(volume: &mut Volume, names: &[&String]) -> Result<Vec<String>> {
+    let mut files: Vec<String> = Vec::new();
+    for name in names {
+        if name.as_str() != "*" {
+            if !files.contains(name) {
+                files.push(String::clone(name));
+            }
+            continue;
+        }
+        let Some(entries) = volume.read_dir(ACPI_DIR)? else {
+            continue;
+        };
+        let mut all: Vec<String> = entries
+            .into_iter()
+            .filter(|file| !file.is_dir && file.name.to_lowercase().ends_with(".aml"))
+            .map(|file| file.name)
+            .collect();
+        all.sort();
+        for file in all {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn install_table(protocol: &AcpiTable, path: &str, data: &[u8]) -> Result {
+    if data.len() < HEADER_SIZE || le32(data, 4) as usize != data.len() {
+        println!("{} is not an ACPI table", path);
+        return Err(uefi::Error::new(Status::VOLUME_CORRUPTED, ()));
+    }
+    let mut key = 0;
+    let status = unsafe { (protocol.install)(protocol, data.as_ptr().cast(), data.len(), &mut key) };
+    if status.is_error() {
+        println!("Firmware refused ACPI table {}: {:?}", path, status);
+        return Err(uefi::Error::new(status, ()));
+    }
+    println!("Installed ACPI table {} from {}", String::from_utf8_lossy(&data[0..4]), path);
+    Ok(())
+}
+
+/// Installs the `acpi-table`s of loader.conf and `entry` and then drops the
+/// firmware tables their `acpi-drop` lines name. Tables are dropped last:
+/// the firmware relinks its root tables when one is installed, and our own
+/// tables must not be dropped.
+pub fn install_for(entry: &BootEntry) -> Result {
+    let conf = LoaderConf::read()?;
+    let names: Vec<&String> = conf.acpi_table.iter().chain(&entry.acpi_table).collect();
+    let drops: Vec<DropRule> = conf
+        .acpi_drop
+        .iter()
+        .chain(&entry.acpi_drop)
+        .filter_map(|spec| {
+            let drop = DropRule::parse(spec);
+            if drop.is_none() {
+                println!("Ignoring acpi-drop {}: expected a 4 character signature", spec);
+            }
+            drop
+        })
+        .collect();
+    if names.is_empty() && drops.is_empty() {
+        return Ok(());
+    }
+    let original = firmware_tables();
+
+    if !names.is_empty() {
+        // The tables' AML would run in the OS unchecked
+        secureboot::refuse_unverified("ACPI tables from loader\\acpi")?;
+        let mut volume = Volume::loader()?;
+        let files = table_files(&mut volume, &names)?;
+        let handle = boot::get_handle_for_protocol::<AcpiTable>().inspect_err(|_| {
+            println!("The firmware can't install ACPI tables");
+        })?;
+        let protocol = open_shared::<AcpiTable>(handle)?;
+        for file in files {
+            let path = volume::join(ACPI_DIR, &file);
+            install_table(&protocol, &path, &volume.read(&path)?)?;
+        }
+    }
+    drop_tables(&original, &drops);
+    Ok(())
+}
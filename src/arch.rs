@@ -0,0 +1,43 @@
+// arch.rs
+// Names and machine types that depend on the architecture the loader is built for
+
+#[cfg(target_arch = "x86_64")]
+mod current {
+    pub const ARCHITECTURE: &str = "x64";
+    pub const FALLBACK_LOADER: &str = "EFI\\BOOT\\BOOTX64.EFI";
+    pub const SHELL: &str = "shellx64.efi";
+    pub const PE_MACHINE: u16 = 0x8664;
+}
+
+#[cfg(target_arch = "aarch64")]
+mod current {
+    pub const ARCHITECTURE: &str = "aa64";
+    pub const FALLBACK_LOADER: &str = "EFI\\BOOT\\BOOTAA64.EFI";
+    pub const SHELL: &str = "shellaa64.efi";
+    pub const PE_MACHINE: u16 = 0xaa64;
+}
+
+#[cfg(target_arch = "riscv64")]
+mod current {
+    pub const ARCHITECTURE: &str = "riscv64";
+    pub const FALLBACK_LOADER: &str = "EFI\\BOOT\\BOOTRISCV64.EFI";
+    pub const SHELL: &str = "shellriscv64.efi";
+    pub const PE_MACHINE: u16 = 0x5064;
+}
+
+/// Name of this architecture in the BLS `architecture` key.
+pub use current::ARCHITECTURE;
+/// Removable media fallback loader path, as firmware boot managers use it.
+pub use current::FALLBACK_LOADER;
+/// UEFI shell at the root of a volume.
+pub use current::SHELL;
+/// PE/COFF machine type of images the firmware can run natively.
+pub use current::PE_MACHINE;
+
+/// EFI byte code runs everywhere the firmware has an interpreter.
+const PE_MACHINE_EBC: u16 = 0x0ebc;
+
+/// Whether the firmware can be expected to start an image for `machine`.
+pub fn can_run(machine: u16) -> bool {
+    machine == PE_MACHINE || machine == PE_MACHINE_EBC
+}
@@ -0,0 +1,203 @@
+// badram.rs
+// Keeps kernels away from known-bad RAM listed as `badram` in loader.conf
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::{Result, println};
+
+use crate::entries_parse::BootEntry;
+use crate::loader_conf::LoaderConf;
+
+const PAGE_SIZE: u64 = boot::PAGE_SIZE as u64;
+
+/// Parses GRUB style `addr,mask[,addr,mask...]` lists of hex numbers.
+fn parse_pairs(lists: &[String]) -> Option<Vec<(u64, u64)>> {
+    let mut pairs = Vec::new();
+    for list in lists {
+        let numbers: Option<Vec<u64>> = list
+            .split(',')
+            .map(|n| u64::from_str_radix(n.trim().trim_start_matches("0x"), 16).ok())
+            .collect();
+        let numbers = numbers.filter(|n| n.len() % 2 == 0)?;
+        pairs.extend(numbers.chunks_exact(2).map(|pair| (pair[0], pair[1])));
+    }
+    Some(pairs)
+}
+
+/// Page ranges below `top` with an address `a` where `a & mask == addr & mask`,
+/// in increasing order.
+fn bad_ranges(addr: u64, mask: u64, top: u64, ranges: &mut Vec<(u64, u64)>) {
+    // Bits fixed by the mask; the offset within a page doesn't matter
+    let fixed = (mask & !(PAGE_SIZE - 1)) | (PAGE_SIZE - 1);
+    let pattern = addr & mask & !(PAGE_SIZE - 1);
+    let mut free = 0u64;
+    loop {
+        let page = pattern | free;
+        if page >= top {
+            break;
+        }
+        match ranges.last_mut() {
+            Some((_, end)) if *end == page => *end += PAGE_SIZE,
+            _ => ranges.push((page, page + PAGE_SIZE)),
+        }
+        // Next combination of the free bits, in increasing order
+        free = (free | fixed).wrapping_add(1) & !fixed;
+        if free == 0 {
+            break;
+        }
+    }
+}
+
+fn is_ram(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::CONVENTIONAL
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+    )
+}
+
+/// Allocates the free bad pages as `UNUSABLE` memory, so that every memory
+/// map handed to a kernel, the EFI one or one converted from it, leaves them
+/// out. Returns the bad ranges in RAM, reserved or not.
+fn reserve(pairs: &[(u64, u64)]) -> Result<Vec<(u64, u64)>> {
+    let map = boot::memory_map(MemoryType::LOADER_DATA)?;
+    let top = map
+        .entries()
+        .map(|d| d.phys_start + d.page_count * PAGE_SIZE)
+        .max()
+        .unwrap_or(0);
+    let mut ranges = Vec::new();
+    for &(addr, mask) in pairs {
+        bad_ranges(addr, mask, top, &mut ranges);
+    }
+    // Pairs may overlap
+    ranges.sort_unstable();
+    ranges.dedup_by(|next, range| {
+        let overlaps = next.0 <= range.1;
+        if overlaps {
+            range.1 = range.1.max(next.1);
+        }
+        overlaps
+    });
+
+    let mut bad = Vec::new();
+    let mut in_use = 0;
+    for d in map.entries().filter(|d| is_ram(d.ty)) {
+        let (start, end) = (d.phys_start, d.phys_start + d.page_count * PAGE_SIZE);
+        for &(bad_start, bad_end) in &ranges {
+            let (from, to) = (bad_start.max(start), bad_end.min(end));
+            if from >= to {
+                continue;
+            }
+            bad.push((from, to));
+            let pages = ((to - from) / PAGE_SIZE) as usize;
+            let reserved = d.ty == MemoryType::CONVENTIONAL
+                && boot::allocate_pages(AllocateType::Address(from), MemoryType::UNUSABLE, pages).is_ok();
+            if !reserved {
+                in_use += pages;
+            }
+        }
+    }
+    bad.sort_unstable();
+    let pages: u64 = bad.iter().map(|(start, end)| (end - start) / PAGE_SIZE).sum();
+    println!("BadRAM: {} bad pages, {} already in use by the firmware", pages, in_use);
+    Ok(bad)
+}
+
+/// `memmap=` parameters marking `ranges` reserved for Linux.
+fn memmap_options(ranges: &[(u64, u64)]) -> String {
+    let mut options = String::new();
+    for (start, end) in ranges {
+        if !options.is_empty() {
+            options.push(' ');
+        }
+        options.push_str(&format!("memmap={:#x}${:#x}", end - start, start));
+    }
+    options
+}
+
+/// Reserves the `badram` pages of loader.conf before anything is loaded for
+/// `entry`, and with `badram-memmap` adds them as `memmap=` to the command
+/// line of Linux entries.
+pub fn reserve_for(entry: &mut BootEntry) -> Result {
+    let conf = LoaderConf::read()?;
+    if conf.badram.is_empty() {
+        return Ok(());
+    }
+    let Some(pairs) = parse_pairs(&conf.badram) else {
+        println!("Ignoring badram: expected pairs of hex address and mask");
+        return Ok(());
+    };
+    let ranges = reserve(&pairs)?;
+    if conf.badram_memmap && !ranges.is_empty() && (entry.linux.is_some() || entry.pxe.is_some() || entry.fw_cfg) {
+        let memmap = memmap_options(&ranges);
+        entry.options = Some(match entry.options.take() {
+            Some(options) => format!("{} {}", options, memmap),
+            None => memmap,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn parses_pairs() {
+        let lists = ["0x1000,0xfffff000".to_string(), " 2000 , ffffe000,0x0,0x0".to_string()];
+        assert_eq!(parse_pairs(&lists), Some(vec![(0x1000, 0xfffff000), (0x2000, 0xffffe000), (0, 0)]));
+        assert_eq!(parse_pairs(&[]), Some(vec![]));
+        assert_eq!(parse_pairs(&["0x1000".to_string()]), None);
+        assert_eq!(parse_pairs(&["0x1000,0xfffff000,0x2000".to_string()]), None);
+        assert_eq!(parse_pairs(&["0x1000,page".to_string()]), None);
+    }
+
+    #[test]
+    fn single_page() {
+        let mut ranges = Vec::new();
+        bad_ranges(0x1234_5678, !0, 1 << 32, &mut ranges);
+        assert_eq!(ranges, [(0x1234_5000, 0x1234_6000)]);
+        // Above the top of memory
+        let mut ranges = Vec::new();
+        bad_ranges(0x1234_5000, !0, 0x1234_5000, &mut ranges);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn free_bits_repeat_pattern() {
+        // Bit 12 free: two adjacent pages, merged
+        let mut ranges = Vec::new();
+        bad_ranges(0x4000, !0x1fff, 1 << 32, &mut ranges);
+        assert_eq!(ranges, [(0x4000, 0x6000)]);
+
+        // Bit 20 free: the page repeats every MiB, up to the top
+        let mut ranges = Vec::new();
+        bad_ranges(0x4000, !0x10_0fff, 0x20_0000, &mut ranges);
+        assert_eq!(ranges, [(0x4000, 0x5000), (0x10_4000, 0x10_5000)]);
+        let mut ranges = Vec::new();
+        bad_ranges(0x4000, !0x10_0fff, 0x10_0000, &mut ranges);
+        assert_eq!(ranges, [(0x4000, 0x5000)]);
+
+        // No fixed bits: everything below the top, as one range
+        let mut ranges = Vec::new();
+        bad_ranges(0, 0, 0x10_0000, &mut ranges);
+        assert_eq!(ranges, [(0, 0x10_0000)]);
+    }
+
+    #[test]
+    fn formats_memmap() {
+        assert_eq!(memmap_options(&[]), "");
+        assert_eq!(
+            memmap_options(&[(0x1000, 0x3000), (0x10000, 0x11000)]),
+            "memmap=0x2000$0x1000 memmap=0x1000$0x10000"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::BootEntry; // adjust if needed
+use crate::entries_parse::rescan_loader_entries;
 use alloc::fmt::format;
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -66,35 +67,82 @@ fn clear() {
     loaded_image.clear().expect("err2");
 }
 
-pub fn boot_menu(entries: &Vec<BootEntry>, input: &mut Input) -> Result<Option<BootEntry>> {
-    if entries.is_empty() {
-        println!("No boot entries found.");
-        return Ok(None);
-    }
+fn volume_suffix(entry: &BootEntry) -> String {
+    entry
+        .volume_label
+        .as_ref()
+        .map_or("".into(), |l| format(format_args!(" [{}]", l)))
+}
+
+fn same_entry(a: &BootEntry, b: &BootEntry) -> bool {
+    a.title == b.title
+        && a.device == b.device
+        && a.linux == b.linux
+        && a.efi == b.efi
+        && a.iso == b.iso
+        && a.pxe == b.pxe
+        && a.fw_cfg == b.fw_cfg
+        && a.memtest == b.memtest
+        && a.secure_boot_keys == b.secure_boot_keys
+        && a.multiboot2 == b.multiboot2
+        && a.limine == b.limine
+        && a.module == b.module
+        && a.devicetree == b.devicetree
+        && a.devicetree_overlay == b.devicetree_overlay
+        && a.acpi_table == b.acpi_table
+        && a.acpi_drop == b.acpi_drop
+}
+
+/// Rebuilds `entries` from scratch and returns the index of the previously
+/// selected entry in the new list, or the closest valid index.
+fn rescan(entries: &mut Vec<BootEntry>, selected: usize) -> usize {
+    clear();
+    println!("Rescanning devices...");
+    let new_entries = match rescan_loader_entries() {
+        Ok(new_entries) => new_entries,
+        Err(e) => {
+            println!("Rescan failed: {:?}", e.status());
+            boot::stall(2_000_000);
+            return selected;
+        }
+    };
+    let previous = entries.get(selected).cloned();
+    *entries = new_entries;
+    previous
+        .and_then(|prev| entries.iter().position(|e| same_entry(e, &prev)))
+        .unwrap_or_else(|| selected.min(entries.len().saturating_sub(1)))
+}
 
+pub fn boot_menu(entries: &mut Vec<BootEntry>, input: &mut Input) -> Result<Option<BootEntry>> {
+    // An empty menu stays up, so devices plugged in later can be found with R
     let mut selected = 0;
 
     loop {
         //Clearing the screen is actually very important!
         clear();
 
-        println!("BOOTLOADER — Select Entry (↑ ↓, Enter to boot, ESC to cancel)\n");
+        println!("BOOTLOADER — Select Entry (↑ ↓, Enter to boot, R to rescan, ESC to cancel)\n");
+
+        if entries.is_empty() {
+            println!("  No boot entries found.");
+        }
 
         for (i, entry) in entries.iter().enumerate() {
             if i == selected {
                 println!(
-                    "> {}{}",
+                    "> {}{}{}",
                     entry.title,
                     entry
                         .version
                         .as_ref()
-                        .map_or("".into(), |v| format(format_args!(" ({})", v)))
+                        .map_or("".into(), |v| format(format_args!(" ({})", v))),
+                    volume_suffix(entry)
                 );
                 if let Some(opts) = &entry.options {
                     println!("    {}", opts);
                 }
             } else {
-                println!("  {}", entry.title);
+                println!("  {}{}", entry.title, volume_suffix(entry));
             }
         }
 
@@ -106,32 +154,32 @@ pub fn boot_menu(entries: &Vec<BootEntry>, input: &mut Input) -> Result<Option<B
         if let Some(key) = input.read_key()? {
             match key {
                 Key::Special(ScanCode::UP) => {
-                    if selected > 0 {
-                        selected -= 1;
-                    }
+                    selected = selected.saturating_sub(1);
                 }
-                Key::Special(ScanCode::DOWN) => {
-                    if selected + 1 < entries.len() {
-                        selected += 1;
-                    }
+                Key::Special(ScanCode::DOWN) if selected + 1 < entries.len() => {
+                    selected += 1;
                 }
                 Key::Special(ScanCode::ESCAPE) => {
                     println!("\nCanceled boot selection.");
                     return Ok(None);
                 }
-                Key::Printable(c) => {
-                    if c == Char16::try_from('\r').unwrap() {
-                        let chosen = entries[selected].clone();
-                        println!("\nSelected: {}", chosen.title);
-                        /*return Ok(Some(if let Some(linux_path) = chosen.linux.clone() {
-                            (chosen, true) // true = kernel
-                        } else if let Some(efi_path) = chosen.efi.clone() {
-                            (efi_path, false) // false = efi
-                        } else {
-                            return Ok(None); // or handle error if neither exists
-                        }));*/
-                        return Ok(Some(chosen));
-                    }
+                Key::Special(ScanCode::FUNCTION_5) => {
+                    selected = rescan(entries, selected);
+                }
+                Key::Printable(c) if c == Char16::try_from('r').unwrap() || c == Char16::try_from('R').unwrap() => {
+                    selected = rescan(entries, selected);
+                }
+                Key::Printable(c) if c == Char16::try_from('\r').unwrap() && !entries.is_empty() => {
+                    let chosen = entries[selected].clone();
+                    println!("\nSelected: {}", chosen.title);
+                    /*return Ok(Some(if let Some(linux_path) = chosen.linux.clone() {
+                        (chosen, true) // true = kernel
+                    } else if let Some(efi_path) = chosen.efi.clone() {
+                        (efi_path, false) // false = efi
+                    } else {
+                        return Ok(None); // or handle error if neither exists
+                    }));*/
+                    return Ok(Some(chosen));
                 }
                 _ => {}
             }
@@ -0,0 +1,176 @@
+// devicetree.rs
+// Loads an entry's devicetree and overlays and installs the result for the kernel
+
+mod fdt;
+mod hwids;
+mod overlay;
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::proto::unsafe_protocol;
+use uefi::{Guid, Result, Status, guid, println, system};
+
+use crate::entries_parse::BootEntry;
+use crate::loader_conf::LoaderConf;
+use crate::secureboot;
+use crate::volume::{Volume, open_shared};
+use fdt::Fdt;
+
+const DEVICE_TREE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+
+/// Free space left in the blob for the firmware's fixups; U-Boot asks
+/// for 12 KiB on top of the tree.
+const FIXUP_SLACK: usize = 16 * 1024;
+
+const DT_APPLY_FIXUPS: u32 = 0x1;
+const DT_RESERVE_MEMORY: u32 = 0x2;
+
+/// `EFI_DT_FIXUP_PROTOCOL` (U-Boot, some EDK2 ports): lets the firmware add
+/// what it knows about the board (memory, MAC addresses, ...) to our tree.
+#[repr(C)]
+#[unsafe_protocol("e617d64c-fe08-46da-f4dc-bbd5870c7300")]
+struct DtFixup {
+    revision: u64,
+    fixup: unsafe extern "efiapi" fn(
+        this: *mut DtFixup,
+        fdt: *mut c_void,
+        buffer_size: *mut usize,
+        flags: u32,
+    ) -> Status,
+}
+
+/// The devicetree the firmware installed, if any.
+fn firmware_devicetree() -> Option<Vec<u8>> {
+    let address = system::with_config_table(|tables| {
+        tables
+            .iter()
+            .find(|table| table.guid == DEVICE_TREE_GUID)
+            .map(|table| table.address as *const u8)
+    })?;
+    let header = unsafe { core::slice::from_raw_parts(address, 8) };
+    let size = fdt::total_size(header)?;
+    Some(unsafe { core::slice::from_raw_parts(address, size) }.to_vec())
+}
+
+fn parse(data: &[u8], path: &str) -> Result<Fdt> {
+    Fdt::parse(data).ok_or_else(|| {
+        println!("{} is not a valid devicetree blob", path);
+        uefi::Error::new(Status::VOLUME_CORRUPTED, ())
+    })
+}
+
+/// What the kernel's EFI stub expects of `/chosen`: the node must exist,
+/// and initrd addresses left by a previous loader would point at garbage.
+fn fixup_chosen(tree: &mut Fdt) {
+    let chosen = tree.root.child_or_insert("chosen");
+    chosen.remove_prop("linux,initrd-start");
+    chosen.remove_prop("linux,initrd-end");
+}
+
+/// Copies `blob` into `size` bytes of pages the OS won't reuse before it
+/// has parsed the tree.
+fn allocate_copy(blob: &[u8], size: usize) -> Result<&'static mut [u8]> {
+    let pages = size.div_ceil(boot::PAGE_SIZE);
+    let memory = boot::allocate_pages(AllocateType::AnyPages, MemoryType::ACPI_RECLAIM, pages)?;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(memory.as_ptr(), pages * boot::PAGE_SIZE) };
+    buffer.fill(0);
+    buffer[..blob.len()].copy_from_slice(blob);
+    Ok(buffer)
+}
+
+fn free(buffer: &mut [u8]) {
+    let pages = buffer.len() / boot::PAGE_SIZE;
+    if let Some(memory) = core::ptr::NonNull::new(buffer.as_mut_ptr()) {
+        let _ = unsafe { boot::free_pages(memory, pages) };
+    }
+}
+
+/// Places `blob` in firmware memory, lets the firmware fix it up if it
+/// can, and returns the final buffer.
+fn fixed_up_copy(blob: &[u8]) -> Result<&'static mut [u8]> {
+    let mut buffer = allocate_copy(blob, blob.len() + FIXUP_SLACK)?;
+    let Some(handle) = boot::find_handles::<DtFixup>().ok().and_then(|h| h.first().copied()) else {
+        return Ok(buffer);
+    };
+    let fixup = open_shared::<DtFixup>(handle)?;
+    let this = &*fixup as *const DtFixup as *mut DtFixup;
+
+    // The firmware tells us how much room it needs if the slack isn't enough
+    let mut retried = false;
+    loop {
+        let mut size = buffer.len();
+        let status = unsafe {
+            (fixup.fixup)(
+                this,
+                buffer.as_mut_ptr().cast(),
+                &mut size,
+                DT_APPLY_FIXUPS | DT_RESERVE_MEMORY,
+            )
+        };
+        if status == Status::BUFFER_TOO_SMALL && !retried {
+            free(buffer);
+            buffer = allocate_copy(blob, size)?;
+            retried = true;
+            continue;
+        }
+        if status.is_error() {
+            println!("Firmware devicetree fixup failed: {:?}", status);
+        }
+        return Ok(buffer);
+    }
+}
+
+/// The devicetree for this machine from the `devicetree-auto` image named
+/// in loader.conf, if there is one.
+fn auto_devicetree() -> Result<Option<Fdt>> {
+    let Some(path) = LoaderConf::read()?.devicetree_auto else {
+        return Ok(None);
+    };
+    let image = Volume::loader()?.read(&path)?;
+    Ok(hwids::select_devicetree(&image))
+}
+
+/// Installs the devicetree of `entry` (its `devicetree` file, else the one
+/// matching the hardware IDs for Linux entries, else the firmware's tree if
+/// it only has overlays) with its `devicetree-overlay`s applied. Entries
+/// without any of these are left alone.
+pub fn install_for(entry: &BootEntry) -> Result {
+    let auto = if entry.devicetree.is_none() && entry.linux.is_some() {
+        auto_devicetree()?
+    } else {
+        None
+    };
+    if entry.devicetree.is_none() && auto.is_none() && entry.devicetree_overlay.is_empty() {
+        return Ok(());
+    }
+    secureboot::refuse_unverified("Devicetree")?;
+    let mut volume = match entry.device {
+        Some(handle) => Volume::open(handle)?,
+        None => Volume::loader()?,
+    };
+
+    let mut tree = match (&entry.devicetree, auto) {
+        (Some(path), _) => {
+            println!("Loading devicetree {}", path);
+            parse(&volume.read(path)?, path)?
+        }
+        (None, Some(tree)) => tree,
+        (None, None) => {
+            let Some(blob) = firmware_devicetree() else {
+                println!("No devicetree to apply the overlays to");
+                return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+            };
+            parse(&blob, "Firmware devicetree")?
+        }
+    };
+    for path in &entry.devicetree_overlay {
+        println!("Applying devicetree overlay {}", path);
+        let overlay = parse(&volume.read(path)?, path)?;
+        overlay::apply(&mut tree, overlay)?;
+    }
+    fixup_chosen(&mut tree);
+
+    let buffer = fixed_up_copy(&tree.to_bytes(0))?;
+    unsafe { boot::install_configuration_table(&DEVICE_TREE_GUID, buffer.as_ptr().cast()) }
+}
@@ -0,0 +1,454 @@
+// fdt.rs
+// Flattened devicetree blobs parsed into an editable tree and written back
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+const HEADER_SIZE: usize = 40;
+/// Version we write; 16 is the oldest one readers have to understand.
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// Node name including the unit address, empty for the root.
+    pub name: String,
+    pub props: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fdt {
+    pub reserved: Vec<(u64, u64)>,
+    pub boot_cpuid: u32,
+    pub root: Node,
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn be64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Nul terminated string at `offset`.
+fn c_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Size of the blob in `data`, from its header, if it looks like a FDT.
+pub fn total_size(data: &[u8]) -> Option<usize> {
+    if be32(data, 0)? != FDT_MAGIC {
+        return None;
+    }
+    Some(be32(data, 4)? as usize)
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.into(),
+            props: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|prop| prop.name == name)
+            .map(|prop| prop.value.as_slice())
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// Value of a string property without its terminating nul.
+    pub fn prop_str(&self, name: &str) -> Option<&str> {
+        c_str(self.prop(name)?, 0)
+    }
+
+    /// Adds or replaces a property.
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        match self.props.iter_mut().find(|prop| prop.name == name) {
+            Some(prop) => prop.value = value,
+            None => self.props.push(Property {
+                name: name.into(),
+                value,
+            }),
+        }
+    }
+
+    pub fn remove_prop(&mut self, name: &str) {
+        self.props.retain(|prop| prop.name != name);
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// The child called `name`, created if it doesn't exist yet.
+    pub fn child_or_insert(&mut self, name: &str) -> &mut Node {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(Node::new(name));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle").or_else(|| self.prop_u32("linux,phandle"))
+    }
+}
+
+/// Components of an absolute node path; unit addresses may be left out if
+/// they are unambiguous, as in `/soc/serial`.
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+fn find_child(node: &Node, name: &str) -> Option<usize> {
+    node.children
+        .iter()
+        .position(|child| child.name == name)
+        .or_else(|| {
+            node.children
+                .iter()
+                .position(|child| child.name.split('@').next() == Some(name))
+        })
+}
+
+impl Fdt {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let size = total_size(data)?;
+        let data = data.get(..size)?;
+        let off_struct = be32(data, 8)? as usize;
+        let off_strings = be32(data, 12)? as usize;
+        let off_rsvmap = be32(data, 16)? as usize;
+        if be32(data, 24)? > FDT_VERSION || be32(data, 20)? < FDT_LAST_COMP_VERSION {
+            return None;
+        }
+        let boot_cpuid = be32(data, 28)?;
+        let strings = data.get(off_strings..)?;
+
+        let mut reserved = Vec::new();
+        let mut offset = off_rsvmap;
+        loop {
+            let (address, size) = (be64(data, offset)?, be64(data, offset + 8)?);
+            if address == 0 && size == 0 {
+                break;
+            }
+            reserved.push((address, size));
+            offset += 16;
+        }
+
+        // Parse the structure block into nested nodes with an explicit stack.
+        let mut stack: Vec<Node> = Vec::new();
+        let mut root = None;
+        let mut offset = off_struct;
+        loop {
+            let token = be32(data, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(data, offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    stack.push(Node::new(name));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop()?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                FDT_PROP => {
+                    let len = be32(data, offset)? as usize;
+                    let name = c_str(strings, be32(data, offset + 4)? as usize)?;
+                    let value = data.get(offset + 8..offset + 8 + len)?.to_vec();
+                    offset = align4(offset + 8 + len);
+                    stack.last_mut()?.props.push(Property {
+                        name: name.into(),
+                        value,
+                    });
+                }
+                FDT_NOP => (),
+                FDT_END => break,
+                _ => return None,
+            }
+        }
+        if !stack.is_empty() {
+            return None;
+        }
+        Some(Fdt {
+            reserved,
+            boot_cpuid,
+            root: root?,
+        })
+    }
+
+    /// Serializes the tree, leaving `slack` bytes of free space at the end
+    /// for firmware that edits the blob in place.
+    pub fn to_bytes(&self, slack: usize) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Strings::default();
+        write_node(&self.root, &mut structure, &mut strings);
+        let strings = strings.data;
+        structure.extend(FDT_END.to_be_bytes());
+
+        let off_rsvmap = HEADER_SIZE;
+        let rsvmap_size = (self.reserved.len() + 1) * 16;
+        let off_struct = off_rsvmap + rsvmap_size;
+        let off_strings = off_struct + structure.len();
+        let size = off_strings + strings.len();
+        let total = align4(size + slack);
+
+        let mut blob = Vec::with_capacity(total);
+        for field in [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            blob.extend(field.to_be_bytes());
+        }
+        for &(address, size) in self.reserved.iter().chain([&(0, 0)]) {
+            blob.extend(address.to_be_bytes());
+            blob.extend(size.to_be_bytes());
+        }
+        blob.extend(structure);
+        blob.extend(strings);
+        blob.resize(total, 0);
+        blob
+    }
+
+    /// Node at the absolute `path`.
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        let mut node = &self.root;
+        for part in components(path) {
+            node = &node.children[find_child(node, part)?];
+        }
+        Some(node)
+    }
+
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        let mut node = &mut self.root;
+        for part in components(path) {
+            let index = find_child(node, part)?;
+            node = &mut node.children[index];
+        }
+        Some(node)
+    }
+
+    /// Path of the node with `phandle`.
+    pub fn path_of_phandle(&self, phandle: u32) -> Option<String> {
+        fn search(node: &Node, phandle: u32, path: &mut String) -> bool {
+            if node.phandle() == Some(phandle) {
+                return true;
+            }
+            let len = path.len();
+            for child in &node.children {
+                path.push('/');
+                path.push_str(&child.name);
+                if search(child, phandle, path) {
+                    return true;
+                }
+                path.truncate(len);
+            }
+            false
+        }
+        let mut path = String::new();
+        if !search(&self.root, phandle, &mut path) {
+            return None;
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        Some(path)
+    }
+
+    /// Highest phandle used in the tree, 0 if there is none.
+    pub fn max_phandle(&self) -> u32 {
+        fn walk(node: &Node) -> u32 {
+            let own = node.phandle().filter(|&p| p != u32::MAX).unwrap_or(0);
+            node.children.iter().map(walk).fold(own, u32::max)
+        }
+        walk(&self.root)
+    }
+}
+
+/// The strings block; every property name is stored once, as dtc does.
+#[derive(Default)]
+struct Strings {
+    data: Vec<u8>,
+    offsets: BTreeMap<String, u32>,
+}
+
+impl Strings {
+    fn offset(&mut self, name: &str) -> u32 {
+        if let Some(&offset) = self.offsets.get(name) {
+            return offset;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend(name.as_bytes());
+        self.data.push(0);
+        self.offsets.insert(name.into(), offset);
+        offset
+    }
+}
+
+fn write_node(node: &Node, out: &mut Vec<u8>, strings: &mut Strings) {
+    out.extend(FDT_BEGIN_NODE.to_be_bytes());
+    out.extend(node.name.as_bytes());
+    out.push(0);
+    out.resize(align4(out.len()), 0);
+    for prop in &node.props {
+        out.extend(FDT_PROP.to_be_bytes());
+        out.extend((prop.value.len() as u32).to_be_bytes());
+        out.extend(strings.offset(&prop.name).to_be_bytes());
+        out.extend(&prop.value);
+        out.resize(align4(out.len()), 0);
+    }
+    for child in &node.children {
+        write_node(child, out, strings);
+    }
+    out.extend(FDT_END_NODE.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        bytes
+    }
+
+    fn tree() -> Fdt {
+        let mut root = Node::new("");
+        root.set_prop("#address-cells", 2u32.to_be_bytes().to_vec());
+        root.set_prop("compatible", string("vendor,board"));
+        let soc = root.child_or_insert("soc");
+        soc.set_prop("ranges", Vec::new());
+        let serial = soc.child_or_insert("serial@1000");
+        serial.set_prop("compatible", string("ns16550a"));
+        serial.set_prop("reg", vec![0, 0, 0x10, 0, 0, 0, 1, 0, 0xff]);
+        serial.set_prop("phandle", 7u32.to_be_bytes().to_vec());
+        root.child_or_insert("chosen").set_prop("bootargs", string("console=ttyS0"));
+        Fdt {
+            reserved: vec![(0x8000_0000, 0x1_0000), (0x9000_0000, 0x2000)],
+            boot_cpuid: 1,
+            root,
+        }
+    }
+
+    /// Header field `index` of `blob`.
+    fn field(blob: &[u8], index: usize) -> usize {
+        be32(blob, index * 4).unwrap() as usize
+    }
+
+    #[test]
+    fn round_trips() {
+        let blob = tree().to_bytes(0);
+        assert_eq!(total_size(&blob), Some(blob.len()));
+        let fdt = Fdt::parse(&blob).unwrap();
+        assert_eq!(fdt.to_bytes(0), blob);
+        assert_eq!(fdt.reserved, tree().reserved);
+        assert_eq!(fdt.boot_cpuid, 1);
+        assert_eq!(fdt.root.prop_str("compatible"), Some("vendor,board"));
+        // Property names are stored once
+        let strings = &blob[field(&blob, 3)..field(&blob, 3) + field(&blob, 8)];
+        assert_eq!(strings.windows(11).filter(|w| w == b"compatible\0").count(), 1);
+
+        let serial = fdt.node("/soc/serial").unwrap();
+        assert_eq!(serial.name, "serial@1000");
+        assert_eq!(serial.prop("reg").unwrap().len(), 9);
+        assert_eq!(fdt.path_of_phandle(7).as_deref(), Some("/soc/serial@1000"));
+        assert_eq!(fdt.max_phandle(), 7);
+        assert!(fdt.node("/soc/missing").is_none());
+    }
+
+    #[test]
+    fn leaves_slack() {
+        let blob = tree().to_bytes(1000);
+        assert_eq!(blob.len(), tree().to_bytes(0).len() + 1000);
+        assert_eq!(total_size(&blob), Some(blob.len()));
+        assert_eq!(Fdt::parse(&blob).unwrap().to_bytes(0), tree().to_bytes(0));
+    }
+
+    #[test]
+    fn skips_nops() {
+        let mut blob = tree().to_bytes(0);
+        let at = field(&blob, 2);
+        blob.splice(at..at, FDT_NOP.to_be_bytes());
+        // The total size, strings offset and structure size grow by the NOP
+        for index in [1, 3, 9] {
+            let value = field(&blob, index) as u32 + 4;
+            blob[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        assert_eq!(Fdt::parse(&blob).unwrap().to_bytes(0), tree().to_bytes(0));
+    }
+
+    #[test]
+    fn rejects_malformed_blobs() {
+        let blob = tree().to_bytes(0);
+        let patched = |index: usize, value: u32| {
+            let mut blob = blob.clone();
+            blob[index..index + 4].copy_from_slice(&value.to_be_bytes());
+            Fdt::parse(&blob)
+        };
+        let (off_struct, off_strings) = (field(&blob, 2), field(&blob, 3));
+
+        assert!(Fdt::parse(&blob[..blob.len() - 1]).is_none());
+        assert!(Fdt::parse(&[]).is_none());
+        assert!(patched(0, 0xfeed_d00d).is_none());
+        // Too old a version, or one only newer readers understand
+        assert!(patched(20, 15).is_none());
+        assert!(patched(24, 18).is_none());
+        assert!(patched(20, 18).is_some());
+        assert!(patched(16, blob.len() as u32).is_none());
+        // Unknown token, missing FDT_END, property outside a node
+        assert!(patched(off_struct, 7).is_none());
+        assert!(patched(off_strings - 4, FDT_NOP).is_none());
+        assert!(patched(off_struct, FDT_PROP).is_none());
+        // Unbalanced nodes
+        assert!(patched(off_strings - 8, FDT_NOP).is_none());
+        assert!(patched(off_strings - 8, FDT_END).is_none());
+        // Property name and value out of range
+        let first_prop = off_struct + 8;
+        assert!(patched(first_prop + 8, 0x10_0000).is_none());
+        assert!(patched(first_prop + 4, 0x10_0000).is_none());
+    }
+}
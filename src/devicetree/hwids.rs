@@ -0,0 +1,164 @@
+// hwids.rs
+// Picks a devicetree by the machine's computer hardware IDs (CHIDs), as systemd's
+// `.hwids`/`.dtbauto` UKI sections describe them
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use sha1::{Digest, Sha1};
+use uefi::{Guid, guid, println};
+
+use super::fdt::Fdt;
+use crate::{pe, smbios};
+
+/// Namespace of the CHIDs Windows' `ComputerHardwareIds` and fwupd compute.
+const CHID_NAMESPACE: Guid = guid!("70ffd812-4c7f-4c7d-0000-000000000000");
+
+/// `.hwids` entry type of devices described by a devicetree.
+const DEVICE_TYPE_DEVICETREE: u32 = 1;
+
+#[derive(Clone, Copy)]
+enum Field {
+    Manufacturer,
+    Family,
+    ProductName,
+    ProductSku,
+    BaseboardManufacturer,
+    BaseboardProduct,
+    BiosVendor,
+    BiosVersion,
+    BiosMajorRelease,
+    BiosMinorRelease,
+    EnclosureKind,
+}
+
+use Field::*;
+
+/// The fields of HardwareID-0 to HardwareID-14, most specific first.
+const CHID_FIELDS: [&[Field]; 15] = [
+    &[Manufacturer, Family, ProductName, ProductSku, BiosVendor, BiosVersion, BiosMajorRelease, BiosMinorRelease],
+    &[Manufacturer, Family, ProductName, BiosVendor, BiosVersion, BiosMajorRelease, BiosMinorRelease],
+    &[Manufacturer, ProductName, BiosVendor, BiosVersion, BiosMajorRelease, BiosMinorRelease],
+    &[Manufacturer, Family, ProductName, ProductSku, BaseboardManufacturer, BaseboardProduct],
+    &[Manufacturer, Family, ProductName, ProductSku],
+    &[Manufacturer, Family, ProductName],
+    &[Manufacturer, ProductSku, BaseboardManufacturer, BaseboardProduct],
+    &[Manufacturer, ProductSku],
+    &[Manufacturer, ProductName, BaseboardManufacturer, BaseboardProduct],
+    &[Manufacturer, ProductName],
+    &[Manufacturer, Family, BaseboardManufacturer, BaseboardProduct],
+    &[Manufacturer, Family],
+    &[Manufacturer, EnclosureKind],
+    &[Manufacturer, BaseboardManufacturer, BaseboardProduct],
+    &[Manufacturer],
+];
+
+/// SMBIOS value of `field`, formatted the way the CHID algorithm hashes it.
+fn field_value(field: Field) -> Option<String> {
+    let (kind, offset) = match field {
+        BiosVendor => (0, 0x04),
+        BiosVersion => (0, 0x05),
+        BiosMajorRelease => (0, 0x14),
+        BiosMinorRelease => (0, 0x15),
+        Manufacturer => (1, 0x04),
+        ProductName => (1, 0x05),
+        ProductSku => (1, 0x19),
+        Family => (1, 0x1a),
+        BaseboardManufacturer => (2, 0x04),
+        BaseboardProduct => (2, 0x05),
+        EnclosureKind => (3, 0x05),
+    };
+    let structure = smbios::find(kind)?;
+    match field {
+        BiosMajorRelease | BiosMinorRelease => Some(format!("{:02x}", structure.byte(offset)?)),
+        EnclosureKind => Some(format!("{:x}", structure.byte(offset)?)),
+        _ => structure.string(offset).map(String::from),
+    }
+}
+
+/// Name-based (v5) UUID over the UTF-16LE `name`, in big-endian order as
+/// RFC 4122 defines it.
+fn chid(name: &str) -> Guid {
+    let mut hasher = Sha1::new();
+    let mut namespace = CHID_NAMESPACE.to_bytes();
+    // Guid keeps the first three fields little-endian
+    namespace[0..4].reverse();
+    namespace[4..6].reverse();
+    namespace[6..8].reverse();
+    hasher.update(namespace);
+    for unit in name.encode_utf16() {
+        hasher.update(unit.to_le_bytes());
+    }
+    let mut bytes: [u8; 16] = hasher.finalize()[..16].try_into().unwrap();
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Guid::from_bytes(bytes)
+}
+
+/// CHIDs of this machine, most specific first. IDs needing an SMBIOS
+/// field the firmware doesn't provide are left out.
+fn machine_chids() -> Vec<Guid> {
+    CHID_FIELDS
+        .iter()
+        .filter_map(|fields| {
+            let values: Option<Vec<String>> = fields.iter().map(|&field| field_value(field)).collect();
+            Some(chid(&values?.join("&")))
+        })
+        .collect()
+}
+
+fn c_str_at(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    core::str::from_utf8(&bytes[..bytes.iter().position(|&b| b == 0)?]).ok()
+}
+
+/// `compatible` of the device in the `.hwids` table `table` matching the
+/// most specific of `chids`.
+fn matching_compatible<'a>(table: &'a [u8], chids: &[Guid]) -> Option<(&'a str, Guid)> {
+    // struct Device { u32 descriptor; EFI_GUID chid; u32 name_offset; u32 compatible_offset; }
+    let mut devices = Vec::new();
+    let mut offset = 0;
+    loop {
+        let descriptor = u32::from_le_bytes(table.get(offset..offset + 4)?.try_into().ok()?);
+        let size = (descriptor & 0x0fff_ffff) as usize;
+        if descriptor == 0 || size < 28 {
+            break;
+        }
+        let device = table.get(offset..offset + size)?;
+        if descriptor >> 28 == DEVICE_TYPE_DEVICETREE {
+            let chid = Guid::from_bytes(device[4..20].try_into().unwrap());
+            let compatible = u32::from_le_bytes(device[24..28].try_into().unwrap()) as usize;
+            devices.push((chid, compatible));
+        }
+        offset += size;
+    }
+
+    chids.iter().find_map(|chid| {
+        let &(_, compatible) = devices.iter().find(|(device, _)| device == chid)?;
+        Some((c_str_at(table, compatible)?, *chid))
+    })
+}
+
+/// The `.dtbauto` devicetree of `image` (a UKI or any PE carrying the
+/// `.hwids` and `.dtbauto` sections) that matches this machine.
+pub fn select_devicetree(image: &[u8]) -> Option<Fdt> {
+    let sections = pe::sections(image)?;
+    let table = sections.iter().find(|section| section.name == b".hwids")?.data;
+    let (compatible, chid) = matching_compatible(table, &machine_chids())?;
+
+    for section in sections.iter().filter(|section| section.name == b".dtbauto") {
+        let Some(tree) = Fdt::parse(section.data) else {
+            continue;
+        };
+        // Compare with the most specific entry, as systemd-stub does
+        if tree.root.prop_str("compatible") == Some(compatible) {
+            println!("Devicetree {} matches hardware ID {}", compatible, chid);
+            return Some(tree);
+        }
+    }
+    println!("Hardware ID {} wants devicetree {}, which isn't there", chid, compatible);
+    None
+}
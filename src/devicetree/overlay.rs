@@ -0,0 +1,391 @@
+// overlay.rs
+// Applies compiled devicetree overlays (dtc -@) to a base tree, like libfdt's fdt_overlay_apply
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::{Result, Status, println};
+
+use super::fdt::{Fdt, Node};
+
+fn invalid(reason: &str) -> uefi::Error {
+    println!("Invalid devicetree overlay: {}", reason);
+    uefi::Error::new(Status::INVALID_PARAMETER, ())
+}
+
+/// Adds `delta` to the big-endian cell at `offset` of `value`.
+fn add_to_cell(value: &mut [u8], offset: usize, delta: u32) -> Result {
+    let cell = value
+        .get_mut(offset..offset + 4)
+        .ok_or_else(|| invalid("fixup offset out of range"))?;
+    let old = u32::from_be_bytes((&*cell).try_into().unwrap());
+    cell.copy_from_slice(&old.wrapping_add(delta).to_be_bytes());
+    Ok(())
+}
+
+/// Moves every phandle defined in the overlay past those of the base.
+fn shift_phandles(node: &mut Node, delta: u32) -> Result {
+    for prop in &mut node.props {
+        if (prop.name == "phandle" || prop.name == "linux,phandle") && prop.value.len() == 4 {
+            add_to_cell(&mut prop.value, 0, delta)?;
+        }
+    }
+    node.children.iter_mut().try_for_each(|child| shift_phandles(child, delta))
+}
+
+/// Updates references between nodes of the overlay itself, which
+/// `__local_fixups__` lists by mirroring the tree's structure.
+fn apply_local_fixups(node: &mut Node, fixups: &Node, delta: u32) -> Result {
+    for fixup in &fixups.props {
+        let prop = node
+            .props
+            .iter_mut()
+            .find(|prop| prop.name == fixup.name)
+            .ok_or_else(|| invalid("local fixup for a missing property"))?;
+        for offset in fixup.value.chunks_exact(4) {
+            let offset = u32::from_be_bytes(offset.try_into().unwrap()) as usize;
+            add_to_cell(&mut prop.value, offset, delta)?;
+        }
+    }
+    for fixups in &fixups.children {
+        let child = node
+            .children
+            .iter_mut()
+            .find(|child| child.name == fixups.name)
+            .ok_or_else(|| invalid("local fixup for a missing node"))?;
+        apply_local_fixups(child, fixups, delta)?;
+    }
+    Ok(())
+}
+
+/// Resolves references to labels of the base tree, listed in `__fixups__`
+/// as `<label> = "<path>:<property>:<offset>", ...`.
+fn apply_external_fixups(base: &Fdt, overlay: &mut Fdt, fixups: &Node) -> Result {
+    let symbols = base.root.child("__symbols__");
+    for fixup in &fixups.props {
+        let target = symbols
+            .and_then(|symbols| symbols.prop_str(&fixup.name))
+            .and_then(|path| base.node(path))
+            .and_then(|node| node.phandle());
+        let Some(phandle) = target else {
+            println!("Overlay references unknown label {}", fixup.name);
+            return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+        };
+
+        for location in fixup.value.split(|&b| b == 0).filter(|s| !s.is_empty()) {
+            let location = core::str::from_utf8(location).map_err(|_| invalid("bad fixup"))?;
+            let mut parts = location.rsplitn(3, ':');
+            let (Some(offset), Some(prop), Some(path)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid("bad fixup"));
+            };
+            let offset: usize = offset.parse().map_err(|_| invalid("bad fixup offset"))?;
+            let value = overlay
+                .node_mut(path)
+                .and_then(|node| node.props.iter_mut().find(|p| p.name == prop))
+                .ok_or_else(|| invalid("fixup for a missing property"))?;
+            let cell = value
+                .value
+                .get_mut(offset..offset + 4)
+                .ok_or_else(|| invalid("fixup offset out of range"))?;
+            cell.copy_from_slice(&phandle.to_be_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// Path in the base tree a fragment applies to.
+fn fragment_target(base: &Fdt, fragment: &Node) -> Result<String> {
+    if let Some(phandle) = fragment.prop_u32("target") {
+        return base
+            .path_of_phandle(phandle)
+            .ok_or_else(|| invalid("fragment target not found"));
+    }
+    let path = fragment
+        .prop_str("target-path")
+        .ok_or_else(|| invalid("fragment without target"))?;
+    if path.starts_with('/') {
+        return Ok(path.into());
+    }
+    // An alias, optionally followed by a path below it
+    let (alias, rest) = path.split_once('/').unwrap_or((path, ""));
+    let mut resolved: String = base
+        .node("/aliases")
+        .and_then(|aliases| aliases.prop_str(alias))
+        .ok_or_else(|| invalid("fragment target alias not found"))?
+        .into();
+    if !rest.is_empty() {
+        resolved.push('/');
+        resolved.push_str(rest);
+    }
+    Ok(resolved)
+}
+
+fn merge(target: &mut Node, source: &Node) {
+    for prop in &source.props {
+        target.set_prop(&prop.name, prop.value.clone());
+    }
+    for child in &source.children {
+        merge(target.child_or_insert(&child.name), child);
+    }
+}
+
+fn is_meta(name: &str) -> bool {
+    matches!(name, "__symbols__" | "__fixups__" | "__local_fixups__")
+}
+
+/// Applies `overlay` to `base`.
+pub fn apply(base: &mut Fdt, mut overlay: Fdt) -> Result {
+    let delta = base.max_phandle();
+    shift_phandles(&mut overlay.root, delta)?;
+    if let Some(fixups) = overlay.root.child("__local_fixups__").cloned() {
+        apply_local_fixups(&mut overlay.root, &fixups, delta)?;
+    }
+    if let Some(fixups) = overlay.root.child("__fixups__").cloned() {
+        apply_external_fixups(base, &mut overlay, &fixups)?;
+    }
+
+    // Targets are resolved before anything is merged, so phandles refer to
+    // the base tree the overlay was compiled against.
+    let mut fragments = Vec::new();
+    for fragment in &overlay.root.children {
+        if is_meta(&fragment.name) {
+            continue;
+        }
+        let Some(contents) = fragment.child("__overlay__") else {
+            continue;
+        };
+        fragments.push((fragment.name.as_str(), fragment_target(base, fragment)?, contents));
+    }
+    for (_, target, contents) in &fragments {
+        let node = base.node_mut(target).ok_or_else(|| {
+            println!("Overlay target {} not found", target);
+            uefi::Error::new(Status::NOT_FOUND, ())
+        })?;
+        merge(node, contents);
+    }
+
+    // Labels of the overlay, rewritten to where their nodes ended up
+    if let Some(symbols) = overlay.root.child("__symbols__") {
+        let mut resolved = Vec::new();
+        for symbol in &symbols.props {
+            let Some(path) = symbol
+                .value
+                .strip_suffix(&[0])
+                .and_then(|path| core::str::from_utf8(path).ok())
+            else {
+                continue;
+            };
+            let mut parts = path.trim_start_matches('/').splitn(3, '/');
+            let path = match (parts.next(), parts.next(), parts.next()) {
+                (Some(fragment), Some("__overlay__"), rest) => {
+                    let Some((_, target, _)) = fragments.iter().find(|f| f.0 == fragment) else {
+                        continue;
+                    };
+                    let mut path = String::from(target.trim_end_matches('/'));
+                    if let Some(rest) = rest {
+                        path += "/";
+                        path += rest;
+                    }
+                    if path.is_empty() {
+                        path.push('/');
+                    }
+                    path
+                }
+                _ => String::from(path),
+            };
+            let mut value = path.into_bytes();
+            value.push(0);
+            resolved.push((symbol.name.clone(), value));
+        }
+        let base_symbols = base.root.child_or_insert("__symbols__");
+        for (name, value) in resolved {
+            base_symbols.set_prop(&name, value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn cell(value: u32) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        bytes
+    }
+
+    fn node(name: &str, props: Vec<(&str, Vec<u8>)>, children: Vec<Node>) -> Node {
+        let mut node = Node::new(name);
+        for (name, value) in props {
+            node.set_prop(name, value);
+        }
+        node.children = children;
+        node
+    }
+
+    fn fdt(root: Node) -> Fdt {
+        Fdt {
+            reserved: Vec::new(),
+            boot_cpuid: 0,
+            root,
+        }
+    }
+
+    /// The base tree, as dtc -@ compiles it, with an `intc` label.
+    fn base() -> Fdt {
+        fdt(node(
+            "",
+            vec![],
+            vec![
+                node("aliases", vec![("serial0", string("/soc/serial@1000"))], vec![]),
+                node(
+                    "soc",
+                    vec![],
+                    vec![
+                        node("interrupt-controller@0", vec![("phandle", cell(1))], vec![]),
+                        node("serial@1000", vec![("status", string("disabled")), ("phandle", cell(2))], vec![]),
+                    ],
+                ),
+                node("__symbols__", vec![("intc", string("/soc/interrupt-controller@0"))], vec![]),
+            ],
+        ))
+    }
+
+    /// An overlay adding `mydev: dev@0 { interrupt-parent = <&intc>; }` to
+    /// the serial0 alias and `consumer { dev = <&mydev>; }` to the root.
+    fn overlay() -> Fdt {
+        fdt(node(
+            "",
+            vec![],
+            vec![
+                node(
+                    "fragment@0",
+                    vec![("target-path", string("serial0"))],
+                    vec![node(
+                        "__overlay__",
+                        vec![("status", string("okay"))],
+                        vec![node(
+                            "dev@0",
+                            vec![("phandle", cell(1)), ("interrupt-parent", cell(u32::MAX))],
+                            vec![],
+                        )],
+                    )],
+                ),
+                node(
+                    "fragment@1",
+                    vec![("target-path", string("/"))],
+                    vec![node(
+                        "__overlay__",
+                        vec![],
+                        vec![node("consumer", vec![("dev", [cell(5), cell(1)].concat())], vec![])],
+                    )],
+                ),
+                node("__symbols__", vec![("mydev", string("/fragment@0/__overlay__/dev@0"))], vec![]),
+                node(
+                    "__fixups__",
+                    vec![("intc", string("/fragment@0/__overlay__/dev@0:interrupt-parent:0"))],
+                    vec![],
+                ),
+                node(
+                    "__local_fixups__",
+                    vec![],
+                    vec![node(
+                        "fragment@1",
+                        vec![],
+                        vec![node("__overlay__", vec![], vec![node("consumer", vec![("dev", cell(4))], vec![])])],
+                    )],
+                ),
+            ],
+        ))
+    }
+
+    fn status(base: &mut Fdt, overlay: Fdt) -> Option<Status> {
+        apply(base, overlay).err().map(|e| e.status())
+    }
+
+    #[test]
+    fn applies_overlay() {
+        let mut base = base();
+        apply(&mut base, overlay()).unwrap();
+        let serial = base.node("/soc/serial@1000").unwrap();
+        assert_eq!(serial.prop_str("status"), Some("okay"));
+        // The overlay's phandles moved past the base's, references followed
+        let dev = base.node("/soc/serial@1000/dev@0").unwrap();
+        assert_eq!(dev.phandle(), Some(3));
+        assert_eq!(dev.prop_u32("interrupt-parent"), Some(1));
+        assert_eq!(base.node("/consumer").unwrap().prop("dev"), Some([cell(5), cell(3)].concat().as_slice()));
+        let symbols = base.node("/__symbols__").unwrap();
+        assert_eq!(symbols.prop_str("mydev"), Some("/soc/serial@1000/dev@0"));
+        assert_eq!(symbols.prop_str("intc"), Some("/soc/interrupt-controller@0"));
+        assert!(base.node("/fragment@0").is_none());
+
+        // And the result survives serialization
+        let blob = base.to_bytes(0);
+        assert_eq!(Fdt::parse(&blob).unwrap().to_bytes(0), blob);
+    }
+
+    #[test]
+    fn targets_by_phandle() {
+        let mut overlay = overlay();
+        let fragment = overlay.node_mut("/fragment@0").unwrap();
+        fragment.remove_prop("target-path");
+        fragment.set_prop("target", cell(2));
+        let mut base = base();
+        apply(&mut base, overlay).unwrap();
+        assert!(base.node("/soc/serial@1000/dev@0").is_some());
+    }
+
+    #[test]
+    fn rejects_bad_overlays() {
+        let invalid = Some(Status::INVALID_PARAMETER);
+
+        let mut unknown_label = overlay();
+        let fixups = unknown_label.node_mut("/__fixups__").unwrap();
+        fixups.set_prop("uart", fixups.prop("intc").unwrap().to_vec());
+        fixups.remove_prop("intc");
+        assert_eq!(status(&mut base(), unknown_label), Some(Status::NOT_FOUND));
+
+        let mut bad_fixup = overlay();
+        bad_fixup.node_mut("/__fixups__").unwrap().set_prop("intc", string("/fragment@0/__overlay__/dev@0"));
+        assert_eq!(status(&mut base(), bad_fixup), invalid);
+
+        let mut fixup_offset = overlay();
+        fixup_offset
+            .node_mut("/__fixups__")
+            .unwrap()
+            .set_prop("intc", string("/fragment@0/__overlay__/dev@0:interrupt-parent:2"));
+        assert_eq!(status(&mut base(), fixup_offset), invalid);
+
+        let mut local_offset = overlay();
+        local_offset.node_mut("/__local_fixups__/fragment@1/__overlay__/consumer").unwrap().set_prop("dev", cell(8));
+        assert_eq!(status(&mut base(), local_offset), invalid);
+
+        let mut local_missing = overlay();
+        local_missing.node_mut("/fragment@1/__overlay__/consumer").unwrap().remove_prop("dev");
+        assert_eq!(status(&mut base(), local_missing), invalid);
+
+        let mut no_target = overlay();
+        no_target.node_mut("/fragment@0").unwrap().remove_prop("target-path");
+        assert_eq!(status(&mut base(), no_target), invalid);
+
+        let mut unknown_alias = overlay();
+        unknown_alias.node_mut("/fragment@0").unwrap().set_prop("target-path", string("serial1"));
+        assert_eq!(status(&mut base(), unknown_alias), invalid);
+
+        let mut unknown_phandle = overlay();
+        let fragment = unknown_phandle.node_mut("/fragment@0").unwrap();
+        fragment.remove_prop("target-path");
+        fragment.set_prop("target", cell(9));
+        assert_eq!(status(&mut base(), unknown_phandle), invalid);
+
+        let mut missing_path = overlay();
+        missing_path.node_mut("/fragment@1").unwrap().set_prop("target-path", string("/missing"));
+        assert_eq!(status(&mut base(), missing_path), Some(Status::NOT_FOUND));
+    }
+}
@@ -0,0 +1,106 @@
+// drivers.rs
+// Loads third-party UEFI drivers (file systems etc.) from the ESP before entry discovery
+
+use alloc::string::{String, ToString};
+use uefi::boot::{self, MemoryType};
+use uefi::proto::device_path::{DevicePath, DevicePathNodeEnum};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::{Result, println};
+
+use crate::loader_conf::LoaderConf;
+use crate::volume::{self, Volume, file_device_path, open_shared};
+
+/// Used when the firmware doesn't tell us where the loader was started from.
+const DEFAULT_LOADER_DIR: &str = "EFI\\BOOT";
+
+/// Directory the loader image lives in, e.g. `\EFI\BOOT` or `\EFI\<loader>`.
+fn loader_dir() -> String {
+    let image_dir = open_shared::<LoadedImage>(boot::image_handle())
+        .ok()
+        .and_then(|image| image.file_path().and_then(file_path_of));
+    image_dir
+        .as_deref()
+        .and_then(|path| path.rsplit_once(['\\', '/']))
+        .map(|(dir, _)| dir.to_string())
+        .unwrap_or_else(|| DEFAULT_LOADER_DIR.to_string())
+}
+
+/// Concatenates the file path nodes of `path` into a single path.
+fn file_path_of(path: &DevicePath) -> Option<String> {
+    let mut file_path = String::new();
+    for node in path.node_iter() {
+        if let Ok(DevicePathNodeEnum::MediaFilePath(node)) = node.as_enum() {
+            let part = node.path_name().to_cstring16().ok()?.to_string();
+            file_path = volume::join(&file_path, &part);
+        }
+    }
+    (!file_path.is_empty()).then_some(file_path)
+}
+
+/// Loads and starts one driver image. Applications are refused, as they
+/// would run right away instead of installing protocols.
+fn start_driver(volume: &mut Volume, path: &str) -> Result {
+    let buffer = volume.read(path)?;
+    let device_path = file_device_path(volume.handle(), path);
+    let image = boot::load_image(
+        boot::image_handle(),
+        boot::LoadImageSource::FromBuffer {
+            buffer: &buffer,
+            file_path: device_path
+                .as_deref()
+                .and_then(|bytes| <&DevicePath>::try_from(bytes).ok()),
+        },
+    )?;
+
+    let code_type = open_shared::<LoadedImage>(image)?.code_type();
+    if code_type != MemoryType::BOOT_SERVICES_CODE && code_type != MemoryType::RUNTIME_SERVICES_CODE
+    {
+        println!("{} is not a driver, skipping.", path);
+        return boot::unload_image(image);
+    }
+    boot::start_image(image)
+}
+
+/// Starts every `*.efi` in loader.conf's `drivers-dir`, or else the
+/// `drivers` directory next to the loader, and connects them to the
+/// hardware. Returns how many drivers were started.
+pub fn load_drivers() -> Result<usize> {
+    let mut volume = Volume::loader()?;
+    let dir = match LoaderConf::read().ok().and_then(|conf| conf.drivers_dir) {
+        Some(dir) => dir,
+        None => volume::join(&loader_dir(), "drivers"),
+    };
+    let Some(files) = volume.read_dir(&dir)? else {
+        return Ok(0);
+    };
+
+    let mut started = 0;
+    for file in files {
+        if file.is_dir || !file.name.to_lowercase().ends_with(".efi") {
+            continue;
+        }
+        let path = volume::join(&dir, &file.name);
+        match start_driver(&mut volume, &path) {
+            Ok(()) => {
+                println!("Loaded driver {}", path);
+                started += 1;
+            }
+            Err(e) => println!("Failed to load driver {}: {:?}", path, e.status()),
+        }
+    }
+
+    if started > 0 {
+        connect_all_controllers()?;
+    }
+    Ok(started)
+}
+
+/// Connects drivers to every handle, picking up devices that appeared after
+/// the firmware's own connect pass (e.g. a freshly inserted USB stick) and
+/// controllers that only a driver we started can handle.
+pub fn connect_all_controllers() -> Result<()> {
+    for handle in boot::locate_handle_buffer(boot::SearchType::AllHandles)?.iter() {
+        let _ = boot::connect_controller(*handle, None, None, true);
+    }
+    Ok(())
+}
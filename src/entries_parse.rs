@@ -1,20 +1,25 @@
 // loader_entries.rs
 // Module to read systemd-boot style entries from ESP
 
-mod fs_handler;
+mod mirror;
+mod removable;
+use crate::arch;
+use crate::drivers;
+use crate::memtest;
+use crate::qemu;
+use crate::secureboot;
+use crate::volume::{self, Volume};
+use crate::xbootldr;
+use mirror::MirrorId;
 use alloc::fmt::format;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use core::str;
-use uefi::proto::media::file::{File, FileAttribute, FileMode, FileType};
-use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::{prelude::*, println};
-//use uefi::proto::media::fs::Directory;
+use uefi::println;
 use crate::alloc::string::ToString;
-use uefi::CStr16;
+use uefi::Handle;
 use uefi::Result;
-use uefi::proto::loaded_image::LoadedImage;
-use uefi::proto::media::file::Directory;
+use uefi::Status;
 
 #[derive(Debug, Clone)]
 pub struct BootEntry {
@@ -25,8 +30,35 @@ pub struct BootEntry {
     pub linux: Option<String>,
     pub initrd: Option<String>,
     pub efi: Option<String>,
+    /// ISO image booted from memory through its removable media loader.
+    pub iso: Option<String>,
+    /// `pxe` line: the kernel and initrd are fetched over TFTP.
+    pub pxe: Option<String>,
+    /// Kernel, initrd and command line are read from QEMU's fw_cfg.
+    pub fw_cfg: bool,
+    /// Built-in memory test instead of a kernel.
+    pub memtest: bool,
+    /// Key set in `loader\keys` to enroll instead of booting.
+    pub secure_boot_keys: Option<String>,
+    /// Multiboot2 kernel, booted with `module`s instead of `linux`/`initrd`.
+    pub multiboot2: Option<String>,
+    /// Limine protocol kernel, also booted with `module`s.
+    pub limine: Option<String>,
+    /// `<path> [cmdline]` of each Multiboot2 or Limine module.
+    pub module: Vec<String>,
+    pub devicetree: Option<String>,
+    pub devicetree_overlay: Vec<String>,
+    /// Tables in the loader's `loader\acpi` to install, `*` for all of them.
+    pub acpi_table: Vec<String>,
+    /// `<signature> [oem-id]` of firmware ACPI tables to hide.
+    pub acpi_drop: Vec<String>,
+    /// BLS `architecture`; entries for other architectures are hidden.
+    pub architecture: Option<String>,
     pub options: Option<String>,
-    
+    /// Volume the entry was found on; paths are resolved relative to it.
+    pub device: Option<Handle>,
+    /// Label of a foreign volume, shown next to the title in the menu.
+    pub volume_label: Option<String>,
 }
 
 impl BootEntry {
@@ -39,182 +71,203 @@ impl BootEntry {
             linux: None,
             initrd: None,
             efi: None,
-            options: None, 
+            iso: None,
+            pxe: None,
+            fw_cfg: false,
+            memtest: false,
+            secure_boot_keys: None,
+            multiboot2: None,
+            limine: None,
+            module: Vec::new(),
+            devicetree: None,
+            devicetree_overlay: Vec::new(),
+            acpi_table: Vec::new(),
+            acpi_drop: Vec::new(),
+            architecture: None,
+            options: None,
+            device: None,
+            volume_label: None,
         }
     }
 }
 
 /// Reads all .conf files under /loader/entries and returns parsed BootEntry list.
+/// Entries are collected from the ESP, the XBOOTLDR partition on the same
+/// disk and then every other volume the firmware exposes, plus ext4
+/// partitions it can't read itself.
 pub fn read_loader_entries() -> Result<Vec<BootEntry>> {
-    //st: &SystemTable<Boot>
-    // Open the SimpleFileSystem for the loaded image's device
-    /*let loaded_image = st.boot_services().handle_protocol::<uefi::proto::loaded_image::LoadedImage>(
-    st.boot_services().image_handle())?
-    .interface;*/
-    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
-    println!("Line 1: {:?}", loaded_image);
-    //let device = unsafe { (*loaded_image.get()).device() };
-    let device_handle = loaded_image.device();
-    //println!("Line2");
-    /*let fs = st.boot_services().handle_protocol::<SimpleFileSystem>(device.unwrap())?
-    .interface;*/
-    let mut sfs = boot::open_protocol_exclusive::<SimpleFileSystem>(device_handle.unwrap())?;
-    //let mut root = unsafe { (*fs.get()).open_volume()? };
-
-    // Open the root directory
-    let mut folder = sfs.open_volume()?;
-    println!("Line3");
-
-    // Navigate to \loader\entries for conf detection
-    open_dir(&mut folder, "loader")?;
-    open_dir(&mut folder, "entries")?;
-    let buf: &mut [u8] = &mut [0; 10000];
+    let esp = volume::loader_device()?;
 
     let mut entries = Vec::new();
-    loop {
-        println!("I ahev entered the loop");
-        //let x = folder.read_entry(buf);
-        //println!("Rsult = {x:#?}")
-        match folder.read_entry(buf) {
-            Err(e) => println!("Error: {e}"),
-            core::prelude::v1::Ok(None) => break,
-            core::prelude::v1::Ok(Some(file_info)) => {
-                let name = file_info.file_name();
-                println!("should execute rn");
-                if !name.to_string().ends_with(".conf") {
-                    println!("Should not executed rihgtnow");
-                    continue;
-                }
-                // Open file
-                let file_handle = folder.open(
-                    //unsafe { CStr16::from_u16_with_nul_unchecked(&to_utf16(&name)) },
-                    &name,
-                    FileMode::Read,
-                    FileAttribute::empty(),
-                )?;
-                let mut file = match file_handle.into_type()? {
-                    FileType::Regular(f) => f,
-                    _ => continue,
-                };
-                // Read content into buffer
-                let mut buf: &mut [u8] = &mut [0; 1000];
-                file.read(&mut buf)?;
-                if let Ok(text) = str::from_utf8(&buf) {
-                    let entry = parse_conf(text);
-                    // Ensure mandatory fields
-                    //if !entry.title.is_empty() && !entry.linux.is_empty() {
-                    entries.push(entry);
-                    //}
-                }
+    let mut scanned = vec![esp];
+    // QEMU -kernel goes first, so a freshly built kernel is one key away
+    if let Some(entry) = qemu::direct_kernel_entry() {
+        scanned.extend(entry.device);
+        entries.push(entry);
+    }
+    read_volume_entries(esp, &mut entries)?;
+    detect_os_entries(esp, &mut entries)?;
+    // Volumes whose entries are listed, so mirrors of them can be skipped.
+    // The ESP goes first: on a mirrored ESP, the copy on the boot disk wins.
+    let mut listed: Vec<(MirrorId, Handle)> = Vec::new();
+    if let Some(id) = mirror::mirror_id(esp) {
+        listed.push((id, esp));
+    }
+
+    match xbootldr::find_xbootldr(esp) {
+        Ok(Some(handle)) => {
+            read_volume_entries(handle, &mut entries)?;
+            scanned.push(handle);
+            if let Some(id) = mirror::mirror_id(handle) {
+                listed.push((id, handle));
             }
         }
+        Ok(None) => (),
+        Err(e) => println!("XBOOTLDR lookup failed: {:?}", e.status()),
     }
-    //Add autodetect for Windows and macOS
-    let mut root = sfs.open_volume()?;
-    for path in [
-        "EFI\\Microsoft\\Boot\\bootmgfw.efi",
-        "EFI\\Apple\\Boot\\boot.efi",
-        "shellx64.efi",
-    ] {
-        //if let Ok(file_handle) = try_open_path(&mut root, path) {
-        if try_open_path(&mut root, path).unwrap() {
-            entries.push(BootEntry {
-                title: format(format_args!("Detected Boot Entry: {}", path)),
-                version: None,
-                machine_id: None,
-                sort_key: None,
-                linux: None,
-                initrd: None,
-                efi: Some(path.into()),
-                options: None,
-            });
-        }
+
+    // USB sticks only get a file system once their controller is connected
+    if let Err(e) = removable::connect_removable_media() {
+        println!("Failed to connect removable media: {:?}", e.status());
     }
-    //Add \EFI\Linux kernel detection
-    if let Some(mut linux_dir) = fs_handler::open_dir(&mut root)? {
-        // Proceed to enumerate EFI/Linux kernels
-        loop {
-            let file = match linux_dir.read_entry(buf).unwrap() {
-                Some(info) => info,
-                None => break,
-            };
-
-            let name = file.file_name().to_string();
-            
-            entries.push(BootEntry {
-                title: format(format_args!("Linux EFI Kernel: {}", name)),
-                version: None,
-                machine_id: None,
-                sort_key: None,
-                linux: None,
-                initrd: None,
-                efi: Some(format(format_args!("EFI\\Linux\\{}", name))),
-                options: None,
-            });
+
+    // Other disks (a Windows ESP, a second Linux install, an ext4 /boot, ...)
+    for handle in volume::volume_handles()? {
+        if scanned.contains(&handle) {
+            continue;
+        }
+        let id = mirror::mirror_id(handle);
+        if let Some(id) = &id
+            && let Some((_, primary)) = listed.iter().find(|(listed_id, _)| listed_id == id)
+        {
+            println!("Skipping mirror of an already listed volume: {}", volume_label(handle));
+            volume::add_mirror(*primary, handle);
+            continue;
+        }
+        if let Some(id) = id {
+            listed.push((id, handle));
+        }
+        let first = entries.len();
+        let scanned_ok = read_volume_entries(handle, &mut entries)
+            .and_then(|_| detect_os_entries(handle, &mut entries));
+        match scanned_ok {
+            Ok(()) => (),
+            // Swap, LVM and the like: nothing we can read.
+            Err(e) if e.status() == Status::UNSUPPORTED => continue,
+            Err(e) => println!("Skipping volume: {:?}", e.status()),
+        }
+        if entries.len() > first {
+            let label = volume_label(handle);
+            for entry in &mut entries[first..] {
+                entry.volume_label = Some(label.clone());
+            }
         }
-    } else {
-        println!("Skipping EFI/Linux kernel detection.");
     }
+
+    match removable::removable_media_entries(esp) {
+        Ok(found) => entries.extend(found),
+        Err(e) => println!("Removable media detection failed: {:?}", e.status()),
+    }
+    entries.extend(secureboot::enroll_entries());
+    entries.push(memtest::entry());
     Ok(entries)
 }
 
-pub fn try_open_path(root: &mut Directory, path: &str) -> Result<bool> {
-    // Convert path to UTF-16 with null terminator
-    let mut path_utf16 = [0u16; 260];
-    let mut len = 0;
-    for ch in path.chars() {
-        if len >= path_utf16.len() - 1 {
-            return Err(uefi::Error::new(Status::BUFFER_TOO_SMALL, ()));
+/// Reconnects all controllers and reads the entries again, for media that
+/// was inserted while the menu is up.
+pub fn rescan_loader_entries() -> Result<Vec<BootEntry>> {
+    drivers::connect_all_controllers()?;
+    read_loader_entries()
+}
+
+/// Human readable name of a volume: its label and partition number.
+fn volume_label(device: Handle) -> String {
+    let label = Volume::open(device)
+        .map(|mut volume| volume.label())
+        .unwrap_or_default();
+    let partition = xbootldr::partition_number(device);
+    match (label.trim(), partition) {
+        ("", Some(n)) => format(format_args!("partition {}", n)),
+        ("", None) => "unnamed volume".to_string(),
+        (label, Some(n)) => format(format_args!("{} (partition {})", label, n)),
+        (label, None) => label.to_string(),
+    }
+}
+
+/// Collects Type #1 entries from /loader/entries and Type #2 kernels from
+/// /EFI/Linux on the volume of `device`. Paths in the returned entries are
+/// relative to that volume.
+fn read_volume_entries(device: Handle, entries: &mut Vec<BootEntry>) -> Result<()> {
+    let mut volume = Volume::open(device)?;
+
+    if let Some(files) = volume.read_dir("loader\\entries")? {
+        for file in files {
+            if file.is_dir || !file.name.ends_with(".conf") {
+                continue;
+            }
+            let path = volume::join("loader\\entries", &file.name);
+            match volume.read_to_string(&path) {
+                Ok(text) => {
+                    let mut entry = parse_conf(&text);
+                    if let Some(architecture) = &entry.architecture
+                        && !architecture.eq_ignore_ascii_case(arch::ARCHITECTURE)
+                    {
+                        println!("Skipping {}: built for {}", path, architecture);
+                        continue;
+                    }
+                    entry.device = Some(device);
+                    entries.push(entry);
+                }
+                Err(e) => println!("Failed to read {}: {:?}", path, e.status()),
+            }
         }
-        path_utf16[len] = ch as u16;
-        len += 1;
+    } else {
+        println!("No /loader/entries on this volume.");
     }
-    path_utf16[len] = 0;
-
-    // Attempt to open the file
-    match root.open(
-        unsafe { CStr16::from_u16_with_nul_unchecked(&path_utf16[..=len]) },
-        FileMode::Read,
-        FileAttribute::empty(),
-    ) {
-        Ok(file_handle) => {
-            match file_handle.into_type()? {
-                FileType::Regular(_) => Ok(true),
-                FileType::Dir(_) => Ok(false), // It's a dir, not a file
+
+    //Add \EFI\Linux kernel detection
+    if let Some(files) = volume.read_dir("EFI\\Linux")? {
+        for file in files {
+            if file.is_dir || !file.name.to_lowercase().ends_with(".efi") {
+                continue;
             }
+
+            entries.push(BootEntry {
+                title: format(format_args!("Linux EFI Kernel: {}", file.name)),
+                efi: Some(volume::join("EFI\\Linux", &file.name)),
+                device: Some(device),
+                ..BootEntry::new()
+            });
         }
-        Err(e) if e.status() == Status::NOT_FOUND => Ok(false),
-        Err(e) => Err(e),
+    } else {
+        println!("Skipping EFI/Linux kernel detection.");
     }
+    Ok(())
 }
 
-/// Open (and move into) a subdirectory by name
-fn open_dir(dir: &mut Directory, name: &str) -> Result<()> {
-    let mut name_utf16 = [0u16; 256];
-    let mut i = 0;
-    for ch in name.encode_utf16() {
-        if i >= 255 {
-            break;
+/// Adds chainload entries for other operating systems found on the volume.
+fn detect_os_entries(device: Handle, entries: &mut Vec<BootEntry>) -> Result<()> {
+    let mut volume = Volume::open(device)?;
+    //Add autodetect for Windows and macOS
+    for path in [
+        "EFI\\Microsoft\\Boot\\bootmgfw.efi",
+        "EFI\\Apple\\Boot\\boot.efi",
+        arch::SHELL,
+    ] {
+        if volume.exists(path)? {
+            entries.push(BootEntry {
+                title: format(format_args!("Detected Boot Entry: {}", path)),
+                efi: Some(path.into()),
+                device: Some(device),
+                ..BootEntry::new()
+            });
         }
-        name_utf16[i] = ch;
-        i += 1;
     }
-    name_utf16[i] = 0;
-    let handle = dir.open(
-        unsafe { CStr16::from_u16_with_nul_unchecked(&name_utf16[..=i]) },
-        FileMode::Read,
-        FileAttribute::empty(),
-    )?;
-    let subdir = match handle.into_type()? {
-        FileType::Dir(d) => d,
-        _ => return Err(uefi::Error::new(uefi::Status::NOT_FOUND, ())),
-    };
-    *dir = subdir;
     Ok(())
 }
 
 /// Parse a single .conf text into BootEntry
-fn parse_conf(text: &str) -> BootEntry {
+pub fn parse_conf(text: &str) -> BootEntry {
     let mut entry = BootEntry::new();
     for line in text.lines() {
         let line = line.trim();
@@ -229,9 +282,22 @@ fn parse_conf(text: &str) -> BootEntry {
                 "sort-key" => entry.sort_key = Some(val.to_string()),
                 "version" => entry.version = Some(val.to_string()),
                 "linux" => entry.linux = Some(val.to_string()),
+                "efi" => entry.efi = Some(val.to_string()),
+                "iso" => entry.iso = Some(val.to_string()),
+                "pxe" => entry.pxe = Some(val.to_string()),
+                "multiboot2" => entry.multiboot2 = Some(val.to_string()),
+                "limine" => entry.limine = Some(val.to_string()),
+                "module" => entry.module.push(val.to_string()),
                 "initrd" => entry.initrd = Some(val.to_string()),
                 "options" => entry.options = Some(val.to_string()),
+                "devicetree" => entry.devicetree = Some(val.to_string()),
+                "devicetree-overlay" => entry
+                    .devicetree_overlay
+                    .extend(val.split_whitespace().map(String::from)),
+                "acpi-table" => entry.acpi_table.extend(val.split_whitespace().map(String::from)),
+                "acpi-drop" => entry.acpi_drop.push(val.to_string()),
                 "machine-id" => entry.machine_id = Some(val.to_string()),
+                "architecture" => entry.architecture = Some(val.to_string()),
                 _ => (),
             }
         }
@@ -0,0 +1,72 @@
+// mirror.rs
+// Recognizes mirrored copies of the same ESP so their entries are only listed once
+
+use crate::ext4::{Disk, UefiDisk};
+use crate::volume::{self, Volume};
+use crate::xbootldr;
+use sha2::{Digest, Sha256};
+use uefi::{Guid, Handle};
+
+const MD_SB_MAGIC: u32 = 0xa92b_4efc;
+const MD_SB_SIZE: usize = 256;
+
+/// What two volumes must share to count as copies of each other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MirrorId {
+    /// Members of the same mdraid array (metadata 1.0 keeps the superblock
+    /// at the end, so each member is a plain FAT volume to the firmware).
+    MdRaid([u8; 16]),
+    /// A cloned ESP: same partition UUID and the same boot entries.
+    Clone { partition: Guid, entries: [u8; 32] },
+}
+
+/// Set UUID of an mdraid 1.0 superblock, which sits 8 KiB before the end
+/// of the partition, aligned down to 4 KiB.
+fn md_set_uuid(device: Handle) -> Option<[u8; 16]> {
+    let disk = UefiDisk::open(device).ok()?;
+    let sectors = disk.size() / 512;
+    if sectors < 16 {
+        return None;
+    }
+    let offset = ((sectors - 16) & !7) * 512;
+    let mut sb = [0u8; MD_SB_SIZE];
+    disk.read_at(offset, &mut sb).ok()?;
+    let magic = u32::from_le_bytes(sb[0..4].try_into().unwrap());
+    let major = u32::from_le_bytes(sb[4..8].try_into().unwrap());
+    if magic != MD_SB_MAGIC || major != 1 {
+        return None;
+    }
+    sb[16..32].try_into().ok()
+}
+
+/// Hash over the names and contents of all `loader/entries/*.conf`.
+fn entries_hash(device: Handle) -> Option<[u8; 32]> {
+    let mut volume = Volume::open(device).ok()?;
+    let mut files = volume.read_dir("loader\\entries").ok()??;
+    files.retain(|file| !file.is_dir && file.name.ends_with(".conf"));
+    if files.is_empty() {
+        return None;
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        let data = volume.read(&volume::join("loader\\entries", &file.name)).ok()?;
+        hasher.update((file.name.len() as u64).to_le_bytes());
+        hasher.update(file.name.as_bytes());
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(&data);
+    }
+    Some(hasher.finalize().into())
+}
+
+/// Identity used to detect mirrors of the volume on `device`, `None` if it
+/// can't be a mirror of anything.
+pub fn mirror_id(device: Handle) -> Option<MirrorId> {
+    if let Some(uuid) = md_set_uuid(device) {
+        return Some(MirrorId::MdRaid(uuid));
+    }
+    let partition = xbootldr::partition_guid(device)?;
+    let entries = entries_hash(device)?;
+    Some(MirrorId::Clone { partition, entries })
+}
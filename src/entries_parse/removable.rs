@@ -0,0 +1,58 @@
+// removable.rs
+// Fallback loader entries for USB sticks and other removable media
+
+use super::{BootEntry, volume_label};
+use crate::arch::FALLBACK_LOADER;
+use crate::volume::{Volume, open_shared};
+use alloc::fmt::format;
+use alloc::vec::Vec;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::{Handle, Result, boot, println};
+
+fn is_removable(handle: Handle) -> bool {
+    open_shared::<BlockIO>(handle)
+        .map(|block| {
+            let media = block.media();
+            media.is_removable_media() && media.is_media_present()
+        })
+        .unwrap_or(false)
+}
+
+/// Connects drivers to all removable block devices, so that the partitions
+/// and file systems on them show up as handles.
+pub fn connect_removable_media() -> Result<()> {
+    for handle in boot::find_handles::<BlockIO>()? {
+        if is_removable(handle) {
+            let _ = boot::connect_controller(handle, None, None, true);
+        }
+    }
+    Ok(())
+}
+
+/// Returns a chainload entry for the fallback loader of every removable
+/// volume other than the one the loader itself was started from.
+pub fn removable_media_entries(esp: Handle) -> Result<Vec<BootEntry>> {
+    let mut entries = Vec::new();
+    for handle in boot::find_handles::<SimpleFileSystem>()? {
+        if handle == esp || !is_removable(handle) {
+            continue;
+        }
+        let found = Volume::open(handle).and_then(|mut volume| volume.exists(FALLBACK_LOADER));
+        match found {
+            Ok(true) => {
+                let label = volume_label(handle);
+                println!("Found removable media: {}", label);
+                entries.push(BootEntry {
+                    title: format(format_args!("Boot from removable media: {}", label)),
+                    efi: Some(FALLBACK_LOADER.into()),
+                    device: Some(handle),
+                    ..BootEntry::new()
+                });
+            }
+            Ok(false) => (),
+            Err(e) => println!("Failed to probe removable media: {:?}", e.status()),
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,927 @@
+// ext4.rs
+// Read-only ext4 reader on top of DiskIO, for /boot partitions the firmware can't read
+
+mod crc32c;
+mod htree;
+
+use crate::volume::{DirEntry, open_shared};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use crc32c::crc32c;
+use uefi::boot::ScopedProtocol;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::disk::DiskIo;
+use uefi::{Handle, Result, Status, println};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+
+const INODE_FLAG_INDEX: u32 = 0x1000;
+const INODE_FLAG_EXTENTS: u32 = 0x8_0000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const DIRENT_TAIL_FILE_TYPE: u8 = 0xde;
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn corrupted(what: &str) -> uefi::Error {
+    println!("ext4: {}", what);
+    uefi::Error::new(Status::VOLUME_CORRUPTED, ())
+}
+
+/// Byte addressed, read-only access to the device holding the file system.
+pub trait Disk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result;
+}
+
+/// A partition accessed through the firmware's `DiskIo` protocol.
+pub struct UefiDisk {
+    disk: ScopedProtocol<DiskIo>,
+    media_id: u32,
+    size: u64,
+}
+
+impl UefiDisk {
+    pub fn open(handle: Handle) -> Result<Self> {
+        let block = open_shared::<BlockIO>(handle)?;
+        let media = block.media();
+        let size = (media.last_block() + 1) * media.block_size() as u64;
+        let disk = open_shared::<DiskIo>(handle)?;
+        Ok(UefiDisk {
+            disk,
+            media_id: media.media_id(),
+            size,
+        })
+    }
+
+    /// Size of the partition in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Disk for UefiDisk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result {
+        self.disk.read_disk(self.media_id, offset, buf)
+    }
+}
+
+/// The parts of an on-disk inode the reader needs.
+#[derive(Debug, Clone)]
+struct Inode {
+    mode: u16,
+    flags: u32,
+    size: u64,
+    block: [u8; 60],
+    csum_seed: u32,
+}
+
+impl Inode {
+    fn kind(&self) -> u16 {
+        self.mode & S_IFMT
+    }
+}
+
+/// A mounted, read-only ext2/3/4 file system.
+pub struct Ext4<D: Disk> {
+    disk: D,
+    block_size: usize,
+    inodes_per_group: u32,
+    inode_size: usize,
+    desc_size: usize,
+    group_count: u32,
+    blocks_count: u64,
+    first_data_block: u64,
+    incompat: u32,
+    metadata_csum: bool,
+    csum_seed: u32,
+    hash_seed: [u32; 4],
+    unsigned_hash: bool,
+    label: String,
+}
+
+impl Ext4<UefiDisk> {
+    /// Mounts the ext4 file system on `handle`, `None` if it holds something else.
+    pub fn probe(handle: Handle) -> Result<Option<Self>> {
+        let disk = UefiDisk::open(handle)?;
+        Ext4::mount(disk)
+    }
+}
+
+impl<D: Disk> Ext4<D> {
+    /// Reads and validates the superblock and group descriptor layout.
+    pub fn mount(disk: D) -> Result<Option<Self>> {
+        let mut sb = [0u8; 1024];
+        disk.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if le16(&sb, 56) != EXT4_MAGIC {
+            return Ok(None);
+        }
+
+        let incompat = le32(&sb, 96);
+        let ro_compat = le32(&sb, 100);
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            println!("ext4: unsupported incompatible features {:#x}", incompat & !INCOMPAT_SUPPORTED);
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            println!("ext4: journal needs recovery, reading possibly stale data");
+        }
+
+        let metadata_csum = ro_compat & RO_COMPAT_METADATA_CSUM != 0;
+        if metadata_csum {
+            if sb[0x175] != 1 {
+                return Err(corrupted("unknown superblock checksum type"));
+            }
+            if crc32c(!0, &sb[..0x3fc]) != le32(&sb, 0x3fc) {
+                return Err(corrupted("superblock checksum mismatch"));
+            }
+        }
+        let csum_seed = if incompat & INCOMPAT_CSUM_SEED != 0 {
+            le32(&sb, 0x270)
+        } else {
+            crc32c(!0, &sb[104..120])
+        };
+
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(corrupted("bogus block size"));
+        }
+        let block_size = 1024usize << log_block_size;
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        let rev_level = le32(&sb, 76);
+        let inode_size = if rev_level == 0 { 128 } else { le16(&sb, 88) as usize };
+        let desc_size = if incompat & INCOMPAT_64BIT != 0 {
+            le16(&sb, 0xfe) as usize
+        } else {
+            32
+        };
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < 128
+            || inode_size > block_size
+            || !inode_size.is_power_of_two()
+            || desc_size < 32
+        {
+            return Err(corrupted("bogus geometry"));
+        }
+
+        let mut blocks_count = le32(&sb, 4) as u64;
+        if incompat & INCOMPAT_64BIT != 0 {
+            blocks_count |= (le32(&sb, 0x150) as u64) << 32;
+        }
+        let first_data_block = le32(&sb, 20) as u64;
+        let group_count = blocks_count
+            .checked_sub(first_data_block)
+            .ok_or_else(|| corrupted("bogus geometry"))?
+            .div_ceil(blocks_per_group as u64) as u32;
+
+        let mut hash_seed = [0u32; 4];
+        for (i, seed) in hash_seed.iter_mut().enumerate() {
+            *seed = le32(&sb, 0xec + i * 4);
+        }
+        let label = String::from_utf8_lossy(&sb[120..136])
+            .trim_end_matches('\0')
+            .to_string();
+
+        Ok(Some(Ext4 {
+            disk,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            group_count,
+            blocks_count,
+            first_data_block,
+            incompat,
+            metadata_csum,
+            csum_seed,
+            hash_seed,
+            unsigned_hash: le32(&sb, 0x160) & FLAGS_UNSIGNED_HASH != 0,
+            label,
+        }))
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result {
+        self.disk.read_at(block * self.block_size as u64, buf)
+    }
+
+    fn inode_table(&self, group: u32) -> Result<u64> {
+        if group >= self.group_count {
+            return Err(corrupted("inode group out of range"));
+        }
+        let gdt_start = (self.first_data_block + 1) * self.block_size as u64;
+        let mut desc = vec![0u8; self.desc_size];
+        self.disk
+            .read_at(gdt_start + group as u64 * self.desc_size as u64, &mut desc)?;
+
+        if self.metadata_csum {
+            let mut crc = crc32c(self.csum_seed, &group.to_le_bytes());
+            crc = crc32c(crc, &desc[..0x1e]);
+            crc = crc32c(crc, &[0, 0]);
+            crc = crc32c(crc, &desc[0x20..]);
+            if (crc & 0xffff) as u16 != le16(&desc, 0x1e) {
+                return Err(corrupted("group descriptor checksum mismatch"));
+            }
+        }
+
+        let mut table = le32(&desc, 8) as u64;
+        if self.incompat & INCOMPAT_64BIT != 0 && self.desc_size >= 64 {
+            table |= (le32(&desc, 0x28) as u64) << 32;
+        }
+        Ok(table)
+    }
+
+    fn read_inode(&self, number: u32) -> Result<Inode> {
+        if number == 0 {
+            return Err(corrupted("inode 0 referenced"));
+        }
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        let offset = self.inode_table(group)? * self.block_size as u64
+            + index as u64 * self.inode_size as u64;
+        let mut raw = vec![0u8; self.inode_size];
+        self.disk.read_at(offset, &mut raw)?;
+
+        let generation = le32(&raw, 0x64);
+        let mut csum_seed = crc32c(self.csum_seed, &number.to_le_bytes());
+        csum_seed = crc32c(csum_seed, &generation.to_le_bytes());
+
+        if self.metadata_csum {
+            let extra_isize = if self.inode_size >= 0x82 { le16(&raw, 0x80) as usize } else { 0 };
+            // The high half of the checksum only exists if the extra fields
+            // reach it and the on-disk inode is big enough to hold it
+            let has_hi = extra_isize >= 4 && self.inode_size >= 0x84;
+            let stored = le16(&raw, 0x7c) as u32 | if has_hi { (le16(&raw, 0x82) as u32) << 16 } else { 0 };
+            let mut copy = raw.clone();
+            copy[0x7c..0x7e].fill(0);
+            if has_hi {
+                copy[0x82..0x84].fill(0);
+            }
+            let crc = crc32c(csum_seed, &copy);
+            let crc = if has_hi { crc } else { crc & 0xffff };
+            if crc != stored {
+                return Err(corrupted("inode checksum mismatch"));
+            }
+        }
+
+        let mut block = [0u8; 60];
+        block.copy_from_slice(&raw[0x28..0x28 + 60]);
+        Ok(Inode {
+            mode: le16(&raw, 0),
+            flags: le32(&raw, 0x20),
+            size: le32(&raw, 4) as u64 | (le32(&raw, 0x6c) as u64) << 32,
+            block,
+            csum_seed,
+        })
+    }
+
+    /// Verifies the checksum tail of an extent tree block.
+    fn check_extent_block(&self, inode: &Inode, block: &[u8]) -> Result {
+        if !self.metadata_csum {
+            return Ok(());
+        }
+        let max = le16(block, 4) as usize;
+        let tail = 12 + 12 * max;
+        if tail + 4 > block.len() {
+            return Err(corrupted("extent block too small for its tail"));
+        }
+        if crc32c(inode.csum_seed, &block[..tail]) != le32(block, tail) {
+            return Err(corrupted("extent block checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    /// Maps the file's logical blocks to `(logical, physical, length,
+    /// initialized)` runs, sorted by logical block.
+    fn extents(&self, inode: &Inode) -> Result<Vec<(u64, u64, u64, bool)>> {
+        let mut runs = Vec::new();
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.walk_extents(inode, &inode.block, 0, &mut runs)?;
+        } else {
+            self.walk_block_map(inode, &mut runs)?;
+        }
+        runs.sort_by_key(|r| r.0);
+        Ok(runs)
+    }
+
+    fn walk_extents(
+        &self,
+        inode: &Inode,
+        node: &[u8],
+        level: usize,
+        runs: &mut Vec<(u64, u64, u64, bool)>,
+    ) -> Result {
+        if le16(node, 0) != EXTENT_MAGIC || level > 5 {
+            return Err(corrupted("bad extent header"));
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if 12 + entries * 12 > node.len() {
+            return Err(corrupted("extent header overflows its block"));
+        }
+        for i in 0..entries {
+            let e = &node[12 + i * 12..24 + i * 12];
+            if depth == 0 {
+                let logical = le32(e, 0) as u64;
+                let raw_len = le16(e, 4) as u64;
+                let (len, initialized) = if raw_len > 32768 {
+                    (raw_len - 32768, false)
+                } else {
+                    (raw_len, true)
+                };
+                let physical = (le16(e, 6) as u64) << 32 | le32(e, 8) as u64;
+                runs.push((logical, physical, len, initialized));
+            } else {
+                let child = (le16(e, 8) as u64) << 32 | le32(e, 4) as u64;
+                let mut block = vec![0u8; self.block_size];
+                self.read_block(child, &mut block)?;
+                self.check_extent_block(inode, &block)?;
+                self.walk_extents(inode, &block, level + 1, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// ext2/3 style direct and indirect block maps.
+    fn walk_block_map(&self, inode: &Inode, runs: &mut Vec<(u64, u64, u64, bool)>) -> Result {
+        let per_block = (self.block_size / 4) as u64;
+        let total = inode.size.div_ceil(self.block_size as u64);
+        let mut push = |logical: u64, physical: u64| {
+            if physical == 0 {
+                return;
+            }
+            match runs.last_mut() {
+                Some(last) if last.0 + last.2 == logical && last.1 + last.2 == physical => last.2 += 1,
+                _ => runs.push((logical, physical, 1, true)),
+            }
+        };
+        for i in 0..12u64.min(total) {
+            push(i, le32(&inode.block, i as usize * 4) as u64);
+        }
+
+        let mut logical = 12u64;
+        for level in 1..=3u32 {
+            if logical >= total {
+                break;
+            }
+            let root = le32(&inode.block, (11 + level as usize) * 4) as u64;
+            let span = per_block.pow(level);
+            if root != 0 {
+                self.walk_indirect(root, level, logical, total, &mut push)?;
+            }
+            logical += span;
+        }
+        Ok(())
+    }
+
+    fn walk_indirect(
+        &self,
+        block: u64,
+        level: u32,
+        first: u64,
+        total: u64,
+        push: &mut impl FnMut(u64, u64),
+    ) -> Result {
+        let per_block = (self.block_size / 4) as u64;
+        let span = per_block.pow(level - 1);
+        let mut buf = vec![0u8; self.block_size];
+        self.read_block(block, &mut buf)?;
+        for i in 0..per_block {
+            let logical = first + i * span;
+            if logical >= total {
+                break;
+            }
+            let child = le32(&buf, i as usize * 4) as u64;
+            if child == 0 {
+                continue;
+            }
+            if level == 1 {
+                push(logical, child);
+            } else {
+                self.walk_indirect(child, level - 1, logical, total, push)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the whole contents of `inode`. Holes and uninitialized extents
+    /// read as zeros; a size beyond the last mapped block is refused rather
+    /// than allocated.
+    fn read_inode_data(&self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            println!("ext4: inline data is not supported");
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+        let bs = self.block_size as u64;
+        let runs = self.extents(inode)?;
+        // Blocks past the last extent are a sparse tail and read as zeros, but
+        // no file we boot from is bigger than the file system holding it.
+        if inode.size > self.blocks_count.saturating_mul(bs) {
+            return Err(corrupted("file size beyond the file system"));
+        }
+        let mut data = vec![0u8; inode.size as usize];
+        for (logical, physical, len, initialized) in runs {
+            let Some(start) = logical.checked_mul(bs).filter(|&start| start < inode.size) else {
+                continue;
+            };
+            if !initialized {
+                continue;
+            }
+            let end = (logical + len).saturating_mul(bs).min(inode.size);
+            let offset = physical
+                .checked_mul(bs)
+                .ok_or_else(|| corrupted("extent beyond the disk"))?;
+            self.disk
+                .read_at(offset, &mut data[start as usize..end as usize])?;
+        }
+        Ok(data)
+    }
+
+    /// Reads one logical block of a directory.
+    fn read_dir_block(&self, inode: &Inode, logical: u64) -> Result<Option<Vec<u8>>> {
+        for (first, physical, len, initialized) in self.extents(inode)? {
+            if logical >= first && logical < first + len {
+                let mut block = vec![0u8; self.block_size];
+                if initialized {
+                    self.read_block(physical + (logical - first), &mut block)?;
+                }
+                return Ok(Some(block));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Verifies a leaf directory block's checksum tail, if it has one.
+    fn check_dir_leaf(&self, inode: &Inode, block: &[u8]) -> Result {
+        if !self.metadata_csum {
+            return Ok(());
+        }
+        let tail = block.len() - 12;
+        let is_tail = le32(block, tail) == 0
+            && le16(block, tail + 4) == 12
+            && block[tail + 6] == 0
+            && block[tail + 7] == DIRENT_TAIL_FILE_TYPE;
+        if !is_tail {
+            // htree interior blocks carry a dx_tail instead
+            return Ok(());
+        }
+        if crc32c(inode.csum_seed, &block[..tail]) != le32(block, tail + 8) {
+            return Err(corrupted("directory block checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    /// Calls `f(name, inode, file_type)` for every live entry of a leaf block.
+    fn for_each_dirent(block: &[u8], mut f: impl FnMut(&[u8], u32, u8) -> bool) -> Result<bool> {
+        let mut off = 0;
+        while off + 8 <= block.len() {
+            let ino = le32(block, off);
+            let rec_len = le16(block, off + 4) as usize;
+            let name_len = block[off + 6] as usize;
+            if rec_len < 8 || off + rec_len > block.len() || 8 + name_len > rec_len {
+                return Err(corrupted("bad directory entry"));
+            }
+            if ino != 0 && name_len > 0 && f(&block[off + 8..off + 8 + name_len], ino, block[off + 7]) {
+                return Ok(true);
+            }
+            off += rec_len;
+        }
+        Ok(false)
+    }
+
+    fn is_htree(&self, dir: &Inode) -> bool {
+        dir.flags & INODE_FLAG_INDEX != 0 && dir.size > self.block_size as u64
+    }
+
+    /// Walks the htree index of `dir` and returns the leaf blocks that may
+    /// contain `name`, or `None` if the index can't be used.
+    fn htree_leaves(&self, dir: &Inode, name: &[u8]) -> Result<Option<Vec<u64>>> {
+        let Some(root) = self.read_dir_block(dir, 0)? else {
+            return Ok(None);
+        };
+        let info_len = root[0x1d] as usize;
+        let mut levels = root[0x1e] as usize;
+        let mut version = root[0x1c];
+        if self.unsigned_hash && version <= htree::DX_HASH_TEA {
+            version += 3;
+        }
+        let Some(hash) = htree::dirhash(name, version, &self.hash_seed) else {
+            return Ok(None);
+        };
+
+        let mut node = root;
+        let mut entries_off = 0x18 + info_len;
+        loop {
+            let count = le16(&node, entries_off + 2) as usize;
+            if count == 0 || entries_off + count * 8 > node.len() {
+                return Err(corrupted("bad htree node"));
+            }
+            // Entry 0 has an implicit hash of 0; find the last entry <= hash.
+            let mut pick = 0;
+            for i in 1..count {
+                if le32(&node, entries_off + i * 8) > hash {
+                    break;
+                }
+                pick = i;
+            }
+            let block = le32(&node, entries_off + pick * 8 + 4) as u64;
+            if levels == 0 {
+                let mut leaves = vec![block];
+                // Names with colliding hashes continue in the following
+                // leaves, which are marked with the low hash bit.
+                for i in pick + 1..count {
+                    let next = le32(&node, entries_off + i * 8);
+                    if next & 1 == 0 || next & !1 != hash {
+                        break;
+                    }
+                    leaves.push(le32(&node, entries_off + i * 8 + 4) as u64);
+                }
+                return Ok(Some(leaves));
+            }
+            levels -= 1;
+            node = match self.read_dir_block(dir, block)? {
+                Some(node) => node,
+                None => return Err(corrupted("htree points past the directory")),
+            };
+            entries_off = 8;
+        }
+    }
+
+    fn lookup_in(&self, dir: &Inode, name: &[u8]) -> Result<Option<u32>> {
+        let blocks: Vec<u64> = match self.is_htree(dir) {
+            true => match self.htree_leaves(dir, name)? {
+                Some(leaves) => leaves,
+                None => (0..dir.size.div_ceil(self.block_size as u64)).collect(),
+            },
+            false => (0..dir.size.div_ceil(self.block_size as u64)).collect(),
+        };
+        for logical in blocks {
+            let Some(block) = self.read_dir_block(dir, logical)? else {
+                continue;
+            };
+            self.check_dir_leaf(dir, &block)?;
+            let mut found = None;
+            Self::for_each_dirent(&block, |entry, ino, _| {
+                if entry == name {
+                    found = Some(ino);
+                    true
+                } else {
+                    false
+                }
+            })?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_link(&self, inode: &Inode) -> Result<String> {
+        // Fast symlinks keep the target in the block map area itself.
+        let target = if inode.flags & INODE_FLAG_EXTENTS == 0 && inode.size < 60 {
+            inode.block[..inode.size as usize].to_vec()
+        } else {
+            self.read_inode_data(inode)?
+        };
+        String::from_utf8(target).map_err(|_| corrupted("symlink target is not UTF-8"))
+    }
+
+    /// Resolves a path from the root directory, following symlinks.
+    fn lookup(&self, components: &[&str]) -> Result<Inode> {
+        let mut pending: Vec<String> = components.iter().rev().map(|c| c.to_string()).collect();
+        let mut dirs = vec![ROOT_INODE];
+        let mut inode = self.read_inode(ROOT_INODE)?;
+        let mut links = 0;
+        while let Some(component) = pending.pop() {
+            match component.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    if dirs.len() > 1 {
+                        dirs.pop();
+                    }
+                    inode = self.read_inode(*dirs.last().unwrap())?;
+                    continue;
+                }
+                _ => (),
+            }
+            if inode.kind() != S_IFDIR {
+                return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+            }
+            let Some(number) = self.lookup_in(&inode, component.as_bytes())? else {
+                return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+            };
+            let next = self.read_inode(number)?;
+            if next.kind() == S_IFLNK {
+                links += 1;
+                if links > MAX_SYMLINK_DEPTH {
+                    return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+                }
+                let target = self.read_link(&next)?;
+                if target.starts_with('/') {
+                    dirs.truncate(1);
+                    inode = self.read_inode(ROOT_INODE)?;
+                }
+                pending.extend(target.split('/').rev().map(|c| c.to_string()));
+                continue;
+            }
+            dirs.push(number);
+            inode = next;
+        }
+        Ok(inode)
+    }
+
+    /// True if `components` names a regular file.
+    pub fn exists(&self, components: &[&str]) -> Result<bool> {
+        match self.lookup(components) {
+            Ok(inode) => Ok(inode.kind() == S_IFREG),
+            Err(e) if e.status() == Status::NOT_FOUND => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads a whole regular file.
+    pub fn read(&self, components: &[&str]) -> Result<Vec<u8>> {
+        let inode = self.lookup(components)?;
+        if inode.kind() != S_IFREG {
+            return Err(uefi::Error::new(Status::INVALID_PARAMETER, ()));
+        }
+        self.read_inode_data(&inode)
+    }
+
+    /// Lists a directory, `None` if it doesn't exist.
+    pub fn read_dir(&self, components: &[&str]) -> Result<Option<Vec<DirEntry>>> {
+        let dir = match self.lookup(components) {
+            Ok(inode) if inode.kind() == S_IFDIR => inode,
+            Ok(_) => return Ok(None),
+            Err(e) if e.status() == Status::NOT_FOUND => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut raw = Vec::new();
+        for logical in 0..dir.size.div_ceil(self.block_size as u64) {
+            let Some(block) = self.read_dir_block(&dir, logical)? else {
+                continue;
+            };
+            // htree index blocks parse as "." and ".." or a single empty
+            // entry spanning the block, so a linear walk sees only leaves.
+            self.check_dir_leaf(&dir, &block)?;
+            Self::for_each_dirent(&block, |name, ino, _| {
+                raw.push((String::from_utf8_lossy(name).to_string(), ino));
+                false
+            })?;
+        }
+
+        let mut entries = Vec::new();
+        for (name, ino) in raw {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let mut inode = self.read_inode(ino)?;
+            if inode.kind() == S_IFLNK {
+                let mut path = components.to_vec();
+                path.push(name.as_str());
+                match self.lookup(&path) {
+                    Ok(target) => inode = target,
+                    Err(_) => continue,
+                }
+            }
+            entries.push(DirEntry {
+                name,
+                is_dir: inode.kind() == S_IFDIR,
+                size: inode.size,
+            });
+        }
+        Ok(Some(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Images made by testdata/make-ext.sh: a 1 KiB block ext4 with 256 byte
+    /// inodes, metadata_csum and an htree indexed `many`, and an ext2 with
+    /// 128 byte inodes and block maps, both holding the same files.
+    const EXT4: &[u8] = include_bytes!("../testdata/ext4.img.zz");
+    const EXT2: &[u8] = include_bytes!("../testdata/ext2.img.zz");
+
+    struct Image(Vec<u8>);
+
+    impl Disk for Image {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result {
+            let start = usize::try_from(offset).unwrap_or(usize::MAX);
+            let data = start
+                .checked_add(buf.len())
+                .and_then(|end| self.0.get(start..end))
+                .ok_or_else(|| uefi::Error::new(Status::DEVICE_ERROR, ()))?;
+            buf.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn image(compressed: &[u8]) -> Vec<u8> {
+        miniz_oxide::inflate::decompress_to_vec_zlib(compressed).unwrap()
+    }
+
+    fn mount(image: Vec<u8>) -> Result<Option<Ext4<Image>>> {
+        Ext4::mount(Image(image))
+    }
+
+    fn kernel() -> Vec<u8> {
+        (0..300000usize).map(|i| ((i * 7 + i / 251) % 256) as u8).collect()
+    }
+
+    /// Byte offset of the inode of `path` in `image`.
+    fn inode_offset(image: &[u8], path: &[&str]) -> usize {
+        let fs = mount(image.to_vec()).unwrap().unwrap();
+        let (name, dir) = path.split_last().unwrap();
+        let dir = fs.lookup(dir).unwrap();
+        let number = fs.lookup_in(&dir, name.as_bytes()).unwrap().unwrap();
+        let group = (number - 1) / fs.inodes_per_group;
+        let index = (number - 1) % fs.inodes_per_group;
+        fs.inode_table(group).unwrap() as usize * fs.block_size + index as usize * fs.inode_size
+    }
+
+    fn check_files(fs: &Ext4<Image>) {
+        let conf = b"title Arch Linux\nlinux /vmlinuz-linux\n";
+        assert_eq!(fs.read(&["loader", "entries", "arch.conf"]).unwrap(), conf);
+        assert_eq!(fs.read(&["vmlinuz-linux"]).unwrap(), kernel());
+        // Fast, absolute and slow symlinks, and ".."
+        assert_eq!(fs.read(&["vmlinuz"]).unwrap(), kernel());
+        assert_eq!(fs.read(&["boot", "entries", "arch.conf"]).unwrap(), conf);
+        assert_eq!(fs.read(&["long"]).unwrap(), conf);
+        assert_eq!(fs.read(&["loader", "..", "loader", "entries", "arch.conf"]).unwrap(), conf);
+        // The hole reads as zeros
+        let sparse = fs.read(&["sparse"]).unwrap();
+        assert_eq!(sparse.len(), 20 * 1024 + 4);
+        assert_eq!(&sparse[..4], b"head");
+        assert!(sparse[4..20 * 1024].iter().all(|&b| b == 0));
+        assert_eq!(&sparse[20 * 1024..], b"tail");
+
+        assert!(fs.exists(&["many", "file-1"]).unwrap());
+        assert!(!fs.exists(&["many"]).unwrap());
+        assert!(!fs.exists(&["missing"]).unwrap());
+        assert!(!fs.exists(&["vmlinuz-linux", "x"]).unwrap());
+        assert_eq!(fs.read(&["missing"]).unwrap_err().status(), Status::NOT_FOUND);
+        assert_eq!(fs.read(&["loader"]).unwrap_err().status(), Status::INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn reads_ext4() {
+        let fs = mount(image(EXT4)).unwrap().unwrap();
+        assert!(fs.metadata_csum && fs.group_count == 2);
+        assert_eq!(fs.label(), "boot");
+        check_files(&fs);
+    }
+
+    #[test]
+    fn reads_ext2() {
+        let fs = mount(image(EXT2)).unwrap().unwrap();
+        assert!(!fs.metadata_csum);
+        assert_eq!(fs.label(), "");
+        check_files(&fs);
+    }
+
+    #[test]
+    fn reads_sparse_tails() {
+        // A file extended past its last block, as `truncate -s` leaves it
+        let conf = b"title Arch Linux\nlinux /vmlinuz-linux\n";
+        let mut data = image(EXT2);
+        let offset = inode_offset(&data, &["loader", "entries", "arch.conf"]);
+        data[offset + 4..offset + 8].copy_from_slice(&10000u32.to_le_bytes());
+        let fs = mount(data).unwrap().unwrap();
+        let file = fs.read(&["loader", "entries", "arch.conf"]).unwrap();
+        assert_eq!(file.len(), 10000);
+        assert_eq!(&file[..conf.len()], conf);
+        assert!(file[conf.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn lists_directories() {
+        let fs = mount(image(EXT4)).unwrap().unwrap();
+        let root = fs.read_dir(&[]).unwrap().unwrap();
+        let kernel = root.iter().find(|entry| entry.name == "vmlinuz").unwrap();
+        assert!(!kernel.is_dir && kernel.size == 300000);
+        assert!(root.iter().any(|entry| entry.name == "boot" && entry.is_dir));
+        assert!(fs.read_dir(&["missing"]).unwrap().is_none());
+        assert!(fs.read_dir(&["vmlinuz"]).unwrap().is_none());
+
+        // Every name of the indexed directory is found through the htree
+        let dir = fs.lookup(&["many"]).unwrap();
+        assert!(fs.is_htree(&dir));
+        let mut names: Vec<_> = fs.read_dir(&["many"]).unwrap().unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        let mut expected: Vec<_> = (1..=300).map(|i| format!("file-{}", i)).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+        for name in &names {
+            assert!(fs.htree_leaves(&dir, name.as_bytes()).unwrap().is_some());
+            assert!(fs.exists(&["many", name]).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn ignores_other_file_systems() {
+        assert!(mount(vec![0; 4096]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_corrupted_superblocks() {
+        let status = |image: Vec<u8>| mount(image).err().map(|e| e.status());
+
+        let mut label = image(EXT4);
+        label[1024 + 120] ^= 1;
+        assert_eq!(status(label), Some(Status::VOLUME_CORRUPTED));
+
+        // Without metadata_csum, so the geometry checks see the changes
+        let mut block_size = image(EXT2);
+        block_size[1024 + 24] = 7;
+        assert_eq!(status(block_size), Some(Status::VOLUME_CORRUPTED));
+        let mut inode_size = image(EXT2);
+        inode_size[1024 + 88] = 200;
+        assert_eq!(status(inode_size), Some(Status::VOLUME_CORRUPTED));
+        let mut blocks = image(EXT2);
+        blocks[1024 + 4..1024 + 8].fill(0);
+        assert_eq!(status(blocks), Some(Status::VOLUME_CORRUPTED));
+        let mut incompat = image(EXT2);
+        incompat[1024 + 98] |= 0x80;
+        assert_eq!(status(incompat), Some(Status::UNSUPPORTED));
+        assert_eq!(status(image(EXT2)[..1500].to_vec()), Some(Status::DEVICE_ERROR));
+    }
+
+    #[test]
+    fn rejects_corrupted_inodes() {
+        let mut data = image(EXT4);
+        let offset = inode_offset(&data, &["loader", "entries", "arch.conf"]);
+        data[offset + 0x20] ^= 0x40;
+        let fs = mount(data).unwrap().unwrap();
+        assert_eq!(fs.read(&["loader", "entries", "arch.conf"]).unwrap_err().status(), Status::VOLUME_CORRUPTED);
+        assert!(fs.read(&["vmlinuz-linux"]).is_ok());
+
+        // A size past the end of the file system isn't allocated
+        let mut data = image(EXT2);
+        let offset = inode_offset(&data, &["vmlinuz-linux"]);
+        data[offset + 4..offset + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[offset + 0x6c..offset + 0x70].copy_from_slice(&u32::MAX.to_le_bytes());
+        let fs = mount(data).unwrap().unwrap();
+        assert_eq!(fs.read(&["vmlinuz-linux"]).unwrap_err().status(), Status::VOLUME_CORRUPTED);
+
+        // An extent header that isn't one
+        let mut data = image(EXT4);
+        let offset = inode_offset(&data, &["vmlinuz-linux"]);
+        data[offset + 0x28] = 0;
+        let fs = mount(data).unwrap().unwrap();
+        assert!(fs.read(&["vmlinuz-linux"]).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupted_directories() {
+        let mut data = image(EXT2);
+        let fs = mount(data.clone()).unwrap().unwrap();
+        let dir = fs.lookup(&["loader", "entries"]).unwrap();
+        let block = fs.extents(&dir).unwrap()[0].1 as usize * fs.block_size;
+        // rec_len of "." past the block
+        data[block + 4..block + 6].copy_from_slice(&2000u16.to_le_bytes());
+        let fs = mount(data).unwrap().unwrap();
+        assert_eq!(fs.read(&["loader", "entries", "arch.conf"]).unwrap_err().status(), Status::VOLUME_CORRUPTED);
+        assert!(fs.read_dir(&["loader", "entries"]).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+// htree.rs
+// Directory index hashes (legacy, half-MD4, TEA), ported from fs/ext4/hash.c
+
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+pub const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+const EXT4_HTREE_EOF_32BIT: u32 = 0x7fff_ffff;
+
+/// Characters are sign-extended on the "signed" hash variants, matching
+/// what a `char` was on the machine that created the file system.
+fn char_value(c: u8, signed: bool) -> u32 {
+    if signed { c as i8 as i32 as u32 } else { c as u32 }
+}
+
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3_fe2du32, 0x37ab_e8f9u32);
+    for &c in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(c, signed).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+fn str2hashbuf(msg: &[u8], buf: &mut [u32], signed: bool) {
+    let mut num = buf.len() as i32;
+    let mut pad = msg.len() as u32 | ((msg.len() as u32) << 8);
+    pad |= pad << 16;
+    let mut val = pad;
+    let len = msg.len().min(buf.len() * 4);
+    let mut out = 0;
+    for (i, &c) in msg[..len].iter().enumerate() {
+        val = char_value(c, signed).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[out] = val;
+            out += 1;
+            val = pad;
+            num -= 1;
+        }
+    }
+    num -= 1;
+    if num >= 0 {
+        buf[out] = val;
+        out += 1;
+    }
+    while out < buf.len() {
+        buf[out] = pad;
+        out += 1;
+    }
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K1: u32 = 0;
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let round = |fun: &dyn Fn(u32, u32, u32) -> u32, a: &mut u32, b: u32, c: u32, d: u32, x: u32, s: u32| {
+        *a = a.wrapping_add(fun(b, c, d)).wrapping_add(x).rotate_left(s);
+    };
+    let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+
+    round(&f, &mut a, b, c, d, input[0].wrapping_add(K1), 3);
+    round(&f, &mut d, a, b, c, input[1].wrapping_add(K1), 7);
+    round(&f, &mut c, d, a, b, input[2].wrapping_add(K1), 11);
+    round(&f, &mut b, c, d, a, input[3].wrapping_add(K1), 19);
+    round(&f, &mut a, b, c, d, input[4].wrapping_add(K1), 3);
+    round(&f, &mut d, a, b, c, input[5].wrapping_add(K1), 7);
+    round(&f, &mut c, d, a, b, input[6].wrapping_add(K1), 11);
+    round(&f, &mut b, c, d, a, input[7].wrapping_add(K1), 19);
+
+    round(&g, &mut a, b, c, d, input[1].wrapping_add(K2), 3);
+    round(&g, &mut d, a, b, c, input[3].wrapping_add(K2), 5);
+    round(&g, &mut c, d, a, b, input[5].wrapping_add(K2), 9);
+    round(&g, &mut b, c, d, a, input[7].wrapping_add(K2), 13);
+    round(&g, &mut a, b, c, d, input[0].wrapping_add(K2), 3);
+    round(&g, &mut d, a, b, c, input[2].wrapping_add(K2), 5);
+    round(&g, &mut c, d, a, b, input[4].wrapping_add(K2), 9);
+    round(&g, &mut b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round(&h, &mut a, b, c, d, input[3].wrapping_add(K3), 3);
+    round(&h, &mut d, a, b, c, input[7].wrapping_add(K3), 9);
+    round(&h, &mut c, d, a, b, input[2].wrapping_add(K3), 11);
+    round(&h, &mut b, c, d, a, input[6].wrapping_add(K3), 15);
+    round(&h, &mut a, b, c, d, input[1].wrapping_add(K3), 3);
+    round(&h, &mut d, a, b, c, input[5].wrapping_add(K3), 9);
+    round(&h, &mut c, d, a, b, input[0].wrapping_add(K3), 11);
+    round(&h, &mut b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9e37_79b9;
+    let mut sum = 0u32;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ b1.wrapping_add(sum) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ b0.wrapping_add(sum) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// Major hash of `name` for the given hash version and superblock seed.
+/// Returns `None` for versions this reader doesn't know (e.g. siphash for
+/// casefolded directories); callers then fall back to a linear scan.
+pub fn dirhash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<u32> {
+    let mut buf = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    if seed.iter().any(|&s| s != 0) {
+        buf = *seed;
+    }
+
+    let hash = match version {
+        DX_HASH_LEGACY => dx_hack_hash(name, true),
+        DX_HASH_LEGACY_UNSIGNED => dx_hack_hash(name, false),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let signed = version == DX_HASH_HALF_MD4;
+            let mut input = [0u32; 8];
+            let mut p = name;
+            while !p.is_empty() {
+                str2hashbuf(p, &mut input, signed);
+                half_md4_transform(&mut buf, &input);
+                p = &p[p.len().min(32)..];
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let signed = version == DX_HASH_TEA;
+            let mut input = [0u32; 4];
+            let mut p = name;
+            while !p.is_empty() {
+                str2hashbuf(p, &mut input, signed);
+                tea_transform(&mut buf, &input);
+                p = &p[p.len().min(16)..];
+            }
+            buf[0]
+        }
+        _ => return None,
+    };
+
+    let hash = hash & !1;
+    if hash == EXT4_HTREE_EOF_32BIT << 1 {
+        Some((EXT4_HTREE_EOF_32BIT - 1) << 1)
+    } else {
+        Some(hash)
+    }
+}
@@ -0,0 +1,68 @@
+// iso.rs
+// Boots ISO images (rescue media) from memory by chainloading their removable media loader
+
+mod eltorito;
+mod ramdisk;
+
+use alloc::vec::Vec;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::{Handle, Result, Status, boot, println};
+
+use crate::drivers;
+use crate::arch::FALLBACK_LOADER;
+use crate::kernel_loader::load_efi_from_path;
+use crate::volume::{Volume, open_shared};
+
+/// File system handle whose device path starts with `prefix`.
+fn find_volume_under(prefix: &[u8]) -> Result<Option<Handle>> {
+    for handle in boot::find_handles::<SimpleFileSystem>()? {
+        let Ok(path) = open_shared::<DevicePath>(handle) else {
+            continue;
+        };
+        if path.as_bytes().starts_with(prefix) {
+            return Ok(Some(handle));
+        }
+    }
+    Ok(None)
+}
+
+/// Exposes the EFI boot image of `iso` as a file system and returns it.
+/// The firmware's RAM disk support is preferred, as it also makes the
+/// whole ISO visible as a CD; our own El Torito reader is the fallback.
+fn mount_iso(iso: &'static [u8]) -> Result<Handle> {
+    if let Some(ramdisk) = ramdisk::register_cd(iso)? {
+        drivers::connect_all_controllers()?;
+        let prefix = &ramdisk[..ramdisk.len() - 4];
+        if let Some(handle) = find_volume_under(prefix)? {
+            return Ok(handle);
+        }
+        println!("RAM disk has no EFI boot partition, reading El Torito directly");
+        ramdisk::unregister(&ramdisk);
+    }
+
+    let Some(boot_image) = eltorito::install_boot_image(iso)? else {
+        println!("ISO has no EFI boot image");
+        return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+    };
+    find_volume_under(&boot_image)?.ok_or_else(|| {
+        println!("No file system driver for the ISO's EFI boot image");
+        uefi::Error::new(Status::NOT_FOUND, ())
+    })
+}
+
+/// Reads the ISO at `path` on `device` (the loader's own volume if `None`)
+/// into memory and starts its removable media loader.
+pub fn boot_iso(device: Option<Handle>, path: &str, cmdline: Option<&str>) -> Result {
+    let mut volume = match device {
+        Some(handle) => Volume::open(handle)?,
+        None => Volume::loader()?,
+    };
+    println!("Loading ISO image {}", path);
+    // The image has to outlive us: the chainloaded loader reads from it.
+    let iso: &'static [u8] = Vec::leak(volume.read(path)?);
+    println!("{} bytes loaded", iso.len());
+
+    let esp = mount_iso(iso)?;
+    load_efi_from_path(Some(esp), FALLBACK_LOADER, None, cmdline)
+}
@@ -0,0 +1,313 @@
+// eltorito.rs
+// Finds the EFI boot image of an El Torito ISO and serves it as a read-only BlockIO
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::{Guid, Handle, Identify, Result, Status, boot, guid, println};
+use uefi_raw::Boolean;
+use uefi_raw::protocol::block::{BlockIoMedia, BlockIoProtocol, Lba};
+
+const ISO_SECTOR_SIZE: usize = 2048;
+const BOOT_RECORD_SECTOR: usize = 17;
+const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+const PLATFORM_EFI: u8 = 0xef;
+const BOOTABLE: u8 = 0x88;
+/// El Torito counts the boot image in 512 byte "virtual sectors".
+const VIRTUAL_SECTOR_SIZE: usize = 512;
+
+/// Device path node of our El Torito view, so the firmware treats it as a
+/// disk of its own.
+const ISO_BOOT_IMAGE_GUID: Guid = guid!("9b8e2c1d-47a5-4e36-b0f2-5d1c7a3e6f84");
+
+/// Byte range of the EFI boot image (a FAT file system) inside the ISO.
+fn efi_boot_image(iso: &[u8]) -> Option<(usize, usize)> {
+    let record = iso.get(BOOT_RECORD_SECTOR * ISO_SECTOR_SIZE..)?.get(..ISO_SECTOR_SIZE)?;
+    if record[0] != 0 || &record[1..6] != b"CD001" || !record[7..].starts_with(EL_TORITO_ID) {
+        return None;
+    }
+    let catalog_sector = u32::from_le_bytes(record[0x47..0x4b].try_into().unwrap()) as usize;
+    let catalog = iso.get(catalog_sector.checked_mul(ISO_SECTOR_SIZE)?..)?.get(..ISO_SECTOR_SIZE)?;
+    // Validation entry: header ID 1 and the 55 AA key.
+    if catalog[0] != 1 || catalog[30] != 0x55 || catalog[31] != 0xaa {
+        return None;
+    }
+
+    // The default entry follows the validation entry and applies to the
+    // platform named there; further platforms come in sections.
+    let mut platform = catalog[1];
+    let mut entries = catalog[32..].chunks_exact(32);
+    let mut found = None;
+    if let Some(default) = entries.next()
+        && platform == PLATFORM_EFI
+        && default[0] == BOOTABLE
+    {
+        found = Some(default);
+    }
+    while found.is_none() {
+        let header = entries.next()?;
+        if header[0] != 0x90 && header[0] != 0x91 {
+            return None;
+        }
+        platform = header[1];
+        let count = u16::from_le_bytes([header[2], header[3]]) as usize;
+        for _ in 0..count {
+            let entry = entries.next()?;
+            if platform == PLATFORM_EFI && entry[0] == BOOTABLE && found.is_none() {
+                found = Some(entry);
+            }
+        }
+        if header[0] == 0x91 {
+            break;
+        }
+    }
+
+    let entry = found?;
+    let sectors = u16::from_le_bytes([entry[6], entry[7]]) as usize;
+    let start = (u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize).checked_mul(ISO_SECTOR_SIZE)?;
+    let image = iso.get(start..)?;
+    // Many ISOs put 0 or 1 here because the image doesn't fit the 16-bit
+    // field; take the size from the FAT boot sector then.
+    let size = if sectors > 1 {
+        sectors * VIRTUAL_SECTOR_SIZE
+    } else {
+        fat_size(image)?
+    };
+    (start.checked_add(size)? <= iso.len()).then_some((start, size))
+}
+
+/// Size of the FAT file system starting at `image`, from its boot sector.
+fn fat_size(image: &[u8]) -> Option<usize> {
+    let boot_sector = image.get(..512)?;
+    if boot_sector[510] != 0x55 || boot_sector[511] != 0xaa {
+        return None;
+    }
+    let bytes_per_sector = u16::from_le_bytes([boot_sector[11], boot_sector[12]]) as usize;
+    let small = u16::from_le_bytes([boot_sector[19], boot_sector[20]]) as usize;
+    let large = u32::from_le_bytes(boot_sector[32..36].try_into().unwrap()) as usize;
+    let sectors = if small != 0 { small } else { large };
+    Some(sectors * bytes_per_sector)
+}
+
+/// Protocol instance handed to the firmware. `proto` must stay first so the
+/// `this` pointer can be cast back.
+#[repr(C)]
+struct BootImage {
+    proto: BlockIoProtocol,
+    media: BlockIoMedia,
+    data: &'static [u8],
+}
+
+unsafe extern "efiapi" fn reset(_this: *mut BlockIoProtocol, _extended: Boolean) -> Status {
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn read_blocks(
+    this: *const BlockIoProtocol,
+    media_id: u32,
+    lba: Lba,
+    buffer_size: usize,
+    buffer: *mut c_void,
+) -> Status {
+    let image = unsafe { &*(this as *const BootImage) };
+    let block_size = image.media.block_size as usize;
+    if media_id != image.media.media_id {
+        return Status::MEDIA_CHANGED;
+    }
+    if buffer.is_null() || !buffer_size.is_multiple_of(block_size) {
+        return Status::INVALID_PARAMETER;
+    }
+    let Some(source) = usize::try_from(lba)
+        .ok()
+        .and_then(|lba| lba.checked_mul(block_size))
+        .and_then(|start| image.data.get(start..start.checked_add(buffer_size)?))
+    else {
+        return Status::INVALID_PARAMETER;
+    };
+    unsafe { core::ptr::copy_nonoverlapping(source.as_ptr(), buffer as *mut u8, buffer_size) };
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn write_blocks(
+    _this: *mut BlockIoProtocol,
+    _media_id: u32,
+    _lba: Lba,
+    _buffer_size: usize,
+    _buffer: *const c_void,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn flush_blocks(_this: *mut BlockIoProtocol) -> Status {
+    Status::SUCCESS
+}
+
+/// Publishes the EFI boot image of `iso` as a BlockIO device and returns
+/// its device path without the end node. `None` if the ISO has no EFI boot
+/// image.
+pub fn install_boot_image(iso: &'static [u8]) -> Result<Option<Vec<u8>>> {
+    let Some((start, size)) = efi_boot_image(iso) else {
+        return Ok(None);
+    };
+    if size < VIRTUAL_SECTOR_SIZE {
+        return Ok(None);
+    }
+    println!("Using El Torito EFI image ({} bytes)", size);
+
+    let mut storage = Vec::new();
+    DevicePathBuilder::with_vec(&mut storage)
+        .push(&build::media::Vendor {
+            vendor_guid: ISO_BOOT_IMAGE_GUID,
+            vendor_defined_data: &[],
+        })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_| uefi::Error::new(Status::OUT_OF_RESOURCES, ()))?;
+    let device_path: &'static [u8] = storage.leak();
+
+    let image = Box::leak(Box::new(BootImage {
+        proto: BlockIoProtocol {
+            revision: 0x0001_0000,
+            media: core::ptr::null(),
+            reset,
+            read_blocks,
+            write_blocks,
+            flush_blocks,
+        },
+        media: BlockIoMedia {
+            media_id: 0,
+            removable_media: Boolean::FALSE,
+            media_present: Boolean::TRUE,
+            logical_partition: Boolean::TRUE,
+            read_only: Boolean::TRUE,
+            write_caching: Boolean::FALSE,
+            block_size: VIRTUAL_SECTOR_SIZE as u32,
+            io_align: 0,
+            last_block: (size / VIRTUAL_SECTOR_SIZE - 1) as u64,
+            lowest_aligned_lba: 0,
+            logical_blocks_per_physical_block: 0,
+            optimal_transfer_length_granularity: 0,
+        },
+        data: &iso[start..start + size],
+    }));
+    image.proto.media = &image.media;
+
+    let handle: Handle = unsafe {
+        let handle = boot::install_protocol_interface(
+            None,
+            &DevicePath::GUID,
+            device_path.as_ptr() as *const c_void,
+        )?;
+        boot::install_protocol_interface(
+            Some(handle),
+            &BlockIoProtocol::GUID,
+            image as *const BootImage as *const c_void,
+        )?
+    };
+    let _ = boot::connect_controller(handle, None, None, true);
+    Ok(Some(device_path[..device_path.len() - 4].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CATALOG_SECTOR: usize = 19;
+    const IMAGE_SECTOR: usize = 20;
+    /// FAT image of 8 sectors of 512 bytes.
+    const IMAGE_SIZE: usize = 8 * 512;
+
+    /// An ISO with a boot record, a catalog of `entries` after the
+    /// validation entry for `platform`, and a FAT boot image.
+    fn iso(platform: u8, entries: &[[u8; 32]]) -> Vec<u8> {
+        let mut iso = alloc::vec![0u8; IMAGE_SECTOR * ISO_SECTOR_SIZE + IMAGE_SIZE];
+        let record = &mut iso[BOOT_RECORD_SECTOR * ISO_SECTOR_SIZE..];
+        record[1..6].copy_from_slice(b"CD001");
+        record[6] = 1;
+        record[7..7 + EL_TORITO_ID.len()].copy_from_slice(EL_TORITO_ID);
+        record[0x47..0x4b].copy_from_slice(&(CATALOG_SECTOR as u32).to_le_bytes());
+
+        let catalog = &mut iso[CATALOG_SECTOR * ISO_SECTOR_SIZE..];
+        catalog[0] = 1;
+        catalog[1] = platform;
+        catalog[30] = 0x55;
+        catalog[31] = 0xaa;
+        for (i, entry) in entries.iter().enumerate() {
+            catalog[32 * (i + 1)..32 * (i + 2)].copy_from_slice(entry);
+        }
+
+        let boot_sector = &mut iso[IMAGE_SECTOR * ISO_SECTOR_SIZE..];
+        boot_sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        boot_sector[19..21].copy_from_slice(&8u16.to_le_bytes());
+        boot_sector[510] = 0x55;
+        boot_sector[511] = 0xaa;
+        iso
+    }
+
+    fn entry(indicator: u8, sectors: u16, sector: usize) -> [u8; 32] {
+        let mut entry = [0u8; 32];
+        entry[0] = indicator;
+        entry[6..8].copy_from_slice(&sectors.to_le_bytes());
+        entry[8..12].copy_from_slice(&(sector as u32).to_le_bytes());
+        entry
+    }
+
+    fn section(last: bool, platform: u8, count: u16) -> [u8; 32] {
+        let mut header = [0u8; 32];
+        header[0] = if last { 0x91 } else { 0x90 };
+        header[1] = platform;
+        header[2..4].copy_from_slice(&count.to_le_bytes());
+        header
+    }
+
+    const IMAGE: Option<(usize, usize)> = Some((IMAGE_SECTOR * ISO_SECTOR_SIZE, IMAGE_SIZE));
+
+    #[test]
+    fn finds_efi_boot_images() {
+        // EFI default entry, sized by the catalog or by the FAT boot sector
+        let iso_default = iso(PLATFORM_EFI, &[entry(BOOTABLE, 8, IMAGE_SECTOR)]);
+        assert_eq!(efi_boot_image(&iso_default), IMAGE);
+        let iso_fat = iso(PLATFORM_EFI, &[entry(BOOTABLE, 1, IMAGE_SECTOR)]);
+        assert_eq!(efi_boot_image(&iso_fat), IMAGE);
+
+        // BIOS default entry, EFI in the last section
+        let iso_section = iso(0, &[
+            entry(BOOTABLE, 4, 0),
+            section(false, 0, 1),
+            entry(BOOTABLE, 4, 0),
+            section(true, PLATFORM_EFI, 2),
+            entry(0, 4, 0),
+            entry(BOOTABLE, 0, IMAGE_SECTOR),
+        ]);
+        assert_eq!(efi_boot_image(&iso_section), IMAGE);
+    }
+
+    #[test]
+    fn rejects_bad_catalogs() {
+        let good = iso(PLATFORM_EFI, &[entry(BOOTABLE, 8, IMAGE_SECTOR)]);
+
+        // No EFI entry, or sections that never end
+        assert_eq!(efi_boot_image(&iso(0, &[entry(BOOTABLE, 8, IMAGE_SECTOR)])), None);
+        assert_eq!(efi_boot_image(&iso(0, &[entry(BOOTABLE, 4, 0), section(false, 0, 0)])), None);
+        assert_eq!(efi_boot_image(&iso(PLATFORM_EFI, &[entry(0, 8, IMAGE_SECTOR)])), None);
+
+        // Broken boot record and validation entry
+        let mut bad = good.clone();
+        bad[BOOT_RECORD_SECTOR * ISO_SECTOR_SIZE + 1] = b'X';
+        assert_eq!(efi_boot_image(&bad), None);
+        let mut bad = good.clone();
+        bad[CATALOG_SECTOR * ISO_SECTOR_SIZE + 31] = 0;
+        assert_eq!(efi_boot_image(&bad), None);
+
+        // Catalog and image beyond the end of the ISO
+        let mut bad = good.clone();
+        bad[BOOT_RECORD_SECTOR * ISO_SECTOR_SIZE + 0x47..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(efi_boot_image(&bad), None);
+        assert_eq!(efi_boot_image(&good[..CATALOG_SECTOR * ISO_SECTOR_SIZE + 100]), None);
+        assert_eq!(efi_boot_image(&good[..IMAGE_SECTOR * ISO_SECTOR_SIZE + 1024]), None);
+        assert_eq!(efi_boot_image(&good[..IMAGE_SECTOR * ISO_SECTOR_SIZE + 256]), None);
+        let far = iso(PLATFORM_EFI, &[entry(BOOTABLE, 8, u32::MAX as usize)]);
+        assert_eq!(efi_boot_image(&far), None);
+    }
+}
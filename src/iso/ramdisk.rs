@@ -0,0 +1,63 @@
+// ramdisk.rs
+// Registers an in-memory ISO through the firmware's EFI_RAM_DISK_PROTOCOL
+
+use alloc::vec::Vec;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::media::RamDiskType;
+use uefi::proto::unsafe_protocol;
+use uefi::{Guid, Handle, Result, Status, StatusExt, boot};
+use uefi_raw::protocol::device_path::DevicePathProtocol;
+
+use crate::volume::open_shared;
+
+#[repr(C)]
+#[unsafe_protocol("ab38a0df-6873-44a9-87e6-d4eb56148449")]
+pub struct RamDisk {
+    register: unsafe extern "efiapi" fn(
+        base: u64,
+        size: u64,
+        disk_type: *const Guid,
+        parent: *const DevicePathProtocol,
+        device_path: *mut *const DevicePathProtocol,
+    ) -> Status,
+    unregister: unsafe extern "efiapi" fn(device_path: *const DevicePathProtocol) -> Status,
+}
+
+fn ramdisk_handle() -> Option<Handle> {
+    boot::find_handles::<RamDisk>().ok()?.first().copied()
+}
+
+/// Registers `image` (which must stay allocated) as a virtual CD and returns
+/// the device path of the new RAM disk. `None` if the firmware has no RAM
+/// disk support.
+pub fn register_cd(image: &'static [u8]) -> Result<Option<Vec<u8>>> {
+    let Some(handle) = ramdisk_handle() else {
+        return Ok(None);
+    };
+    let ramdisk = open_shared::<RamDisk>(handle)?;
+
+    let mut device_path: *const DevicePathProtocol = core::ptr::null();
+    unsafe {
+        (ramdisk.register)(
+            image.as_ptr() as u64,
+            image.len() as u64,
+            &RamDiskType::VIRTUAL_CD.0,
+            core::ptr::null(),
+            &mut device_path,
+        )
+    }
+    .to_result()?;
+
+    let path = unsafe { DevicePath::from_ffi_ptr(device_path.cast()) };
+    Ok(Some(path.as_bytes().to_vec()))
+}
+
+/// Removes a RAM disk registered by [`register_cd`].
+pub fn unregister(device_path: &[u8]) {
+    let Some(handle) = ramdisk_handle() else {
+        return;
+    };
+    if let Ok(ramdisk) = open_shared::<RamDisk>(handle) {
+        let _ = unsafe { (ramdisk.unregister)(device_path.as_ptr().cast()) };
+    }
+}
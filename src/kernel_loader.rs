@@ -4,134 +4,111 @@
 use alloc::borrow::ToOwned;
 use alloc::ffi::CString;
 use alloc::vec::Vec;
-use uefi::CStr16;
 use uefi::println;
 use uefi::proto::loaded_image::LoadedImage;
-use uefi::proto::media::{
-    file::{File, FileAttribute, FileInfo, FileMode, FileType},
-    fs::SimpleFileSystem,
-};
-use uefi::{Result, boot};
+use uefi::proto::device_path::DevicePath;
+use uefi::{Handle, Result, Status, boot};
+
+use crate::{arch, luks2, pe, secureboot};
+use crate::volume::{Volume, file_device_path};
+
+mod initrd;
+// Only the x86_64 handoffs build their own memory map
+#[cfg(target_arch = "x86_64")]
+mod memory;
+mod zboot;
+
+#[cfg(target_arch = "x86_64")]
+pub use memory::convert_memory_map;
 
+/// Loads `kernel_path` from the volume `device` (the loader's own volume if
+/// `None`) and passes the initrd and command line as load options.
 pub fn load_efi_from_path(
+    device: Option<Handle>,
     kernel_path: &str,
     initrd_path: Option<&str>,
     cmdline: Option<&str>,
 ) -> Result {
-    // Get the loaded image protocol for the current image (BOOTX64.EFI)
-    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
-
-    // Get the device handle where this image was loaded from
-    let device_handle = loaded_image.device();
-
-    // Open the Simple File System protocol on the same device
-    let mut sfs = boot::open_protocol_exclusive::<SimpleFileSystem>(device_handle.unwrap())?;
-
-    // Open the root directory
-    let mut current_dir = sfs.open_volume()?;
+    // Use the device this image was loaded from, unless the entry lives on
+    // another volume (e.g. XBOOTLDR)
+    let mut volume = match device {
+        Some(handle) => Volume::open(handle)?,
+        None => Volume::loader()?,
+    };
 
     println!("Loading kernel from path: {}", kernel_path);
+    let kernel_device_path = file_device_path(volume.handle(), kernel_path);
+    let filename = kernel_path.rsplit(['/', '\\']).next().unwrap_or(kernel_path);
+
+    let kernel_buffer = volume.read(kernel_path)?;
+    println!("{} size: {} bytes", filename, kernel_buffer.len());
+
+    // The EFI stub can only read `initrd=` from a firmware file system, so
+    // initrds on e.g. ext4 are served through the initrd media protocol.
+    let mut initrd_path = initrd_path;
+    if let Some(initrd) = initrd_path
+        && !volume.firmware_readable()
+    {
+        let data = volume.read(initrd)?;
+        println!("Serving initrd {} ({} bytes) via LoadFile2", initrd, data.len());
+        initrd::install(data)?;
+        initrd_path = None;
+    }
 
-    // Split the path and navigate to the correct directory
-    let kernel_path = kernel_path.replace('/', "\\");
-    let path_parts: Vec<&str> = kernel_path
-        .split('\\') //.filter(|part| !part.is_empty())
-        .collect();
-    let filename = path_parts.last().unwrap();
-
-    // Navigate through directories if path has subdirectories
-    for &dir_name in &path_parts[..path_parts.len() - 1] {
-        if !dir_name.is_empty() {
-            println!("Navigating to directory: {}", dir_name);
-
-            // Convert to UTF-16 string
-            let mut dir_name_utf16 = [0u16; 256];
-            let mut i = 0;
-            for ch in dir_name.chars() {
-                if i >= 255 {
-                    break;
-                }
-                dir_name_utf16[i] = ch as u16;
-                i += 1;
-            }
-            dir_name_utf16[i] = 0; // Null terminator
-
-            let dir_handle = current_dir.open(
-                unsafe { CStr16::from_u16_with_nul_unchecked(&dir_name_utf16[..=i]) },
-                FileMode::Read,
-                FileAttribute::empty(),
-            )?;
+    start_efi(
+        filename,
+        &kernel_buffer,
+        kernel_device_path
+            .as_deref()
+            .and_then(|bytes| <&DevicePath>::try_from(bytes).ok()),
+        initrd_path,
+        cmdline,
+    )
+}
 
-            current_dir = match dir_handle.into_type()? {
-                FileType::Dir(dir) => dir,
-                FileType::Regular(_) => {
-                    println!("{} is not a directory", dir_name);
-                    return Err(uefi::Error::new(uefi::Status::INVALID_PARAMETER, ()));
-                }
-            };
-        }
+/// Starts a kernel that is already in memory (e.g. fetched over the
+/// network). The initrd, if any, is served via LoadFile2.
+pub fn load_efi_from_memory(
+    name: &str,
+    kernel_buffer: &[u8],
+    initrd: Option<Vec<u8>>,
+    cmdline: Option<&str>,
+) -> Result {
+    println!("{} size: {} bytes", name, kernel_buffer.len());
+    if let Some(data) = initrd {
+        println!("Serving initrd ({} bytes) via LoadFile2", data.len());
+        initrd::install(data)?;
     }
+    start_efi(name, kernel_buffer, None, None, cmdline)
+}
 
-    // Convert filename to UTF-16
-    let mut filename_utf16 = [0u16; 256];
-    let mut i = 0;
-    for ch in filename.chars() {
-        if i >= 255 {
-            break;
-        }
-        filename_utf16[i] = ch as u16;
-        i += 1;
+/// Loads the image in `kernel_buffer` and sets its load options. An
+/// `initrd_path` is passed as `initrd=`, so it must be on a firmware
+/// readable volume.
+fn start_efi(
+    filename: &str,
+    kernel_buffer: &[u8],
+    kernel_device_path: Option<&DevicePath>,
+    initrd_path: Option<&str>,
+    cmdline: Option<&str>,
+) -> Result {
+    // With Secure Boot, the signature of the buffer as read is what counts,
+    // and it is checked before anything in it is decompressed
+    secureboot::check(filename, kernel_buffer)?;
+
+    // Compressed kernels are unpacked here instead of by their own stub
+    let unpacked = zboot::unpack(kernel_buffer)?;
+    let image = unpacked.as_deref().unwrap_or(kernel_buffer);
+
+    // Firmware may fail in odd ways on images for another architecture
+    if let Some(machine) = pe::machine(image)
+        && !arch::can_run(machine)
+    {
+        println!("{} is built for PE machine type {:#06x}, not {:#06x}", filename, machine, arch::PE_MACHINE);
+        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
     }
-    filename_utf16[i] = 0; // Null terminator
-
-    // Open the kernel file
-    let kernel_file_handle = current_dir.open(
-        unsafe { CStr16::from_u16_with_nul_unchecked(&filename_utf16[..=i]) },
-        FileMode::Read,
-        FileAttribute::empty(),
-    )?;
 
-    let mut kernel_file = match kernel_file_handle.into_type()? {
-        FileType::Regular(file) => file,
-        FileType::Dir(_) => {
-            println!("{} is a directory, not a file", filename);
-            return Err(uefi::Error::new(uefi::Status::INVALID_PARAMETER, ()));
-        }
-    };
-
-    // Get file info to determine size
-    let mut info_buffer = [0u8; 200]; // Should be enough for FileInfo
-    let file_info = kernel_file.get_info::<FileInfo>(&mut info_buffer);
-    let file_size = file_info.unwrap().file_size() as usize;
-
-    println!("{} size: {} bytes", filename, file_size);
-
-    // Allocate memory for the kernel image
-    let kernel_pages = (file_size + 4095) / 4096; // Round up to page boundary
-    let kernel_addr = boot::allocate_pages(
-        boot::AllocateType::AnyPages,
-        boot::MemoryType::LOADER_DATA,
-        kernel_pages,
-    )?;
-
-    // Read the kernel file into memory
-    let kernel_buffer = unsafe { core::slice::from_raw_parts_mut(kernel_addr.as_ptr(), file_size) };
-
-    kernel_file.read(kernel_buffer)?;
-    println!(
-        "{} loaded into memory at 0x{:x}",
-        filename,
-        kernel_addr.as_ptr() as usize
-    );
-
-    // Load the image
-    let kernel_image_handle = boot::load_image(
-        boot::image_handle(),
-        boot::LoadImageSource::FromBuffer {
-            buffer: kernel_buffer,
-            file_path: None,
-        },
-    )?;
+    let kernel_image_handle = secureboot::load_image(image, kernel_device_path)?;
 
     let mut kernel_loaded_image_device =
         boot::open_protocol_exclusive::<LoadedImage>(kernel_image_handle)?;
@@ -160,14 +137,16 @@ pub fn load_efi_from_path(
     println!("{}\n{:?}\n{:?}",options_str, initrd_path, cmdline);
     let options = CString::new(options_str).unwrap();
     //let options_bytes = options.as_bytes_with_nul();
-    let ptr: *const u8 = options.as_ptr() as *const u8;
+    let ptr: *const u8 = options.as_ptr().cast();
     let len: u32  = options.as_bytes().len() as u32;             // without null terminator
-    let len_with_nul = options.as_bytes_with_nul().len();
 
     unsafe {
         kernel_loaded_image_device.set_load_options(ptr, len);
     }
 
+    // Everything is in memory now; don't leave disk keys behind for the OS.
+    luks2::wipe_keys();
+
     println!("{} image loaded, starting execution...", filename);
 
     // Start the kernel image
@@ -181,15 +160,12 @@ extern crate alloc;
 
 mod memory;
 use memory::{convert_memory_map_to_e820, validate_e820_map, print_e820_map, install_e820_map};
-use alloc::vec::Vec;
 use uefi::println;
 use core::{ptr, slice};
 use uefi::prelude::*;
 use uefi::boot;
-use uefi::proto::loaded_image::LoadedImage;
-use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::proto::media::file::{File, FileMode, FileType, FileInfo, FileAttribute};
-use uefi::{CStr16, Result};
+use uefi::Result;
+use crate::volume::Volume;
 
 const KERNEL_LOAD_ADDR: u64 = 0x100000;
 const BOOT_PARAMS_ADDR: usize = 0x90000;
@@ -372,115 +348,12 @@ let kernel_addr = boot::allocate_pages(
     }
 }
 
-/// Helper to read a file into a Vec<u8> using the newer UEFI crate API
+/// Helper to read a file into a Vec<u8> from the loader's own volume
 fn read_file_to_vec(path: &str) -> Result<Vec<u8>> {
-    // Get the loaded image protocol for the current image
-    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle()).unwrap();
-
-    // Get the device handle where this image was loaded from
-    let device_handle = loaded_image.device().unwrap();
-
-    // Open the Simple File System protocol on the same device
-    let mut sfs = boot::open_protocol_exclusive::<SimpleFileSystem>(device_handle).unwrap();
-
-    // Open the root directory
-    let mut current_dir = sfs.open_volume().unwrap();
-
-    // Normalize path separators - replace forward slashes with backslashes
-    let normalized_path = path.replace('/', "\\");
-
-    // Split the path and navigate to the correct directory
-    let path_parts: Vec<&str> = normalized_path.split('\\')
-        .filter(|part| !part.is_empty())
-        .collect();
-
-    if path_parts.is_empty() {
-        panic!("Empty file path: {}", path);
-    }
-
-    let filename = path_parts.last().unwrap();
-
-    // Navigate through directories if path has subdirectories
-    for &dir_name in &path_parts[..path_parts.len() - 1] {
-        // Convert to UTF-16 string
-        let mut dir_name_utf16 = [0u16; 256];
-        let mut utf16_len = 0;
-
-        for ch in dir_name.chars() {
-            if utf16_len >= 255 {
-                panic!("Directory name too long: {}", dir_name);
-            }
-            dir_name_utf16[utf16_len] = ch as u16;
-            utf16_len += 1;
-        }
-        dir_name_utf16[utf16_len] = 0;
-
-        let dir_handle = current_dir.open(
-            unsafe { CStr16::from_u16_with_nul_unchecked(&dir_name_utf16[..=utf16_len]) },
-            FileMode::Read,
-            FileAttribute::empty(),
-        ).unwrap();
-
-        current_dir = match dir_handle.into_type()? {
-            FileType::Dir(dir) => dir,
-            FileType::Regular(_) => panic!("{} is not a directory", dir_name),
-        };
-    }
-
-    // Convert filename to UTF-16
-    let mut filename_utf16 = [0u16; 256];
-    let mut utf16_len = 0;
-
-    for ch in filename.chars() {
-        if utf16_len >= 255 {
-            panic!("Filename too long: {}", filename);
-        }
-        filename_utf16[utf16_len] = ch as u16;
-        utf16_len += 1;
-    }
-    filename_utf16[utf16_len] = 0;
-
-    // Open the file
-    let handle = current_dir.open(
-        unsafe { CStr16::from_u16_with_nul_unchecked(&filename_utf16[..=utf16_len]) },
-        FileMode::Read,
-        FileAttribute::empty(),
-    ).unwrap();
-
-    let mut file = match handle.into_type()? {
-        FileType::Regular(f) => f,
-        FileType::Dir(_) => panic!("{} is not a regular file", filename),
-    };
+    let buf = Volume::loader()?.read(path)?;
 
-    // Get file size
-    let mut info_buffer = [0u8; 200];
-    let file_info = file.get_info::<FileInfo>(&mut info_buffer).expect("Failed to get file info");
-    let file_size = file_info.file_size() as usize;
-
-    if file_size == 0 {
-        panic!("File {} is empty", filename);
-    }
-
-    // Read file into vector
-    let mut buf = Vec::with_capacity(file_size);
-    let mut total_read = 0;
-
-    while total_read < file_size {
-        let mut chunk = [0u8; 4096];
-        let bytes_to_read = core::cmp::min(chunk.len(), file_size - total_read);
-        let bytes_read = file.read(&mut chunk[..bytes_to_read]).unwrap();
-
-        if bytes_read == 0 {
-            break; // EOF reached
-        }
-
-        buf.extend_from_slice(&chunk[..bytes_read]);
-        total_read += bytes_read;
-    }
-
-    if total_read != file_size {
-        panic!("Failed to read complete file {}: expected {} bytes, got {} bytes",
-               filename, file_size, total_read);
+    if buf.is_empty() {
+        panic!("File {} is empty", path);
     }
 
     Ok(buf)
@@ -489,7 +362,6 @@ fn read_file_to_vec(path: &str) -> Result<Vec<u8>> {
 /*
 extern crate alloc;
 
-use alloc::vec::Vec;
 use core::{ptr, slice};
 use uefi::prelude::*;
 use uefi::proto::media::fs::SimpleFileSystem;
@@ -0,0 +1,82 @@
+// initrd.rs
+// Serves an initrd to the Linux EFI stub through the LINUX_EFI_INITRD_MEDIA LoadFile2 protocol
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::{Guid, Handle, Identify, Result, Status, boot, guid};
+use uefi_raw::Boolean;
+use uefi_raw::protocol::device_path::DevicePathProtocol;
+use uefi_raw::protocol::media::LoadFile2Protocol;
+
+/// Vendor media node the EFI stub looks for (since Linux 5.8) before it
+/// falls back to `initrd=` on the command line.
+const LINUX_EFI_INITRD_MEDIA_GUID: Guid = guid!("5568e427-68fc-4f3d-ac74-ca555231cc68");
+
+/// The protocol instance handed to the firmware. `proto` must stay first so
+/// the `this` pointer can be cast back.
+#[repr(C)]
+struct InitrdLoader {
+    proto: LoadFile2Protocol,
+    data: Vec<u8>,
+}
+
+unsafe extern "efiapi" fn load_file(
+    this: *mut LoadFile2Protocol,
+    _file_path: *const DevicePathProtocol,
+    boot_policy: Boolean,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+) -> Status {
+    if this.is_null() || buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    if bool::from(boot_policy) {
+        return Status::UNSUPPORTED;
+    }
+    let loader = unsafe { &*(this as *const InitrdLoader) };
+    let len = loader.data.len();
+    unsafe {
+        if buffer.is_null() || *buffer_size < len {
+            *buffer_size = len;
+            return Status::BUFFER_TOO_SMALL;
+        }
+        core::ptr::copy_nonoverlapping(loader.data.as_ptr(), buffer as *mut u8, len);
+        *buffer_size = len;
+    }
+    Status::SUCCESS
+}
+
+/// Installs a handle that serves `data` as the initrd. The kernel is started
+/// right after, so the protocol and its device path are intentionally leaked.
+pub fn install(data: Vec<u8>) -> Result<Handle> {
+    let mut storage = Vec::new();
+    DevicePathBuilder::with_vec(&mut storage)
+        .push(&build::media::Vendor {
+            vendor_guid: LINUX_EFI_INITRD_MEDIA_GUID,
+            vendor_defined_data: &[],
+        })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_| uefi::Error::new(Status::OUT_OF_RESOURCES, ()))?;
+    let device_path: &'static mut [u8] = storage.leak();
+
+    let loader = Box::leak(Box::new(InitrdLoader {
+        proto: LoadFile2Protocol { load_file },
+        data,
+    }));
+
+    unsafe {
+        let handle = boot::install_protocol_interface(
+            None,
+            &DevicePath::GUID,
+            device_path.as_ptr() as *const c_void,
+        )?;
+        boot::install_protocol_interface(
+            Some(handle),
+            &LoadFile2Protocol::GUID,
+            loader as *const InitrdLoader as *const c_void,
+        )
+    }
+}
@@ -1,5 +1,49 @@
 // memory.rs
-// UEFI to E820 memory map conversion for bzImage Linux kernel boot
+// UEFI memory map conversion for the kernel handoffs that build their own
+// map, and an E820 sketch for bzImage Linux kernel boot
+
+use alloc::vec::Vec;
+use uefi::boot::MemoryType;
+use uefi::mem::memory_map::MemoryMap;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Converts `map` into `entries` of base, length and the type `kind` gives
+/// each descriptor: sorted, with neighbours of the same type merged, and
+/// with `extra` added unless it overlaps the map. `entries` has its capacity
+/// reserved already, so this runs without boot services.
+pub fn convert_memory_map(
+    map: &impl MemoryMap,
+    kind: impl Fn(MemoryType) -> u64,
+    extra: Option<[u64; 3]>,
+    entries: &mut Vec<[u64; 3]>,
+) {
+    for d in map.entries() {
+        if d.page_count > 0 && entries.len() < entries.capacity() {
+            entries.push([d.phys_start, d.page_count * PAGE_SIZE, kind(d.ty)]);
+        }
+    }
+    if let Some([base, length, ty]) = extra
+        && entries.len() < entries.capacity()
+        && !entries.iter().any(|e| e[0] < base + length && base < e[0] + e[1])
+    {
+        entries.push([base, length, ty]);
+    }
+    entries.sort_unstable_by_key(|e| e[0]);
+
+    let mut kept = 0;
+    for i in 0..entries.len() {
+        let entry = entries[i];
+        if kept > 0 && entries[kept - 1][0] + entries[kept - 1][1] == entry[0] && entries[kept - 1][2] == entry[2] {
+            entries[kept - 1][1] += entry[1];
+        } else {
+            entries[kept] = entry;
+            kept += 1;
+        }
+    }
+    entries.truncate(kept);
+}
+
 // AI slop but don't delete
 /*
 
@@ -0,0 +1,232 @@
+// zboot.rs
+// Unpacks EFI zboot kernels (a small PE stub carrying a gzip or zstd compressed image)
+
+use alloc::vec;
+use alloc::vec::Vec;
+use uefi::{Result, Status, println};
+
+const ZBOOT_MAGIC: &[u8] = b"zimg";
+/// Largest decompressed kernel; the size comes from the image itself, so it
+/// is bounded before anything is allocated for it.
+const MAX_KERNEL_SIZE: usize = 512 << 20;
+
+/// The compressed kernel as described by the zboot header.
+struct Payload<'a> {
+    data: &'a [u8],
+    /// What follows the payload; zstd images append the decompressed size
+    /// there as the compressed stream doesn't carry it.
+    trailer: &'a [u8],
+    compression: &'a [u8],
+}
+
+/// Header of `linux/drivers/firmware/efi/libstub/zboot-header.S`, which
+/// takes the place of the DOS header fields a PE loader doesn't look at.
+fn parse_header(image: &[u8]) -> Option<Payload<'_>> {
+    if image.get(0..2)? != b"MZ" || image.get(4..8)? != ZBOOT_MAGIC {
+        return None;
+    }
+    let offset = u32::from_le_bytes(image.get(8..12)?.try_into().ok()?) as usize;
+    let size = u32::from_le_bytes(image.get(12..16)?.try_into().ok()?) as usize;
+    let name = image.get(24..56)?;
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Some(Payload {
+        data: image.get(offset..offset.checked_add(size)?)?,
+        trailer: image.get(offset + size..)?,
+        compression: &name[..len],
+    })
+}
+
+fn le32_size(bytes: Option<&[u8]>) -> Result<usize> {
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(|bytes| u32::from_le_bytes(bytes) as usize)
+        .ok_or_else(|| uefi::Error::new(Status::VOLUME_CORRUPTED, ()))
+}
+
+/// Refuses decompressed sizes beyond [`MAX_KERNEL_SIZE`].
+fn check_size(size: usize) -> Result {
+    if size > MAX_KERNEL_SIZE {
+        println!("zboot kernel claims {} bytes decompressed, more than {}", size, MAX_KERNEL_SIZE);
+        return Err(uefi::Error::new(Status::BAD_BUFFER_SIZE, ()));
+    }
+    Ok(())
+}
+
+/// Raw deflate data of a gzip member (RFC 1952), and the size it inflates
+/// to, from the trailer.
+fn gzip_deflate(data: &[u8]) -> Option<(&[u8], usize)> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+
+    if data.len() < 18 || data[0..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        pos += 2 + len;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let end = data.len() - 8;
+    let size = u32::from_le_bytes(data[end + 4..].try_into().unwrap()) as usize;
+    Some((data.get(pos..end)?, size))
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let corrupted = || uefi::Error::new(Status::VOLUME_CORRUPTED, ());
+    let (deflate, size) = gzip_deflate(data).ok_or_else(corrupted)?;
+    check_size(size)?;
+    let mut out = vec![0u8; size];
+    let written = miniz_oxide::inflate::decompress_slice_iter_to_slice(
+        &mut out,
+        core::iter::once(deflate),
+        false,
+        true,
+    )
+    .map_err(|_| corrupted())?;
+    if written != size {
+        return Err(corrupted());
+    }
+    Ok(out)
+}
+
+fn unzstd(data: &[u8], size: usize) -> Result<Vec<u8>> {
+    check_size(size)?;
+    let mut out = vec![0u8; size];
+    let written = ruzstd::decoding::FrameDecoder::new()
+        .decode_all(data, &mut out)
+        .map_err(|_| uefi::Error::new(Status::VOLUME_CORRUPTED, ()))?;
+    out.truncate(written);
+    Ok(out)
+}
+
+/// The kernel inside `image` if it is a zboot image, `None` for anything
+/// else. Booting the inner image directly leaves load options and the
+/// initrd protocol to the real kernel stub.
+pub fn unpack(image: &[u8]) -> Result<Option<Vec<u8>>> {
+    let Some(payload) = parse_header(image) else {
+        return Ok(None);
+    };
+    let inner = match payload.compression {
+        b"gzip" => gunzip(payload.data)?,
+        // Linux's Makefile.zboot names zstd payloads after their level
+        b"zstd22" | b"zstd" => unzstd(payload.data, le32_size(payload.trailer.get(..4))?)?,
+        other => {
+            println!(
+                "Unsupported zboot compression: {}",
+                core::str::from_utf8(other).unwrap_or("?")
+            );
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+    };
+    if !inner.starts_with(b"MZ") {
+        println!("zboot payload is not a PE image");
+        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+    }
+    println!("Decompressed zboot kernel: {} bytes", inner.len());
+    Ok(Some(inner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `gzip -9` of [`inner`], keeping the file name "Image" in the header.
+    const GZIP: &str = "1f8b0808a1b1d56a0203496d61676500f38dcacccb4b2d52c84e2dca4bcd51c8cc4d4c4f55205b080014310b7e4e000000";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn inner() -> Vec<u8> {
+        [b"MZ".as_slice(), &b"inner kernel image ".repeat(4)].concat()
+    }
+
+    /// A zboot image laid out like zboot-header.S: the header in the DOS
+    /// header, the PE stub, then the payload followed by `trailer`.
+    fn zboot(compression: &str, payload: &[u8], trailer: &[u8]) -> Vec<u8> {
+        const PAYLOAD_OFFSET: usize = 0x200;
+        let mut image = vec![0u8; PAYLOAD_OFFSET];
+        image[0..4].copy_from_slice(b"MZ\0\0");
+        image[4..8].copy_from_slice(ZBOOT_MAGIC);
+        image[8..12].copy_from_slice(&(PAYLOAD_OFFSET as u32).to_le_bytes());
+        image[12..16].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        image[24..24 + compression.len()].copy_from_slice(compression.as_bytes());
+        image[0x38..0x3c].copy_from_slice(&0x818223cdu32.to_le_bytes());
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        image[0x40..0x44].copy_from_slice(b"PE\0\0");
+        image.extend(payload);
+        image.extend(trailer);
+        // The rest of the stub's sections
+        image.extend([0; 64]);
+        image
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+    }
+
+    #[test]
+    fn parses_header() {
+        let image = zboot("zstd22", b"payload", &[1, 2, 3, 4]);
+        let payload = parse_header(&image).unwrap();
+        assert_eq!(payload.data, b"payload");
+        assert_eq!(payload.compression, b"zstd22");
+        assert_eq!(&payload.trailer[..4], [1, 2, 3, 4]);
+
+        assert!(parse_header(&inner()).is_none());
+        assert!(parse_header(&image[..20]).is_none());
+        let mut past_end = image.clone();
+        past_end[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_header(&past_end).is_none());
+    }
+
+    #[test]
+    fn unpacks_gzip() {
+        let image = zboot("gzip", &hex(GZIP), &[]);
+        assert_eq!(unpack(&image).unwrap(), Some(inner()));
+        assert_eq!(unpack(&inner()).unwrap(), None);
+    }
+
+    #[test]
+    fn unpacks_zstd() {
+        let size = (inner().len() as u32).to_le_bytes();
+        for name in ["zstd22", "zstd"] {
+            let image = zboot(name, &zstd(&inner()), &size);
+            assert_eq!(unpack(&image).unwrap(), Some(inner()));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_payloads() {
+        let status = |image: Vec<u8>| unpack(&image).err().map(|e| e.status());
+        let gzip = hex(GZIP);
+
+        assert_eq!(status(zboot("lzma", &gzip, &[])), Some(Status::UNSUPPORTED));
+        assert!(status(zboot("gzip", &gzip[..gzip.len() - 10], &[])).is_some());
+        assert_eq!(status(zboot("gzip", &gzip[..12], &[])), Some(Status::VOLUME_CORRUPTED));
+        // Sizes that don't match the data, or are too large to allocate
+        let mut size = gzip.clone();
+        let end = size.len();
+        size[end - 4..].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(status(zboot("gzip", &size, &[])), Some(Status::VOLUME_CORRUPTED));
+        size[end - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(status(zboot("gzip", &size, &[])), Some(Status::BAD_BUFFER_SIZE));
+        let zstd = zstd(&inner());
+        assert_eq!(status(zboot("zstd22", &zstd, &u32::MAX.to_le_bytes())), Some(Status::BAD_BUFFER_SIZE));
+        assert_eq!(status(zboot("zstd22", &zstd, &10u32.to_le_bytes())), Some(Status::VOLUME_CORRUPTED));
+        // A payload that isn't a PE image
+        let not_pe = self::zstd(b"ELF");
+        assert_eq!(status(zboot("zstd22", &not_pe, &3u32.to_le_bytes())), Some(Status::LOAD_ERROR));
+    }
+}
@@ -0,0 +1,19 @@
+// limine.rs
+// Boots kernels that speak the Limine boot protocol (x86-64, base revisions 0 to 2)
+
+#[cfg(target_arch = "x86_64")]
+mod imp;
+
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+    use uefi::{Result, Status, println};
+
+    use crate::entries_parse::BootEntry;
+
+    pub fn boot_limine(_path: &str, _entry: &BootEntry) -> Result {
+        println!("Limine kernels can only be started by the x86_64 loader");
+        Err(uefi::Error::new(Status::UNSUPPORTED, ()))
+    }
+}
+
+pub use imp::boot_limine;
@@ -0,0 +1,366 @@
+// imp.rs
+// The x86-64 Limine loader: ELF loading, request answers, paging and the handoff
+
+mod cpu;
+mod elf;
+mod paging;
+mod requests;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::{Result, Status, println};
+
+use crate::entries_parse::BootEntry;
+use crate::volume::Volume;
+use crate::{acpi, kernel_loader, luks2, multiboot2, secureboot};
+use paging::{PageTables, SIZE_2M, SIZE_4K};
+use requests::{Arena, Request};
+
+/// Memory of the kernel and its modules, reported as such in the memory map.
+const KERNEL_MEMORY: MemoryType = MemoryType::custom(0x8000_4c49);
+
+const HHDM_OFFSET: u64 = 0xffff_8000_0000_0000;
+const SUPPORTED_BASE_REVISION: u64 = 2;
+/// Stack of the BSP unless the kernel asks for more, and of each AP.
+const STACK_SIZE: u64 = 64 * 1024;
+
+mod memmap {
+    pub const USABLE: u64 = 0;
+    pub const RESERVED: u64 = 1;
+    pub const ACPI_RECLAIMABLE: u64 = 2;
+    pub const ACPI_NVS: u64 = 3;
+    pub const BAD_MEMORY: u64 = 4;
+    pub const BOOTLOADER_RECLAIMABLE: u64 = 5;
+    pub const EXECUTABLE_AND_MODULES: u64 = 6;
+    pub const FRAMEBUFFER: u64 = 7;
+}
+
+/// Size of `struct limine_file`.
+const FILE_SIZE: usize = 112;
+/// Size of `struct limine_mp_info`.
+const MP_INFO_SIZE: usize = 32;
+
+/// A file handed to the kernel: the kernel itself or a module.
+struct File {
+    address: u64,
+    size: u64,
+    path: String,
+    cmdline: String,
+}
+
+impl File {
+    fn new(data: &[u8], path: &str, cmdline: &str) -> Result<Self> {
+        let pages = data.len().div_ceil(boot::PAGE_SIZE).max(1);
+        let memory = boot::allocate_pages(AllocateType::AnyPages, KERNEL_MEMORY, pages)?;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), memory.as_ptr(), data.len()) };
+        Ok(File {
+            address: memory.as_ptr() as u64,
+            size: data.len() as u64,
+            path: String::from(path),
+            cmdline: String::from(cmdline),
+        })
+    }
+
+    /// Writes the `struct limine_file` and returns its address.
+    fn write(&self, arena: &mut Arena) -> u64 {
+        let file = arena.alloc(FILE_SIZE);
+        let path = arena.string(&self.path);
+        let cmdline = arena.string(&self.cmdline);
+        arena.put(file, 8, self.address + HHDM_OFFSET);
+        arena.put(file, 16, self.size);
+        arena.put(file, 24, path);
+        arena.put(file, 32, cmdline);
+        file
+    }
+}
+
+fn memmap_type(ty: MemoryType) -> u64 {
+    match ty {
+        MemoryType::CONVENTIONAL | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => memmap::USABLE,
+        MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => memmap::BOOTLOADER_RECLAIMABLE,
+        KERNEL_MEMORY => memmap::EXECUTABLE_AND_MODULES,
+        MemoryType::ACPI_RECLAIM => memmap::ACPI_RECLAIMABLE,
+        MemoryType::ACPI_NON_VOLATILE => memmap::ACPI_NVS,
+        MemoryType::UNUSABLE => memmap::BAD_MEMORY,
+        _ => memmap::RESERVED,
+    }
+}
+
+/// Reads a `module` line's file; the rest of the line is its command line.
+fn load_module(volume: &mut Volume, spec: &str) -> Result<File> {
+    let (path, cmdline) = spec
+        .split_once(char::is_whitespace)
+        .map_or((spec, ""), |(path, cmdline)| (path, cmdline.trim_start()));
+    let file = File::new(&volume.read(path)?, path, cmdline)?;
+    println!("Loaded module {}", path);
+    Ok(file)
+}
+
+/// Responses that can only be completed once boot services are gone.
+#[derive(Default)]
+struct Pending {
+    /// Response, pointer array and entries.
+    memmap: Option<(u64, u64, u64)>,
+    efi_memmap: Option<u64>,
+    /// Response, pointer array and one `limine_mp_info` per CPU.
+    mp: Option<(u64, u64, Vec<u64>)>,
+}
+
+/// Writes the responses to everything in `requests` the loader knows, and
+/// sets up those finished after exiting boot services.
+#[allow(clippy::too_many_arguments)]
+fn respond(
+    arena: &mut Arena,
+    requests: &[Request],
+    kernel: &elf::Kernel,
+    kernel_file: &File,
+    modules: &[File],
+    framebuffer: Option<&multiboot2::Framebuffer>,
+    cpus: &[cpu::Cpu],
+    memmap_capacity: usize,
+) -> Pending {
+    let mut pending = Pending::default();
+    for request in requests {
+        match request.id() {
+            requests::BOOTLOADER_INFO => {
+                let response = arena.respond(request, 24);
+                let name = arena.string(env!("CARGO_PKG_NAME"));
+                let version = arena.string(env!("CARGO_PKG_VERSION"));
+                arena.put(response, 8, name);
+                arena.put(response, 16, version);
+            }
+            requests::STACK_SIZE | requests::ENTRY_POINT => {
+                arena.respond(request, 8);
+            }
+            requests::HHDM => {
+                let response = arena.respond(request, 16);
+                arena.put(response, 8, HHDM_OFFSET);
+            }
+            requests::FRAMEBUFFER => {
+                let Some(fb) = framebuffer else {
+                    continue;
+                };
+                let response = arena.respond(request, 24);
+                let list = arena.alloc(8);
+                let info = arena.alloc(64);
+                arena.put(response, 8, 1);
+                arena.put(response, 16, list);
+                arena.put(list, 0, info);
+                arena.put(info, 0, fb.address + HHDM_OFFSET);
+                arena.put(info, 8, fb.width as u64);
+                arena.put(info, 16, fb.height as u64);
+                arena.put(info, 24, fb.pitch as u64);
+                arena.put_bytes(info, 32, &(fb.bpp as u16).to_le_bytes());
+                // RGB memory model, then size and shift of each color
+                let [(red_shift, red), (green_shift, green), (blue_shift, blue)] = fb.fields;
+                arena.put_bytes(info, 34, &[1, red, red_shift, green, green_shift, blue, blue_shift]);
+            }
+            requests::PAGING_MODE => {
+                // 4-level paging, the only mode set up
+                arena.respond(request, 16);
+            }
+            requests::MP => {
+                let response = arena.respond(request, 32);
+                let list = arena.alloc(cpus.len() * 8);
+                let infos = cpus
+                    .iter()
+                    .map(|cpu| {
+                        let info = arena.alloc(MP_INFO_SIZE);
+                        arena.put_bytes(info, 0, &cpu.processor_id.to_le_bytes());
+                        arena.put_bytes(info, 4, &cpu.lapic_id.to_le_bytes());
+                        info
+                    })
+                    .collect();
+                pending.mp = Some((response, list, infos));
+            }
+            requests::MEMMAP => {
+                let response = arena.respond(request, 24);
+                let list = arena.alloc(memmap_capacity * 8);
+                let entries = arena.alloc(memmap_capacity * 24);
+                pending.memmap = Some((response, list, entries));
+            }
+            requests::EXECUTABLE_FILE => {
+                let response = arena.respond(request, 16);
+                let file = kernel_file.write(arena);
+                arena.put(response, 8, file);
+            }
+            requests::MODULE => {
+                let response = arena.respond(request, 24);
+                let list = arena.alloc(modules.len() * 8);
+                for (i, module) in modules.iter().enumerate() {
+                    let file = module.write(arena);
+                    arena.put(list, i * 8, file);
+                }
+                arena.put(response, 8, modules.len() as u64);
+                arena.put(response, 16, list);
+            }
+            requests::RSDP => {
+                if let Some(rsdp) = acpi::rsdp(true).or_else(|| acpi::rsdp(false)) {
+                    let response = arena.respond(request, 16);
+                    arena.put(response, 8, rsdp.as_ptr() as u64 + HHDM_OFFSET);
+                }
+            }
+            requests::EFI_SYSTEM_TABLE => {
+                if let Some(table) = uefi::table::system_table_raw() {
+                    let response = arena.respond(request, 16);
+                    arena.put(response, 8, table.as_ptr() as u64 + HHDM_OFFSET);
+                }
+            }
+            requests::EFI_MEMMAP => pending.efi_memmap = Some(arena.respond(request, 40)),
+            requests::EXECUTABLE_ADDRESS => {
+                let response = arena.respond(request, 24);
+                arena.put(response, 8, kernel.physical_base);
+                arena.put(response, 16, kernel.virtual_base);
+            }
+            // Requests the loader doesn't know stay unanswered, as the protocol allows
+            _ => (),
+        }
+    }
+    pending
+}
+
+/// Boots the Limine protocol kernel `path` of `entry` with its `module`s
+/// and `options` as the kernel command line.
+pub fn boot_limine(path: &str, entry: &BootEntry) -> Result {
+    // ELF kernels carry no signature
+    secureboot::refuse_unverified(path)?;
+    let mut volume = match entry.device {
+        Some(handle) => Volume::open(handle)?,
+        None => Volume::loader()?,
+    };
+    println!("Loading Limine kernel {}", path);
+    let image = volume.read(path)?;
+    let kernel = elf::load(&image, path)?;
+    let (requests, base_revision) = requests::find(kernel.memory);
+    if let Some(tag) = base_revision {
+        let revision = unsafe { *tag.add(2) };
+        if revision > SUPPORTED_BASE_REVISION {
+            println!(
+                "{} needs Limine base revision {}, only up to {} is supported",
+                path, revision, SUPPORTED_BASE_REVISION
+            );
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+        unsafe { *tag.add(2) = 0 };
+    }
+    let find = |id: [u64; 2]| requests.iter().find(|request| request.id() == id);
+
+    let kernel_file = File::new(&image, path, entry.options.as_deref().unwrap_or(""))?;
+    drop(image);
+    let modules: Vec<File> = entry
+        .module
+        .iter()
+        .map(|spec| load_module(&mut volume, spec))
+        .collect::<Result<_>>()?;
+    let framebuffer = multiboot2::framebuffer(None);
+
+    let stack_size = find(requests::STACK_SIZE).map_or(STACK_SIZE, |r| r.field(0).max(STACK_SIZE));
+    let stack_pages = stack_size.div_ceil(SIZE_4K);
+    let stack = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, stack_pages as usize)?;
+    let stack_top = stack.as_ptr() as u64 + stack_pages * SIZE_4K + HHDM_OFFSET;
+    let entry_point = find(requests::ENTRY_POINT).map_or(kernel.entry, |r| r.field(0));
+
+    let mp = find(requests::MP);
+    let cpus = if mp.is_some() { cpu::cpus() } else { Vec::new() };
+    let x2apic = mp.is_some_and(|r| r.field(0) & 1 != 0) && cpu::x2apic_supported();
+    let ap_stacks = match cpus.len() {
+        0 => 0,
+        count => {
+            let pages = count * (STACK_SIZE / SIZE_4K) as usize;
+            boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)?.as_ptr() as u64
+        }
+    };
+
+    // The identity map and HHDM cover all memory, MMIO in the map and the
+    // framebuffer, but at least 4 GiB
+    let map = boot::memory_map(MemoryType::LOADER_DATA)?;
+    let framebuffer_range = framebuffer
+        .as_ref()
+        .map(|fb| (fb.address, (fb.pitch as u64 * fb.height as u64).next_multiple_of(SIZE_4K)));
+    let top = map
+        .entries()
+        .map(|d| d.phys_start + d.page_count * SIZE_4K)
+        .chain(framebuffer_range.map(|(base, length)| base + length))
+        .fold(0x1_0000_0000, u64::max)
+        .next_multiple_of(SIZE_2M);
+    // Room for the entries the map gains until boot services are gone
+    let memmap_capacity = map.entries().count() + 64;
+    drop(map);
+
+    let mut tables = PageTables::new(top, kernel.memory.len() as u64)?;
+    tables.map(0, 0, top, SIZE_2M)?;
+    tables.map(HHDM_OFFSET, 0, top, SIZE_2M)?;
+    tables.map(kernel.virtual_base, kernel.physical_base, kernel.memory.len() as u64, SIZE_4K)?;
+    let low_page = cpu::LowPage::prepare(tables.root())?;
+
+    let strings: usize = modules.iter().chain([&kernel_file]).map(|f| f.path.len() + f.cmdline.len()).sum();
+    let files = (modules.len() + 1) * (FILE_SIZE + 64);
+    let arena_size = 16 * 1024 + strings + files + memmap_capacity * 32 + cpus.len() * (MP_INFO_SIZE + 16);
+    let mut arena = Arena::new(arena_size, HHDM_OFFSET)?;
+    let pending = respond(
+        &mut arena,
+        &requests,
+        &kernel,
+        &kernel_file,
+        &modules,
+        framebuffer.as_ref(),
+        &cpus,
+        memmap_capacity,
+    );
+
+    // Everything is in memory now; don't leave disk keys behind for the OS.
+    luks2::wipe_keys();
+
+    println!("Starting Limine kernel at {:#x}", entry_point);
+    let mut entries = Vec::with_capacity(memmap_capacity);
+    let map = unsafe { boot::exit_boot_services(None) };
+
+    if let Some((response, list, structs)) = pending.memmap {
+        let framebuffer = framebuffer_range.map(|(base, length)| [base, length, memmap::FRAMEBUFFER]);
+        kernel_loader::convert_memory_map(&map, memmap_type, framebuffer, &mut entries);
+        for (i, entry) in entries.iter().enumerate() {
+            let address = structs + i as u64 * 24;
+            for (field, value) in entry.iter().enumerate() {
+                arena.put(address, field * 8, *value);
+            }
+            arena.put(list, i * 8, address);
+        }
+        arena.put(response, 8, entries.len() as u64);
+        arena.put(response, 16, list);
+    }
+    if let Some(response) = pending.efi_memmap {
+        let meta = map.meta();
+        arena.put(response, 8, map.buffer().as_ptr() as u64 + HHDM_OFFSET);
+        arena.put(response, 16, meta.map_size as u64);
+        arena.put(response, 24, meta.desc_size as u64);
+        arena.put(response, 32, meta.desc_version as u64);
+    }
+    // The EFI memory map stays where it is for the kernel
+    core::mem::forget(map);
+
+    if let Some((response, list, infos)) = pending.mp {
+        if x2apic {
+            cpu::enable_x2apic();
+        }
+        let x2apic = cpu::x2apic_enabled();
+        let bsp = cpu::bsp_lapic_id();
+        let mut count = 0;
+        for (i, (cpu, info)) in cpus.iter().zip(infos).enumerate() {
+            let stack = ap_stacks + (i as u64 + 1) * STACK_SIZE + HHDM_OFFSET;
+            let started = cpu.lapic_id == bsp
+                || ((x2apic || cpu.lapic_id < 0xff) && low_page.start_ap(cpu.lapic_id, stack, info, x2apic));
+            if started {
+                arena.put(list, count * 8, info);
+                count += 1;
+            }
+        }
+        arena.put_bytes(response, 8, &(x2apic as u32).to_le_bytes());
+        arena.put_bytes(response, 12, &bsp.to_le_bytes());
+        arena.put(response, 16, count as u64);
+        arena.put(response, 24, list);
+    }
+
+    unsafe { low_page.enter(tables.root(), stack_top, entry_point) }
+}
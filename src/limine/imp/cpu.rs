@@ -0,0 +1,350 @@
+// cpu.rs
+// GDT, AP startup (INIT-SIPI-SIPI into a parking loop) and the jump into the kernel
+
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ptr::{read_volatile, write_volatile};
+use uefi::Result;
+use uefi::boot::{self, AllocateType, MemoryType};
+
+use crate::acpi;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_EXTD: u64 = 1 << 10;
+const X2APIC_ID: u32 = 0x802;
+const X2APIC_ICR: u32 = 0x830;
+const XAPIC_ID: u64 = 0x20;
+const XAPIC_ICR_LOW: u64 = 0x300;
+const XAPIC_ICR_HIGH: u64 = 0x310;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0x4500;
+const ICR_STARTUP: u32 = 0x4600;
+
+// Real mode entry of the APs, copied to a page below 1 MiB that also holds
+// the GDT of the handoff. The BSP fills in CR3 and the far jump target, and
+// before each AP the stack and `limine_smp_info` to pass. The AP switches
+// straight to long mode, signals `booted` and spins on `goto_address`.
+global_asm!(
+    ".global limine_ap_start",
+    ".global limine_ap_far_target",
+    ".global limine_ap_long_mode",
+    ".global limine_gdt",
+    ".global limine_gdtr",
+    ".global limine_ap_cr3",
+    ".global limine_ap_x2apic",
+    ".global limine_ap_stack",
+    ".global limine_ap_info",
+    ".global limine_ap_booted",
+    ".global limine_ap_end",
+    ".code16",
+    "limine_ap_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    lgdt [limine_ap_gdtr_offset]",
+    "    mov eax, cr4",
+    "    or eax, 0x20",
+    "    mov cr4, eax",
+    "    mov eax, dword ptr [limine_ap_cr3_offset]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xc0000080",
+    "    rdmsr",
+    "    or eax, 0x100",
+    "    wrmsr",
+    "    mov eax, cr0",
+    "    or eax, 0x80000001",
+    "    mov cr0, eax",
+    // jmp far 0x28:limine_ap_long_mode with a 32-bit offset
+    "    .byte 0x66, 0xea",
+    "limine_ap_far_target:",
+    "    .long 0",
+    "    .word 0x28",
+    ".code64",
+    "limine_ap_long_mode:",
+    "    mov ax, 0x30",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov ss, ax",
+    "    cmp qword ptr [rip + limine_ap_x2apic], 0",
+    "    je 2f",
+    "    mov ecx, 0x1b",
+    "    rdmsr",
+    "    or eax, 0xc00",
+    "    wrmsr",
+    "2:",
+    "    mov rsp, [rip + limine_ap_stack]",
+    "    mov rdi, [rip + limine_ap_info]",
+    "    mov byte ptr [rip + limine_ap_booted], 1",
+    "3:",
+    "    pause",
+    "    mov rax, [rdi + 16]",
+    "    test rax, rax",
+    "    jz 3b",
+    "    xor ebp, ebp",
+    "    call rax",
+    "4:",
+    "    cli",
+    "    hlt",
+    "    jmp 4b",
+    ".p2align 3",
+    // Null, 16-bit, 32-bit and 64-bit code and data, as the protocol lays it out
+    "limine_gdt:",
+    "    .quad 0",
+    "    .quad 0x00009a000000ffff",
+    "    .quad 0x000092000000ffff",
+    "    .quad 0x00cf9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "    .quad 0x00af9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "limine_gdtr:",
+    "    .word 55",
+    "    .quad 0",
+    ".p2align 3",
+    "limine_ap_cr3:",
+    "    .quad 0",
+    "limine_ap_x2apic:",
+    "    .quad 0",
+    "limine_ap_stack:",
+    "    .quad 0",
+    "limine_ap_info:",
+    "    .quad 0",
+    "limine_ap_booted:",
+    "    .quad 0",
+    "limine_ap_end:",
+    // DS is the trampoline page, so 16-bit code addresses data by offset
+    ".set limine_ap_gdtr_offset, limine_gdtr - limine_ap_start",
+    ".set limine_ap_cr3_offset, limine_ap_cr3 - limine_ap_start",
+);
+
+unsafe extern "C" {
+    static limine_ap_start: u8;
+    static limine_ap_far_target: u8;
+    static limine_ap_long_mode: u8;
+    static limine_gdt: u8;
+    static limine_gdtr: u8;
+    static limine_ap_cr3: u8;
+    static limine_ap_x2apic: u8;
+    static limine_ap_stack: u8;
+    static limine_ap_info: u8;
+    static limine_ap_booted: u8;
+    static limine_ap_end: u8;
+}
+
+/// Offset of a trampoline label from its start.
+fn offset_of(label: *const u8) -> usize {
+    label as usize - (&raw const limine_ap_start) as usize
+}
+
+fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack)) };
+    (high as u64) << 32 | low as u64
+}
+
+fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack))
+    };
+}
+
+pub fn x2apic_enabled() -> bool {
+    rdmsr(IA32_APIC_BASE) & APIC_BASE_EXTD != 0
+}
+
+fn xapic_register(offset: u64) -> *mut u32 {
+    ((rdmsr(IA32_APIC_BASE) & 0x000f_ffff_ffff_f000) + offset) as *mut u32
+}
+
+pub fn x2apic_supported() -> bool {
+    __cpuid(1).ecx & (1 << 21) != 0
+}
+
+/// Switches the BSP's local APIC to x2APIC mode.
+pub fn enable_x2apic() {
+    wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | APIC_BASE_EXTD);
+}
+
+pub fn bsp_lapic_id() -> u32 {
+    if x2apic_enabled() {
+        rdmsr(X2APIC_ID) as u32
+    } else {
+        unsafe { read_volatile(xapic_register(XAPIC_ID)) >> 24 }
+    }
+}
+
+fn send_ipi(lapic_id: u32, command: u32) {
+    if x2apic_enabled() {
+        wrmsr(X2APIC_ICR, (lapic_id as u64) << 32 | command as u64);
+        return;
+    }
+    unsafe {
+        write_volatile(xapic_register(XAPIC_ICR_HIGH), lapic_id << 24);
+        write_volatile(xapic_register(XAPIC_ICR_LOW), command);
+        while read_volatile(xapic_register(XAPIC_ICR_LOW)) & ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// An enabled CPU from the MADT.
+pub struct Cpu {
+    pub processor_id: u32,
+    pub lapic_id: u32,
+}
+
+/// The enabled processors the MADT lists, BSP included.
+pub fn cpus() -> Vec<Cpu> {
+    let mut cpus: Vec<Cpu> = Vec::new();
+    let Some(madt) = acpi::find_table(b"APIC") else {
+        return cpus;
+    };
+    let mut at = 44;
+    while at + 2 <= madt.len() {
+        let (kind, length) = (madt[at], madt[at + 1] as usize);
+        if length < 2 || at + length > madt.len() {
+            break;
+        }
+        let entry = &madt[at..at + length];
+        let u32_at = |offset: usize| u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap());
+        let cpu = match kind {
+            // Processor Local APIC
+            0 if length >= 8 && u32_at(4) & 1 != 0 => Some(Cpu {
+                processor_id: entry[2] as u32,
+                lapic_id: entry[3] as u32,
+            }),
+            // Processor Local x2APIC
+            9 if length >= 16 && u32_at(8) & 1 != 0 => Some(Cpu {
+                processor_id: u32_at(12),
+                lapic_id: u32_at(4),
+            }),
+            _ => None,
+        };
+        if let Some(cpu) = cpu
+            && !cpus.iter().any(|c| c.lapic_id == cpu.lapic_id)
+        {
+            cpus.push(cpu);
+        }
+        at += length;
+    }
+    cpus
+}
+
+/// The page below 1 MiB with the GDT and the AP entry code.
+pub struct LowPage {
+    base: u64,
+    /// TSC ticks per millisecond, for the startup delays once boot
+    /// services (and their stall) are gone.
+    ticks_per_ms: u64,
+}
+
+impl LowPage {
+    pub fn prepare(cr3: u64) -> Result<Self> {
+        let start = &raw const limine_ap_start;
+        let size = offset_of(&raw const limine_ap_end);
+        let page = boot::allocate_pages(AllocateType::MaxAddress(0xf_ffff), MemoryType::LOADER_CODE, 1)?;
+        let base = page.as_ptr() as u64;
+        unsafe { core::ptr::copy_nonoverlapping(start, page.as_ptr(), size) };
+
+        let low = LowPage { base, ticks_per_ms: 0 };
+        low.write_u32(offset_of(&raw const limine_ap_far_target), (base as usize + offset_of(&raw const limine_ap_long_mode)) as u32);
+        low.write_u64(offset_of(&raw const limine_gdtr) + 2, base + offset_of(&raw const limine_gdt) as u64);
+        low.write_u64(offset_of(&raw const limine_ap_cr3), cr3);
+
+        let before = unsafe { _rdtsc() };
+        boot::stall(1000);
+        let ticks_per_ms = unsafe { _rdtsc() } - before;
+        Ok(LowPage { ticks_per_ms, ..low })
+    }
+
+    fn write_u32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base as usize + offset) as *mut u32, value) };
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        unsafe { write_volatile((self.base as usize + offset) as *mut u64, value) };
+    }
+
+    fn booted(&self) -> bool {
+        unsafe { read_volatile((self.base as usize + offset_of(&raw const limine_ap_booted)) as *const u8) != 0 }
+    }
+
+    fn delay_us(&self, microseconds: u64) {
+        let end = unsafe { _rdtsc() } + self.ticks_per_ms * microseconds / 1000;
+        while unsafe { _rdtsc() } < end {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Starts the AP `lapic_id` into the parking loop with `stack` and
+    /// `info` (both kernel visible addresses). Runs after boot services are
+    /// gone; returns whether the AP came up.
+    pub fn start_ap(&self, lapic_id: u32, stack: u64, info: u64, x2apic: bool) -> bool {
+        self.write_u64(offset_of(&raw const limine_ap_x2apic), x2apic as u64);
+        self.write_u64(offset_of(&raw const limine_ap_stack), stack);
+        self.write_u64(offset_of(&raw const limine_ap_info), info);
+        self.write_u64(offset_of(&raw const limine_ap_booted), 0);
+
+        let vector = (self.base >> 12) as u32;
+        send_ipi(lapic_id, ICR_INIT);
+        self.delay_us(10_000);
+        for _ in 0..2 {
+            send_ipi(lapic_id, ICR_STARTUP | vector);
+            self.delay_us(200);
+            if self.booted() {
+                return true;
+            }
+        }
+        for _ in 0..100 {
+            self.delay_us(1000);
+            if self.booted() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Loads the protocol's GDT and page tables and jumps to `entry` on
+    /// `stack`, with a zero return address pushed.
+    pub unsafe fn enter(&self, cr3: u64, stack: u64, entry: u64) -> ! {
+        let gdtr = self.base + offset_of(&raw const limine_gdtr) as u64;
+        unsafe {
+            asm!(
+                "cli",
+                "lgdt [rsi]",
+                "push 0x28",
+                "lea rax, [rip + 2f]",
+                "push rax",
+                "retfq",
+                "2:",
+                "mov ax, 0x30",
+                "mov ds, ax",
+                "mov es, ax",
+                "mov fs, ax",
+                "mov gs, ax",
+                "mov ss, ax",
+                "mov cr3, rdx",
+                "mov rsp, rcx",
+                "push 0",
+                "push r8",
+                "xor eax, eax",
+                "xor ebx, ebx",
+                "xor ecx, ecx",
+                "xor edx, edx",
+                "xor esi, esi",
+                "xor edi, edi",
+                "xor ebp, ebp",
+                "xor r8d, r8d",
+                "ret",
+                in("rsi") gdtr,
+                in("rdx") cr3,
+                in("rcx") stack,
+                in("r8") entry,
+                options(noreturn),
+            )
+        }
+    }
+}
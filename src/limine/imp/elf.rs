@@ -0,0 +1,171 @@
+// elf.rs
+// Loads a higher half ELF64 kernel and applies its relative relocations
+
+use uefi::boot::{self, AllocateType};
+use uefi::{Result, Status, println};
+
+use super::KERNEL_MEMORY;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const R_X86_64_NONE: u64 = 0;
+const R_X86_64_RELATIVE: u64 = 8;
+
+/// Limine kernels live in the top 2 GiB of the address space.
+const KERNEL_SPACE: u64 = 0xffff_ffff_8000_0000;
+const PAGE_SIZE: u64 = boot::PAGE_SIZE as u64;
+
+pub struct Kernel {
+    /// The loaded image, from `virtual_base` to the end of the last segment.
+    pub memory: &'static mut [u8],
+    pub physical_base: u64,
+    pub virtual_base: u64,
+    pub entry: u64,
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn le64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+fn program_headers(image: &[u8]) -> Option<impl Iterator<Item = Option<ProgramHeader>> + '_> {
+    let phoff = le64(image, 32)? as usize;
+    let phentsize = le16(image, 54)? as usize;
+    let phnum = le16(image, 56)? as usize;
+    Some((0..phnum).map(move |i| {
+        let header = image.get(phoff.checked_add(i * phentsize)?..)?;
+        Some(ProgramHeader {
+            kind: le32(header, 0)?,
+            offset: le64(header, 8)?,
+            vaddr: le64(header, 16)?,
+            filesz: le64(header, 32)?,
+            memsz: le64(header, 40)?,
+            align: le64(header, 48)?,
+        })
+    }))
+}
+
+/// Applies the `R_X86_64_RELATIVE` relocations of a PIE kernel loaded at its
+/// link address.
+fn relocate(memory: &mut [u8], virtual_base: u64, dynamic: &ProgramHeader) -> Option<()> {
+    let at = |vaddr: u64| vaddr.checked_sub(virtual_base).map(|offset| offset as usize);
+    let table = memory.get(at(dynamic.vaddr)?..at(dynamic.vaddr)?.checked_add(dynamic.memsz as usize)?)?;
+    let (mut rela, mut size, mut entry_size) = (0, 0, 24);
+    for entry in table.chunks_exact(16) {
+        let value = le64(entry, 8)?;
+        match le64(entry, 0)? {
+            DT_NULL => break,
+            DT_RELA => rela = value,
+            DT_RELASZ => size = value,
+            DT_RELAENT => entry_size = value,
+            _ => (),
+        }
+    }
+    if rela == 0 || entry_size < 24 {
+        return Some(());
+    }
+    for i in 0..size / entry_size {
+        let entry = at(rela.checked_add(i * entry_size)?)?;
+        let offset = le64(memory, entry)?;
+        let kind = le64(memory, entry + 8)? & 0xffff_ffff;
+        let addend = le64(memory, entry + 16)?;
+        match kind {
+            R_X86_64_NONE => (),
+            R_X86_64_RELATIVE => {
+                memory.get_mut(at(offset)?..at(offset)?.checked_add(8)?)?.copy_from_slice(&addend.to_le_bytes())
+            }
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+/// Copies the segments of the kernel `image` to physical memory, mapped
+/// later at their link addresses.
+pub fn load(image: &[u8], path: &str) -> Result<Kernel> {
+    let invalid = |reason: &str| {
+        println!("{}: {}", path, reason);
+        uefi::Error::new(Status::LOAD_ERROR, ())
+    };
+    if image.get(0..4) != Some(b"\x7fELF") || image.get(4) != Some(&2) || image.get(5) != Some(&1) {
+        return Err(invalid("not a little-endian ELF64 file"));
+    }
+    let kind = le16(image, 16).unwrap_or(0);
+    if le16(image, 18) != Some(EM_X86_64) || !matches!(kind, ET_EXEC | ET_DYN) {
+        return Err(invalid("not an x86-64 executable"));
+    }
+    let headers: Option<alloc::vec::Vec<_>> = program_headers(image).ok_or_else(|| invalid("no program headers"))?.collect();
+    let headers = headers.ok_or_else(|| invalid("truncated program headers"))?;
+    let loads = || headers.iter().filter(|h| h.kind == PT_LOAD && h.memsz > 0);
+
+    let low = loads().map(|h| h.vaddr).min().ok_or_else(|| invalid("nothing to load"))? & !(PAGE_SIZE - 1);
+    // Segments running past the top of the address space are rejected
+    let ends: Option<alloc::vec::Vec<u64>> = loads()
+        .map(|h| h.vaddr.checked_add(h.memsz)?.checked_next_multiple_of(PAGE_SIZE))
+        .collect();
+    let high = ends
+        .ok_or_else(|| invalid("segment beyond the end of the address space"))?
+        .into_iter()
+        .max()
+        .unwrap_or(low);
+    if low < KERNEL_SPACE {
+        return Err(invalid("not linked in the top 2 GiB of the address space"));
+    }
+    let align = loads().map(|h| h.align).max().unwrap_or(0).max(PAGE_SIZE);
+
+    // Over-allocate to honor the largest segment alignment
+    let pages = ((high - low) / PAGE_SIZE) as usize;
+    let slack = (align / PAGE_SIZE) as usize - 1;
+    let allocation = boot::allocate_pages(AllocateType::AnyPages, KERNEL_MEMORY, pages + slack)?;
+    let physical_base = (allocation.as_ptr() as u64)
+        .checked_next_multiple_of(align)
+        .ok_or_else(|| invalid("segment alignment too large"))?;
+    let memory = unsafe { core::slice::from_raw_parts_mut(physical_base as *mut u8, (high - low) as usize) };
+    memory.fill(0);
+    for header in loads() {
+        let data = header
+            .offset
+            .checked_add(header.filesz)
+            .and_then(|end| image.get(header.offset as usize..end as usize))
+            .filter(|_| header.filesz <= header.memsz)
+            .ok_or_else(|| invalid("segment outside of the file"))?;
+        let at = (header.vaddr - low) as usize;
+        memory[at..at + data.len()].copy_from_slice(data);
+    }
+
+    if kind == ET_DYN
+        && let Some(dynamic) = headers.iter().find(|h| h.kind == PT_DYNAMIC)
+    {
+        relocate(memory, low, dynamic).ok_or_else(|| invalid("unsupported relocations"))?;
+    }
+
+    Ok(Kernel {
+        memory,
+        physical_base,
+        virtual_base: low,
+        entry: le64(image, 24).unwrap_or(0),
+    })
+}
@@ -0,0 +1,85 @@
+// paging.rs
+// 4-level x86-64 page tables for the handoff: identity map, HHDM and kernel
+
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::{Result, Status};
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE: u64 = 1 << 7;
+const ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+pub const SIZE_4K: u64 = 0x1000;
+pub const SIZE_2M: u64 = 0x20_0000;
+const SIZE_1G: u64 = 0x4000_0000;
+
+/// Page tables carved from one allocation below 4 GiB, so that APs can
+/// load CR3 while still in 32-bit code.
+pub struct PageTables {
+    pool: &'static mut [[u64; 512]],
+    used: usize,
+}
+
+impl PageTables {
+    /// Room for the tables mapping `top` bytes twice (identity and HHDM)
+    /// with 2 MiB pages plus a kernel of `kernel_size` bytes with 4 KiB ones.
+    pub fn new(top: u64, kernel_size: u64) -> Result<Self> {
+        let gigabytes = top.div_ceil(SIZE_1G) as usize;
+        let pages = 2 * (gigabytes + gigabytes.div_ceil(512)) + (kernel_size / SIZE_2M) as usize + 8;
+        let memory = boot::allocate_pages(AllocateType::MaxAddress(0xffff_ffff), MemoryType::LOADER_DATA, pages)?;
+        let pool = unsafe { core::slice::from_raw_parts_mut(memory.as_ptr().cast::<[u64; 512]>(), pages) };
+        pool.fill([0; 512]);
+        // The first table is the PML4
+        Ok(PageTables { pool, used: 1 })
+    }
+
+    /// Physical address of the PML4, for CR3.
+    pub fn root(&self) -> u64 {
+        self.pool.as_ptr() as u64
+    }
+
+    fn index_of(&self, table: u64) -> usize {
+        ((table - self.root()) / SIZE_4K) as usize
+    }
+
+    /// The table `entry` of `table` points to, created if missing.
+    fn next(&mut self, table: u64, entry: usize) -> Result<u64> {
+        let index = self.index_of(table);
+        let value = self.pool[index][entry];
+        if value & PRESENT != 0 {
+            return Ok(value & ADDRESS);
+        }
+        if self.used == self.pool.len() {
+            return Err(uefi::Error::new(Status::OUT_OF_RESOURCES, ()));
+        }
+        let next = self.root() + self.used as u64 * SIZE_4K;
+        self.used += 1;
+        self.pool[index][entry] = next | PRESENT | WRITABLE;
+        Ok(next)
+    }
+
+    /// Maps one page of `size` (4 KiB or 2 MiB) at `virt` to `phys`.
+    fn map_page(&mut self, virt: u64, phys: u64, size: u64) -> Result {
+        let entry = |level: u32| ((virt >> (12 + 9 * level)) & 0x1ff) as usize;
+        let pdpt = self.next(self.root(), entry(3))?;
+        let pd = self.next(pdpt, entry(2))?;
+        if size == SIZE_2M {
+            let index = self.index_of(pd);
+            self.pool[index][entry(1)] = phys | PRESENT | WRITABLE | HUGE;
+            return Ok(());
+        }
+        let pt = self.next(pd, entry(1))?;
+        let index = self.index_of(pt);
+        self.pool[index][entry(0)] = phys | PRESENT | WRITABLE;
+        Ok(())
+    }
+
+    /// Maps `size` bytes at `virt` to `phys` with pages of `page`; all
+    /// three must be multiples of it.
+    pub fn map(&mut self, virt: u64, phys: u64, size: u64, page: u64) -> Result {
+        for offset in (0..size).step_by(page as usize) {
+            self.map_page(virt + offset, phys + offset, page)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+// requests.rs
+// Finds the Limine requests in the loaded kernel and lays out the responses
+
+use alloc::vec::Vec;
+use uefi::Result;
+use uefi::boot::{self, AllocateType, MemoryType};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+const BASE_REVISION_MAGIC: [u64; 2] = [0xf9562b2d5c95a6c8, 0x6a7b384944536bdc];
+const START_MARKER: [u64; 4] = [0xf6b8f4b39de7d1ae, 0xfab91a6940fcb9cf, 0x785c6ed015d3e316, 0x181e920a7852b9d9];
+const END_MARKER: [u64; 2] = [0xadc0e0531bb10d03, 0x9572709f31764c62];
+
+pub const BOOTLOADER_INFO: [u64; 2] = [0xf55038d8e2a1202f, 0x279426fcf5f59740];
+pub const STACK_SIZE: [u64; 2] = [0x224ef0460a8e8926, 0xe1cb0fc25f46ea3d];
+pub const HHDM: [u64; 2] = [0x48dcf1cb8ad2b852, 0x63984e959a98244b];
+pub const FRAMEBUFFER: [u64; 2] = [0x9d5827dcd881dd75, 0xa3148604f6fab11b];
+pub const PAGING_MODE: [u64; 2] = [0x95c1a0edab0944cb, 0xa4e5cb3842f7488a];
+pub const MP: [u64; 2] = [0x95a67b819a1b857e, 0xa0b61b723b6a73e0];
+pub const MEMMAP: [u64; 2] = [0x67cf3d9d378a806f, 0xe304acdfc50c3c62];
+pub const ENTRY_POINT: [u64; 2] = [0x13d86c035a1cd3e1, 0x2b0caa89d8f3026a];
+pub const EXECUTABLE_FILE: [u64; 2] = [0xad97e90e83f1ed67, 0x31eb5d1c5ff23b69];
+pub const MODULE: [u64; 2] = [0x3e7e279702be32af, 0xca1c4f3bd1280cee];
+pub const RSDP: [u64; 2] = [0xc5e77b6b397e7b43, 0x27637845accdcf3c];
+pub const EFI_SYSTEM_TABLE: [u64; 2] = [0x5ceba5163eaaf6d6, 0x0a6981610cf65fcc];
+pub const EFI_MEMMAP: [u64; 2] = [0x7df62a431d6872d5, 0xa4fcdfb3e57306c8];
+pub const EXECUTABLE_ADDRESS: [u64; 2] = [0x71ba76863cc55f63, 0xb2644a48c516a487];
+
+/// A request in the loaded kernel image.
+pub struct Request {
+    words: *mut u64,
+}
+
+impl Request {
+    pub fn id(&self) -> [u64; 2] {
+        unsafe { [*self.words.add(2), *self.words.add(3)] }
+    }
+
+    /// Request specific field `index`, after the ID, revision and response.
+    pub fn field(&self, index: usize) -> u64 {
+        unsafe { *self.words.add(6 + index) }
+    }
+
+    pub fn respond(&self, response: u64) {
+        unsafe { *self.words.add(5) = response };
+    }
+}
+
+/// The requests in `image`, between the start and end markers if it has
+/// them, and the base revision tag.
+pub fn find(image: &mut [u8]) -> (Vec<Request>, Option<*mut u64>) {
+    let (_, words, _) = unsafe { image.align_to_mut::<u64>() };
+    let base = words.as_mut_ptr();
+    let position = |pattern: &[u64], from: usize| {
+        words[from..]
+            .windows(pattern.len())
+            .position(|window| window == pattern)
+            .map(|at| from + at)
+    };
+    let range = match position(&START_MARKER, 0) {
+        Some(start) => start + 4..position(&END_MARKER, start).unwrap_or(words.len()),
+        None => 0..words.len(),
+    };
+
+    let mut requests = Vec::new();
+    let mut at = range.start;
+    while let Some(found) = position(&COMMON_MAGIC, at).filter(|&found| found + 6 <= range.end) {
+        requests.push(Request {
+            words: base.wrapping_add(found),
+        });
+        at = found + 4;
+    }
+    let base_revision = position(&BASE_REVISION_MAGIC, 0)
+        .filter(|&found| found + 3 <= words.len())
+        .map(|found| base.wrapping_add(found));
+    (requests, base_revision)
+}
+
+/// Bootloader reclaimable memory for the responses, handed out to the
+/// kernel through the HHDM.
+pub struct Arena {
+    memory: &'static mut [u8],
+    used: usize,
+    hhdm: u64,
+}
+
+impl Arena {
+    pub fn new(size: usize, hhdm: u64) -> Result<Self> {
+        let pages = size.div_ceil(boot::PAGE_SIZE);
+        let memory = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)?;
+        let memory = unsafe { core::slice::from_raw_parts_mut(memory.as_ptr(), pages * boot::PAGE_SIZE) };
+        memory.fill(0);
+        Ok(Arena { memory, used: 0, hhdm })
+    }
+
+    /// `size` zeroed bytes; returns their HHDM address. The arena is sized
+    /// up front, running out is a bug.
+    pub fn alloc(&mut self, size: usize) -> u64 {
+        let start = self.used.next_multiple_of(16);
+        assert!(start + size <= self.memory.len(), "Limine response arena too small");
+        self.used = start + size;
+        self.memory[start..].as_ptr() as u64 + self.hhdm
+    }
+
+    /// Writes `value` at `offset` of the allocation at `address`.
+    pub fn put(&mut self, address: u64, offset: usize, value: u64) {
+        let at = (address - self.hhdm) as usize - self.memory.as_ptr() as usize + offset;
+        self.memory[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Like [`Arena::put`] for the smaller fields of a structure.
+    pub fn put_bytes(&mut self, address: u64, offset: usize, bytes: &[u8]) {
+        let at = (address - self.hhdm) as usize - self.memory.as_ptr() as usize + offset;
+        self.memory[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A response of `size` bytes with revision 0 for `request`.
+    pub fn respond(&mut self, request: &Request, size: usize) -> u64 {
+        let response = self.alloc(size);
+        request.respond(response);
+        response
+    }
+
+    /// A nul terminated copy of `value`.
+    pub fn string(&mut self, value: &str) -> u64 {
+        let address = self.alloc(value.len() + 1);
+        self.put_bytes(address, 0, value.as_bytes());
+        address
+    }
+}
@@ -0,0 +1,67 @@
+// loader_conf.rs
+// Global settings from \loader\loader.conf on the loader's volume
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use uefi::Result;
+
+use crate::volume::Volume;
+
+const LOADER_CONF_PATH: &str = "loader\\loader.conf";
+
+#[derive(Debug, Default)]
+pub struct LoaderConf {
+    /// PE image with `.hwids`/`.dtbauto` sections to pick the devicetree
+    /// from for entries that don't name one.
+    pub devicetree_auto: Option<String>,
+    /// Tables in `loader\acpi` installed for every entry.
+    pub acpi_table: Vec<String>,
+    /// `<signature> [oem-id]` of firmware tables hidden from every entry.
+    pub acpi_drop: Vec<String>,
+    /// GRUB style `addr,mask[,addr,mask...]` lists of bad RAM.
+    pub badram: Vec<String>,
+    /// Also pass the bad RAM to Linux as `memmap=` parameters.
+    pub badram_memmap: bool,
+    /// Directory on the loader's volume to start drivers from instead of
+    /// `drivers` next to the loader image.
+    pub drivers_dir: Option<String>,
+    /// `off`, `manual`, `if-safe` or `force`: whether keys in `loader\keys`
+    /// are offered or enrolled in Setup Mode.
+    pub secure_boot_enroll: Option<String>,
+}
+
+impl LoaderConf {
+    /// Reads loader.conf; a missing file gives the defaults.
+    pub fn read() -> Result<Self> {
+        let mut volume = Volume::loader()?;
+        if !volume.exists(LOADER_CONF_PATH)? {
+            return Ok(Self::default());
+        }
+        Ok(Self::parse(&volume.read_to_string(LOADER_CONF_PATH)?))
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut conf = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, val)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let val = val.trim_start();
+            match key {
+                "drivers-dir" => conf.drivers_dir = Some(val.to_string()),
+                "devicetree-auto" => conf.devicetree_auto = Some(val.to_string()),
+                "acpi-table" => conf.acpi_table.extend(val.split_whitespace().map(String::from)),
+                "acpi-drop" => conf.acpi_drop.push(val.to_string()),
+                "badram" => conf.badram.push(val.to_string()),
+                "secure-boot-enroll" => conf.secure_boot_enroll = Some(val.to_string()),
+                "badram-memmap" => conf.badram_memmap = matches!(val, "yes" | "true" | "on" | "1"),
+                _ => (),
+            }
+        }
+        conf
+    }
+}
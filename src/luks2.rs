@@ -0,0 +1,690 @@
+// luks2.rs
+// Unlocks LUKS2 encrypted partitions and exposes them as decrypted BlockIO devices
+
+mod blockio;
+mod xts;
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding};
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::{Char16, Handle, Result, ResultExt, Status, boot, print, println, system};
+use zeroize::Zeroizing;
+
+use crate::ext4::{Disk, UefiDisk};
+use xts::Xts;
+
+pub use blockio::wipe_keys;
+
+const LUKS2_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+const LUKS2_MAGIC_SECONDARY: &[u8; 6] = b"SKUL\xba\xbe";
+const BINARY_HEADER_SIZE: usize = 4096;
+const CHECKSUM_OFFSET: usize = 448;
+const MAX_HEADER_SIZE: u64 = 4 * 1024 * 1024;
+/// Keyslot areas are always encrypted in 512 byte sectors.
+const KEYSLOT_SECTOR_SIZE: usize = 512;
+const SUPPORTED_CIPHER: &str = "aes-xts-plain64";
+const MAX_PASSPHRASE_TRIES: usize = 3;
+/// Stripes of the anti-forensic split; cryptsetup never writes another count.
+const AF_STRIPES: usize = 4000;
+
+fn bad_header(what: &str) -> uefi::Error {
+    println!("LUKS2: {}", what);
+    uefi::Error::new(Status::VOLUME_CORRUPTED, ())
+}
+
+/// Reads a number that LUKS2 stores either as a JSON number or, for 64-bit
+/// values, as a decimal string.
+fn number(value: &Value, key: &str) -> Result<u64> {
+    match &value[key] {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| bad_header(key))
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    value[key].as_str().ok_or_else(|| bad_header(key))
+}
+
+fn base64(value: &Value, key: &str) -> Result<Vec<u8>> {
+    Base64::decode_vec(string(value, key)?).map_err(|_| bad_header(key))
+}
+
+/// Object keys listed in an array of decimal strings (`"keyslots": ["0"]`).
+fn ids(value: &Value, key: &str) -> Vec<String> {
+    value[key]
+        .as_array()
+        .map(|ids| ids.iter().filter_map(|id| id.as_str()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+enum Kdf {
+    Pbkdf2 { iterations: u32, salt: Vec<u8> },
+    Argon2 { params: Params, algorithm: Algorithm, salt: Vec<u8> },
+}
+
+impl Kdf {
+    fn parse(kdf: &Value) -> Result<Self> {
+        match string(kdf, "type")? {
+            "pbkdf2" => {
+                if string(kdf, "hash")? != "sha256" {
+                    return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+                }
+                Ok(Kdf::Pbkdf2 {
+                    iterations: number(kdf, "iterations")? as u32,
+                    salt: base64(kdf, "salt")?,
+                })
+            }
+            kind @ ("argon2i" | "argon2id") => {
+                let params = Params::new(
+                    number(kdf, "memory")? as u32,
+                    number(kdf, "time")? as u32,
+                    number(kdf, "cpus")? as u32,
+                    None,
+                )
+                .map_err(|_| bad_header("argon2 parameters"))?;
+                let algorithm = match kind {
+                    "argon2i" => Algorithm::Argon2i,
+                    _ => Algorithm::Argon2id,
+                };
+                Ok(Kdf::Argon2 {
+                    params,
+                    algorithm,
+                    salt: base64(kdf, "salt")?,
+                })
+            }
+            _ => Err(uefi::Error::new(Status::UNSUPPORTED, ())),
+        }
+    }
+
+    fn derive(&self, passphrase: &[u8], out: &mut [u8]) -> Result {
+        match self {
+            Kdf::Pbkdf2 { iterations, salt } => {
+                pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, *iterations, out);
+                Ok(())
+            }
+            Kdf::Argon2 {
+                params,
+                algorithm,
+                salt,
+            } => Argon2::new(*algorithm, Version::V0x13, params.clone())
+                .hash_password_into(passphrase, salt, out)
+                .map_err(|_| uefi::Error::new(Status::OUT_OF_RESOURCES, ())),
+        }
+    }
+}
+
+struct Keyslot {
+    id: String,
+    key_size: usize,
+    priority: u64,
+    area_offset: u64,
+    area_key_size: usize,
+    stripes: usize,
+    kdf: Kdf,
+}
+
+impl Keyslot {
+    /// Parses keyslot `id`; its key material has to fit its area, which has
+    /// to end before the data segment at `segment_offset`.
+    fn parse(id: &str, slot: &Value, segment_offset: u64) -> Result<Self> {
+        let area = &slot["area"];
+        let af = &slot["af"];
+        if string(slot, "type")? != "luks2"
+            || string(area, "type")? != "raw"
+            || string(area, "encryption")? != SUPPORTED_CIPHER
+            || string(af, "type")? != "luks1"
+            || string(af, "hash")? != "sha256"
+        {
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+        // The checksum doesn't authenticate the JSON, so these sizes are
+        // checked before anything is allocated or read with them
+        let key_size = number(slot, "key_size")?;
+        let area_key_size = number(area, "key_size")?;
+        let stripes = number(af, "stripes")?;
+        if !matches!(key_size, 32 | 64) || !matches!(area_key_size, 32 | 64) {
+            return Err(bad_header("bad keyslot key size"));
+        }
+        if stripes != AF_STRIPES as u64 {
+            return Err(bad_header("bad anti-forensic stripe count"));
+        }
+        let area_offset = number(area, "offset")?;
+        let area_size = number(area, "size")?;
+        let split_size = (key_size * stripes).next_multiple_of(KEYSLOT_SECTOR_SIZE as u64);
+        if split_size > area_size || area_offset.checked_add(area_size).is_none_or(|end| end > segment_offset) {
+            return Err(bad_header("keyslot area out of bounds"));
+        }
+        Ok(Keyslot {
+            id: id.to_string(),
+            key_size: key_size as usize,
+            // Priority 1 ("normal") is the default when the key is absent.
+            priority: number(slot, "priority").unwrap_or(1),
+            area_offset,
+            area_key_size: area_key_size as usize,
+            stripes: stripes as usize,
+            kdf: Kdf::parse(&slot["kdf"])?,
+        })
+    }
+}
+
+/// A `pbkdf2` digest used to recognize the correct volume key.
+struct KeyDigest {
+    keyslots: Vec<String>,
+    iterations: u32,
+    salt: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+impl KeyDigest {
+    fn matches(&self, key: &[u8]) -> bool {
+        let mut check = Zeroizing::new(vec![0u8; self.digest.len()]);
+        pbkdf2::pbkdf2_hmac::<Sha256>(key, &self.salt, self.iterations, &mut check);
+        check.as_slice() == self.digest.as_slice()
+    }
+}
+
+/// The encrypted data area of the volume.
+pub struct Segment {
+    pub offset: u64,
+    /// `None` when the segment extends to the end of the device.
+    pub size: Option<u64>,
+    pub iv_tweak: u64,
+    pub sector_size: usize,
+}
+
+/// The parts of a LUKS2 header needed to unlock the first data segment.
+pub struct Header {
+    pub label: String,
+    keyslots: Vec<Keyslot>,
+    digests: Vec<KeyDigest>,
+    pub segment: Segment,
+}
+
+impl Header {
+    /// Reads the header from the start of `disk`, falling back to the
+    /// secondary copy if the primary one is damaged. `None` if the device
+    /// isn't LUKS2 at all.
+    pub fn read(disk: &impl Disk) -> Result<Option<Self>> {
+        let mut binary = vec![0u8; BINARY_HEADER_SIZE];
+        disk.read_at(0, &mut binary)?;
+        if &binary[..6] != LUKS2_MAGIC || u16::from_be_bytes([binary[6], binary[7]]) != 2 {
+            return Ok(None);
+        }
+        let hdr_size = u64::from_be_bytes(binary[8..16].try_into().unwrap());
+        match Self::read_copy(disk, 0, LUKS2_MAGIC) {
+            Ok(header) => Ok(Some(header)),
+            Err(e) if e.status() == Status::VOLUME_CORRUPTED => {
+                println!("LUKS2: primary header damaged, trying the secondary one");
+                Self::read_copy(disk, hdr_size, LUKS2_MAGIC_SECONDARY).map(Some)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_copy(disk: &impl Disk, offset: u64, magic: &[u8; 6]) -> Result<Self> {
+        let mut binary = vec![0u8; BINARY_HEADER_SIZE];
+        disk.read_at(offset, &mut binary)?;
+        if &binary[..6] != magic {
+            return Err(bad_header("bad magic"));
+        }
+        let hdr_size = u64::from_be_bytes(binary[8..16].try_into().unwrap());
+        if hdr_size <= BINARY_HEADER_SIZE as u64 || hdr_size > MAX_HEADER_SIZE {
+            return Err(bad_header("bad header size"));
+        }
+        let mut header = vec![0u8; hdr_size as usize];
+        disk.read_at(offset, &mut header)?;
+
+        let csum_alg = &header[72..104];
+        if !csum_alg.starts_with(b"sha256\0") {
+            println!("LUKS2: unsupported header checksum");
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+        let mut expected = [0u8; 32];
+        expected.copy_from_slice(&header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 32]);
+        header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 64].fill(0);
+        if Sha256::digest(&header).as_slice() != expected {
+            return Err(bad_header("header checksum mismatch"));
+        }
+
+        let label = String::from_utf8_lossy(&header[24..72])
+            .trim_end_matches('\0')
+            .to_string();
+        let json = &header[BINARY_HEADER_SIZE..];
+        let json = &json[..json.iter().position(|&b| b == 0).unwrap_or(json.len())];
+        let metadata: Value =
+            serde_json::from_slice(json).map_err(|_| bad_header("invalid JSON metadata"))?;
+        Self::parse(label, &metadata)
+    }
+
+    fn parse(label: String, metadata: &Value) -> Result<Self> {
+        let (segment_id, segment) = metadata["segments"]
+            .as_object()
+            .and_then(|segments| {
+                segments
+                    .iter()
+                    .filter(|(_, segment)| segment["type"] == "crypt")
+                    .min_by_key(|(id, _)| id.parse::<u32>().unwrap_or(u32::MAX))
+            })
+            .ok_or_else(|| bad_header("no crypt segment"))?;
+        if string(segment, "encryption")? != SUPPORTED_CIPHER {
+            println!("LUKS2: unsupported cipher {}", string(segment, "encryption")?);
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+        let segment = Segment {
+            offset: number(segment, "offset")?,
+            size: match string(segment, "size") {
+                Ok("dynamic") => None,
+                _ => Some(number(segment, "size")?),
+            },
+            iv_tweak: number(segment, "iv_tweak")?,
+            sector_size: number(segment, "sector_size")? as usize,
+        };
+        if !matches!(segment.sector_size, 512 | 1024 | 2048 | 4096) {
+            return Err(bad_header("bad sector size"));
+        }
+
+        let mut digests = Vec::new();
+        for digest in metadata["digests"].as_object().into_iter().flat_map(|d| d.values()) {
+            if !ids(digest, "segments").contains(segment_id)
+                || string(digest, "type")? != "pbkdf2"
+                || string(digest, "hash")? != "sha256"
+            {
+                continue;
+            }
+            digests.push(KeyDigest {
+                keyslots: ids(digest, "keyslots"),
+                iterations: number(digest, "iterations")? as u32,
+                salt: base64(digest, "salt")?,
+                digest: base64(digest, "digest")?,
+            });
+        }
+
+        let mut keyslots = Vec::new();
+        for (id, slot) in metadata["keyslots"].as_object().into_iter().flatten() {
+            if !digests.iter().any(|digest| digest.keyslots.contains(id)) {
+                continue;
+            }
+            match Keyslot::parse(id, slot, segment.offset) {
+                // Priority 0 means "only use when asked for explicitly".
+                Ok(slot) if slot.priority == 0 => (),
+                Ok(slot) => keyslots.push(slot),
+                Err(e) => println!("LUKS2: skipping keyslot {}: {:?}", id, e.status()),
+            }
+        }
+        keyslots.sort_by_key(|slot| core::cmp::Reverse(slot.priority));
+        if keyslots.is_empty() {
+            println!("LUKS2: no usable keyslot");
+            return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+        }
+
+        Ok(Header {
+            label,
+            keyslots,
+            digests,
+            segment,
+        })
+    }
+
+    /// Tries `passphrase` against every keyslot and returns the volume key,
+    /// or `None` if no keyslot accepts it.
+    pub fn unlock(&self, disk: &impl Disk, passphrase: &[u8]) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        for slot in &self.keyslots {
+            let key = slot_key(disk, slot, passphrase)?;
+            let accepted = self
+                .digests
+                .iter()
+                .filter(|digest| digest.keyslots.contains(&slot.id))
+                .any(|digest| digest.matches(&key));
+            if accepted {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Decrypts the key material of `slot` with `passphrase` and merges the
+/// anti-forensic stripes into a candidate volume key.
+fn slot_key(disk: &impl Disk, slot: &Keyslot, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let mut area_key = Zeroizing::new(vec![0u8; slot.area_key_size]);
+    slot.kdf.derive(passphrase, &mut area_key)?;
+    let cipher = Xts::new(&area_key)?;
+
+    let split_size = slot.key_size * slot.stripes;
+    let mut split = Zeroizing::new(vec![0u8; split_size.next_multiple_of(KEYSLOT_SECTOR_SIZE)]);
+    disk.read_at(slot.area_offset, &mut split)?;
+    cipher.decrypt_sectors(&mut split, KEYSLOT_SECTOR_SIZE, 0);
+    Ok(af_merge(&split[..split_size], slot.key_size, slot.stripes))
+}
+
+/// Inverse of the LUKS anti-forensic split: XOR all stripes together,
+/// diffusing the running value with SHA-256 after each but the last.
+fn af_merge(split: &[u8], key_size: usize, stripes: usize) -> Zeroizing<Vec<u8>> {
+    let mut key = Zeroizing::new(vec![0u8; key_size]);
+    for (i, stripe) in split.chunks_exact(key_size).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        if i + 1 < stripes {
+            diffuse(&mut key);
+        }
+    }
+    key
+}
+
+fn diffuse(data: &mut [u8]) {
+    for (i, block) in data.chunks_mut(32).enumerate() {
+        let hash = Sha256::new()
+            .chain_update((i as u32).to_be_bytes())
+            .chain_update(&*block)
+            .finalize();
+        let len = block.len();
+        block.copy_from_slice(&hash[..len]);
+    }
+}
+
+/// Reads a passphrase from the console, echoing `*` for each character.
+/// `None` if the user pressed ESC.
+fn read_passphrase(prompt: &str) -> Result<Option<Zeroizing<String>>> {
+    print!("{}", prompt);
+    let mut passphrase = Zeroizing::new(String::new());
+    loop {
+        let key = system::with_stdin(|input| {
+            let mut events = [input.wait_for_key_event().unwrap()];
+            boot::wait_for_event(&mut events).discard_errdata()?;
+            input.read_key()
+        })?;
+        match key {
+            Some(Key::Printable(c)) if c == Char16::try_from('\r').unwrap() => {
+                println!();
+                return Ok(Some(passphrase));
+            }
+            Some(Key::Printable(c)) if c == Char16::try_from('\u{8}').unwrap() => {
+                if passphrase.is_empty() {
+                    continue;
+                }
+                passphrase.pop();
+                print!("\u{8} \u{8}");
+            }
+            Some(Key::Printable(c)) => {
+                passphrase.push(char::from(c));
+                print!("*");
+            }
+            Some(Key::Special(ScanCode::ESCAPE)) => {
+                println!();
+                return Ok(None);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// If `handle` holds a LUKS2 volume, asks for its passphrase and returns a
+/// new handle with the decrypted contents as a read-only `BlockIO`.
+/// `None` if it isn't LUKS2 or the user gave up, `ACCESS_DENIED` after too
+/// many wrong passphrases. A partition unlocked before is not asked for again.
+pub fn unlock_partition(handle: Handle, name: &str) -> Result<Option<Handle>> {
+    if let Some(unlocked) = blockio::unlocked(handle) {
+        return Ok(Some(unlocked));
+    }
+    let disk = UefiDisk::open(handle)?;
+    let Some(header) = Header::read(&disk)? else {
+        return Ok(None);
+    };
+    let name = match header.label.as_str() {
+        "" => name.to_string(),
+        label => alloc::format!("{} ({})", name, label),
+    };
+
+    for attempt in 1..=MAX_PASSPHRASE_TRIES {
+        let prompt = alloc::format!("Passphrase for encrypted {}: ", name);
+        let Some(passphrase) = read_passphrase(&prompt)? else {
+            println!("Skipping encrypted {}.", name);
+            return Ok(None);
+        };
+        println!("Unlocking...");
+        if let Some(key) = header.unlock(&disk, passphrase.as_bytes())? {
+            let cipher = Xts::new(&key)?;
+            return blockio::install(handle, disk, &header.segment, cipher).map(Some);
+        }
+        println!("Wrong passphrase ({}/{}).", attempt, MAX_PASSPHRASE_TRIES);
+    }
+    Err(uefi::Error::new(Status::ACCESS_DENIED, ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::Aes256;
+    use aes::cipher::generic_array::GenericArray;
+    use aes::cipher::{BlockEncrypt, KeyInit};
+    use serde_json::json;
+
+    const PASSPHRASE: &[u8] = b"correct horse";
+    const ITERATIONS: u32 = 1000;
+    const HEADER_SIZE: usize = 16384;
+    const AREA_OFFSET: u64 = 32768;
+    const AREA_SIZE: u64 = 258048;
+    const SEGMENT_OFFSET: u64 = AREA_OFFSET + AREA_SIZE;
+
+    struct Image(Vec<u8>);
+
+    impl Disk for Image {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result {
+            let start = usize::try_from(offset).unwrap_or(usize::MAX);
+            let data = start
+                .checked_add(buf.len())
+                .and_then(|end| self.0.get(start..end))
+                .ok_or_else(|| uefi::Error::new(Status::DEVICE_ERROR, ()))?;
+            buf.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn volume_key() -> Vec<u8> {
+        (100..164).collect()
+    }
+
+    fn plaintext() -> Vec<u8> {
+        (0..1024).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// aes-xts-plain64 encryption of 512 byte sectors with a 64 byte key,
+    /// written independently of `Xts`.
+    fn encrypt(key: &[u8], data: &mut [u8], first_sector: u64) {
+        let data_key = Aes256::new(GenericArray::from_slice(&key[..32]));
+        let tweak_key = Aes256::new(GenericArray::from_slice(&key[32..]));
+        for (i, sector) in data.chunks_exact_mut(KEYSLOT_SECTOR_SIZE).enumerate() {
+            let mut tweak = [0u8; 16];
+            tweak[..8].copy_from_slice(&(first_sector + i as u64).to_le_bytes());
+            tweak_key.encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+            for block in sector.chunks_exact_mut(16) {
+                block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+                data_key.encrypt_block(GenericArray::from_mut_slice(block));
+                block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+                let carry = tweak[15] >> 7;
+                tweak = (u128::from_le_bytes(tweak) << 1).to_le_bytes();
+                tweak[0] ^= 0x87 * carry;
+            }
+        }
+    }
+
+    /// The anti-forensic split of `key`, with arbitrary stripes but the last.
+    fn af_split(key: &[u8]) -> Vec<u8> {
+        let mut split: Vec<u8> = (0..key.len() * AF_STRIPES).map(|i| (i * 31 % 251) as u8).collect();
+        let mut mixed = vec![0u8; key.len()];
+        for stripe in split.chunks_exact(key.len()).take(AF_STRIPES - 1) {
+            mixed.iter_mut().zip(stripe).for_each(|(m, s)| *m ^= s);
+            diffuse(&mut mixed);
+        }
+        let last = split.len() - key.len();
+        split[last..].iter_mut().zip(mixed.iter().zip(key)).for_each(|(s, (m, k))| *s = m ^ k);
+        split
+    }
+
+    fn metadata() -> Value {
+        let mut digest = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&volume_key(), &[2; 32], ITERATIONS, &mut digest);
+        json!({
+            "keyslots": {"0": {
+                "type": "luks2",
+                "key_size": 64,
+                "af": {"type": "luks1", "stripes": AF_STRIPES, "hash": "sha256"},
+                "area": {
+                    "type": "raw",
+                    "offset": AREA_OFFSET.to_string(),
+                    "size": AREA_SIZE.to_string(),
+                    "encryption": SUPPORTED_CIPHER,
+                    "key_size": 64,
+                },
+                "kdf": {"type": "pbkdf2", "hash": "sha256", "iterations": ITERATIONS, "salt": Base64::encode_string(&[1; 32])},
+            }},
+            "segments": {"0": {
+                "type": "crypt",
+                "offset": SEGMENT_OFFSET.to_string(),
+                "size": "dynamic",
+                "iv_tweak": "0",
+                "encryption": SUPPORTED_CIPHER,
+                "sector_size": 512,
+            }},
+            "digests": {"0": {
+                "type": "pbkdf2",
+                "keyslots": ["0"],
+                "segments": ["0"],
+                "hash": "sha256",
+                "iterations": ITERATIONS,
+                "salt": Base64::encode_string(&[2; 32]),
+                "digest": Base64::encode_string(&digest),
+            }},
+        })
+    }
+
+    fn header_copy(magic: &[u8; 6], metadata: &Value) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[..6].copy_from_slice(magic);
+        header[6..8].copy_from_slice(&2u16.to_be_bytes());
+        header[8..16].copy_from_slice(&(HEADER_SIZE as u64).to_be_bytes());
+        header[24..28].copy_from_slice(b"root");
+        header[72..78].copy_from_slice(b"sha256");
+        let json = serde_json::to_vec(metadata).unwrap();
+        header[BINARY_HEADER_SIZE..BINARY_HEADER_SIZE + json.len()].copy_from_slice(&json);
+        let checksum = Sha256::digest(&header);
+        header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 32].copy_from_slice(&checksum);
+        header
+    }
+
+    /// A volume with both header copies, keyslot 0 opened by `PASSPHRASE`
+    /// and `plaintext()` encrypted in the data segment.
+    fn volume() -> Vec<u8> {
+        let mut image = vec![0u8; SEGMENT_OFFSET as usize];
+        image[..HEADER_SIZE].copy_from_slice(&header_copy(LUKS2_MAGIC, &metadata()));
+        image[HEADER_SIZE..2 * HEADER_SIZE].copy_from_slice(&header_copy(LUKS2_MAGIC_SECONDARY, &metadata()));
+
+        let mut area_key = [0u8; 64];
+        pbkdf2::pbkdf2_hmac::<Sha256>(PASSPHRASE, &[1; 32], ITERATIONS, &mut area_key);
+        let mut split = af_split(&volume_key());
+        split.resize(split.len().next_multiple_of(KEYSLOT_SECTOR_SIZE), 0);
+        encrypt(&area_key, &mut split, 0);
+        image[AREA_OFFSET as usize..AREA_OFFSET as usize + split.len()].copy_from_slice(&split);
+
+        let mut data = plaintext();
+        encrypt(&volume_key(), &mut data, 0);
+        image.extend(data);
+        image
+    }
+
+    #[test]
+    fn merges_split_key() {
+        let key = volume_key();
+        assert_eq!(*af_merge(&af_split(&key), key.len(), AF_STRIPES), key);
+        assert_ne!(*af_merge(&af_split(&key)[64..], key.len(), AF_STRIPES - 1), key);
+    }
+
+    #[test]
+    fn unlocks_volume() {
+        let disk = Image(volume());
+        let header = Header::read(&disk).unwrap().unwrap();
+        assert_eq!(header.label, "root");
+        assert_eq!(header.segment.offset, SEGMENT_OFFSET);
+        assert!(header.segment.size.is_none());
+        assert!(header.unlock(&disk, b"wrong").unwrap().is_none());
+        let key = header.unlock(&disk, PASSPHRASE).unwrap().unwrap();
+        assert_eq!(*key, volume_key());
+
+        let mut data = disk.0[SEGMENT_OFFSET as usize..].to_vec();
+        Xts::new(&key).unwrap().decrypt_sectors(&mut data, header.segment.sector_size, header.segment.iv_tweak);
+        assert_eq!(data, plaintext());
+    }
+
+    #[test]
+    fn falls_back_to_secondary_header() {
+        let mut image = volume();
+        image[BINARY_HEADER_SIZE + 10] ^= 1;
+        let disk = Image(image);
+        let header = Header::read(&disk).unwrap().unwrap();
+        assert!(header.unlock(&disk, PASSPHRASE).unwrap().is_some());
+
+        let mut image = disk.0;
+        image[HEADER_SIZE + BINARY_HEADER_SIZE + 10] ^= 1;
+        assert_eq!(Header::read(&Image(image)).err().map(|e| e.status()), Some(Status::VOLUME_CORRUPTED));
+    }
+
+    #[test]
+    fn rejects_bad_header_sizes() {
+        let mut image = volume();
+        image[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(Header::read(&Image(image)).is_err());
+        let mut image = volume();
+        image[8..16].copy_from_slice(&(BINARY_HEADER_SIZE as u64).to_be_bytes());
+        assert!(Header::read(&Image(image)).is_err());
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        assert!(Header::read(&Image(vec![0; 8192])).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_keyslots() {
+        let slot = |edit: fn(&mut Value)| {
+            let mut slot = metadata()["keyslots"]["0"].clone();
+            edit(&mut slot);
+            Keyslot::parse("0", &slot, SEGMENT_OFFSET).err().map(|e| e.status())
+        };
+        let corrupted = Some(Status::VOLUME_CORRUPTED);
+        assert_eq!(slot(|_| ()), None);
+        assert_eq!(slot(|s| s["key_size"] = json!(48)), corrupted);
+        assert_eq!(slot(|s| s["key_size"] = json!("many")), corrupted);
+        assert_eq!(slot(|s| s["area"]["key_size"] = json!(1u64 << 40)), corrupted);
+        assert_eq!(slot(|s| s["af"]["stripes"] = json!(1)), corrupted);
+        // Areas too small for the split key, overflowing or overlapping the data
+        assert_eq!(slot(|s| s["area"]["size"] = json!("4096")), corrupted);
+        assert_eq!(slot(|s| s["area"]["offset"] = json!(u64::MAX.to_string())), corrupted);
+        assert_eq!(slot(|s| s["area"]["offset"] = json!(SEGMENT_OFFSET - 4096)), corrupted);
+        assert_eq!(slot(|s| s["area"]["encryption"] = json!("aes-cbc-essiv:sha256")), Some(Status::UNSUPPORTED));
+        assert_eq!(slot(|s| s["kdf"]["type"] = json!("scrypt")), Some(Status::UNSUPPORTED));
+    }
+
+    #[test]
+    fn rejects_bad_metadata() {
+        let header = |edit: fn(&mut Value)| {
+            let mut metadata = metadata();
+            edit(&mut metadata);
+            Header::parse(String::new(), &metadata).err().map(|e| e.status())
+        };
+        let corrupted = Some(Status::VOLUME_CORRUPTED);
+        assert_eq!(header(|_| ()), None);
+        // Keyslots that are unusable or without a digest of the segment
+        assert_eq!(header(|m| m["keyslots"]["0"]["af"]["stripes"] = json!(0)), Some(Status::UNSUPPORTED));
+        assert_eq!(header(|m| m["digests"]["0"]["segments"] = json!(["1"])), Some(Status::UNSUPPORTED));
+        assert_eq!(header(|m| m["digests"]["0"]["salt"] = json!("not base64!")), corrupted);
+        assert_eq!(header(|m| m["segments"]["0"]["type"] = json!("linear")), corrupted);
+        assert_eq!(header(|m| m["segments"]["0"]["sector_size"] = json!(520)), corrupted);
+        assert_eq!(header(|m| m["segments"]["0"]["encryption"] = json!("serpent-xts-plain64")), Some(Status::UNSUPPORTED));
+    }
+}
@@ -0,0 +1,200 @@
+// blockio.rs
+// Publishes an unlocked LUKS2 segment as a read-only BlockIO device
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::proto::media::block::BlockIO;
+use uefi::{Guid, Handle, Identify, Result, Status, boot, guid};
+use uefi_raw::Boolean;
+use uefi_raw::protocol::block::{BlockIoMedia, BlockIoProtocol, Lba};
+
+use super::Segment;
+use super::xts::Xts;
+use crate::ext4::{Disk, UefiDisk};
+use crate::volume::open_shared;
+
+/// Vendor node appended to the partition's device path for the decrypted
+/// view, so the firmware treats it as a separate device.
+const CRYPT_DEVICE_GUID: Guid = guid!("6d3b9f5e-2c4a-4f7b-9d1e-8a0c5b7e3f21");
+
+/// Protocol instance handed to the firmware. `proto` must stay first so the
+/// `this` pointer can be cast back.
+#[repr(C)]
+struct CryptDevice {
+    proto: BlockIoProtocol,
+    media: BlockIoMedia,
+    disk: UefiDisk,
+    /// Dropped (and thereby zeroed) by [`wipe_keys`].
+    cipher: Option<Xts>,
+    offset: u64,
+    iv_tweak: u64,
+    /// The encrypted partition, and the handle of this view once installed.
+    parent: Handle,
+    handle: Option<Handle>,
+}
+
+/// Every device unlocked so far, so their keys can be wiped before handoff.
+struct Unlocked(UnsafeCell<Vec<*mut CryptDevice>>);
+
+// Boot services are single threaded.
+unsafe impl Sync for Unlocked {}
+
+static UNLOCKED: Unlocked = Unlocked(UnsafeCell::new(Vec::new()));
+
+unsafe extern "efiapi" fn reset(_this: *mut BlockIoProtocol, _extended: Boolean) -> Status {
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn read_blocks(
+    this: *const BlockIoProtocol,
+    media_id: u32,
+    lba: Lba,
+    buffer_size: usize,
+    buffer: *mut c_void,
+) -> Status {
+    let device = unsafe { &*(this as *const CryptDevice) };
+    let block_size = device.media.block_size as usize;
+    if media_id != device.media.media_id {
+        return Status::MEDIA_CHANGED;
+    }
+    if buffer.is_null() || !buffer_size.is_multiple_of(block_size) {
+        return Status::INVALID_PARAMETER;
+    }
+    let blocks = (buffer_size / block_size) as u64;
+    if lba.saturating_add(blocks) > device.media.last_block + 1 {
+        return Status::INVALID_PARAMETER;
+    }
+    let Some(cipher) = &device.cipher else {
+        return Status::DEVICE_ERROR;
+    };
+
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, buffer_size) };
+    let offset = device.offset + lba * block_size as u64;
+    if device.disk.read_at(offset, data).is_err() {
+        return Status::DEVICE_ERROR;
+    }
+    // dm-crypt counts the tweak in 512 byte units but IVs in whole sectors.
+    let first_iv = device.iv_tweak / (block_size as u64 / 512) + lba;
+    cipher.decrypt_sectors(data, block_size, first_iv);
+    Status::SUCCESS
+}
+
+unsafe extern "efiapi" fn write_blocks(
+    _this: *mut BlockIoProtocol,
+    _media_id: u32,
+    _lba: Lba,
+    _buffer_size: usize,
+    _buffer: *const c_void,
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+unsafe extern "efiapi" fn flush_blocks(_this: *mut BlockIoProtocol) -> Status {
+    Status::SUCCESS
+}
+
+/// Builds the device path of the decrypted view of `parent`.
+fn crypt_device_path(parent: Handle) -> Result<Vec<u8>> {
+    let parent_path = open_shared::<DevicePath>(parent)?;
+    let mut storage = Vec::new();
+    let mut builder = DevicePathBuilder::with_vec(&mut storage);
+    for node in parent_path.node_iter() {
+        builder = builder
+            .push(&node)
+            .map_err(|_| uefi::Error::new(Status::OUT_OF_RESOURCES, ()))?;
+    }
+    builder
+        .push(&build::media::Vendor {
+            vendor_guid: CRYPT_DEVICE_GUID,
+            vendor_defined_data: &[],
+        })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_| uefi::Error::new(Status::OUT_OF_RESOURCES, ()))?;
+    Ok(storage)
+}
+
+/// Installs the decrypted view of `segment` on a new handle and connects
+/// drivers to it, so file systems on it show up like on any other disk.
+pub fn install(parent: Handle, disk: UefiDisk, segment: &Segment, cipher: Xts) -> Result<Handle> {
+    let size = match segment.size {
+        Some(size) => size,
+        None => disk.size().saturating_sub(segment.offset),
+    };
+    let block_size = segment.sector_size as u64;
+    if size < block_size {
+        return Err(uefi::Error::new(Status::VOLUME_CORRUPTED, ()));
+    }
+    let media_id = open_shared::<BlockIO>(parent)?
+        .media()
+        .media_id();
+
+    let device_path: &'static mut [u8] = crypt_device_path(parent)?.leak();
+    let device = Box::leak(Box::new(CryptDevice {
+        proto: BlockIoProtocol {
+            revision: 0x0001_0000,
+            media: core::ptr::null(),
+            reset,
+            read_blocks,
+            write_blocks,
+            flush_blocks,
+        },
+        media: BlockIoMedia {
+            media_id,
+            removable_media: Boolean::FALSE,
+            media_present: Boolean::TRUE,
+            logical_partition: Boolean::TRUE,
+            read_only: Boolean::TRUE,
+            write_caching: Boolean::FALSE,
+            block_size: block_size as u32,
+            io_align: 0,
+            last_block: size / block_size - 1,
+            lowest_aligned_lba: 0,
+            logical_blocks_per_physical_block: 0,
+            optimal_transfer_length_granularity: 0,
+        },
+        disk,
+        cipher: Some(cipher),
+        offset: segment.offset,
+        iv_tweak: segment.iv_tweak,
+        parent,
+        handle: None,
+    }));
+    device.proto.media = &device.media;
+    unsafe { (*UNLOCKED.0.get()).push(device) };
+
+    let handle = unsafe {
+        let handle = boot::install_protocol_interface(
+            None,
+            &DevicePath::GUID,
+            device_path.as_ptr() as *const c_void,
+        )?;
+        boot::install_protocol_interface(
+            Some(handle),
+            &BlockIoProtocol::GUID,
+            device as *const CryptDevice as *const c_void,
+        )?
+    };
+    device.handle = Some(handle);
+    let _ = boot::connect_controller(handle, None, None, true);
+    Ok(handle)
+}
+
+/// The decrypted view installed for `parent` earlier, if any.
+pub fn unlocked(parent: Handle) -> Option<Handle> {
+    unsafe { (*UNLOCKED.0.get()).iter() }
+        .map(|&device| unsafe { &*device })
+        .find(|device| device.parent == parent)
+        .and_then(|device| device.handle)
+}
+
+/// Zeroes the volume keys of all unlocked devices. Must be called before
+/// control is handed to the next stage; the devices fail reads afterwards.
+pub fn wipe_keys() {
+    for &device in unsafe { (*UNLOCKED.0.get()).iter() } {
+        unsafe { (*device).cipher = None };
+    }
+}
@@ -0,0 +1,114 @@
+// xts.rs
+// AES-XTS sector decryption with plain64 IVs, as used by dm-crypt
+
+use alloc::boxed::Box;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256};
+use uefi::{Result, Status};
+
+/// The two halves of an XTS key: data key and tweak key.
+enum Keys {
+    Aes128(Box<(Aes128, Aes128)>),
+    Aes256(Box<(Aes256, Aes256)>),
+}
+
+/// An `aes-xts-plain64` cipher. The expanded keys are zeroed when dropped.
+pub struct Xts {
+    keys: Keys,
+}
+
+impl Xts {
+    /// `key` is the full XTS key: 32 bytes for AES-128, 64 for AES-256.
+    pub fn new(key: &[u8]) -> Result<Self> {
+        let keys = match key.len() {
+            32 => Keys::Aes128(Box::new((
+                Aes128::new(GenericArray::from_slice(&key[..16])),
+                Aes128::new(GenericArray::from_slice(&key[16..])),
+            ))),
+            64 => Keys::Aes256(Box::new((
+                Aes256::new(GenericArray::from_slice(&key[..32])),
+                Aes256::new(GenericArray::from_slice(&key[32..])),
+            ))),
+            _ => return Err(uefi::Error::new(Status::UNSUPPORTED, ())),
+        };
+        Ok(Xts { keys })
+    }
+
+    /// Decrypts consecutive sectors of `sector_size` bytes in place, the
+    /// first of which has IV `first_sector`.
+    pub fn decrypt_sectors(&self, data: &mut [u8], sector_size: usize, first_sector: u64) {
+        for (i, sector) in data.chunks_exact_mut(sector_size).enumerate() {
+            match &self.keys {
+                Keys::Aes128(keys) => decrypt_sector(&keys.0, &keys.1, sector, first_sector + i as u64),
+                Keys::Aes256(keys) => decrypt_sector(&keys.0, &keys.1, sector, first_sector + i as u64),
+            }
+        }
+    }
+}
+
+/// Multiplies the tweak by x in GF(2^128), little-endian as XTS defines it.
+fn next_tweak(tweak: &mut [u8; 16]) {
+    let mut carry = 0;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
+
+fn decrypt_sector<C: BlockEncrypt + BlockDecrypt>(
+    data_key: &C,
+    tweak_key: &C,
+    sector: &mut [u8],
+    iv: u64,
+) {
+    let mut tweak = [0u8; 16];
+    tweak[..8].copy_from_slice(&iv.to_le_bytes());
+    tweak_key.encrypt_block(GenericArray::from_mut_slice(&mut tweak));
+
+    for block in sector.chunks_exact_mut(16) {
+        block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+        data_key.decrypt_block(GenericArray::from_mut_slice(block));
+        block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+        next_tweak(&mut tweak);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn ieee_1619_vector() {
+        // XTS-AES-128 vector 2 of IEEE 1619
+        let key = [[0x11; 16], [0x22; 16]].concat();
+        let mut data = hex("c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0");
+        Xts::new(&key).unwrap().decrypt_sectors(&mut data, 32, 0x3333333333);
+        assert_eq!(data, [0x44; 32]);
+    }
+
+    #[test]
+    fn aes256_sectors() {
+        let key: Vec<u8> = (0..64).collect();
+        let mut data = hex(concat!(
+            "0976f139b289f2dd570e3b8caa596f98f86a162f8768ffbd7ad06c74d403f32a",
+            "28d18ee70810a5e99705c7de0cc8f58dc859d6feb5b8bc1846400e0944afffed",
+        ));
+        Xts::new(&key).unwrap().decrypt_sectors(&mut data, 32, 1);
+        assert_eq!(data, (0..64).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn rejects_key_sizes() {
+        assert!(Xts::new(&[0; 16]).is_err());
+        assert!(Xts::new(&[0; 48]).is_err());
+    }
+}
@@ -1,11 +1,30 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
+mod acpi;
+mod arch;
+mod badram;
 mod boot_selector;
+mod devicetree;
+mod drivers;
 mod kernel_loader;
 mod entries_parse;
+mod ext4;
+mod iso;
+mod limine;
+mod loader_conf;
+mod luks2;
+mod memtest;
+mod multiboot2;
+mod pe;
+mod pxe;
+mod qemu;
+mod random_seed;
+mod secureboot;
+mod smbios;
+mod volume;
+mod xbootldr;
 extern crate alloc;
-use alloc::vec::Vec;
 use boot_selector::boot_menu;
 use kernel_loader::load_efi_from_path;
 use entries_parse::BootEntry;
@@ -14,11 +33,7 @@ use uefi::boot::{self, SearchType};
 use uefi::prelude::*;
 use uefi::println;
 use uefi::proto::console::text::Input;
-use uefi::proto::device_path::text::{AllowShortcuts, DevicePathToText, DisplayOnly};
-use uefi::proto::loaded_image::LoadedImage;
-use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode, FileType};
-use uefi::proto::media::fs::SimpleFileSystem;
-use uefi::{CStr16, Identify, Result};
+use uefi::Identify;
 
 #[entry]
 fn main() -> Status {
@@ -32,11 +47,78 @@ fn main() -> Status {
         .first()
         .expect("No handle supports TextInput protocol");
     let mut input = boot::open_protocol_exclusive::<Input>(handle).unwrap();
-    let entries = read_loader_entries().unwrap();
-
-    if let Ok(Some(entry)) = boot_menu(&entries, &mut input) {
-        if let Some(path_linux) = entry.linux {
-            load_efi_from_path(&path_linux, entry.initrd.as_deref(), entry.options.as_deref()).unwrap();
+    // File system drivers have to be running before we look for entries
+    if let Err(e) = drivers::load_drivers() {
+        println!("Failed to load drivers: {:?}", e.status());
+    }
+    // Setup Mode is left by the reboot after enrolling
+    if let Err(e) = secureboot::enroll_automatically() {
+        println!("Failed to enroll Secure Boot keys: {:?}", e.status());
+    }
+    let mut entries = read_loader_entries().unwrap();
+
+    while let Ok(Some(mut entry)) = boot_menu(&mut entries, &mut input) {
+        // The memory test goes back to the menu
+        if entry.memtest {
+            if let Err(e) = memtest::run() {
+                println!("Memory test failed: {:?}", e.status());
+                boot::stall(2_000_000);
+            }
+            continue;
+        }
+        if let Some(name) = &entry.secure_boot_keys {
+            if let Err(e) = secureboot::enroll(name, true) {
+                println!("Failed to enroll Secure Boot keys {}: {:?}", name, e.status());
+                boot::stall(2_000_000);
+            }
+            continue;
+        }
+        if let Err(e) = random_seed::process_random_seed() {
+            println!("Failed to process random seed: {:?}", e.status());
+        }
+        // Before anything is loaded into the bad pages
+        if let Err(e) = badram::reserve_for(&mut entry) {
+            println!("Failed to reserve bad RAM: {:?}", e.status());
+        }
+        // An entry whose tables can't be installed isn't booted without them
+        if let Err(e) = acpi::install_for(&entry) {
+            println!("Failed to load the ACPI tables, not booting {}: {:?}", entry.title, e.status());
+            boot::stall(2_000_000);
+            continue;
+        }
+        if let Err(e) = devicetree::install_for(&entry) {
+            println!("Failed to load the devicetree, not booting {}: {:?}", entry.title, e.status());
+            boot::stall(2_000_000);
+            continue;
+        }
+        // A failed boot goes back to the menu
+        let booted = if entry.fw_cfg {
+            qemu::boot_fw_cfg(entry.options.as_deref())
+                .inspect_err(|e| println!("Direct kernel boot failed: {:?}", e.status()))
+        // Network entries name TFTP paths in `linux`/`initrd`
+        } else if let Some(spec) = &entry.pxe {
+            pxe::boot_pxe(spec, &entry).inspect_err(|e| println!("Network boot failed: {:?}", e.status()))
+        } else if let Some(path) = &entry.multiboot2 {
+            multiboot2::boot_multiboot2(path, &entry)
+                .inspect_err(|e| println!("Failed to boot Multiboot2 kernel {}: {:?}", path, e.status()))
+        } else if let Some(path) = &entry.limine {
+            limine::boot_limine(path, &entry)
+                .inspect_err(|e| println!("Failed to boot Limine kernel {}: {:?}", path, e.status()))
+        } else if let Some(path_linux) = &entry.linux {
+            load_efi_from_path(entry.device, path_linux, entry.initrd.as_deref(), entry.options.as_deref())
+                .inspect_err(|e| println!("Failed to boot {}: {:?}", path_linux, e.status()))
+        } else if let Some(path_efi) = &entry.efi {
+            load_efi_from_path(entry.device, path_efi, None, entry.options.as_deref())
+                .inspect_err(|e| println!("Failed to boot {}: {:?}", path_efi, e.status()))
+        } else if let Some(path_iso) = &entry.iso {
+            iso::boot_iso(entry.device, path_iso, entry.options.as_deref())
+                .inspect_err(|e| println!("Failed to boot ISO {}: {:?}", path_iso, e.status()))
+        } else {
+            Ok(())
+        };
+        if booted.is_err() {
+            boot::stall(2_000_000);
+            continue;
         }
             /*match load_kernel_image(
                 &path_linux,
@@ -57,6 +139,7 @@ fn main() -> Status {
             load_efi_from_path(&path_efi).unwrap();
         }*/
         //load_efi_from_path(&selected).unwrap();
+        break;
     }
 
     boot::stall(100_000_000);
@@ -0,0 +1,233 @@
+// memtest.rs
+// Built-in RAM test over the free conventional memory, offered as a menu entry
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::{Char16, Result, ResultExt, print, println, system};
+
+use crate::entries_parse::BootEntry;
+use crate::volume::Volume;
+
+const PAGE_SIZE: u64 = boot::PAGE_SIZE as u64;
+/// Memory is claimed, tested and given back in chunks of this size, so the
+/// firmware keeps most of the free memory while the test runs.
+const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+const MOVING_INVERSION_PATTERNS: [u64; 4] = [0, 0x5555_5555_5555_5555, 0x3333_3333_3333_3333, 0x0f0f_0f0f_0f0f_0f0f];
+/// Failing ranges kept; a dying DIMM shouldn't exhaust the loader's memory.
+const MAX_RANGES: usize = 256;
+const LOADER_CONF_PATH: &str = "loader\\loader.conf";
+
+/// The "Memory test" menu entry.
+pub fn entry() -> BootEntry {
+    let mut entry = BootEntry::new();
+    entry.title = String::from("Memory test");
+    entry.memtest = true;
+    entry
+}
+
+/// Failing pages, merged into ranges.
+#[derive(Default)]
+struct Failures {
+    ranges: Vec<(u64, u64)>,
+    /// More failures than `MAX_RANGES` ranges could hold.
+    overflow: bool,
+}
+
+impl Failures {
+    fn record(&mut self, address: u64) {
+        let page = address & !(PAGE_SIZE - 1);
+        if self.ranges.iter().rev().any(|&(start, end)| (start..end).contains(&page)) {
+            return;
+        }
+        if let Some(range) = self.ranges.iter_mut().find(|(start, end)| *end == page || page + PAGE_SIZE == *start) {
+            *range = (range.0.min(page), range.1.max(page + PAGE_SIZE));
+        } else if self.ranges.len() < MAX_RANGES {
+            self.ranges.push((page, page + PAGE_SIZE));
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    /// The ranges sorted, with touching ones joined.
+    fn merged(&self) -> Vec<(u64, u64)> {
+        let mut ranges = self.ranges.clone();
+        ranges.sort_unstable();
+        ranges.dedup_by(|next, range| {
+            let touches = next.0 <= range.1;
+            if touches {
+                range.1 = range.1.max(next.1);
+            }
+            touches
+        });
+        ranges
+    }
+}
+
+/// Fills `words` with `pattern`, then checks and inverts every word going
+/// up, and checks and restores it going down.
+fn moving_inversions(words: *mut u64, count: usize, pattern: u64, failures: &mut Failures) {
+    unsafe {
+        for i in 0..count {
+            write_volatile(words.add(i), pattern);
+        }
+        for i in 0..count {
+            if read_volatile(words.add(i)) != pattern {
+                failures.record(words.add(i) as u64);
+            }
+            write_volatile(words.add(i), !pattern);
+        }
+        for i in (0..count).rev() {
+            if read_volatile(words.add(i)) != !pattern {
+                failures.record(words.add(i) as u64);
+            }
+            write_volatile(words.add(i), pattern);
+        }
+    }
+}
+
+/// Writes each word's own address, then its complement, checking after
+/// each pass. Catches address lines that alias.
+fn address_in_address(words: *mut u64, count: usize, failures: &mut Failures) {
+    for invert in [0, !0] {
+        unsafe {
+            for i in 0..count {
+                write_volatile(words.add(i), words.add(i) as u64 ^ invert);
+            }
+            for i in 0..count {
+                if read_volatile(words.add(i)) != words.add(i) as u64 ^ invert {
+                    failures.record(words.add(i) as u64);
+                }
+            }
+        }
+    }
+}
+
+fn escape_pressed() -> bool {
+    let key = system::with_stdin(|input| input.read_key());
+    matches!(key, Ok(Some(Key::Special(ScanCode::ESCAPE))))
+}
+
+fn wait_for_key() -> Result<Option<Key>> {
+    system::with_stdin(|input| {
+        let mut events = [input.wait_for_key_event().unwrap()];
+        boot::wait_for_event(&mut events).discard_errdata()?;
+        input.read_key()
+    })
+}
+
+/// `badram` line for `ranges`, each split into naturally aligned power of
+/// two blocks that an address/mask pair can describe.
+fn badram_line(ranges: &[(u64, u64)]) -> String {
+    let mut pairs = Vec::new();
+    for &(mut start, end) in ranges {
+        while start < end {
+            let mut size = 1u64 << start.trailing_zeros().min(63);
+            while start + size > end {
+                size /= 2;
+            }
+            pairs.push(format!("{:#x},{:#x}", start, !(size - 1)));
+            start += size;
+        }
+    }
+    format!("badram {}", pairs.join(","))
+}
+
+/// Appends `line` to loader.conf on the loader's volume.
+fn save(line: &str) -> Result {
+    let mut volume = Volume::loader()?;
+    let mut text = if volume.exists(LOADER_CONF_PATH)? {
+        volume.read_to_string(LOADER_CONF_PATH)?
+    } else {
+        String::new()
+    };
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(line);
+    text.push('\n');
+    // Written next to it first, so an interrupted write can't truncate it
+    let new_path = format!("{}.new", LOADER_CONF_PATH);
+    volume.write(&new_path, text.as_bytes())?;
+    if volume.exists(LOADER_CONF_PATH)? {
+        volume.delete(LOADER_CONF_PATH)?;
+    }
+    volume.rename(&new_path, LOADER_CONF_PATH)
+}
+
+/// Tests the conventional memory of the UEFI memory map with moving
+/// inversions and address-in-address, reports the failing ranges and offers
+/// to add them to loader.conf as `badram`.
+pub fn run() -> Result {
+    let map = boot::memory_map(MemoryType::LOADER_DATA)?;
+    let regions: Vec<(u64, u64)> = map
+        .entries()
+        .filter(|d| d.ty == MemoryType::CONVENTIONAL)
+        // Page 0 can't be handed out as a pointer
+        .map(|d| (d.phys_start.max(PAGE_SIZE), d.phys_start + d.page_count * PAGE_SIZE))
+        .filter(|(start, end)| start < end)
+        .collect();
+    drop(map);
+    let total: u64 = regions.iter().map(|(start, end)| end - start).sum();
+    println!("Testing {} MiB of free memory, ESC to stop", total / (1024 * 1024));
+
+    let mut failures = Failures::default();
+    let mut tested = 0;
+    'regions: for &(start, end) in &regions {
+        for chunk in (start..end).step_by(CHUNK_SIZE as usize) {
+            let size = CHUNK_SIZE.min(end - chunk);
+            let pages = (size / PAGE_SIZE) as usize;
+            // Taken by the firmware since the map was read
+            let Ok(memory) = boot::allocate_pages(AllocateType::Address(chunk), MemoryType::LOADER_DATA, pages) else {
+                continue;
+            };
+            print!("\r{:#014x}-{:#014x} ({}%)", chunk, chunk + size - 1, tested * 100 / total.max(1));
+            let words = memory.as_ptr().cast::<u64>();
+            let count = size as usize / 8;
+            for pattern in MOVING_INVERSION_PATTERNS {
+                moving_inversions(words, count, pattern, &mut failures);
+            }
+            address_in_address(words, count, &mut failures);
+            unsafe { boot::free_pages(memory, pages)? };
+            tested += size;
+            if escape_pressed() {
+                println!("\nStopped");
+                break 'regions;
+            }
+        }
+    }
+    println!("\rTested {} MiB", tested / (1024 * 1024));
+
+    let ranges = failures.merged();
+    if ranges.is_empty() {
+        println!("No errors found.");
+    } else {
+        println!("Errors in:");
+        for (start, end) in &ranges {
+            println!("  {:#014x}-{:#014x}", start, end - 1);
+        }
+        if failures.overflow {
+            println!("  ... and more, only the first {} ranges are listed", MAX_RANGES);
+        }
+        let line = badram_line(&ranges);
+        println!("{}", line);
+        print!("Add this line to loader.conf? [y/N] ");
+        let answer = wait_for_key()?;
+        println!();
+        if let Some(Key::Printable(c)) = answer
+            && (c == Char16::try_from('y').unwrap() || c == Char16::try_from('Y').unwrap())
+        {
+            match save(&line) {
+                Ok(()) => println!("Saved, the pages are reserved from the next boot on."),
+                Err(e) => println!("Failed to save loader.conf: {:?}", e.status()),
+            }
+        }
+    }
+    println!("Press any key to return to the menu");
+    wait_for_key()?;
+    Ok(())
+}
@@ -0,0 +1,365 @@
+// multiboot2.rs
+// Loads Multiboot2 kernels and their modules and hands over to them
+
+mod elf;
+mod handoff;
+mod info;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::mem::memory_map::MemoryMap;
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+use uefi::{Result, Status, println};
+
+use crate::entries_parse::BootEntry;
+use crate::volume::{Volume, open_shared};
+use crate::{acpi, luks2, secureboot};
+use elf::Segment;
+use handoff::Trampoline;
+pub use info::Framebuffer;
+use info::Info;
+
+const HEADER_MAGIC: u32 = 0xe852_50d6;
+/// The header has to be in the first 32 KiB of the image.
+const HEADER_SEARCH: usize = 32 * 1024;
+const ARCHITECTURE_I386: u32 = 0;
+
+const TAG_OPTIONAL: u16 = 1;
+const TAG_END: u16 = 0;
+const TAG_INFORMATION_REQUEST: u16 = 1;
+const TAG_ADDRESS: u16 = 2;
+const TAG_ENTRY_ADDRESS: u16 = 3;
+const TAG_CONSOLE_FLAGS: u16 = 4;
+const TAG_FRAMEBUFFER: u16 = 5;
+const TAG_MODULE_ALIGN: u16 = 6;
+const TAG_EFI_BS: u16 = 7;
+const TAG_ENTRY_ADDRESS_EFI64: u16 = 9;
+const TAG_RELOCATABLE: u16 = 10;
+
+const PAGE_SIZE: u64 = boot::PAGE_SIZE as u64;
+
+/// The a.out kludge: the file is loaded as is, from `load` on.
+struct Address {
+    header: u32,
+    load: u32,
+    load_end: u32,
+    bss_end: u32,
+}
+
+/// Where the kernel may be moved if its link address is taken.
+struct Relocatable {
+    min: u32,
+    max: u32,
+    align: u32,
+}
+
+#[derive(Default)]
+struct Header {
+    /// Offset of the header in the file.
+    offset: usize,
+    address: Option<Address>,
+    entry: Option<u32>,
+    efi64_entry: Option<u32>,
+    /// The kernel can run with boot services still there.
+    keep_boot_services: bool,
+    /// Preferred framebuffer resolution, 0 for no preference.
+    framebuffer: Option<(u32, u32)>,
+    relocatable: Option<Relocatable>,
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn find_header(image: &[u8]) -> Option<usize> {
+    (0..image.len().min(HEADER_SEARCH)).step_by(8).find(|&offset| {
+        let fields: Option<Vec<u32>> = (0..4).map(|i| le32(image, offset + i * 4)).collect();
+        fields.is_some_and(|f| f[0] == HEADER_MAGIC && f.iter().fold(0u32, |sum, &v| sum.wrapping_add(v)) == 0)
+    })
+}
+
+fn parse_header(image: &[u8], path: &str) -> Result<Header> {
+    let Some(offset) = find_header(image) else {
+        println!("{} has no Multiboot2 header", path);
+        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+    };
+    if le32(image, offset + 4) != Some(ARCHITECTURE_I386) {
+        println!("{} is not a Multiboot2 kernel for i386", path);
+        return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+    }
+    let mut header = Header {
+        offset,
+        ..Header::default()
+    };
+
+    let end = offset + le32(image, offset + 8).unwrap_or(0) as usize;
+    let mut at = offset + 16;
+    while at + 8 <= end {
+        let (Some(kind), Some(flags), Some(size)) = (le16(image, at), le16(image, at + 2), le32(image, at + 4)) else {
+            break;
+        };
+        let size = size as usize;
+        if kind == TAG_END || size < 8 {
+            break;
+        }
+        let field = |i: usize| le32(image, at + 8 + i * 4).unwrap_or(0);
+        let required = flags & TAG_OPTIONAL == 0;
+        match kind {
+            TAG_INFORMATION_REQUEST if required => {
+                for i in 0..(size - 8) / 4 {
+                    if !info::SUPPORTED.contains(&field(i)) {
+                        println!("{} needs boot information {}, which isn't supported", path, field(i));
+                        return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+                    }
+                }
+            }
+            TAG_ADDRESS => {
+                header.address = Some(Address {
+                    header: field(0),
+                    load: field(1),
+                    load_end: field(2),
+                    bss_end: field(3),
+                })
+            }
+            TAG_ENTRY_ADDRESS => header.entry = Some(field(0)),
+            TAG_FRAMEBUFFER => header.framebuffer = Some((field(0), field(1))),
+            TAG_EFI_BS => header.keep_boot_services = true,
+            TAG_ENTRY_ADDRESS_EFI64 => header.efi64_entry = Some(field(0)),
+            TAG_RELOCATABLE => {
+                header.relocatable = Some(Relocatable {
+                    min: field(0),
+                    max: field(1),
+                    align: field(2),
+                })
+            }
+            // Text console and modules on page boundaries are always fine
+            TAG_INFORMATION_REQUEST | TAG_CONSOLE_FLAGS | TAG_MODULE_ALIGN => (),
+            _ if required => {
+                println!("{} needs Multiboot2 header tag {}, which isn't supported", path, kind);
+                return Err(uefi::Error::new(Status::UNSUPPORTED, ()));
+            }
+            _ => (),
+        }
+        at += size.next_multiple_of(8);
+    }
+    Ok(header)
+}
+
+/// What to load where, and the entry point.
+fn segments<'a>(image: &'a [u8], header: &Header, path: &str) -> Result<(Vec<Segment<'a>>, u64)> {
+    let invalid = || {
+        println!("{} has an invalid Multiboot2 address tag", path);
+        uefi::Error::new(Status::LOAD_ERROR, ())
+    };
+    if let Some(address) = &header.address {
+        let start = address
+            .header
+            .checked_sub(address.load)
+            .and_then(|before| header.offset.checked_sub(before as usize))
+            .ok_or_else(invalid)?;
+        let end = match address.load_end {
+            0 => image.len(),
+            load_end => start + load_end.checked_sub(address.load).ok_or_else(invalid)? as usize,
+        };
+        let data = image.get(start..end).ok_or_else(invalid)?;
+        let size = match address.bss_end {
+            0 => data.len() as u64,
+            bss_end => bss_end.saturating_sub(address.load).max(data.len() as u32) as u64,
+        };
+        let Some(entry) = header.entry else {
+            println!("{} has no Multiboot2 entry address", path);
+            return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+        };
+        let segment = Segment {
+            address: address.load as u64,
+            data,
+            size,
+        };
+        return Ok((vec![segment], entry as u64));
+    }
+
+    let Some((segments, entry)) = elf::segments(image).filter(|(segments, _)| !segments.is_empty()) else {
+        println!("{} is neither an x86 ELF file nor has a Multiboot2 address tag", path);
+        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+    };
+    Ok((segments, header.entry.map_or(entry, u64::from)))
+}
+
+/// Copies `segments` to their addresses, or somewhere `relocatable` allows
+/// if those are taken. Returns how far the kernel was moved.
+fn place(segments: &[Segment], relocatable: Option<&Relocatable>) -> Result<u64> {
+    let low = segments.iter().map(|s| s.address).min().unwrap_or(0);
+    let high = segments.iter().map(|s| s.address + s.size).max().unwrap_or(0);
+    let start = low & !(PAGE_SIZE - 1);
+    let pages = (high - start).div_ceil(PAGE_SIZE) as usize;
+
+    let base = match boot::allocate_pages(AllocateType::Address(start), MemoryType::LOADER_CODE, pages) {
+        Ok(memory) => memory.as_ptr() as u64,
+        Err(e) => {
+            let Some(relocatable) = relocatable else {
+                println!("The kernel needs {:#x}-{:#x}, which is in use", low, high);
+                return Err(e);
+            };
+            let align = (relocatable.align as u64).max(PAGE_SIZE);
+            let slack = (align / PAGE_SIZE) as usize;
+            let memory = boot::allocate_pages(
+                AllocateType::MaxAddress(relocatable.max as u64),
+                MemoryType::LOADER_CODE,
+                pages + slack,
+            )?;
+            let moved_low = (memory.as_ptr() as u64 + (low - start)).next_multiple_of(align);
+            if moved_low < relocatable.min as u64 || moved_low + (high - low) > relocatable.max as u64 {
+                println!("No room for the kernel between {:#x} and {:#x}", relocatable.min, relocatable.max);
+                return Err(uefi::Error::new(Status::OUT_OF_RESOURCES, ()));
+            }
+            moved_low - (low - start)
+        }
+    };
+
+    let offset = base.wrapping_sub(start);
+    let memory = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, pages * PAGE_SIZE as usize) };
+    memory.fill(0);
+    for segment in segments {
+        let at = (segment.address - start) as usize;
+        memory[at..at + segment.data.len()].copy_from_slice(segment.data);
+    }
+    Ok(offset)
+}
+
+/// Reads a `module` line's file below 4 GiB; the rest of the line is the
+/// module's command line.
+fn load_module(volume: &mut Volume, spec: &str) -> Result<(u32, u32, String)> {
+    let (path, cmdline) = spec
+        .split_once(char::is_whitespace)
+        .map_or((spec, ""), |(path, cmdline)| (path, cmdline.trim_start()));
+    let data = volume.read(path)?;
+    let pages = data.len().div_ceil(boot::PAGE_SIZE).max(1);
+    let memory = boot::allocate_pages(AllocateType::MaxAddress(0xffff_ffff), MemoryType::LOADER_DATA, pages)?;
+    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), memory.as_ptr(), data.len()) };
+    println!("Loaded module {}", path);
+    let start = memory.as_ptr() as usize as u32;
+    Ok((start, start + data.len() as u32, String::from(cmdline)))
+}
+
+/// The GOP framebuffer, switched to the resolution the kernel asked for if
+/// the firmware has such a mode. Also used for Limine kernels.
+pub fn framebuffer(request: Option<(u32, u32)>) -> Option<Framebuffer> {
+    let handle = boot::get_handle_for_protocol::<GraphicsOutput>().ok()?;
+    let mut gop = open_shared::<GraphicsOutput>(handle).ok()?;
+    if let Some((width, height)) = request
+        && width != 0
+        && height != 0
+    {
+        let wanted = (width as usize, height as usize);
+        if let Some(mode) = gop.modes().find(|mode| mode.info().resolution() == wanted) {
+            let _ = gop.set_mode(&mode);
+        }
+    }
+
+    let mode = gop.current_mode_info();
+    let (fields, bpp) = match mode.pixel_format() {
+        PixelFormat::Rgb => ([(0, 8), (8, 8), (16, 8)], 32),
+        PixelFormat::Bgr => ([(16, 8), (8, 8), (0, 8)], 32),
+        PixelFormat::Bitmask => {
+            let mask = mode.pixel_bitmask()?;
+            let field = |bits: u32| (bits.trailing_zeros() as u8, bits.count_ones() as u8);
+            let all = mask.red | mask.green | mask.blue | mask.reserved;
+            ([field(mask.red), field(mask.green), field(mask.blue)], (32 - all.leading_zeros()) as u8)
+        }
+        PixelFormat::BltOnly => return None,
+    };
+    let (width, height) = mode.resolution();
+    Some(Framebuffer {
+        address: gop.frame_buffer().as_mut_ptr() as u64,
+        pitch: (mode.stride() * bpp.div_ceil(8) as usize) as u32,
+        width: width as u32,
+        height: height as u32,
+        bpp,
+        fields,
+    })
+}
+
+/// Boots the `multiboot2` kernel `path` of `entry` with its `module`s. The
+/// kernel is entered at its EFI amd64 entry point if it can run on boot
+/// services, else in 32-bit protected mode after exiting them.
+pub fn boot_multiboot2(path: &str, entry: &BootEntry) -> Result {
+    let trampoline = Trampoline::prepare().inspect_err(|_| {
+        println!("Multiboot2 kernels can only be started by the x86_64 loader");
+    })?;
+    // ELF kernels carry no signature
+    secureboot::refuse_unverified(path)?;
+    let mut volume = match entry.device {
+        Some(handle) => Volume::open(handle)?,
+        None => Volume::loader()?,
+    };
+    println!("Loading Multiboot2 kernel {}", path);
+    let image = volume.read(path)?;
+    let header = parse_header(&image, path)?;
+    let (segments, entry_point) = segments(&image, &header, path)?;
+    let offset = place(&segments, header.relocatable.as_ref())?;
+    let load_base = segments.iter().map(|s| s.address).min().unwrap_or(0).wrapping_add(offset);
+    let modules: Vec<_> = entry
+        .module
+        .iter()
+        .map(|spec| load_module(&mut volume, spec))
+        .collect::<Result<_>>()?;
+    let framebuffer = framebuffer(header.framebuffer);
+
+    // The memory map only grows by a few entries until the handoff
+    let cmdline = entry.options.as_deref().unwrap_or("");
+    let strings: usize = cmdline.len() + modules.iter().map(|(_, _, cmdline)| cmdline.len() + 32).sum::<usize>();
+    let map_size = boot::memory_map(MemoryType::LOADER_DATA)?.meta().map_size;
+    let mut info = Info::allocate(4096 + strings + 4 * map_size)?;
+    info.string(info::TAG_CMDLINE, cmdline);
+    info.string(info::TAG_BOOT_LOADER_NAME, concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")));
+    for (start, end, cmdline) in &modules {
+        info.module(*start, *end, cmdline);
+    }
+    if let Some(framebuffer) = &framebuffer {
+        info.framebuffer(framebuffer);
+    }
+    if let Some(table) = uefi::table::system_table_raw() {
+        info.u64(info::TAG_EFI64, table.as_ptr() as u64);
+    }
+    if let Some(rsdp) = acpi::rsdp(false) {
+        info.bytes(info::TAG_ACPI_OLD, rsdp);
+    }
+    if let Some(rsdp) = acpi::rsdp(true) {
+        info.bytes(info::TAG_ACPI_NEW, rsdp);
+    }
+    if header.relocatable.is_some() {
+        info.load_base(load_base as u32);
+    }
+
+    // Everything is in memory now; don't leave disk keys behind for the OS.
+    luks2::wipe_keys();
+
+    if header.keep_boot_services
+        && let Some(efi64_entry) = header.efi64_entry
+    {
+        let efi64_entry = (efi64_entry as u64).wrapping_add(offset);
+        println!("Starting Multiboot2 kernel at {:#x} with boot services", efi64_entry);
+        info.u64(info::TAG_EFI64_IH, boot::image_handle().as_ptr() as u64);
+        info.empty(info::TAG_EFI_BS);
+        info.memory_map(&boot::memory_map(MemoryType::LOADER_DATA)?, false);
+        let address = info.finish();
+        unsafe { handoff::efi_amd64(efi64_entry, address) }
+    }
+
+    let entry_point = entry_point.wrapping_add(offset);
+    if entry_point > u32::MAX as u64 {
+        println!("The kernel's entry point {:#x} is out of reach of protected mode", entry_point);
+        return Err(uefi::Error::new(Status::LOAD_ERROR, ()));
+    }
+    println!("Starting Multiboot2 kernel at {:#x}", entry_point);
+    let map = unsafe { boot::exit_boot_services(None) };
+    info.memory_map(&map, true);
+    let address = info.finish();
+    unsafe { trampoline.protected_mode(entry_point as u32, address) }
+}
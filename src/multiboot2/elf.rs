@@ -0,0 +1,74 @@
+// elf.rs
+// Loadable segments of ELF32/ELF64 Multiboot2 kernels
+
+use alloc::vec::Vec;
+
+const PT_LOAD: u32 = 1;
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+/// A part of the kernel to place at a physical address: `data` followed by
+/// zeros up to `size` bytes.
+pub struct Segment<'a> {
+    pub address: u64,
+    pub data: &'a [u8],
+    pub size: u64,
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn le64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// The `PT_LOAD` segments of `image` at their physical addresses, and the
+/// physical entry point. `None` if `image` isn't a little-endian x86 ELF.
+pub fn segments(image: &[u8]) -> Option<(Vec<Segment<'_>>, u64)> {
+    if image.get(0..4)? != b"\x7fELF" || *image.get(5)? != 1 {
+        return None;
+    }
+    let wide = match *image.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    if !matches!(le16(image, 18)?, EM_386 | EM_X86_64) {
+        return None;
+    }
+    let (entry, phoff, phentsize, phnum) = if wide {
+        (le64(image, 24)?, le64(image, 32)? as usize, le16(image, 54)?, le16(image, 56)?)
+    } else {
+        (le32(image, 24)? as u64, le32(image, 28)? as usize, le16(image, 42)?, le16(image, 44)?)
+    };
+
+    let mut segments = Vec::new();
+    let mut physical_entry = entry;
+    for i in 0..phnum as usize {
+        let header = image.get(phoff + i * phentsize as usize..)?;
+        if le32(header, 0)? != PT_LOAD {
+            continue;
+        }
+        let (offset, vaddr, paddr, filesz, memsz) = if wide {
+            (le64(header, 8)?, le64(header, 16)?, le64(header, 24)?, le64(header, 32)?, le64(header, 40)?)
+        } else {
+            let field = |at| le32(header, at).map(u64::from);
+            (field(4)?, field(8)?, field(12)?, field(16)?, field(20)?)
+        };
+        if filesz > memsz {
+            return None;
+        }
+        let data = image.get(offset as usize..offset.checked_add(filesz)? as usize)?;
+        // Higher half kernels link the entry point at its virtual address
+        if (vaddr..vaddr + memsz).contains(&entry) {
+            physical_entry = entry - vaddr + paddr;
+        }
+        segments.push(Segment { address: paddr, data, size: memsz });
+    }
+    Some((segments, physical_entry))
+}
@@ -0,0 +1,138 @@
+// handoff.rs
+// Jumps into a Multiboot2 kernel, in 64-bit EFI mode or in 32-bit protected mode
+
+#[cfg(target_arch = "x86_64")]
+mod imp {
+    use core::arch::{asm, global_asm};
+    use uefi::Result;
+    use uefi::boot::{self, AllocateType, MemoryType};
+
+    /// What the kernel finds in EAX.
+    const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+    // Leaves long mode for 32-bit protected mode without paging, as the
+    // i386 machine state of the Multiboot2 spec asks. Copied below 4 GiB
+    // before use; must stay position independent. Takes the entry point in
+    // EDI and the boot information in ESI.
+    global_asm!(
+        ".global multiboot2_trampoline_start",
+        ".global multiboot2_trampoline_end",
+        ".code64",
+        "multiboot2_trampoline_start:",
+        "    cli",
+        "    lea rax, [rip + 3f]",
+        "    sub rsp, 16",
+        "    mov word ptr [rsp + 6], 23",
+        "    mov [rsp + 8], rax",
+        "    lgdt [rsp + 6]",
+        // Far return into the 32-bit code segment (compatibility mode)
+        "    lea rax, [rip + 2f]",
+        "    push 0x08",
+        "    push rax",
+        "    retfq",
+        ".code32",
+        "2:",
+        "    mov ax, 0x10",
+        "    mov ds, ax",
+        "    mov es, ax",
+        "    mov fs, ax",
+        "    mov gs, ax",
+        "    mov ss, ax",
+        // Paging off, which also leaves long mode, then clear EFER.LME and CR4.PAE
+        "    mov eax, cr0",
+        "    and eax, 0x7fffffff",
+        "    mov cr0, eax",
+        "    mov ecx, 0xc0000080",
+        "    rdmsr",
+        "    and eax, 0xfffffeff",
+        "    wrmsr",
+        "    mov eax, cr4",
+        "    and eax, 0xffffffdf",
+        "    mov cr4, eax",
+        "    mov eax, {magic}",
+        "    mov ebx, esi",
+        "    jmp edi",
+        ".p2align 3",
+        // Null, flat 32-bit code and flat data descriptors
+        "3:",
+        "    .quad 0",
+        "    .quad 0x00cf9a000000ffff",
+        "    .quad 0x00cf92000000ffff",
+        "multiboot2_trampoline_end:",
+        ".code64",
+        magic = const BOOTLOADER_MAGIC,
+    );
+
+    unsafe extern "C" {
+        static multiboot2_trampoline_start: u8;
+        static multiboot2_trampoline_end: u8;
+    }
+
+    /// The protected mode trampoline, copied to a page below 4 GiB that
+    /// stays identity mapped while paging is turned off.
+    pub struct Trampoline(u64);
+
+    impl Trampoline {
+        pub fn prepare() -> Result<Self> {
+            let start = &raw const multiboot2_trampoline_start;
+            let end = &raw const multiboot2_trampoline_end;
+            let code = unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) };
+            let page = boot::allocate_pages(AllocateType::MaxAddress(0xffff_ffff), MemoryType::LOADER_CODE, 1)?;
+            unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), page.as_ptr(), code.len()) };
+            Ok(Trampoline(page.as_ptr() as u64))
+        }
+
+        /// Enters `entry` in 32-bit protected mode. Boot services must be
+        /// gone already.
+        pub unsafe fn protected_mode(&self, entry: u32, info: u32) -> ! {
+            unsafe {
+                asm!(
+                    "jmp {trampoline}",
+                    trampoline = in(reg) self.0,
+                    in("edi") entry,
+                    in("esi") info,
+                    options(noreturn),
+                )
+            }
+        }
+    }
+
+    /// Calls the 64-bit EFI entry point with boot services still running.
+    pub unsafe fn efi_amd64(entry: u64, info: u32) -> ! {
+        unsafe {
+            asm!(
+                "mov ebx, {info:e}",
+                "and rsp, -16",
+                "call {entry}",
+                "ud2",
+                info = in(reg) info,
+                entry = in(reg) entry,
+                in("eax") BOOTLOADER_MAGIC,
+                options(noreturn),
+            )
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod imp {
+    use uefi::{Result, Status};
+
+    pub struct Trampoline;
+
+    impl Trampoline {
+        pub fn prepare() -> Result<Self> {
+            Err(uefi::Error::new(Status::UNSUPPORTED, ()))
+        }
+
+        pub unsafe fn protected_mode(&self, _entry: u32, _info: u32) -> ! {
+            unreachable!()
+        }
+    }
+
+    pub unsafe fn efi_amd64(_entry: u64, _info: u32) -> ! {
+        unreachable!()
+    }
+}
+
+pub use imp::{Trampoline, efi_amd64};
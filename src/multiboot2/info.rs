@@ -0,0 +1,206 @@
+// info.rs
+// Builds the Multiboot2 boot information structure in memory below 4 GiB
+
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::mem::memory_map::{MemoryMap, MemoryMapOwned};
+use uefi::Result;
+
+pub const TAG_CMDLINE: u32 = 1;
+pub const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+pub const TAG_EFI64: u32 = 12;
+pub const TAG_ACPI_OLD: u32 = 14;
+pub const TAG_ACPI_NEW: u32 = 15;
+const TAG_EFI_MMAP: u32 = 17;
+pub const TAG_EFI_BS: u32 = 18;
+pub const TAG_EFI64_IH: u32 = 20;
+const TAG_LOAD_BASE_ADDR: u32 = 21;
+
+/// Tags the loader can provide, for the kernel's information request.
+pub const SUPPORTED: [u32; 13] = [
+    TAG_CMDLINE,
+    TAG_BOOT_LOADER_NAME,
+    TAG_MODULE,
+    TAG_BASIC_MEMINFO,
+    TAG_MMAP,
+    TAG_FRAMEBUFFER,
+    TAG_EFI64,
+    TAG_ACPI_OLD,
+    TAG_ACPI_NEW,
+    TAG_EFI_MMAP,
+    TAG_EFI_BS,
+    TAG_EFI64_IH,
+    TAG_LOAD_BASE_ADDR,
+];
+
+const MMAP_AVAILABLE: u32 = 1;
+const MMAP_RESERVED: u32 = 2;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_NVS: u32 = 4;
+const MMAP_BADRAM: u32 = 5;
+
+/// A GOP framebuffer in direct RGB mode.
+pub struct Framebuffer {
+    pub address: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    /// Position and size of the red, green and blue fields.
+    pub fields: [(u8, u8); 3],
+}
+
+pub struct Info {
+    buffer: &'static mut [u8],
+    len: usize,
+}
+
+impl Info {
+    /// Room for `size` bytes of tags in pages below 4 GiB, as the kernel
+    /// gets the address in a 32-bit register.
+    pub fn allocate(size: usize) -> Result<Self> {
+        let pages = size.div_ceil(boot::PAGE_SIZE);
+        let memory = boot::allocate_pages(AllocateType::MaxAddress(0xffff_ffff), MemoryType::LOADER_DATA, pages)?;
+        let buffer = unsafe { core::slice::from_raw_parts_mut(memory.as_ptr(), pages * boot::PAGE_SIZE) };
+        buffer.fill(0);
+        // total_size and reserved come first
+        Ok(Info { buffer, len: 8 })
+    }
+
+    /// Appends a tag with `len` bytes of zeroed payload and returns the
+    /// payload, `None` if it doesn't fit. Doesn't allocate, so it can be
+    /// used after exiting boot services.
+    fn tag(&mut self, kind: u32, len: usize) -> Option<&mut [u8]> {
+        let start = self.len;
+        let end = start + 8 + len;
+        let next = end.next_multiple_of(8);
+        if next > self.buffer.len() {
+            return None;
+        }
+        self.buffer[start..start + 4].copy_from_slice(&kind.to_le_bytes());
+        self.buffer[start + 4..start + 8].copy_from_slice(&((8 + len) as u32).to_le_bytes());
+        self.len = next;
+        Some(&mut self.buffer[start + 8..end])
+    }
+
+    pub fn bytes(&mut self, kind: u32, data: &[u8]) {
+        if let Some(payload) = self.tag(kind, data.len()) {
+            payload.copy_from_slice(data);
+        }
+    }
+
+    /// A nul terminated string tag.
+    pub fn string(&mut self, kind: u32, value: &str) {
+        if let Some(payload) = self.tag(kind, value.len() + 1) {
+            payload[..value.len()].copy_from_slice(value.as_bytes());
+        }
+    }
+
+    pub fn u64(&mut self, kind: u32, value: u64) {
+        self.bytes(kind, &value.to_le_bytes());
+    }
+
+    pub fn empty(&mut self, kind: u32) {
+        self.tag(kind, 0);
+    }
+
+    pub fn module(&mut self, start: u32, end: u32, cmdline: &str) {
+        if let Some(payload) = self.tag(TAG_MODULE, 8 + cmdline.len() + 1) {
+            payload[0..4].copy_from_slice(&start.to_le_bytes());
+            payload[4..8].copy_from_slice(&end.to_le_bytes());
+            payload[8..8 + cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        }
+    }
+
+    pub fn load_base(&mut self, address: u32) {
+        self.bytes(TAG_LOAD_BASE_ADDR, &address.to_le_bytes());
+    }
+
+    pub fn framebuffer(&mut self, fb: &Framebuffer) {
+        let Some(payload) = self.tag(TAG_FRAMEBUFFER, 30) else {
+            return;
+        };
+        payload[0..8].copy_from_slice(&fb.address.to_le_bytes());
+        payload[8..12].copy_from_slice(&fb.pitch.to_le_bytes());
+        payload[12..16].copy_from_slice(&fb.width.to_le_bytes());
+        payload[16..20].copy_from_slice(&fb.height.to_le_bytes());
+        payload[20] = fb.bpp;
+        // Direct RGB color
+        payload[21] = 1;
+        for (i, (position, size)) in fb.fields.iter().enumerate() {
+            payload[24 + i * 2] = *position;
+            payload[25 + i * 2] = *size;
+        }
+    }
+
+    /// Basic memory information, the Multiboot2 memory map and the EFI
+    /// memory map from `map`. Boot services memory only counts as available
+    /// once boot services are gone.
+    pub fn memory_map(&mut self, map: &MemoryMapOwned, exited: bool) {
+        let kind = |ty: MemoryType| match ty {
+            MemoryType::CONVENTIONAL => MMAP_AVAILABLE,
+            MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+                if exited =>
+            {
+                MMAP_AVAILABLE
+            }
+            MemoryType::ACPI_RECLAIM => MMAP_ACPI_RECLAIMABLE,
+            MemoryType::ACPI_NON_VOLATILE => MMAP_NVS,
+            MemoryType::UNUSABLE => MMAP_BADRAM,
+            _ => MMAP_RESERVED,
+        };
+
+        // End of the available memory running on from `start`
+        let contiguous_end = |start: u64| {
+            let mut end = start;
+            while let Some(next) = map
+                .entries()
+                .filter(|d| kind(d.ty) == MMAP_AVAILABLE)
+                .map(|d| (d.phys_start, d.phys_start + d.page_count * boot::PAGE_SIZE as u64))
+                .find(|&(from, to)| from <= end && to > end)
+                .map(|(_, to)| to)
+            {
+                end = next;
+            }
+            end
+        };
+        let lower = contiguous_end(0).min(640 * 1024) / 1024;
+        let upper = (contiguous_end(0x10_0000) - 0x10_0000) / 1024;
+        if let Some(payload) = self.tag(TAG_BASIC_MEMINFO, 8) {
+            payload[0..4].copy_from_slice(&(lower as u32).to_le_bytes());
+            payload[4..8].copy_from_slice(&(upper.min(u32::MAX as u64) as u32).to_le_bytes());
+        }
+
+        let count = map.entries().count();
+        if let Some(payload) = self.tag(TAG_MMAP, 8 + count * 24) {
+            payload[0..4].copy_from_slice(&24u32.to_le_bytes());
+            for (entry, d) in payload[8..].chunks_mut(24).zip(map.entries()) {
+                entry[0..8].copy_from_slice(&d.phys_start.to_le_bytes());
+                entry[8..16].copy_from_slice(&(d.page_count * boot::PAGE_SIZE as u64).to_le_bytes());
+                entry[16..20].copy_from_slice(&kind(d.ty).to_le_bytes());
+            }
+        }
+
+        let meta = map.meta();
+        let raw = &map.buffer()[..meta.map_size];
+        if let Some(payload) = self.tag(TAG_EFI_MMAP, 8 + raw.len()) {
+            payload[0..4].copy_from_slice(&(meta.desc_size as u32).to_le_bytes());
+            payload[4..8].copy_from_slice(&meta.desc_version.to_le_bytes());
+            payload[8..].copy_from_slice(raw);
+        }
+    }
+
+    /// Terminates the structure and returns its address.
+    pub fn finish(mut self) -> u32 {
+        self.tag(0, 0);
+        let len = self.len as u32;
+        self.buffer[0..4].copy_from_slice(&len.to_le_bytes());
+        self.buffer.as_ptr() as usize as u32
+    }
+}
@@ -0,0 +1,214 @@
+// pe.rs
+// Minimal PE/COFF reader for the sections and Authenticode layout of UKIs and similar images
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub struct Section<'a> {
+    /// Section name without the nul padding, e.g. `.linux`.
+    pub name: &'a [u8],
+    /// Raw section contents, cut to the virtual size.
+    pub data: &'a [u8],
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Offset of the PE signature, if `image` is a PE image.
+fn pe_header(image: &[u8]) -> Option<usize> {
+    if image.get(0..2)? != b"MZ" {
+        return None;
+    }
+    let pe = le32(image, 0x3c)? as usize;
+    (image.get(pe..pe + 4)? == b"PE\0\0").then_some(pe)
+}
+
+/// Machine type of the PE image `image`.
+pub fn machine(image: &[u8]) -> Option<u16> {
+    le16(image, pe_header(image)? + 4)
+}
+
+/// Sections of the PE image `image`, `None` if it isn't one.
+pub fn sections(image: &[u8]) -> Option<Vec<Section<'_>>> {
+    let pe = pe_header(image)?;
+    let count = le16(image, pe + 6)? as usize;
+    let optional_size = le16(image, pe + 20)? as usize;
+    let table = pe + 24 + optional_size;
+
+    let mut sections = Vec::with_capacity(count);
+    for i in 0..count {
+        let header = image.get(table + i * 40..table + (i + 1) * 40)?;
+        let name = &header[..8];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(8)];
+        let virtual_size = le32(header, 8)? as usize;
+        let raw_size = le32(header, 16)? as usize;
+        let raw_offset = le32(header, 20)? as usize;
+        let size = if virtual_size == 0 { raw_size } else { virtual_size.min(raw_size) };
+        sections.push(Section {
+            name,
+            data: image.get(raw_offset..raw_offset.checked_add(size)?)?,
+        });
+    }
+    Some(sections)
+}
+
+/// The parts of `image` its Authenticode hash covers, in hashing order, and
+/// its certificate table (empty if it has none).
+pub fn authenticode_layout(image: &[u8]) -> Option<(Vec<&[u8]>, &[u8])> {
+    let pe = pe_header(image)?;
+    let optional = pe + 24;
+    let directories = match le16(image, optional)? {
+        0x10b => optional + 96,
+        0x20b => optional + 112,
+        _ => return None,
+    };
+    let has_security = le32(image, directories - 4)? > 4;
+    let checksum = optional + 64;
+    let security = directories + 4 * 8;
+    let headers_size = le32(image, optional + 60)? as usize;
+
+    // Everything but the checksum and the certificate table entry
+    let mut parts = vec![image.get(..checksum)?];
+    if has_security {
+        parts.push(image.get(checksum + 4..security)?);
+        parts.push(image.get(security + 8..headers_size)?);
+    } else {
+        parts.push(image.get(checksum + 4..headers_size)?);
+    }
+
+    let count = le16(image, pe + 6)? as usize;
+    let table = optional + le16(image, pe + 20)? as usize;
+    let mut sections = Vec::with_capacity(count);
+    for i in 0..count {
+        let header = table + i * 40;
+        let (size, offset) = (le32(image, header + 16)? as usize, le32(image, header + 20)? as usize);
+        if size != 0 {
+            sections.push((offset, size));
+        }
+    }
+    sections.sort_unstable();
+    let mut hashed = headers_size;
+    for (offset, size) in sections {
+        parts.push(image.get(offset..offset.checked_add(size)?)?);
+        hashed += size;
+    }
+
+    let (certificates_offset, certificates_size) = if has_security {
+        (le32(image, security)? as usize, le32(image, security + 4)? as usize)
+    } else {
+        (0, 0)
+    };
+    // Data after the last section, except for the certificate table
+    let end = image.len().checked_sub(certificates_size)?;
+    if end > hashed {
+        parts.push(&image[hashed..end]);
+    }
+    let certificates = image.get(certificates_offset..certificates_offset.checked_add(certificates_size)?)?;
+    Some((parts, certificates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUM: usize = 0x98;
+    const SECURITY: usize = 0xe8;
+    const HEADERS: usize = 0x200;
+
+    /// A PE32+ image with `.sbat` and `.text` sections (in that order in the
+    /// table, but not on disk), 16 bytes of trailing data and a 16 byte
+    /// certificate table if `signed`.
+    fn image(signed: bool) -> Vec<u8> {
+        let mut image = vec![0u8; 0x280];
+        let mut put = |offset: usize, bytes: &[u8]| image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, b"MZ");
+        put(0x3c, &0x40u32.to_le_bytes());
+        put(0x40, b"PE\0\0");
+        put(0x44, &0x8664u16.to_le_bytes());
+        put(0x46, &2u16.to_le_bytes());
+        put(0x54, &240u16.to_le_bytes());
+        put(0x58, &0x20bu16.to_le_bytes());
+        put(0x58 + 60, &(HEADERS as u32).to_le_bytes());
+        put(CHECKSUM, &0xdeadbeefu32.to_le_bytes());
+        put(0xc4, &if signed { 16u32 } else { 4 }.to_le_bytes());
+        put(SECURITY, &0x270u32.to_le_bytes());
+        put(SECURITY + 4, &0x10u32.to_le_bytes());
+        for (header, name, size, offset) in [(0x148, b".sbat\0\0\0", 0x40u32, 0x220u32), (0x170, b".text\0\0\0", 0x20, 0x200)] {
+            put(header, name);
+            put(header + 8, &size.to_le_bytes());
+            put(header + 16, &size.to_le_bytes());
+            put(header + 20, &offset.to_le_bytes());
+        }
+        put(0x220, b"sbat,1,SBAT Version,sbat,1,https://example.org\n");
+        for (i, byte) in image.iter_mut().enumerate().skip(HEADERS) {
+            *byte |= i as u8 & 0x80;
+        }
+        if !signed {
+            image.truncate(0x270);
+        }
+        image
+    }
+
+    #[test]
+    fn reads_sections() {
+        let image = image(true);
+        assert_eq!(machine(&image), Some(0x8664));
+        let sections = sections(&image).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name, b".sbat");
+        assert_eq!(sections[0].data, &image[0x220..0x260]);
+        assert_eq!(sections[1].name, b".text");
+    }
+
+    #[test]
+    fn authenticode_skips_checksum_and_certificates() {
+        let image = image(true);
+        let (parts, certificates) = authenticode_layout(&image).unwrap();
+        assert_eq!(certificates, &image[0x270..]);
+        let mut expected = image[..CHECKSUM].to_vec();
+        expected.extend(&image[CHECKSUM + 4..SECURITY]);
+        expected.extend(&image[SECURITY + 8..0x270]);
+        assert_eq!(parts.concat(), expected);
+        // Sections in file order, then the trailing data
+        assert_eq!(parts[3], &image[0x200..0x220]);
+        assert_eq!(parts[4], &image[0x220..0x260]);
+        assert_eq!(parts[5], &image[0x260..0x270]);
+    }
+
+    #[test]
+    fn authenticode_of_unsigned_image() {
+        let image = image(false);
+        let (parts, certificates) = authenticode_layout(&image).unwrap();
+        assert!(certificates.is_empty());
+        let mut expected = image[..CHECKSUM].to_vec();
+        expected.extend(&image[CHECKSUM + 4..]);
+        assert_eq!(parts.concat(), expected);
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        let image = image(true);
+        assert!(sections(&image[..0x150]).is_none());
+        assert!(authenticode_layout(&image[..0x100]).is_none());
+        assert!(authenticode_layout(&image[..0x210]).is_none());
+
+        let mut bad_magic = image.clone();
+        bad_magic[0x58] = 0;
+        assert!(authenticode_layout(&bad_magic).is_none());
+        let mut not_pe = image.clone();
+        not_pe[0x40] = b'X';
+        assert!(machine(&not_pe).is_none() && sections(&not_pe).is_none());
+        // Certificate table and section past the end of the file
+        let mut certificates = image.clone();
+        certificates[SECURITY + 4] = 0x20;
+        assert!(authenticode_layout(&certificates).is_none());
+        let mut section = image.clone();
+        section[0x148 + 20..0x148 + 24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(sections(&section).is_none() && authenticode_layout(&section).is_none());
+    }
+}
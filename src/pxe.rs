@@ -0,0 +1,180 @@
+// pxe.rs
+// Fetches kernels, initrds and entries over TFTP through the firmware's PXE base code
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use uefi::boot::ScopedProtocol;
+use uefi::proto::network::IpAddress;
+use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet};
+use uefi::{CStr8, Handle, Result, Status, boot, println};
+
+use crate::entries_parse::{BootEntry, parse_conf};
+use crate::kernel_loader::load_efi_from_memory;
+use crate::volume;
+
+/// Where a `pxe` entry gets its files from: `pxe <server> [<remote .conf>]`,
+/// with `dhcp` as server for the next-server of the DHCP reply.
+struct PxeSpec<'a> {
+    server: Option<[u8; 4]>,
+    conf: Option<&'a str>,
+}
+
+fn parse_spec(spec: &str) -> Option<PxeSpec<'_>> {
+    let mut words = spec.split_whitespace();
+    let server = match words.next()? {
+        "dhcp" => None,
+        address => Some(parse_ipv4(address)?),
+    };
+    Some(PxeSpec {
+        server,
+        conf: words.next(),
+    })
+}
+
+fn parse_ipv4(text: &str) -> Option<[u8; 4]> {
+    let mut address = [0u8; 4];
+    let mut parts = text.split('.');
+    for byte in &mut address {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(address)
+}
+
+/// Starts the PXE base code on `handle` and configures it via DHCP unless
+/// the firmware already did (e.g. when the loader itself was PXE booted).
+fn start(handle: Handle) -> Result<ScopedProtocol<BaseCode>> {
+    let mut pxe = boot::open_protocol_exclusive::<BaseCode>(handle)?;
+    match pxe.start(false) {
+        Err(e) if e.status() != Status::ALREADY_STARTED => return Err(e),
+        _ => (),
+    }
+    if !pxe.mode().dhcp_ack_received() {
+        println!("Requesting an address via DHCP...");
+        pxe.dhcp(true)?;
+    }
+    Ok(pxe)
+}
+
+/// The first NIC with PXE support that gets a DHCP lease, preferring the
+/// one the loader was booted from.
+fn open_nic() -> Result<ScopedProtocol<BaseCode>> {
+    let mut handles = match boot::find_handles::<BaseCode>() {
+        Ok(handles) => handles,
+        Err(e) if e.status() == Status::NOT_FOUND => {
+            println!("No network interface supports PXE (is the firmware network stack enabled?)");
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    if let Ok(loader) = volume::loader_device()
+        && let Some(index) = handles.iter().position(|&handle| handle == loader)
+    {
+        handles.swap(0, index);
+    }
+
+    let mut last_error = uefi::Error::new(Status::NOT_FOUND, ());
+    for handle in handles {
+        match start(handle) {
+            Ok(pxe) if pxe.mode().using_ipv6() => {
+                println!("Skipping IPv6-only network interface");
+            }
+            Ok(pxe) => return Ok(pxe),
+            Err(e) => {
+                println!("PXE setup failed on a network interface: {:?}", e.status());
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// TFTP server from the DHCP reply, or the proxy DHCP offer if the DHCP
+/// server itself didn't name one.
+fn dhcp_server(pxe: &BaseCode) -> Option<[u8; 4]> {
+    let mode = pxe.mode();
+    let ack: &DhcpV4Packet = mode.dhcp_ack().as_ref();
+    if ack.bootp_si_addr != [0; 4] {
+        return Some(ack.bootp_si_addr);
+    }
+    if mode.proxy_offer_received() {
+        let offer: &DhcpV4Packet = mode.proxy_offer().as_ref();
+        if offer.bootp_si_addr != [0; 4] {
+            return Some(offer.bootp_si_addr);
+        }
+    }
+    None
+}
+
+/// Reads `path` from the TFTP server `server`.
+fn fetch(pxe: &mut BaseCode, server: &IpAddress, path: &str) -> Result<Vec<u8>> {
+    let mut name: Vec<u8> = path.replace('\\', "/").into_bytes();
+    name.push(0);
+    let name = CStr8::from_bytes_with_nul(&name)
+        .map_err(|_| uefi::Error::new(Status::INVALID_PARAMETER, ()))?;
+
+    println!("Fetching {} via TFTP", path);
+    let result = pxe.tftp_get_file_size(server, name).and_then(|size| {
+        let mut data = vec![0u8; size as usize];
+        let read = pxe.tftp_read_file(server, name, Some(&mut data))?;
+        data.truncate(read as usize);
+        Ok(data)
+    });
+    if let Err(e) = &result {
+        if pxe.mode().tftp_error_received() {
+            let error = &pxe.mode().tftp_error().error_string;
+            let len = error.iter().position(|&b| b == 0).unwrap_or(error.len());
+            println!("TFTP error for {}: {}", path, String::from_utf8_lossy(&error[..len]));
+        } else {
+            println!("TFTP read of {} failed: {:?}", path, e.status());
+        }
+    }
+    result
+}
+
+/// Boots `entry` over the network as described by its `pxe` line: the
+/// `linux`/`efi` and `initrd` paths (or those of the remote `.conf`) are
+/// fetched from the TFTP server.
+pub fn boot_pxe(spec: &str, entry: &BootEntry) -> Result {
+    let Some(spec) = parse_spec(spec) else {
+        println!("Invalid pxe line: {} (expected `dhcp` or an IPv4 address)", spec);
+        return Err(uefi::Error::new(Status::INVALID_PARAMETER, ()));
+    };
+
+    let mut pxe = open_nic()?;
+    let Some(server) = spec.server.or_else(|| dhcp_server(&pxe)) else {
+        println!("The DHCP reply names no TFTP server; set one on the pxe line");
+        return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+    };
+    let [a, b, c, d] = server;
+    println!("Using TFTP server {}.{}.{}.{}", a, b, c, d);
+    let server = IpAddress::new_v4(server);
+
+    let remote;
+    let entry = match spec.conf {
+        Some(conf) => {
+            let text = fetch(&mut pxe, &server, conf)?;
+            let mut parsed = parse_conf(&String::from_utf8_lossy(&text));
+            if parsed.options.is_none() {
+                parsed.options = entry.options.clone();
+            }
+            remote = parsed;
+            &remote
+        }
+        None => entry,
+    };
+
+    let Some(kernel_path) = entry.linux.as_deref().or(entry.efi.as_deref()) else {
+        println!("PXE entry has no linux or efi path");
+        return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+    };
+    let kernel = fetch(&mut pxe, &server, kernel_path)?;
+    let initrd = entry
+        .initrd
+        .as_deref()
+        .map(|path| fetch(&mut pxe, &server, path))
+        .transpose()?;
+
+    let name = kernel_path.rsplit(['/', '\\']).next().unwrap_or(kernel_path);
+    load_efi_from_memory(name, &kernel, initrd, entry.options.as_deref())
+}
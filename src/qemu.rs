@@ -0,0 +1,90 @@
+// qemu.rs
+// Offers the kernel QEMU was started with (-kernel/-initrd/-append) as a boot entry
+
+mod fw_cfg;
+
+use alloc::string::String;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::{Guid, Handle, Result, boot, guid, println};
+
+use crate::entries_parse::BootEntry;
+use crate::kernel_loader::load_efi_from_memory;
+use crate::volume::{Volume, open_shared};
+
+/// Vendor media node of OVMF's QemuKernelLoaderFs, which serves the blobs
+/// as the files `kernel`, `initrd` and (on newer versions) `cmdline`.
+const QEMU_KERNEL_LOADER_FS_GUID: Guid = guid!("1428f772-b64a-441e-b8c3-9ebdd7f893c7");
+
+const TITLE: &str = "Direct kernel from hypervisor";
+
+/// The QemuKernelLoaderFs volume, if the firmware provides one.
+fn kernel_loader_fs() -> Option<Handle> {
+    let handles = boot::find_handles::<SimpleFileSystem>().ok()?;
+    handles.into_iter().find(|&handle| {
+        open_shared::<DevicePath>(handle).is_ok_and(|path| {
+            // Header (type, subtype, length), then the vendor GUID
+            let bytes = path.as_bytes();
+            bytes.len() >= 20
+                && bytes[0] == 0x04
+                && bytes[1] == 0x03
+                && bytes[4..20] == QEMU_KERNEL_LOADER_FS_GUID.to_bytes()
+        })
+    })
+}
+
+/// Entry for the kernel handed over by QEMU, `None` when not running under
+/// QEMU or started without `-kernel`. The firmware's file system is used
+/// if there is one, so the entry boots through the normal Linux path;
+/// otherwise the blobs are read from fw_cfg when the entry is started.
+pub fn direct_kernel_entry() -> Option<BootEntry> {
+    if let Some(handle) = kernel_loader_fs() {
+        let mut volume = Volume::open(handle).ok()?;
+        let files = volume.read_dir("").ok()??;
+        let size = |name: &str| {
+            files
+                .iter()
+                .find(|file| file.name == name)
+                .map_or(0, |file| file.size)
+        };
+        if size("kernel") == 0 {
+            return None;
+        }
+        let options = if size("cmdline") != 0 {
+            volume
+                .read_to_string("cmdline")
+                .ok()
+                .map(|cmdline| String::from(cmdline.trim_end_matches(['\0', '\n'])))
+        } else if fw_cfg::present() {
+            fw_cfg::command_line().ok().flatten()
+        } else {
+            None
+        };
+        return Some(BootEntry {
+            title: TITLE.into(),
+            linux: Some("kernel".into()),
+            initrd: (size("initrd") != 0).then(|| "initrd".into()),
+            options,
+            device: Some(handle),
+            ..BootEntry::new()
+        });
+    }
+
+    if !fw_cfg::present() || fw_cfg::kernel_size() == 0 {
+        return None;
+    }
+    Some(BootEntry {
+        title: TITLE.into(),
+        fw_cfg: true,
+        options: fw_cfg::command_line().ok().flatten(),
+        ..BootEntry::new()
+    })
+}
+
+/// Reads the kernel and initrd from fw_cfg and starts the kernel.
+pub fn boot_fw_cfg(cmdline: Option<&str>) -> Result {
+    println!("Reading kernel from fw_cfg");
+    let kernel = fw_cfg::kernel()?;
+    let initrd = fw_cfg::initrd()?;
+    load_efi_from_memory("kernel", &kernel, initrd, cmdline)
+}
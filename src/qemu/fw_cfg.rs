@@ -0,0 +1,179 @@
+// fw_cfg.rs
+// Reads the -kernel/-initrd/-append blobs from QEMU's fw_cfg I/O ports
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use uefi::{Result, Status};
+
+const KEY_SIGNATURE: u16 = 0x00;
+const KEY_ID: u16 = 0x01;
+const KEY_KERNEL_SIZE: u16 = 0x08;
+const KEY_INITRD_SIZE: u16 = 0x0b;
+const KEY_KERNEL_DATA: u16 = 0x11;
+const KEY_INITRD_DATA: u16 = 0x12;
+const KEY_CMDLINE_SIZE: u16 = 0x14;
+const KEY_CMDLINE_DATA: u16 = 0x15;
+const KEY_SETUP_SIZE: u16 = 0x17;
+const KEY_SETUP_DATA: u16 = 0x18;
+
+/// Bit in the `KEY_ID` features telling that the DMA interface exists.
+const ID_DMA: u32 = 1 << 1;
+
+#[cfg(target_arch = "x86_64")]
+mod port {
+    use core::arch::asm;
+    use core::sync::atomic::{Ordering, compiler_fence};
+    use uefi::{Result, Status};
+
+    const SELECTOR: u16 = 0x510;
+    const DATA: u16 = 0x511;
+    const DMA_ADDRESS: u16 = 0x514;
+
+    const DMA_ERROR: u32 = 1 << 0;
+    const DMA_READ: u32 = 1 << 1;
+    const DMA_SELECT: u32 = 1 << 3;
+
+    /// `FWCfgDmaAccess`; all fields are big-endian.
+    #[repr(C, align(8))]
+    struct DmaAccess {
+        control: u32,
+        length: u32,
+        address: u64,
+    }
+
+    unsafe fn outw(port: u16, value: u16) {
+        unsafe { asm!("out dx, ax", in("dx") port, in("ax") value, options(nostack)) };
+    }
+
+    unsafe fn outl(port: u16, value: u32) {
+        unsafe { asm!("out dx, eax", in("dx") port, in("eax") value, options(nostack)) };
+    }
+
+    unsafe fn inb(port: u16) -> u8 {
+        let value: u8;
+        unsafe { asm!("in al, dx", in("dx") port, out("al") value, options(nostack)) };
+        value
+    }
+
+    /// Only touch the ports under a hypervisor: on real hardware 0x510 may
+    /// belong to something else entirely.
+    pub fn hypervisor_present() -> bool {
+        let leaf = core::arch::x86_64::__cpuid(1);
+        leaf.ecx & (1 << 31) != 0
+    }
+
+    /// Byte-wise read of the item `key`, slow but always available.
+    pub fn read(key: u16, buffer: &mut [u8]) {
+        unsafe {
+            outw(SELECTOR, key);
+            for byte in buffer {
+                *byte = inb(DATA);
+            }
+        }
+    }
+
+    /// DMA read of the item `key`, orders of magnitude faster for kernels.
+    pub fn read_dma(key: u16, buffer: &mut [u8]) -> Result {
+        let access = DmaAccess {
+            control: ((key as u32) << 16 | DMA_SELECT | DMA_READ).to_be(),
+            length: (buffer.len() as u32).to_be(),
+            address: (buffer.as_mut_ptr() as u64).to_be(),
+        };
+        // Boot services memory is identity mapped, so this is the physical
+        // address. Writing the low half starts the transfer.
+        let address = &access as *const DmaAccess as u64;
+        compiler_fence(Ordering::SeqCst);
+        unsafe {
+            outl(DMA_ADDRESS, ((address >> 32) as u32).to_be());
+            outl(DMA_ADDRESS + 4, (address as u32).to_be());
+        }
+        loop {
+            let control = u32::from_be(unsafe { core::ptr::read_volatile(&access.control) });
+            if control & DMA_ERROR != 0 {
+                return Err(uefi::Error::new(Status::DEVICE_ERROR, ()));
+            }
+            if control == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        compiler_fence(Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod port {
+    use uefi::{Result, Status};
+
+    pub fn hypervisor_present() -> bool {
+        false
+    }
+
+    pub fn read(_key: u16, _buffer: &mut [u8]) {}
+
+    pub fn read_dma(_key: u16, _buffer: &mut [u8]) -> Result {
+        Err(uefi::Error::new(Status::UNSUPPORTED, ()))
+    }
+}
+
+fn read_u32(key: u16) -> u32 {
+    let mut value = [0u8; 4];
+    port::read(key, &mut value);
+    u32::from_le_bytes(value)
+}
+
+/// Whether QEMU's fw_cfg device is there.
+pub fn present() -> bool {
+    if !port::hypervisor_present() {
+        return false;
+    }
+    let mut signature = [0u8; 4];
+    port::read(KEY_SIGNATURE, &mut signature);
+    &signature == b"QEMU"
+}
+
+/// Reads the item `data_key`, whose size is stored in `size_key`.
+fn read_blob(size_key: u16, data_key: u16) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; read_u32(size_key) as usize];
+    if data.is_empty() {
+        return Ok(data);
+    }
+    if read_u32(KEY_ID) & ID_DMA != 0 {
+        port::read_dma(data_key, &mut data)?;
+    } else {
+        port::read(data_key, &mut data);
+    }
+    Ok(data)
+}
+
+/// Size of the `-kernel` image, 0 if QEMU was started without one.
+pub fn kernel_size() -> u32 {
+    read_u32(KEY_SETUP_SIZE) + read_u32(KEY_KERNEL_SIZE)
+}
+
+/// The `-kernel` image. QEMU splits a bzImage into its real mode setup
+/// code and the rest; glued back together it is the original PE file.
+pub fn kernel() -> Result<Vec<u8>> {
+    let mut kernel = read_blob(KEY_SETUP_SIZE, KEY_SETUP_DATA)?;
+    kernel.extend(read_blob(KEY_KERNEL_SIZE, KEY_KERNEL_DATA)?);
+    if kernel.is_empty() {
+        return Err(uefi::Error::new(Status::NOT_FOUND, ()));
+    }
+    Ok(kernel)
+}
+
+/// The `-initrd` image, if any.
+pub fn initrd() -> Result<Option<Vec<u8>>> {
+    let initrd = read_blob(KEY_INITRD_SIZE, KEY_INITRD_DATA)?;
+    Ok((!initrd.is_empty()).then_some(initrd))
+}
+
+/// The `-append` command line, if any.
+pub fn command_line() -> Result<Option<String>> {
+    let data = read_blob(KEY_CMDLINE_SIZE, KEY_CMDLINE_DATA)?;
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let cmdline = String::from_utf8_lossy(&data[..len]).trim().into();
+    Ok(Some(cmdline).filter(|cmdline: &String| !cmdline.is_empty()))
+}
@@ -0,0 +1,229 @@
+// random_seed.rs
+// systemd-boot compatible handling of \loader\random-seed
+
+use core::ffi::c_void;
+use core::mem;
+use sha2::{Digest, Sha256};
+use uefi::boot::{self, MemoryType};
+use crate::volume::{self, Volume};
+use uefi::proto::media::file::{File, FileMode};
+use uefi::proto::rng::Rng;
+use uefi::runtime::{self, VariableVendor};
+use uefi::{Guid, Result, Status, cstr16, guid, println, system};
+
+/// Configuration table the Linux EFI stub picks its boot seed up from.
+pub const LINUX_EFI_RANDOM_SEED_TABLE_GUID: Guid = guid!("1ce1e5bc-7ceb-42f2-81e5-8aadf180f57b");
+/// Vendor GUID of the systemd-boot loader interface variables.
+pub const LOADER_GUID: Guid = guid!("4a67b082-0a4c-41cf-b6c7-440b29bb8c4f");
+
+const HASH_LABEL: &[u8] = b"systemd-boot random seed label v1";
+const HASH_VALUE_SIZE: usize = 32;
+const DESIRED_SEED_SIZE: usize = 32;
+const RANDOM_MAX_SIZE_MIN: usize = 32;
+const RANDOM_MAX_SIZE_MAX: usize = 32 * 1024;
+const RANDOM_SEED_PATH: &str = "loader\\random-seed";
+
+/// Layout of the `LINUX_EFI_RANDOM_SEED_TABLE` configuration table.
+#[repr(C)]
+struct LinuxEfiRandomSeed {
+    size: u32,
+    seed: [u8; 0],
+}
+
+fn hash_sized(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update((data.len() as u64).to_ne_bytes());
+    hasher.update(data);
+}
+
+fn zero(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(b, 0) };
+    }
+}
+
+/// Fills `buf` from the firmware RNG protocol. Returns false if there is none.
+fn firmware_random_bytes(buf: &mut [u8]) -> bool {
+    let Ok(handle) = boot::get_handle_for_protocol::<Rng>() else {
+        return false;
+    };
+    let Ok(mut rng) = boot::open_protocol_exclusive::<Rng>(handle) else {
+        return false;
+    };
+    rng.get_rng(None, buf).is_ok()
+}
+
+fn next_monotonic_count() -> Option<u64> {
+    let st = uefi::table::system_table_raw()?;
+    let bt = unsafe { st.as_ref().boot_services };
+    if bt.is_null() {
+        return None;
+    }
+    let mut count = 0u64;
+    match unsafe { ((*bt).get_next_monotonic_count)(&mut count) } {
+        Status::SUCCESS => Some(count),
+        _ => None,
+    }
+}
+
+/// Mixes the on-disk seed with the firmware RNG, `LoaderSystemToken`, the
+/// monotonic counter and the current time, writes a refreshed seed back to
+/// `\loader\random-seed` and passes a derived seed to the kernel through the
+/// `LINUX_EFI_RANDOM_SEED_TABLE` configuration table.
+///
+/// Must run right before the selected entry is started. A missing seed file
+/// is not an error, the loader simply boots without one. Like systemd-boot,
+/// the file is only used if the firmware RNG or `LoaderSystemToken` makes
+/// the result unique to this machine.
+pub fn process_random_seed() -> Result {
+    let mut random_bytes = [0u8; DESIRED_SEED_SIZE];
+    let have_rng = firmware_random_bytes(&mut random_bytes);
+    if !have_rng {
+        println!("No firmware RNG available, not mixing it into the seed.");
+    }
+
+    let mut token_buf = [0u8; 256];
+    let token_len = runtime::get_variable(
+        cstr16!("LoaderSystemToken"),
+        &VariableVendor(LOADER_GUID),
+        &mut token_buf,
+    )
+    .map(|(token, _)| token.len())
+    .ok();
+
+    // Without either, a seed file copied along with the disk image would hand
+    // every clone the same seed, so don't credit it to the kernel at all.
+    if !have_rng && token_len.is_none() {
+        println!("LoaderSystemToken not set and no firmware RNG, not passing on the random seed.");
+        zero(&mut token_buf);
+        return Ok(());
+    }
+
+    // Open the seed before touching anything, so skipping it leaves an
+    // earlier stage's seed table intact.
+    let mut volume = Volume::loader()?;
+    let mut file = match volume.open_file(RANDOM_SEED_PATH, FileMode::ReadWrite) {
+        Ok(file) => file,
+        Err(e) if e.status() == Status::NOT_FOUND || e.status() == Status::WRITE_PROTECTED => {
+            println!("No random seed file, skipping.");
+            zero(&mut random_bytes);
+            zero(&mut token_buf);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut seed = volume::read_all(&mut file)?;
+    let file_size = seed.len();
+    if !(RANDOM_MAX_SIZE_MIN..=RANDOM_MAX_SIZE_MAX).contains(&file_size) {
+        println!("Random seed file has bogus size {}, ignoring.", file_size);
+        zero(&mut seed);
+        zero(&mut random_bytes);
+        zero(&mut token_buf);
+        return Ok(());
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(HASH_LABEL);
+
+    // Chain in a seed left by an earlier stage, if any.
+    let previous = system::with_config_table(|tables| {
+        tables
+            .iter()
+            .find(|t| t.guid == LINUX_EFI_RANDOM_SEED_TABLE_GUID)
+            .map(|t| t.address as *mut LinuxEfiRandomSeed)
+    });
+    match previous {
+        Some(table) => {
+            let size = unsafe { (*table).size } as usize;
+            let size = size.min(RANDOM_MAX_SIZE_MAX);
+            let seed = unsafe {
+                core::slice::from_raw_parts_mut(
+                    (table as *mut u8).add(mem::size_of::<LinuxEfiRandomSeed>()),
+                    size,
+                )
+            };
+            hash_sized(&mut hasher, seed);
+            zero(seed);
+        }
+        None => hash_sized(&mut hasher, &[]),
+    }
+
+    if have_rng {
+        hash_sized(&mut hasher, &random_bytes);
+    }
+    zero(&mut random_bytes);
+
+    if let Some(len) = token_len {
+        hash_sized(&mut hasher, &token_buf[..len]);
+    }
+    zero(&mut token_buf);
+
+    hash_sized(&mut hasher, &seed);
+    zero(&mut seed);
+
+    match next_monotonic_count() {
+        Some(count) => hash_sized(&mut hasher, &count.to_ne_bytes()),
+        None => hash_sized(&mut hasher, &[]),
+    }
+    match runtime::get_time() {
+        Ok(now) => {
+            let now = unsafe {
+                core::slice::from_raw_parts(
+                    &now as *const _ as *const u8,
+                    mem::size_of_val(&now),
+                )
+            };
+            hash_sized(&mut hasher, now);
+        }
+        Err(_) => hash_sized(&mut hasher, &[]),
+    }
+
+    let mut hash_key: [u8; HASH_VALUE_SIZE] = hasher.finalize().into();
+
+    // The seed for the next boot and the one for the kernel are derived from
+    // the same key, but must never be equal.
+    let derive = |n: u8| -> [u8; HASH_VALUE_SIZE] {
+        let mut h = Sha256::new();
+        h.update(hash_key);
+        h.update([n]);
+        h.finalize().into()
+    };
+    let mut new_disk_seed = derive(0);
+    let mut kernel_seed = derive(1);
+    zero(&mut hash_key);
+
+    // Refresh the file before handing anything out, otherwise a crash would
+    // make the next boot reuse the same seed.
+    file.set_position(0)?;
+    let written = file.write(&new_disk_seed[..DESIRED_SEED_SIZE]);
+    zero(&mut new_disk_seed);
+    if let Err(e) = written {
+        println!("Failed to write random seed file: {:?}", e.status());
+        zero(&mut kernel_seed);
+        return Err(e.to_err_without_payload());
+    }
+    volume::set_file_size(&mut file, DESIRED_SEED_SIZE as u64)?;
+    file.flush()?;
+
+    let table_size = mem::size_of::<LinuxEfiRandomSeed>() + DESIRED_SEED_SIZE;
+    let table = boot::allocate_pool(MemoryType::ACPI_RECLAIM, table_size)?;
+    unsafe {
+        let table = table.as_ptr() as *mut LinuxEfiRandomSeed;
+        (*table).size = DESIRED_SEED_SIZE as u32;
+        core::ptr::copy_nonoverlapping(
+            kernel_seed.as_ptr(),
+            (table as *mut u8).add(mem::size_of::<LinuxEfiRandomSeed>()),
+            DESIRED_SEED_SIZE,
+        );
+    }
+    zero(&mut kernel_seed);
+
+    unsafe {
+        boot::install_configuration_table(
+            &LINUX_EFI_RANDOM_SEED_TABLE_GUID,
+            table.as_ptr() as *const c_void,
+        )?;
+    }
+    println!("Random seed refreshed and passed to the kernel.");
+    Ok(())
+}
@@ -0,0 +1,201 @@
+// secureboot.rs
+// Secure Boot checks for kernels and UKIs the loader starts from memory:
+// through shim when it is there, against db/dbx with Authenticode otherwise,
+// and SBAT generations against shim's revocations
+
+mod authenticode;
+mod der;
+mod enroll;
+mod rsa;
+mod sbat;
+mod security;
+mod shim;
+mod x509;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use uefi::proto::device_path::DevicePath;
+use uefi::runtime::{self, VariableVendor};
+use uefi::{CStr16, Guid, Handle, Result, Status, boot, cstr16, guid, println};
+
+pub use enroll::{enroll, enroll_automatically, entries as enroll_entries};
+
+pub const CERT_SHA256: Guid = guid!("c1c41626-504c-4092-aca9-41f936934328");
+pub const CERT_X509: Guid = guid!("a5c059a1-94e4-4aa7-87b5-ab155c2bf072");
+pub const CERT_X509_SHA256: Guid = guid!("3bd2a492-96c0-4079-b420-fcf98ef103ed");
+
+/// Size of an `EFI_SIGNATURE_LIST` header.
+const SIGNATURE_LIST_SIZE: usize = 28;
+
+/// Digest algorithms of Authenticode signatures and certificates.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Hash {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Hash {
+    const OIDS: [(Hash, &'static [u8]); 4] = [
+        (Hash::Sha1, &[0x2b, 0x0e, 0x03, 0x02, 0x1a]),
+        (Hash::Sha256, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]),
+        (Hash::Sha384, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02]),
+        (Hash::Sha512, &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03]),
+    ];
+
+    /// From a digest algorithm OID.
+    fn from_oid(oid: &[u8]) -> Option<Self> {
+        Self::OIDS.iter().find(|(_, o)| *o == oid).map(|(hash, _)| *hash)
+    }
+
+    /// From a `sha*WithRSAEncryption` signature algorithm OID.
+    fn from_signature_oid(oid: &[u8]) -> Option<Self> {
+        let [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, number] = oid else {
+            return None;
+        };
+        match number {
+            5 => Some(Hash::Sha1),
+            11 => Some(Hash::Sha256),
+            12 => Some(Hash::Sha384),
+            13 => Some(Hash::Sha512),
+            _ => None,
+        }
+    }
+
+    fn oid(self) -> &'static [u8] {
+        Self::OIDS.iter().find(|(hash, _)| *hash == self).unwrap().1
+    }
+
+    /// Digest of `parts` one after the other.
+    fn digest(self, parts: &[&[u8]]) -> Vec<u8> {
+        fn run<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+            let mut hasher = D::new();
+            for part in parts {
+                hasher.update(part);
+            }
+            hasher.finalize().to_vec()
+        }
+        match self {
+            Hash::Sha1 => run::<Sha1>(parts),
+            Hash::Sha256 => run::<Sha256>(parts),
+            Hash::Sha384 => run::<Sha384>(parts),
+            Hash::Sha512 => run::<Sha512>(parts),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// One entry of a signature database such as db or dbx.
+pub struct Signature {
+    /// `EFI_CERT_*` type of the list it came from.
+    pub kind: Guid,
+    pub data: Vec<u8>,
+}
+
+/// The entries of the `EFI_SIGNATURE_LIST`s in `data`; a malformed list ends
+/// the parsing.
+pub fn signature_lists(mut data: &[u8]) -> Vec<Signature> {
+    let mut signatures = Vec::new();
+    while data.len() >= SIGNATURE_LIST_SIZE {
+        let kind = Guid::from_bytes(data[0..16].try_into().unwrap());
+        let le32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let (list_size, header_size, signature_size) = (le32(16), le32(20), le32(24));
+        let start = SIGNATURE_LIST_SIZE + header_size;
+        if list_size > data.len() || start > list_size || signature_size < 16 {
+            break;
+        }
+        // Each entry starts with its owner's GUID
+        for entry in data[start..list_size].chunks_exact(signature_size) {
+            signatures.push(Signature {
+                kind,
+                data: entry[16..].to_vec(),
+            });
+        }
+        data = &data[list_size..];
+    }
+    signatures
+}
+
+/// The entries of the signature database variable `name`; empty if unset.
+fn database(name: &CStr16) -> Vec<Signature> {
+    runtime::get_variable_boxed(name, &VariableVendor::IMAGE_SECURITY_DATABASE)
+        .map(|(data, _)| signature_lists(&data))
+        .unwrap_or_default()
+}
+
+/// True if the firmware enforces Secure Boot.
+pub fn enabled() -> bool {
+    runtime::get_variable_boxed(cstr16!("SecureBoot"), &VariableVendor::GLOBAL_VARIABLE)
+        .is_ok_and(|(data, _)| data.first() == Some(&1))
+}
+
+/// Refuses `what`, which the loader reads from disk and passes on without a
+/// signature to check, while Secure Boot is on.
+pub fn refuse_unverified(what: &str) -> Result {
+    if enabled() {
+        println!("{}: refused by Secure Boot, it can't be verified", what);
+        return Err(uefi::Error::new(Status::SECURITY_VIOLATION, ()));
+    }
+    Ok(())
+}
+
+/// Checks `image` like the firmware would, plus shim's MOK lists if the
+/// loader was started through shim.
+fn verify(name: &str, image: &[u8]) -> Result {
+    if let Some(status) = shim::verify(image) {
+        if status.is_success() {
+            println!("{}: verified by shim", name);
+            return Ok(());
+        }
+        println!("{}: refused by shim: {:?}", name, status);
+        return Err(uefi::Error::new(Status::SECURITY_VIOLATION, ()));
+    }
+
+    let (db, dbx) = (database(cstr16!("db")), database(cstr16!("dbx")));
+    match authenticode::verify(image, &authenticode::Policy::new(&db, &dbx)) {
+        Ok(reason) => {
+            println!("{}: {}", name, reason);
+            Ok(())
+        }
+        Err(reason) => {
+            println!("{}: refused by Secure Boot, {}", name, reason);
+            Err(uefi::Error::new(Status::SECURITY_VIOLATION, ()))
+        }
+    }
+}
+
+/// Refuses `image` unless it is signed and not revoked, while Secure Boot is
+/// on. Called on the image as read, before anything in it is unpacked.
+pub fn check(name: &str, image: &[u8]) -> Result {
+    if !enabled() {
+        return Ok(());
+    }
+    verify(name, image)?;
+    sbat::check(name, image)
+}
+
+/// `LoadImage` for `image`, which [`check`] has accepted (or which was
+/// unpacked from an image it accepted). With Secure Boot on it is loaded
+/// past the firmware's own db check, so that shim signed kernels work.
+pub fn load_image(image: &[u8], file_path: Option<&DevicePath>) -> Result<Handle> {
+    let load = || {
+        boot::load_image(
+            boot::image_handle(),
+            boot::LoadImageSource::FromBuffer {
+                buffer: image,
+                file_path,
+            },
+        )
+    };
+    if !enabled() {
+        return load();
+    }
+    security::allow(image, load)
+}
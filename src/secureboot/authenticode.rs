@@ -0,0 +1,262 @@
+// authenticode.rs
+// Authenticode signatures of PE images, checked against db and dbx
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::der::{self, Element, Reader};
+use super::x509::Certificate;
+use super::{CERT_SHA256, CERT_X509, CERT_X509_SHA256, Hash, Signature, hex};
+use crate::pe;
+
+const SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+const SPC_INDIRECT_DATA: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+const MESSAGE_DIGEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x04];
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+/// Longest certificate chain followed from the signer to db.
+const MAX_CHAIN: usize = 8;
+
+/// One PKCS#7 signature from the certificate table.
+struct SignedData<'a> {
+    certificates: Vec<Certificate<'a>>,
+    /// Contents of the `SpcIndirectDataContent`, what the signer signed.
+    content: &'a [u8],
+    image_hash: Hash,
+    image_digest: &'a [u8],
+    signer: Signer<'a>,
+}
+
+struct Signer<'a> {
+    issuer: &'a [u8],
+    serial: &'a [u8],
+    hash: Hash,
+    attributes: Option<Element<'a>>,
+    signature: &'a [u8],
+}
+
+/// The PKCS#7 blobs of the `WIN_CERTIFICATE` entries in `table`.
+fn signatures(mut table: &[u8]) -> Vec<&[u8]> {
+    let mut blobs = Vec::new();
+    while table.len() >= 8 {
+        let length = u32::from_le_bytes(table[0..4].try_into().unwrap()) as usize;
+        let revision = u16::from_le_bytes(table[4..6].try_into().unwrap());
+        let kind = u16::from_le_bytes(table[6..8].try_into().unwrap());
+        if length < 8 || length > table.len() {
+            break;
+        }
+        if revision == WIN_CERT_REVISION_2_0 && kind == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            blobs.push(&table[8..length]);
+        }
+        table = &table[length.next_multiple_of(8).min(table.len())..];
+    }
+    blobs
+}
+
+fn algorithm(element: Element) -> Option<Hash> {
+    Hash::from_oid(element.reader().expect(der::OID)?.contents)
+}
+
+fn parse_signer(signer: Element<'_>) -> Option<Signer<'_>> {
+    let mut fields = signer.reader();
+    fields.expect(der::INTEGER)?;
+    let mut issuer_and_serial = fields.expect(der::SEQUENCE)?.reader();
+    let issuer = issuer_and_serial.expect(der::SEQUENCE)?.raw;
+    let serial = der::unsigned(&issuer_and_serial.expect(der::INTEGER)?);
+    let hash = algorithm(fields.expect(der::SEQUENCE)?)?;
+    let attributes = fields.optional(der::context(0));
+    fields.expect(der::SEQUENCE)?;
+    let signature = fields.expect(der::OCTET_STRING)?.contents;
+    Some(Signer {
+        issuer,
+        serial,
+        hash,
+        attributes,
+        signature,
+    })
+}
+
+fn parse(blob: &[u8]) -> Option<SignedData<'_>> {
+    // The blob may be padded after the ContentInfo
+    let mut content_info = Reader::new(blob).expect(der::SEQUENCE)?.reader();
+    if content_info.expect(der::OID)?.contents != SIGNED_DATA {
+        return None;
+    }
+    let signed = content_info.expect(der::context(0))?.reader().expect(der::SEQUENCE)?;
+    let mut fields = signed.reader();
+    fields.expect(der::INTEGER)?;
+    fields.expect(der::SET)?;
+
+    let mut encapsulated = fields.expect(der::SEQUENCE)?.reader();
+    if encapsulated.expect(der::OID)?.contents != SPC_INDIRECT_DATA {
+        return None;
+    }
+    let content = encapsulated.expect(der::context(0))?.reader().expect(der::SEQUENCE)?;
+    let mut indirect = content.reader();
+    indirect.expect(der::SEQUENCE)?;
+    let mut digest_info = indirect.expect(der::SEQUENCE)?.reader();
+    let image_hash = algorithm(digest_info.expect(der::SEQUENCE)?)?;
+    let image_digest = digest_info.expect(der::OCTET_STRING)?.contents;
+
+    let certificates = fields
+        .optional(der::context(0))
+        .map(|set| set.reader().filter_map(|c| Certificate::parse(c.raw)).collect())
+        .unwrap_or_default();
+    fields.optional(der::context(1));
+    let signer = parse_signer(fields.expect(der::SET)?.reader().expect(der::SEQUENCE)?)?;
+
+    Some(SignedData {
+        certificates,
+        content: content.contents,
+        image_hash,
+        image_digest,
+        signer,
+    })
+}
+
+/// True if `certificate` made the signer's signature over the content.
+fn signer_valid(data: &SignedData, certificate: &Certificate) -> bool {
+    let signer = &data.signer;
+    let Some(key) = &certificate.public_key else {
+        return false;
+    };
+    let content_digest = signer.hash.digest(&[data.content]);
+    let Some(attributes) = signer.attributes else {
+        return key.verify(signer.signature, signer.hash, &content_digest);
+    };
+    let digest_matches = attributes.reader().any(|attribute| {
+        let mut fields = attribute.reader();
+        fields.expect(der::OID).is_some_and(|oid| oid.contents == MESSAGE_DIGEST)
+            && fields
+                .expect(der::SET)
+                .and_then(|values| values.reader().expect(der::OCTET_STRING))
+                .is_some_and(|digest| digest.contents == content_digest)
+    });
+    // The attributes are signed as a SET, not with their implicit [0] tag
+    let signed_digest = signer.hash.digest(&[&[der::SET], &attributes.raw[1..]]);
+    digest_matches && key.verify(signer.signature, signer.hash, &signed_digest)
+}
+
+/// The db and dbx entries that apply to certificates.
+pub struct Policy<'a> {
+    pub trusted: Vec<Certificate<'a>>,
+    pub trusted_hashes: Vec<&'a [u8]>,
+    pub revoked: Vec<Certificate<'a>>,
+    /// SHA-256 of revoked certificates' `TBSCertificate`s.
+    pub revoked_tbs: Vec<&'a [u8]>,
+    pub revoked_hashes: Vec<&'a [u8]>,
+}
+
+impl<'a> Policy<'a> {
+    pub fn new(db: &'a [Signature], dbx: &'a [Signature]) -> Self {
+        let certificates = |list: &'a [Signature]| {
+            list.iter()
+                .filter(|s| s.kind == CERT_X509)
+                .filter_map(|s| Certificate::parse(&s.data))
+                .collect()
+        };
+        let hashes = |list: &'a [Signature], kind| {
+            list.iter()
+                .filter(|s| s.kind == kind)
+                .filter_map(|s| s.data.get(..32))
+                .collect()
+        };
+        Policy {
+            trusted: certificates(db),
+            trusted_hashes: hashes(db, CERT_SHA256),
+            revoked: certificates(dbx),
+            revoked_tbs: hashes(dbx, CERT_X509_SHA256),
+            revoked_hashes: hashes(dbx, CERT_SHA256),
+        }
+    }
+
+    fn is_revoked(&self, certificate: &Certificate) -> bool {
+        let tbs_hash = Hash::Sha256.digest(&[certificate.tbs]);
+        self.revoked.iter().any(|r| r.raw == certificate.raw) || self.revoked_tbs.contains(&tbs_hash.as_slice())
+    }
+
+    /// The db certificate `certificate` is or was issued by.
+    fn anchor(&self, certificate: &Certificate) -> Option<&Certificate<'a>> {
+        self.trusted
+            .iter()
+            .find(|t| t.raw == certificate.raw || certificate.signed_by(t))
+    }
+}
+
+/// Checks the signatures of `image`. On success, says what allowed it; on
+/// refusal, which certificate or hash failed.
+pub fn verify(image: &[u8], policy: &Policy) -> core::result::Result<String, String> {
+    let Some((parts, table)) = pe::authenticode_layout(image) else {
+        return Err(String::from("not a valid PE image"));
+    };
+    let sha256 = Hash::Sha256.digest(&parts);
+    if policy.revoked_hashes.contains(&sha256.as_slice()) {
+        return Err(format!("its SHA-256 {} is forbidden by dbx", hex(&sha256)));
+    }
+
+    let mut allowed = None;
+    let mut failure = None;
+    for blob in signatures(table) {
+        let Some(data) = parse(blob) else {
+            failure = Some(String::from("a signature could not be parsed"));
+            continue;
+        };
+        if data.image_hash.digest(&parts) != data.image_digest {
+            failure = Some(String::from("the signed hash doesn't match the image"));
+            continue;
+        }
+        let Some(signer) = data
+            .certificates
+            .iter()
+            .find(|c| c.issuer == data.signer.issuer && c.serial == data.signer.serial)
+        else {
+            failure = Some(String::from("the signer's certificate is missing"));
+            continue;
+        };
+        if !signer_valid(&data, signer) {
+            failure = Some(format!("the signature by '{}' is invalid", signer.name()));
+            continue;
+        }
+
+        // Walk up the chain until a db certificate vouches for it
+        let mut current = signer;
+        for _ in 0..MAX_CHAIN {
+            if policy.is_revoked(current) {
+                return Err(format!("certificate '{}' is revoked by dbx", current.name()));
+            }
+            if let Some(anchor) = policy.anchor(current) {
+                if policy.is_revoked(anchor) {
+                    return Err(format!("certificate '{}' is revoked by dbx", anchor.name()));
+                }
+                allowed.get_or_insert_with(|| {
+                    if anchor.raw == signer.raw {
+                        format!("signed by '{}' from db", signer.name())
+                    } else {
+                        format!("signed by '{}', trusted through '{}' in db", signer.name(), anchor.name())
+                    }
+                });
+                break;
+            }
+            match data
+                .certificates
+                .iter()
+                .find(|c| c.raw != current.raw && current.signed_by(c))
+            {
+                Some(issuer) => current = issuer,
+                None => {
+                    failure = Some(format!("signer '{}' is not trusted by db", signer.name()));
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(allowed) = allowed {
+        return Ok(allowed);
+    }
+    if policy.trusted_hashes.contains(&sha256.as_slice()) {
+        return Ok(format!("SHA-256 {} is allowed by db", hex(&sha256)));
+    }
+    Err(failure.unwrap_or_else(|| format!("not signed, and its SHA-256 {} is not in db", hex(&sha256))))
+}
@@ -0,0 +1,153 @@
+// der.rs
+// Just enough DER to walk PKCS#7 and X.509 structures
+
+pub const INTEGER: u8 = 0x02;
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const NULL: u8 = 0x05;
+pub const OID: u8 = 0x06;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+
+/// Constructed context specific tag `[n]`.
+pub const fn context(n: u8) -> u8 {
+    0xa0 | n
+}
+
+/// One element: its tag, its contents and its whole encoding.
+#[derive(Clone, Copy)]
+pub struct Element<'a> {
+    pub tag: u8,
+    pub contents: &'a [u8],
+    pub raw: &'a [u8],
+}
+
+impl<'a> Element<'a> {
+    /// The elements inside a constructed one.
+    pub fn reader(&self) -> Reader<'a> {
+        Reader::new(self.contents)
+    }
+}
+
+/// Reads consecutive elements. Multi-byte tags and indefinite lengths
+/// aren't DER and end the iteration.
+#[derive(Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    /// Parses the single element `data` must consist of.
+    pub fn single(data: &'a [u8]) -> Option<Element<'a>> {
+        let mut reader = Reader::new(data);
+        reader.next().filter(|_| reader.data.is_empty())
+    }
+
+    /// The next element, which must have `tag`.
+    pub fn expect(&mut self, tag: u8) -> Option<Element<'a>> {
+        self.next().filter(|element| element.tag == tag)
+    }
+
+    /// The next element if it has `tag`, for OPTIONAL fields.
+    pub fn optional(&mut self, tag: u8) -> Option<Element<'a>> {
+        if self.data.first() == Some(&tag) {
+            self.next()
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Element<'a>> {
+        let tag = *self.data.first()?;
+        if tag & 0x1f == 0x1f {
+            return None;
+        }
+        let first = *self.data.get(1)? as usize;
+        let (length, header) = match first {
+            0..=0x7f => (first, 2),
+            0x81..=0x84 => {
+                let count = first - 0x80;
+                let bytes = self.data.get(2..2 + count)?;
+                (bytes.iter().fold(0, |length, &b| length << 8 | b as usize), 2 + count)
+            }
+            _ => return None,
+        };
+        let end = header.checked_add(length)?;
+        let raw = self.data.get(..end)?;
+        self.data = &self.data[end..];
+        Some(Element {
+            tag,
+            contents: &raw[header..],
+            raw,
+        })
+    }
+}
+
+/// Contents of the INTEGER `element` without the leading zero of positive
+/// numbers with the top bit set.
+pub fn unsigned<'a>(element: &Element<'a>) -> &'a [u8] {
+    match element.contents {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        contents => contents,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_nested_elements() {
+        let data = [SEQUENCE, 6, INTEGER, 1, 5, OCTET_STRING, 1, 0xaa, NULL, 0];
+        let mut reader = Reader::new(&data);
+        let sequence = reader.expect(SEQUENCE).unwrap();
+        assert_eq!(sequence.raw, &data[..8]);
+        let mut fields = sequence.reader();
+        assert_eq!(fields.expect(INTEGER).unwrap().contents, [5]);
+        assert!(fields.optional(NULL).is_none());
+        assert_eq!(fields.optional(OCTET_STRING).unwrap().contents, [0xaa]);
+        assert!(fields.next().is_none());
+        assert!(reader.expect(NULL).unwrap().contents.is_empty());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn reads_long_lengths() {
+        let mut data = vec![OCTET_STRING, 0x82, 0x01, 0x00];
+        data.extend([7; 256]);
+        let element = Reader::single(&data).unwrap();
+        assert_eq!(element.contents.len(), 256);
+        assert_eq!(element.raw.len(), data.len());
+    }
+
+    #[test]
+    fn rejects_malformed_elements() {
+        // Truncated contents and length
+        assert!(Reader::single(&[OCTET_STRING, 3, 1, 2]).is_none());
+        assert!(Reader::single(&[OCTET_STRING, 0x82, 1]).is_none());
+        assert!(Reader::single(&[OCTET_STRING]).is_none());
+        // Indefinite and oversized lengths, multi-byte tags
+        assert!(Reader::single(&[SEQUENCE, 0x80, 0, 0]).is_none());
+        assert!(Reader::single(&[OCTET_STRING, 0x85, 0, 0, 0, 0, 1, 0]).is_none());
+        assert!(Reader::single(&[0x1f, 0x81, 0x01, 0]).is_none());
+        // Trailing data and unexpected tags
+        assert!(Reader::single(&[NULL, 0, 0]).is_none());
+        assert!(Reader::new(&[NULL, 0]).expect(INTEGER).is_none());
+    }
+
+    #[test]
+    fn strips_sign_byte() {
+        let element = Reader::single(&[INTEGER, 3, 0, 0x80, 1]).unwrap();
+        assert_eq!(unsigned(&element), [0x80, 1]);
+        let zero = Reader::single(&[INTEGER, 1, 0]).unwrap();
+        assert_eq!(unsigned(&zero), [0]);
+    }
+}
@@ -0,0 +1,168 @@
+// enroll.rs
+// Enrolls the Secure Boot keys in loader\keys\<name> while the firmware is
+// in Setup Mode, like systemd-boot's secure-boot-enroll
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::proto::console::text::Key;
+use uefi::runtime::{self, ResetType, VariableAttributes, VariableVendor};
+use uefi::{CStr16, Result, Status, boot, cstr16, print, println, system};
+
+use crate::entries_parse::BootEntry;
+use crate::loader_conf::LoaderConf;
+use crate::smbios;
+use crate::volume::{self, Volume};
+
+const KEYS_DIR: &str = "loader\\keys";
+/// Key set enrolled without asking with `secure-boot-enroll if-safe`/`force`.
+const AUTO_KEYS: &str = "auto";
+/// Seconds to press a key before keys are enrolled.
+const COUNTDOWN: u32 = 15;
+
+/// The variables to write, in order: PK goes last, as it ends Setup Mode.
+/// dbx is optional.
+const VARIABLES: [(&CStr16, &str, VariableVendor, bool); 4] = [
+    (cstr16!("db"), "db.auth", VariableVendor::IMAGE_SECURITY_DATABASE, true),
+    (cstr16!("dbx"), "dbx.auth", VariableVendor::IMAGE_SECURITY_DATABASE, false),
+    (cstr16!("KEK"), "KEK.auth", VariableVendor::GLOBAL_VARIABLE, true),
+    (cstr16!("PK"), "PK.auth", VariableVendor::GLOBAL_VARIABLE, true),
+];
+
+/// `secure-boot-enroll` from loader.conf.
+#[derive(PartialEq)]
+enum Mode {
+    Off,
+    Manual,
+    IfSafe,
+    Force,
+}
+
+fn mode(conf: &LoaderConf) -> Mode {
+    match conf.secure_boot_enroll.as_deref() {
+        Some("off") => Mode::Off,
+        Some("if-safe") => Mode::IfSafe,
+        Some("force") => Mode::Force,
+        _ => Mode::Manual,
+    }
+}
+
+/// True while the firmware accepts a new PK without authentication.
+pub fn setup_mode() -> bool {
+    runtime::get_variable_boxed(cstr16!("SetupMode"), &VariableVendor::GLOBAL_VARIABLE)
+        .is_ok_and(|(data, _)| data.first() == Some(&1))
+}
+
+/// Enrolling keys can only brick a virtual machine's (throwaway) variable
+/// store, so `if-safe` enrolls automatically only there.
+fn virtual_machine() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    if core::arch::x86_64::__cpuid(1).ecx & (1 << 31) != 0 {
+        return true;
+    }
+    smbios::virtual_machine()
+}
+
+/// Key sets in `loader\keys` with at least PK, KEK and db.
+fn key_sets(volume: &mut Volume) -> Vec<String> {
+    let Ok(Some(dirs)) = volume.read_dir(KEYS_DIR) else {
+        return Vec::new();
+    };
+    dirs.into_iter()
+        .filter(|dir| dir.is_dir)
+        .map(|dir| dir.name)
+        .filter(|name| {
+            VARIABLES
+                .iter()
+                .filter(|(_, _, _, required)| *required)
+                .all(|(_, file, _, _)| {
+                    volume
+                        .exists(&volume::join(&volume::join(KEYS_DIR, name), file))
+                        .unwrap_or(false)
+                })
+        })
+        .collect()
+}
+
+/// "Enroll Secure Boot keys" menu entries, only in Setup Mode.
+pub fn entries() -> Vec<BootEntry> {
+    if !setup_mode() || LoaderConf::read().is_ok_and(|conf| mode(&conf) == Mode::Off) {
+        return Vec::new();
+    }
+    let Ok(mut volume) = Volume::loader() else {
+        return Vec::new();
+    };
+    key_sets(&mut volume)
+        .into_iter()
+        .map(|name| {
+            let mut entry = BootEntry::new();
+            entry.title = format!("Enroll Secure Boot keys: {}", name);
+            entry.secure_boot_keys = Some(name);
+            entry
+        })
+        .collect()
+}
+
+/// Counts down, returning false if a key was pressed.
+fn countdown() -> Result<bool> {
+    for remaining in (1..=COUNTDOWN).rev() {
+        print!("\rEnrolling in {:2} s, press any key to abort.", remaining);
+        for _ in 0..10 {
+            boot::stall(100_000);
+            if let Some(Key::Printable(_) | Key::Special(_)) = system::with_stdin(|input| input.read_key())? {
+                println!("\nAborted.");
+                return Ok(false);
+            }
+        }
+    }
+    println!();
+    Ok(true)
+}
+
+/// Writes the key set `name` to the firmware and reboots. Returns if the
+/// user aborted or a variable couldn't be written.
+pub fn enroll(name: &str, ask: bool) -> Result {
+    let mut volume = Volume::loader()?;
+    let dir = volume::join(KEYS_DIR, name);
+    println!("Enrolling Secure Boot keys from {}", dir);
+    if ask {
+        println!("Warning: custom Secure Boot keys can keep firmware drivers from loading and the machine from booting.");
+        if !countdown()? {
+            return Ok(());
+        }
+    }
+
+    let attributes = VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS
+        | VariableAttributes::TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+    for (variable, file, vendor, required) in &VARIABLES {
+        let path = volume::join(&dir, file);
+        if !required && !volume.exists(&path)? {
+            continue;
+        }
+        let data = volume.read(&path)?;
+        if let Err(e) = runtime::set_variable(variable, vendor, attributes, &data) {
+            println!("Failed to write {}: {:?}", variable, e.status());
+            return Err(uefi::Error::new(e.status(), ()));
+        }
+        println!("Wrote {}", variable);
+    }
+
+    println!("Secure Boot keys enrolled, rebooting.");
+    boot::stall(2_000_000);
+    runtime::reset(ResetType::COLD, Status::SUCCESS, None)
+}
+
+/// Enrolls the `auto` key set without a menu entry if loader.conf asks for
+/// it with `secure-boot-enroll force`, or `if-safe` in a virtual machine.
+pub fn enroll_automatically() -> Result {
+    let mode = mode(&LoaderConf::read()?);
+    if !(mode == Mode::Force || mode == Mode::IfSafe && virtual_machine()) || !setup_mode() {
+        return Ok(());
+    }
+    if key_sets(&mut Volume::loader()?).iter().any(|name| name == AUTO_KEYS) {
+        enroll(AUTO_KEYS, mode != Mode::Force)?;
+    }
+    Ok(())
+}
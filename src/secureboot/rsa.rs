@@ -0,0 +1,253 @@
+// rsa.rs
+// RSA PKCS#1 v1.5 signature verification; public key operations only, so
+// nothing here needs to be constant time
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use super::Hash;
+use super::der::{self, Reader};
+
+/// A public key with the modulus as little-endian 32-bit limbs and what
+/// Montgomery multiplication needs.
+pub struct PublicKey {
+    modulus: Vec<u32>,
+    exponent: Vec<u8>,
+    /// Size of the modulus in bytes, and of signatures.
+    size: usize,
+    /// -modulus⁻¹ mod 2³².
+    inverse: u32,
+    /// R² mod modulus, with R = 2^(32 · limbs).
+    r2: Vec<u32>,
+}
+
+fn limbs(bytes: &[u8], count: usize) -> Vec<u32> {
+    let mut limbs = vec![0u32; count];
+    for (i, &byte) in bytes.iter().rev().enumerate() {
+        limbs[i / 4] |= (byte as u32) << (8 * (i % 4));
+    }
+    limbs
+}
+
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+/// `a -= b`, returning the borrow.
+fn subtract(a: &mut [u32], b: &[u32]) -> bool {
+    let mut borrow = false;
+    for (a, &b) in a.iter_mut().zip(b) {
+        let (value, first) = a.overflowing_sub(b);
+        let (value, second) = value.overflowing_sub(borrow as u32);
+        *a = value;
+        borrow = first || second;
+    }
+    borrow
+}
+
+impl PublicKey {
+    /// Parses a PKCS#1 `RSAPublicKey`.
+    pub fn from_der(data: &[u8]) -> Option<Self> {
+        let key = Reader::single(data).filter(|key| key.tag == der::SEQUENCE)?;
+        let mut fields = key.reader();
+        let modulus = der::unsigned(&fields.expect(der::INTEGER)?);
+        let exponent = der::unsigned(&fields.expect(der::INTEGER)?).to_vec();
+        let modulus = &modulus[modulus.iter().position(|&b| b != 0)?..];
+        if modulus.len() < 128 || modulus[modulus.len() - 1] & 1 == 0 {
+            return None;
+        }
+        let count = modulus.len().div_ceil(4);
+        let modulus_limbs = limbs(modulus, count);
+
+        // Newton's iteration doubles the correct low bits each round
+        let mut inverse = 1u32;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(modulus_limbs[0].wrapping_mul(inverse)));
+        }
+
+        // R² mod modulus by doubling 1 as often as R² has bits
+        let mut r2 = vec![0u32; count];
+        r2[0] = 1;
+        for _ in 0..2 * 32 * count {
+            let carry = r2[count - 1] >> 31;
+            for i in (1..count).rev() {
+                r2[i] = r2[i] << 1 | r2[i - 1] >> 31;
+            }
+            r2[0] <<= 1;
+            if carry != 0 || compare(&r2, &modulus_limbs) != Ordering::Less {
+                subtract(&mut r2, &modulus_limbs);
+            }
+        }
+
+        Some(PublicKey {
+            modulus: modulus_limbs,
+            exponent,
+            size: modulus.len(),
+            inverse: inverse.wrapping_neg(),
+            r2,
+        })
+    }
+
+    /// a · b · R⁻¹ mod modulus (CIOS Montgomery multiplication).
+    fn multiply(&self, a: &[u32], b: &[u32]) -> Vec<u32> {
+        let n = &self.modulus;
+        let count = n.len();
+        let mut t = vec![0u32; count + 2];
+        for &b in b {
+            let mut carry = 0u64;
+            for j in 0..count {
+                let sum = t[j] as u64 + a[j] as u64 * b as u64 + carry;
+                t[j] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[count] as u64 + carry;
+            t[count] = sum as u32;
+            t[count + 1] = (sum >> 32) as u32;
+
+            let q = t[0].wrapping_mul(self.inverse);
+            let mut carry = (t[0] as u64 + q as u64 * n[0] as u64) >> 32;
+            for j in 1..count {
+                let sum = t[j] as u64 + q as u64 * n[j] as u64 + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[count] as u64 + carry;
+            t[count - 1] = sum as u32;
+            t[count] = t[count + 1] + (sum >> 32) as u32;
+            t[count + 1] = 0;
+        }
+        // t < 2 · modulus here
+        if t[count] != 0 || compare(&t[..count], n) != Ordering::Less {
+            subtract(&mut t[..count], n);
+        }
+        t.truncate(count);
+        t
+    }
+
+    /// signature^exponent mod modulus as big-endian bytes.
+    fn public_operation(&self, signature: &[u8]) -> Option<Vec<u8>> {
+        if signature.len() != self.size {
+            return None;
+        }
+        let count = self.modulus.len();
+        let base = limbs(signature, count);
+        if compare(&base, &self.modulus) != Ordering::Less {
+            return None;
+        }
+        let base = self.multiply(&base, &self.r2);
+        let mut result = base.clone();
+        let top = self.exponent.first().map_or(0, |&b| 8 - b.leading_zeros() as usize);
+        let bits = (self.exponent.len() * 8).checked_sub(8 - top)?;
+        for bit in (0..bits.checked_sub(1)?).rev() {
+            result = self.multiply(&result, &result);
+            if self.exponent[self.exponent.len() - 1 - bit / 8] >> (bit % 8) & 1 != 0 {
+                result = self.multiply(&result, &base);
+            }
+        }
+        let mut one = vec![0u32; count];
+        one[0] = 1;
+        let result = self.multiply(&result, &one);
+
+        let mut bytes = vec![0u8; self.size];
+        for (i, byte) in bytes.iter_mut().rev().enumerate() {
+            *byte = (result[i / 4] >> (8 * (i % 4))) as u8;
+        }
+        Some(bytes)
+    }
+
+    /// Checks the PKCS#1 v1.5 `signature` of a message whose `hash` digest is
+    /// `digest`.
+    pub fn verify(&self, signature: &[u8], hash: Hash, digest: &[u8]) -> bool {
+        let Some(message) = self.public_operation(signature) else {
+            return false;
+        };
+        let [0, 1, padded @ ..] = message.as_slice() else {
+            return false;
+        };
+        let Some(end) = padded.iter().position(|&b| b != 0xff) else {
+            return false;
+        };
+        if end < 8 || padded[end] != 0 {
+            return false;
+        }
+        digest_info(&padded[end + 1..]).is_some_and(|(oid, value)| oid == hash.oid() && value == digest)
+    }
+}
+
+/// OID and digest of a `DigestInfo`; the NULL parameters are optional.
+fn digest_info(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let info = Reader::single(data).filter(|info| info.tag == der::SEQUENCE)?;
+    let mut fields = info.reader();
+    let algorithm = fields.expect(der::SEQUENCE)?;
+    let mut parameters = algorithm.reader();
+    let oid = parameters.expect(der::OID)?;
+    parameters.optional(der::NULL);
+    if parameters.next().is_some() {
+        return None;
+    }
+    let digest = fields.expect(der::OCTET_STRING)?;
+    fields.next().is_none().then_some((oid.contents, digest.contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    /// 1024-bit `RSAPublicKey` and its SHA-256 PKCS#1 v1.5 signature of
+    /// "abc", made with openssl.
+    const KEY: &str = "30818902818100e432e8553849e9f00fbab5f9bf5ff36f7def6beaf892034d7d5105adfbc81d35592c7c3441161b628983464aaf088b7c52ce1be72079b4d63081fe3617526e1ea42a8cb108b065f853bc5c79347fc67397ec50eb265436a238af4c2624190b2349884f433c16cecbb62de9b8398bb2f68545564fc25aaeaea67ecfe6c600ed050203010001";
+    const SIGNATURE: &str = "a9dff387ffd16a0a5cdbba1808f1b00acdc4b5c570f9bca505088251919ddfdd94f6b2d88bad9fbd6a56df98ec72fccb65049badfc27254990690ffc5702c8f7ccd6fd81dd2895eafaffaee5215a2563a3cb1e55f89e31eca8e90d571df5882595124be6c4dd6700fbaa05aff1ff552e6f2f5437c75ce0b15ff4259800447792";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn key() -> PublicKey {
+        PublicKey::from_der(&hex(KEY)).unwrap()
+    }
+
+    #[test]
+    fn verifies_signature() {
+        let digest = Sha256::digest(b"abc");
+        assert!(key().verify(&hex(SIGNATURE), Hash::Sha256, &digest));
+    }
+
+    #[test]
+    fn rejects_wrong_message() {
+        let key = key();
+        let signature = hex(SIGNATURE);
+        assert!(!key.verify(&signature, Hash::Sha256, &Sha256::digest(b"abd")));
+        assert!(!key.verify(&signature, Hash::Sha1, &Sha256::digest(b"abc")[..20]));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let key = key();
+        let digest = Sha256::digest(b"abc");
+        let mut signature = hex(SIGNATURE);
+        signature[64] ^= 1;
+        assert!(!key.verify(&signature, Hash::Sha256, &digest));
+
+        let mut long = hex(SIGNATURE);
+        long.insert(0, 0);
+        assert!(!key.verify(&long, Hash::Sha256, &digest));
+        assert!(!key.verify(&long[2..], Hash::Sha256, &digest));
+        assert!(!key.verify(&[], Hash::Sha256, &digest));
+        // Not below the modulus
+        assert!(!key.verify(&[0xff; 128], Hash::Sha256, &digest));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let key = hex(KEY);
+        assert!(PublicKey::from_der(&key[..key.len() - 1]).is_none());
+        // Even modulus
+        let mut even = key.clone();
+        even[134] ^= 1;
+        assert!(PublicKey::from_der(&even).is_none());
+        // Too small a modulus
+        assert!(PublicKey::from_der(&[der::SEQUENCE, 6, der::INTEGER, 1, 0x0f, der::INTEGER, 1, 3]).is_none());
+    }
+}
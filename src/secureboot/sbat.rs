@@ -0,0 +1,158 @@
+// sbat.rs
+// SBAT: the loader's own `.sbat` section for shim, and the check of images'
+// component generations against shim's SbatLevel revocations
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::runtime::{self, VariableVendor};
+use uefi::{Guid, Result, Status, cstr16, guid, println};
+
+use crate::pe;
+
+/// Vendor GUID of shim's variables.
+const SHIM_LOCK: Guid = guid!("605dab50-e046-4300-abb6-3dd810dd8b23");
+
+/// Vendor and URL of the loader's line. Builds signed by a distribution set
+/// `SBAT_VENDOR` and `SBAT_URL` in the environment to name themselves.
+const VENDOR: &str = match option_env!("SBAT_VENDOR") {
+    Some(vendor) => vendor,
+    None => "Rust UEFI Bootloader",
+};
+const URL: &str = match option_env!("SBAT_URL") {
+    Some(url) => url,
+    None => env!("CARGO_PKG_REPOSITORY"),
+};
+
+/// CSV lines `component,generation,vendor,package,version,url`. Raise the
+/// generation here when a Secure Boot bypass in the loader is fixed.
+const METADATA: &[&str] = &[
+    "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n",
+    env!("CARGO_PKG_NAME"),
+    ",1,",
+    VENDOR,
+    ",",
+    env!("CARGO_PKG_NAME"),
+    ",",
+    env!("CARGO_PKG_VERSION"),
+    ",",
+    URL,
+    "\n",
+];
+
+const fn length(parts: &[&str]) -> usize {
+    let mut total = 0;
+    let mut i = 0;
+    while i < parts.len() {
+        total += parts[i].len();
+        i += 1;
+    }
+    total
+}
+
+const fn bytes<const N: usize>(parts: &[&str]) -> [u8; N] {
+    let mut out = [0; N];
+    let (mut at, mut i) = (0, 0);
+    while i < parts.len() {
+        let part = parts[i].as_bytes();
+        let mut j = 0;
+        while j < part.len() {
+            out[at] = part[j];
+            at += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    out
+}
+
+// shim refuses to start images without it
+#[used]
+#[unsafe(link_section = ".sbat")]
+static SBAT: [u8; length(METADATA)] = bytes(METADATA);
+
+/// `(component, generation)` of an SBAT CSV line; `None` without a valid
+/// generation.
+fn component(line: &str) -> Option<(&str, u32)> {
+    let mut fields = line.split(',');
+    let component = fields.next()?.trim();
+    let generation = fields.next()?.trim().parse().ok()?;
+    Some((component, generation))
+}
+
+/// Text of a nul padded section or variable, empty if it isn't UTF-8.
+fn text(data: &[u8]) -> &str {
+    core::str::from_utf8(&data[..data.iter().position(|&b| b == 0).unwrap_or(data.len())]).unwrap_or("")
+}
+
+/// The lowest allowed generation of each component, from shim's SbatLevel
+/// (or its runtime copy). Empty without shim's revocations.
+fn revocations() -> Vec<(String, u32)> {
+    let vendor = VariableVendor(SHIM_LOCK);
+    let Ok((data, _)) = runtime::get_variable_boxed(cstr16!("SbatLevel"), &vendor)
+        .or_else(|_| runtime::get_variable_boxed(cstr16!("SbatLevelRT"), &vendor))
+    else {
+        return Vec::new();
+    };
+    // The first line is `sbat,<version>,<date>`, which checks the images'
+    // `sbat` line like any component
+    text(&data)
+        .lines()
+        .filter_map(|line| component(line).map(|(c, g)| (String::from(c), g)))
+        .collect()
+}
+
+/// Refuses `image` if SbatLevel revokes a generation in its `.sbat`
+/// section. Images without one pass.
+pub fn check(name: &str, image: &[u8]) -> Result {
+    let Some(section) = pe::sections(image).and_then(|s| s.into_iter().find(|s| s.name == b".sbat")) else {
+        return Ok(());
+    };
+    let entries: Option<Vec<_>> = text(section.data)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(component)
+        .collect();
+    let Some(entries) = entries.filter(|entries| !entries.is_empty()) else {
+        println!("{}: refused by SBAT, its .sbat section is malformed", name);
+        return Err(uefi::Error::new(Status::SECURITY_VIOLATION, ()));
+    };
+    let revoked = revocations();
+    for (component, generation) in entries {
+        if let Some((_, minimum)) = revoked.iter().find(|(c, minimum)| c == component && *minimum > generation) {
+            println!(
+                "{}: refused by SBAT, {} generation {} is revoked (SbatLevel requires {})",
+                name, component, generation, minimum
+            );
+            return Err(uefi::Error::new(Status::SECURITY_VIOLATION, ()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_section_parses() {
+        let lines: Vec<_> = text(&SBAT).lines().map(component).collect();
+        assert_eq!(lines, [Some(("sbat", 1)), Some((env!("CARGO_PKG_NAME"), 1))]);
+        assert_eq!(text(&SBAT).lines().nth(1).unwrap().split(',').count(), 6);
+    }
+
+    #[test]
+    fn parses_components() {
+        assert_eq!(component("grub, 3 ,Free Software Foundation"), Some(("grub", 3)));
+        assert_eq!(component("sbat,1,2021030218"), Some(("sbat", 1)));
+        assert_eq!(component("grub"), None);
+        assert_eq!(component("grub,three,x"), None);
+        assert_eq!(component("grub,-1"), None);
+    }
+
+    #[test]
+    fn cuts_text_at_nul() {
+        assert_eq!(text(b"sbat,1\n\0\0\0"), "sbat,1\n");
+        assert_eq!(text(b"\xff\xfe"), "");
+        assert_eq!(text(b""), "");
+    }
+}
@@ -0,0 +1,131 @@
+// security.rs
+// Lets LoadImage accept an image the loader has verified, by wrapping the
+// firmware's Security Arch protocols for the duration of the call
+
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use core::ptr;
+use uefi::proto::unsafe_protocol;
+use uefi::{Status, boot};
+use uefi_raw::Boolean;
+use uefi_raw::protocol::device_path::DevicePathProtocol;
+
+use crate::volume::open_shared;
+
+type FileAuthenticationState =
+    unsafe extern "efiapi" fn(this: *const SecurityArch, status: u32, file: *const DevicePathProtocol) -> Status;
+
+type FileAuthentication = unsafe extern "efiapi" fn(
+    this: *const Security2Arch,
+    file: *const DevicePathProtocol,
+    buffer: *const c_void,
+    size: usize,
+    boot_policy: Boolean,
+) -> Status;
+
+#[repr(C)]
+#[unsafe_protocol("a46423e3-4617-49f1-b9ff-d1bfa9115839")]
+struct SecurityArch {
+    file_authentication_state: FileAuthenticationState,
+}
+
+#[repr(C)]
+#[unsafe_protocol("94ab2f58-1438-4ef1-9152-18941a3a0e68")]
+struct Security2Arch {
+    file_authentication: FileAuthentication,
+}
+
+/// The approved buffer and the firmware's handlers while they're wrapped.
+struct Approved {
+    buffer: *const u8,
+    size: usize,
+    original: Option<FileAuthenticationState>,
+    original2: Option<FileAuthentication>,
+}
+
+struct State(UnsafeCell<Approved>);
+
+// Boot services are single threaded.
+unsafe impl Sync for State {}
+
+static STATE: State = State(UnsafeCell::new(Approved {
+    buffer: ptr::null(),
+    size: 0,
+    original: None,
+    original2: None,
+}));
+
+unsafe extern "efiapi" fn file_authentication_state(
+    this: *const SecurityArch,
+    status: u32,
+    file: *const DevicePathProtocol,
+) -> Status {
+    let state = unsafe { &*STATE.0.get() };
+    // Only called with a device path; the image is the approved one as
+    // long as the override is in place
+    match state.original {
+        Some(_) if !state.buffer.is_null() => Status::SUCCESS,
+        Some(original) => unsafe { original(this, status, file) },
+        None => Status::SUCCESS,
+    }
+}
+
+unsafe extern "efiapi" fn file_authentication(
+    this: *const Security2Arch,
+    file: *const DevicePathProtocol,
+    buffer: *const c_void,
+    size: usize,
+    boot_policy: Boolean,
+) -> Status {
+    let state = unsafe { &*STATE.0.get() };
+    if buffer.cast::<u8>() == state.buffer && size == state.size {
+        return Status::SUCCESS;
+    }
+    match state.original2 {
+        Some(original) => unsafe { original(this, file, buffer, size, boot_policy) },
+        None => Status::SUCCESS,
+    }
+}
+
+/// Runs `load` with the firmware accepting `image` as if db had allowed
+/// it. Other images are still checked by the firmware.
+pub fn allow<T>(image: &[u8], load: impl FnOnce() -> T) -> T {
+    let mut security = boot::get_handle_for_protocol::<SecurityArch>()
+        .and_then(open_shared::<SecurityArch>)
+        .ok();
+    let mut security2 = boot::get_handle_for_protocol::<Security2Arch>()
+        .and_then(open_shared::<Security2Arch>)
+        .ok();
+    unsafe {
+        let state = &mut *STATE.0.get();
+        state.buffer = image.as_ptr();
+        state.size = image.len();
+        if let Some(protocol) = security.as_mut().and_then(|p| p.get_mut()) {
+            state.original = Some(protocol.file_authentication_state);
+            protocol.file_authentication_state = file_authentication_state;
+        }
+        if let Some(protocol) = security2.as_mut().and_then(|p| p.get_mut()) {
+            state.original2 = Some(protocol.file_authentication);
+            protocol.file_authentication = file_authentication;
+        }
+    }
+
+    let result = load();
+
+    unsafe {
+        let state = &mut *STATE.0.get();
+        if let (Some(protocol), Some(original)) = (security.as_mut().and_then(|p| p.get_mut()), state.original) {
+            protocol.file_authentication_state = original;
+        }
+        if let (Some(protocol), Some(original)) = (security2.as_mut().and_then(|p| p.get_mut()), state.original2) {
+            protocol.file_authentication = original;
+        }
+        *state = Approved {
+            buffer: ptr::null(),
+            size: 0,
+            original: None,
+            original2: None,
+        };
+    }
+    result
+}
@@ -0,0 +1,34 @@
+// shim.rs
+// shim's SHIM_LOCK protocol, which checks images against db, dbx and the MOK lists
+
+use core::ffi::c_void;
+use uefi::proto::unsafe_protocol;
+use uefi::{Status, boot};
+
+use crate::volume::open_shared;
+
+// shim declares its protocol functions without EFIAPI, so on x86_64 they
+// use the System V calling convention
+#[cfg(target_arch = "x86_64")]
+type Verify = unsafe extern "sysv64" fn(buffer: *const c_void, size: u32) -> Status;
+#[cfg(not(target_arch = "x86_64"))]
+type Verify = unsafe extern "efiapi" fn(buffer: *const c_void, size: u32) -> Status;
+
+#[repr(C)]
+#[unsafe_protocol("605dab50-e046-4300-abb6-3dd810dd8b23")]
+struct ShimLock {
+    verify: Verify,
+    hash: *const c_void,
+    context: *const c_void,
+}
+
+/// Asks shim whether `image` may run. `None` if the loader wasn't started
+/// through shim.
+pub fn verify(image: &[u8]) -> Option<Status> {
+    let handle = boot::get_handle_for_protocol::<ShimLock>().ok()?;
+    let shim = open_shared::<ShimLock>(handle).ok()?;
+    let Ok(size) = u32::try_from(image.len()) else {
+        return Some(Status::BAD_BUFFER_SIZE);
+    };
+    Some(unsafe { (shim.verify)(image.as_ptr().cast(), size) })
+}
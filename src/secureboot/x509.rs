@@ -0,0 +1,148 @@
+// x509.rs
+// The parts of X.509 certificates Secure Boot checks need
+
+use alloc::format;
+use alloc::string::String;
+
+use super::Hash;
+use super::der::{self, Reader};
+use super::rsa::PublicKey;
+
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+pub struct Certificate<'a> {
+    /// The whole certificate, as db and dbx store them.
+    pub raw: &'a [u8],
+    /// Encoded `TBSCertificate`, what the issuer signed.
+    pub tbs: &'a [u8],
+    pub serial: &'a [u8],
+    /// Encoded issuer and subject `Name`s, compared byte by byte.
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    /// `None` for keys other than RSA.
+    pub public_key: Option<PublicKey>,
+    signature_algorithm: &'a [u8],
+    signature: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(raw: &'a [u8]) -> Option<Self> {
+        let certificate = Reader::single(raw).filter(|c| c.tag == der::SEQUENCE)?;
+        let mut fields = certificate.reader();
+        let tbs = fields.expect(der::SEQUENCE)?;
+        let algorithm = fields.expect(der::SEQUENCE)?.reader().expect(der::OID)?;
+        let signature = fields.expect(der::BIT_STRING)?;
+
+        let mut tbs_fields = tbs.reader();
+        tbs_fields.optional(der::context(0));
+        let serial = tbs_fields.expect(der::INTEGER)?;
+        tbs_fields.expect(der::SEQUENCE)?;
+        let issuer = tbs_fields.expect(der::SEQUENCE)?;
+        tbs_fields.expect(der::SEQUENCE)?;
+        let subject = tbs_fields.expect(der::SEQUENCE)?;
+        let key_info = tbs_fields.expect(der::SEQUENCE)?;
+
+        let mut key_fields = key_info.reader();
+        let key_algorithm = key_fields.expect(der::SEQUENCE)?.reader().expect(der::OID)?;
+        let key = key_fields.expect(der::BIT_STRING)?;
+        let public_key = match key.contents {
+            [0, key @ ..] if key_algorithm.contents == RSA_ENCRYPTION => PublicKey::from_der(key),
+            _ => None,
+        };
+
+        Some(Certificate {
+            raw,
+            tbs: tbs.raw,
+            serial: der::unsigned(&serial),
+            issuer: issuer.raw,
+            subject: subject.raw,
+            public_key,
+            signature_algorithm: algorithm.contents,
+            signature: signature.contents.get(1..)?,
+        })
+    }
+
+    /// True if `issuer`'s key made the signature of this certificate.
+    pub fn signed_by(&self, issuer: &Certificate) -> bool {
+        let (Some(key), Some(hash)) = (&issuer.public_key, Hash::from_signature_oid(self.signature_algorithm)) else {
+            return false;
+        };
+        self.issuer == issuer.subject && key.verify(self.signature, hash, &hash.digest(&[self.tbs]))
+    }
+
+    /// The subject's common name, or the serial number if it has none.
+    pub fn name(&self) -> String {
+        common_name(self.subject).unwrap_or_else(|| {
+            let serial: String = self.serial.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("serial {}", serial)
+        })
+    }
+}
+
+/// The first CN of an encoded `Name`.
+fn common_name(name: &[u8]) -> Option<String> {
+    let name = Reader::single(name)?;
+    for rdn in name.reader() {
+        for attribute in rdn.reader() {
+            let mut fields = attribute.reader();
+            if fields.expect(der::OID)?.contents == COMMON_NAME {
+                return Some(String::from_utf8_lossy(fields.next()?.contents).into_owned());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Self-signed "CN=Test CA" with the 1024-bit key of the RSA tests, made
+    /// with openssl.
+    const CA: &str = "308201d030820139a003020102021478d44b2b76d2df4af5cc105324993a8590e195b7300d06092a864886f70d01010b050030123110300e06035504030c07546573742043413020170d3236313031393035343631355a180f32313236303932353035343631355a30123110300e06035504030c075465737420434130819f300d06092a864886f70d010101050003818d0030818902818100e432e8553849e9f00fbab5f9bf5ff36f7def6beaf892034d7d5105adfbc81d35592c7c3441161b628983464aaf088b7c52ce1be72079b4d63081fe3617526e1ea42a8cb108b065f853bc5c79347fc67397ec50eb265436a238af4c2624190b2349884f433c16cecbb62de9b8398bb2f68545564fc25aaeaea67ecfe6c600ed050203010001a321301f301d0603551d0e04160414592379b8d39910ff0bb1a89a8d00323faab295f7300d06092a864886f70d01010b050003818100490ef799dd1fee4001a752da2204c1d01cc0cc9de32f93b4eb364aeda407b0920b29690161b2ed36b9ba50b75a3f3a93a649cec508a61b27b65c9ad6541677a05442153ebac97720af4e1ecc8d8513c14e34b0c1eed51571f4bd09a21f2682e50dd07ae41c28d542f7330b279a1bfa68550d63926e2f917aac0e16b6c6aeda42";
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_self_signed() {
+        let data = hex(CA);
+        let ca = Certificate::parse(&data).unwrap();
+        assert_eq!(ca.raw, data);
+        assert_eq!(ca.issuer, ca.subject);
+        assert_eq!(ca.serial, hex("78d44b2b76d2df4af5cc105324993a8590e195b7"));
+        assert_eq!(ca.name(), "Test CA");
+        assert!(ca.public_key.is_some());
+        assert!(ca.signed_by(&ca));
+    }
+
+    #[test]
+    fn rejects_tampered_certificate() {
+        let mut data = hex(CA);
+        // A byte of the subject key identifier, inside the signed part
+        data[0x131] ^= 1;
+        let ca = Certificate::parse(&data).unwrap();
+        assert!(!ca.signed_by(&ca));
+    }
+
+    #[test]
+    fn rejects_malformed_certificates() {
+        let data = hex(CA);
+        assert!(Certificate::parse(&data[..data.len() - 1]).is_none());
+        assert!(Certificate::parse(&data[4..]).is_none());
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(Certificate::parse(&trailing).is_none());
+    }
+
+    #[test]
+    fn name_without_common_name() {
+        // SEQUENCE { SET { SEQUENCE { OID 2.5.4.10 (O), UTF8String "x" } } }
+        let name = [0x30, 0x0c, 0x31, 0x0a, 0x30, 0x08, 0x06, 0x03, 0x55, 0x04, 0x0a, 0x0c, 0x01, b'x'];
+        assert_eq!(common_name(&name), None);
+        assert_eq!(common_name(&name[..5]), None);
+    }
+}
@@ -0,0 +1,96 @@
+// smbios.rs
+// Read access to the firmware's SMBIOS structures
+
+use uefi::system;
+use uefi::table::cfg::{SMBIOS_GUID, SMBIOS3_GUID};
+
+/// One SMBIOS structure: its formatted area and the strings after it.
+pub struct Structure {
+    formatted: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Structure {
+    pub fn kind(&self) -> u8 {
+        self.formatted[0]
+    }
+
+    /// Byte at `offset` of the formatted area, `None` on older SMBIOS
+    /// versions whose structure ends before it.
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    /// String referenced by the index at `offset`, trimmed of surrounding
+    /// whitespace. `None` if unset.
+    pub fn string(&self, offset: usize) -> Option<&'static str> {
+        let index = self.byte(offset)? as usize;
+        if index == 0 {
+            return None;
+        }
+        let string = self.strings.split(|&b| b == 0).nth(index - 1)?;
+        let string = core::str::from_utf8(string).ok()?.trim();
+        (!string.is_empty()).then_some(string)
+    }
+}
+
+/// Address and size of the structure table, preferring the 64-bit SMBIOS 3
+/// entry point.
+fn structure_table() -> Option<&'static [u8]> {
+    let (v3, v2) = system::with_config_table(|tables| {
+        let find = |guid| {
+            tables
+                .iter()
+                .find(|table| table.guid == guid)
+                .map(|table| table.address as *const u8)
+        };
+        (find(SMBIOS3_GUID), find(SMBIOS_GUID))
+    });
+    if let Some(entry) = v3 {
+        let entry = unsafe { core::slice::from_raw_parts(entry, 0x18) };
+        if entry.starts_with(b"_SM3_") {
+            let size = u32::from_le_bytes(entry[0x0c..0x10].try_into().unwrap()) as usize;
+            let address = u64::from_le_bytes(entry[0x10..0x18].try_into().unwrap());
+            return Some(unsafe { core::slice::from_raw_parts(address as *const u8, size) });
+        }
+    }
+    let entry = unsafe { core::slice::from_raw_parts(v2?, 0x1f) };
+    if !entry.starts_with(b"_SM_") {
+        return None;
+    }
+    let size = u16::from_le_bytes(entry[0x16..0x18].try_into().unwrap()) as usize;
+    let address = u32::from_le_bytes(entry[0x18..0x1c].try_into().unwrap());
+    Some(unsafe { core::slice::from_raw_parts(address as usize as *const u8, size) })
+}
+
+/// The first structure of type `kind`.
+pub fn find(kind: u8) -> Option<Structure> {
+    let mut table = structure_table()?;
+    while table.len() >= 4 {
+        let length = table[1] as usize;
+        if length < 4 || length > table.len() {
+            return None;
+        }
+        // Strings end with a double nul; a structure without strings has
+        // just the two nuls.
+        let end = length + table[length..].windows(2).position(|pair| pair == [0, 0])? + 2;
+        let structure = Structure {
+            formatted: &table[..length],
+            strings: &table[length..end],
+        };
+        if structure.kind() == kind {
+            return Some(structure);
+        }
+        // Type 127 marks the end of the table
+        if structure.kind() == 127 {
+            return None;
+        }
+        table = &table[end..];
+    }
+    None
+}
+
+/// True if the BIOS information (type 0) says this is a virtual machine.
+pub fn virtual_machine() -> bool {
+    find(0).and_then(|bios| bios.byte(0x13)).is_some_and(|extension| extension & (1 << 4) != 0)
+}
@@ -0,0 +1,473 @@
+// volume.rs
+// Shared access to files on UEFI volumes (ESP, XBOOTLDR, other disks)
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::str;
+use uefi::boot::{self, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::proto::ProtocolPointer;
+use uefi::proto::device_path::DevicePath;
+use uefi::proto::device_path::build::{self, DevicePathBuilder};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::{
+    Directory, File, FileAttribute, FileHandle, FileInfo, FileMode, FileSystemVolumeLabel,
+    RegularFile,
+};
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::{CStr16, CString16, Handle, Result, Status, println};
+
+use crate::ext4::{Ext4, UefiDisk};
+
+/// Opens a protocol without taking ownership of it, so drivers bound to the
+/// handle keep working.
+pub fn open_shared<P: ProtocolPointer + ?Sized>(handle: Handle) -> Result<ScopedProtocol<P>> {
+    unsafe {
+        boot::open_protocol::<P>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+}
+
+/// Device handle the loader itself was started from (normally the ESP).
+pub fn loader_device() -> Result<Handle> {
+    let loaded_image = open_shared::<LoadedImage>(boot::image_handle())?;
+    loaded_image
+        .device()
+        .ok_or_else(|| uefi::Error::new(Status::NOT_FOUND, ()))
+}
+
+/// Splits `path` into its components. Both `/` and `\` separate them,
+/// empty components are dropped and `.`/`..` are rejected.
+fn path_components(path: &str) -> Result<Vec<&str>> {
+    let mut components = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" => continue,
+            "." | ".." => return Err(uefi::Error::new(Status::INVALID_PARAMETER, ())),
+            part => components.push(part),
+        }
+    }
+    Ok(components)
+}
+
+/// Handles of every volume [`Volume::open`] may be able to read: all
+/// firmware file systems first, then partitions without one, which might
+/// hold ext4.
+pub fn volume_handles() -> Result<Vec<Handle>> {
+    let mut handles = boot::find_handles::<SimpleFileSystem>()?;
+    for handle in boot::find_handles::<BlockIO>()? {
+        if handles.contains(&handle) {
+            continue;
+        }
+        let Ok(block) = open_shared::<BlockIO>(handle) else {
+            continue;
+        };
+        if block.media().is_logical_partition() && block.media().is_media_present() {
+            handles.push(handle);
+        }
+    }
+    Ok(handles)
+}
+
+/// Splits `path` into an absolute UEFI path (`\a\b`), following the
+/// rules of [`path_components`]. Anything that does not fit UCS-2 is
+/// rejected too.
+pub fn parse_path(path: &str) -> Result<CString16> {
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for part in path_components(path)? {
+        normalized.push('\\');
+        normalized.push_str(part);
+    }
+    if normalized.is_empty() {
+        normalized.push('\\');
+    }
+    CString16::try_from(normalized.as_str())
+        .map_err(|_| uefi::Error::new(Status::INVALID_PARAMETER, ()))
+}
+
+/// Joins a directory and a file name into a path accepted by [`parse_path`].
+pub fn join(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir.trim_end_matches(['/', '\\']));
+    path.push('\\');
+    path.push_str(name);
+    path
+}
+
+/// Builds the full device path of `path` on `device`, so the started image
+/// knows which volume it (and relative paths like `initrd=`) came from.
+pub fn file_device_path(device: Handle, path: &str) -> Option<Vec<u8>> {
+    let device_path = open_shared::<DevicePath>(device).ok()?;
+    let file_path = parse_path(path).ok()?;
+
+    let mut storage = Vec::new();
+    let mut builder = DevicePathBuilder::with_vec(&mut storage);
+    for node in device_path.node_iter() {
+        builder = builder.push(&node).ok()?;
+    }
+    builder
+        .push(&build::media::FilePath {
+            path_name: &file_path,
+        })
+        .ok()?
+        .finalize()
+        .ok()?;
+    Some(storage)
+}
+
+/// One entry returned by [`Volume::read_dir`].
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// Pairs of (primary, mirror) volume handles, see [`add_mirror`].
+struct Mirrors(UnsafeCell<Vec<(Handle, Handle)>>);
+
+// Boot services are single threaded.
+unsafe impl Sync for Mirrors {}
+
+static MIRRORS: Mirrors = Mirrors(UnsafeCell::new(Vec::new()));
+
+/// Records `mirror` as a copy of `primary` (e.g. the other half of a
+/// mirrored ESP). Reads from `primary` that fail are retried on it.
+pub fn add_mirror(primary: Handle, mirror: Handle) {
+    let mirrors = unsafe { &mut *MIRRORS.0.get() };
+    if !mirrors.contains(&(primary, mirror)) {
+        mirrors.push((primary, mirror));
+    }
+}
+
+fn mirrors_of(primary: Handle) -> Vec<Handle> {
+    let mirrors = unsafe { &*MIRRORS.0.get() };
+    mirrors
+        .iter()
+        .filter(|(p, _)| *p == primary)
+        .map(|(_, mirror)| *mirror)
+        .collect()
+}
+
+/// How a [`Volume`] reaches its files.
+enum Backend {
+    /// A file system the firmware (or a driver it loaded) understands.
+    Firmware(Directory),
+    /// An ext4 partition read by our own driver. `base` is the directory
+    /// paths are resolved against: the root, or `boot` when the volume is a
+    /// root file system with `/boot` as a plain directory.
+    Ext4 { fs: Box<Ext4<UefiDisk>>, base: Vec<String> },
+}
+
+/// A mounted volume: any `SimpleFileSystem` the firmware exposes, or an
+/// ext4 partition it can't read by itself. The ext4 side is read-only.
+pub struct Volume {
+    handle: Handle,
+    backend: Backend,
+}
+
+impl Volume {
+    /// Opens the file system on `handle`. Partitions without a firmware
+    /// file system are probed for ext4; anything else is `UNSUPPORTED`.
+    pub fn open(handle: Handle) -> Result<Self> {
+        let backend = match open_shared::<SimpleFileSystem>(handle) {
+            Ok(mut sfs) => Backend::Firmware(sfs.open_volume()?),
+            Err(e) if e.status() == Status::UNSUPPORTED => {
+                let fs = Ext4::probe(handle)?
+                    .ok_or_else(|| uefi::Error::new(Status::UNSUPPORTED, ()))?;
+                let base = ext4_base(&fs)?;
+                Backend::Ext4 {
+                    fs: Box::new(fs),
+                    base,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Volume { handle, backend })
+    }
+
+    /// Opens the volume the loader was started from.
+    pub fn loader() -> Result<Self> {
+        Self::open(loader_device()?)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    /// False for volumes only this loader can read, whose files therefore
+    /// can't be handed to other images by path (e.g. `initrd=`).
+    pub fn firmware_readable(&self) -> bool {
+        matches!(self.backend, Backend::Firmware(_))
+    }
+
+    fn root(&mut self) -> Result<&mut Directory> {
+        match &mut self.backend {
+            Backend::Firmware(root) => Ok(root),
+            Backend::Ext4 { .. } => Err(uefi::Error::new(Status::UNSUPPORTED, ())),
+        }
+    }
+
+    fn open_handle(&mut self, path: &str, mode: FileMode) -> Result<FileHandle> {
+        let path = parse_path(path)?;
+        let attributes = match mode {
+            FileMode::CreateReadWrite => FileAttribute::ARCHIVE,
+            _ => FileAttribute::empty(),
+        };
+        self.root()?.open(&path, mode, attributes)
+    }
+
+    /// Opens a regular file; directories yield `INVALID_PARAMETER`.
+    /// Only available on firmware file systems.
+    pub fn open_file(&mut self, path: &str, mode: FileMode) -> Result<RegularFile> {
+        self.open_handle(path, mode)?
+            .into_regular_file()
+            .ok_or_else(|| uefi::Error::new(Status::INVALID_PARAMETER, ()))
+    }
+
+    /// Opens a directory; regular files yield `INVALID_PARAMETER`.
+    /// Only available on firmware file systems.
+    pub fn open_dir(&mut self, path: &str) -> Result<Directory> {
+        self.open_handle(path, FileMode::Read)?
+            .into_directory()
+            .ok_or_else(|| uefi::Error::new(Status::INVALID_PARAMETER, ()))
+    }
+
+    /// True if `path` exists and is a regular file.
+    pub fn exists(&mut self, path: &str) -> Result<bool> {
+        if let Backend::Ext4 { fs, base } = &self.backend {
+            return fs.exists(&ext4_path(base, path)?);
+        }
+        match self.open_handle(path, FileMode::Read) {
+            Ok(handle) => Ok(handle.into_regular_file().is_some()),
+            Err(e) if e.status() == Status::NOT_FOUND => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads a whole file, whatever its size. If that fails, the file is
+    /// read from the volume's mirrors instead.
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>> {
+        let error = match self.read_local(path) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+        for mirror in mirrors_of(self.handle) {
+            println!("Failed to read {} ({:?}), trying mirror", path, error.status());
+            if let Ok(data) = Volume::open(mirror).and_then(|mut volume| volume.read_local(path)) {
+                return Ok(data);
+            }
+        }
+        Err(error)
+    }
+
+    fn read_local(&mut self, path: &str) -> Result<Vec<u8>> {
+        if let Backend::Ext4 { fs, base } = &self.backend {
+            return fs.read(&ext4_path(base, path)?);
+        }
+        let mut file = self.open_file(path, FileMode::Read)?;
+        read_all(&mut file)
+    }
+
+    /// Reads a whole file as UTF-8.
+    pub fn read_to_string(&mut self, path: &str) -> Result<String> {
+        let data = self.read(path)?;
+        String::from_utf8(data).map_err(|_| uefi::Error::new(Status::VOLUME_CORRUPTED, ()))
+    }
+
+    /// Creates or replaces `path` with `data`. Fails with `UNSUPPORTED` on
+    /// read-only backends, as do [`Volume::rename`] and [`Volume::delete`].
+    pub fn write(&mut self, path: &str, data: &[u8]) -> Result {
+        let mut file = self.open_file(path, FileMode::CreateReadWrite)?;
+        file.write(data).map_err(|e| e.to_err_without_payload())?;
+        set_file_size(&mut file, data.len() as u64)?;
+        file.flush()
+    }
+
+    /// Renames `from` to `to`, both paths on this volume. `to` is passed to
+    /// the firmware as an absolute path; most firmware file systems only
+    /// rename within the same directory.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result {
+        let new_name = parse_path(to)?;
+        let mut file = self.open_handle(from, FileMode::ReadWrite)?;
+        let info = file.get_boxed_info::<FileInfo>()?;
+        let mut storage = info_storage(&new_name);
+        let new_info = FileInfo::new(
+            storage.as_mut_bytes(),
+            info.file_size(),
+            info.physical_size(),
+            *info.create_time(),
+            *info.last_access_time(),
+            *info.modification_time(),
+            info.attribute(),
+            &new_name,
+        )
+        .map_err(|_| uefi::Error::new(Status::BUFFER_TOO_SMALL, ()))?;
+        file.set_info(new_info)?;
+        file.flush()
+    }
+
+    /// Deletes a file or an empty directory. The root can't be deleted.
+    pub fn delete(&mut self, path: &str) -> Result {
+        if path_components(path)?.is_empty() {
+            return Err(uefi::Error::new(Status::ACCESS_DENIED, ()));
+        }
+        self.open_handle(path, FileMode::ReadWrite)?.delete()
+    }
+
+    /// Lists `path`, without `.` and `..`. A missing directory is `None`.
+    pub fn read_dir(&mut self, path: &str) -> Result<Option<Vec<DirEntry>>> {
+        if let Backend::Ext4 { fs, base } = &self.backend {
+            return fs.read_dir(&ext4_path(base, path)?);
+        }
+        let mut dir = match self.open_dir(path) {
+            Ok(dir) => dir,
+            Err(e) if e.status() == Status::NOT_FOUND || e.status() == Status::INVALID_PARAMETER => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        while let Some(info) = dir.read_entry_boxed()? {
+            let name = info.file_name().to_string();
+            if name == "." || name == ".." {
+                continue;
+            }
+            entries.push(DirEntry {
+                name,
+                is_dir: info.is_directory(),
+                size: info.file_size(),
+            });
+        }
+        Ok(Some(entries))
+    }
+
+    /// File system label, empty if the volume has none.
+    pub fn label(&mut self) -> String {
+        let root = match &mut self.backend {
+            Backend::Firmware(root) => root,
+            Backend::Ext4 { fs, .. } => return fs.label().to_string(),
+        };
+        root.get_boxed_info::<FileSystemVolumeLabel>()
+            .map(|info| info.volume_label().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Picks the directory an ext4 volume is read from: a separate `/boot`
+/// partition has `loader` at its root, a root file system has it below
+/// `/boot`.
+fn ext4_base(fs: &Ext4<UefiDisk>) -> Result<Vec<String>> {
+    if fs.read_dir(&["loader"])?.is_none() && fs.read_dir(&["boot", "loader"])?.is_some() {
+        return Ok(vec!["boot".to_string()]);
+    }
+    Ok(Vec::new())
+}
+
+/// Components of `path` below `base` on an ext4 volume.
+fn ext4_path<'a>(base: &'a [String], path: &'a str) -> Result<Vec<&'a str>> {
+    let mut components: Vec<&str> = base.iter().map(String::as_str).collect();
+    components.extend(path_components(path)?);
+    Ok(components)
+}
+
+/// Reads `file` from its current position to the end.
+pub fn read_all(file: &mut RegularFile) -> Result<Vec<u8>> {
+    let info: Box<FileInfo> = file.get_boxed_info()?;
+    let mut buf = vec![0u8; info.file_size() as usize];
+    let mut total = 0;
+    while total < buf.len() {
+        let read = file
+            .read(&mut buf[total..])
+            .map_err(|e| e.to_err_without_payload())?;
+        if read == 0 {
+            break;
+        }
+        total += read;
+    }
+    buf.truncate(total);
+    Ok(buf)
+}
+
+/// Truncates or extends `file` to `size` bytes.
+pub fn set_file_size(file: &mut RegularFile, size: u64) -> Result {
+    let info: Box<FileInfo> = file.get_boxed_info()?;
+    if info.file_size() == size {
+        return Ok(());
+    }
+    let mut storage = info_storage(info.file_name());
+    let new_info = FileInfo::new(
+        storage.as_mut_bytes(),
+        size,
+        info.physical_size(),
+        *info.create_time(),
+        *info.last_access_time(),
+        *info.modification_time(),
+        info.attribute(),
+        info.file_name(),
+    )
+    .map_err(|_| uefi::Error::new(Status::BUFFER_TOO_SMALL, ()))?;
+    file.set_info(new_info)
+}
+
+/// 8-byte aligned scratch space big enough for a `FileInfo` naming `name`.
+struct InfoStorage(Vec<u64>);
+
+impl InfoStorage {
+    fn as_mut_bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.0.as_mut_ptr() as *mut u8, self.0.len() * 8) }
+    }
+}
+
+fn info_storage(name: &CStr16) -> InfoStorage {
+    let bytes = 128 + (name.num_chars() + 1) * 2;
+    InfoStorage(vec![0u64; bytes.div_ceil(8)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(path: &str) -> Option<String> {
+        parse_path(path).ok().map(|path| path.to_string())
+    }
+
+    #[test]
+    fn splits_components() {
+        assert_eq!(path_components("/loader//entries\\arch.conf").unwrap(), ["loader", "entries", "arch.conf"]);
+        assert!(path_components("").unwrap().is_empty());
+        assert!(path_components("\\/").unwrap().is_empty());
+        assert!(path_components("loader/../EFI").is_err());
+        assert!(path_components("./loader").is_err());
+        // Only whole components are special
+        assert_eq!(path_components("a..b/.hidden").unwrap(), ["a..b", ".hidden"]);
+    }
+
+    #[test]
+    fn parses_absolute_paths() {
+        assert_eq!(parsed("loader/loader.conf").as_deref(), Some("\\loader\\loader.conf"));
+        assert_eq!(parsed("\\EFI\\Linux\\").as_deref(), Some("\\EFI\\Linux"));
+        assert_eq!(parsed("").as_deref(), Some("\\"));
+        assert_eq!(parsed("/").as_deref(), Some("\\"));
+        assert_eq!(parsed("EFI/ü.efi").as_deref(), Some("\\EFI\\ü.efi"));
+        // Rename targets and deleted paths can't escape or leave UCS-2
+        assert_eq!(parsed("loader/../../x"), None);
+        assert_eq!(parsed("loader/entries/🐧.conf"), None);
+        assert_eq!(parsed("a\0b"), None);
+    }
+
+    #[test]
+    fn joins_paths() {
+        assert_eq!(join("loader\\entries\\", "arch.conf"), "loader\\entries\\arch.conf");
+        assert_eq!(join("loader/", "x"), "loader\\x");
+        assert_eq!(parsed(&join("", "x")).as_deref(), Some("\\x"));
+    }
+}
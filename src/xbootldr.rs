@@ -0,0 +1,211 @@
+// xbootldr.rs
+// Locates the Extended Boot Loader partition that lives on the same disk as the ESP
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::luks2;
+use crate::volume::{Volume, open_shared};
+use uefi::boot;
+use uefi::proto::device_path::{DevicePath, DevicePathNodeEnum};
+use uefi::proto::device_path::media::PartitionSignature;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::partition::PartitionInfo;
+use uefi::{Guid, Handle, Result, Status, guid, println};
+
+/// GPT partition type of the Extended Boot Loader partition.
+pub const XBOOTLDR_GUID: Guid = guid!("bc13c2ff-59e6-4262-a352-b275fd6f7172");
+
+const GPT_HEADER_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Splits a partition device path into the disk part and its HardDrive node.
+/// Returns the length in bytes of the disk part and the partition's GPT
+/// unique GUID, if the last media node is a GPT HardDrive node.
+fn split_partition_path(path: &DevicePath) -> Option<(usize, Option<Guid>)> {
+    let mut offset = 0;
+    let mut result = None;
+    for node in path.node_iter() {
+        if let Ok(DevicePathNodeEnum::MediaHardDrive(hd)) = node.as_enum() {
+            let signature = match hd.partition_signature() {
+                PartitionSignature::Guid(guid) => Some(guid),
+                _ => None,
+            };
+            result = Some((offset, signature));
+        }
+        offset += usize::from(node.length());
+    }
+    result
+}
+
+/// Partition number of the HardDrive node in the device path of `device`.
+pub fn partition_number(device: Handle) -> Option<u32> {
+    let path = open_shared::<DevicePath>(device).ok()?;
+    path.node_iter()
+        .filter_map(|node| match node.as_enum() {
+            Ok(DevicePathNodeEnum::MediaHardDrive(hd)) => Some(hd.partition_number()),
+            _ => None,
+        })
+        .last()
+}
+
+/// GPT unique partition GUID from the device path of `device`.
+pub fn partition_guid(device: Handle) -> Option<Guid> {
+    let path = open_shared::<DevicePath>(device).ok()?;
+    split_partition_path(&path).and_then(|(_, unique)| unique)
+}
+
+/// Returns the bytes of `path` without its end node.
+fn path_bytes(path: &DevicePath) -> &[u8] {
+    let bytes = path.as_bytes();
+    &bytes[..bytes.len() - 4]
+}
+
+/// Checks the partition type through the `PartitionInfo` protocol.
+fn partition_info_is_xbootldr(handle: Handle) -> Option<bool> {
+    let info = open_shared::<PartitionInfo>(handle).ok()?;
+    let entry = info.gpt_partition_entry()?;
+    let ty = entry.partition_type_guid;
+    Some(ty.0 == XBOOTLDR_GUID)
+}
+
+/// Bytes to read for a GPT entry array, rounded up to whole blocks. `None`
+/// for entry sizes the spec doesn't allow (128 times a power of two; we take
+/// any multiple of 8 up to one block) or more entries than we look through.
+fn entry_array_len(entry_count: usize, entry_size: usize, block_size: usize) -> Option<usize> {
+    if entry_size < 128 || entry_size > block_size || !entry_size.is_multiple_of(8) {
+        return None;
+    }
+    if entry_count == 0 || entry_count > 1024 {
+        return None;
+    }
+    let len = entry_count.checked_mul(entry_size)?;
+    len.div_ceil(block_size).checked_mul(block_size)
+}
+
+/// Fallback for firmware without `PartitionInfo`: read the GPT of the disk
+/// and look the partition up by its unique GUID.
+fn gpt_is_xbootldr(disk: Handle, unique: Guid) -> Result<bool> {
+    let block = open_shared::<BlockIO>(disk)?;
+    let media = block.media();
+    let block_size = media.block_size() as usize;
+    let media_id = media.media_id();
+
+    if block_size < 512 {
+        return Ok(false);
+    }
+    let mut header = vec![0u8; block_size];
+    block.read_blocks(media_id, 1, &mut header)?;
+    if &header[..8] != GPT_HEADER_SIGNATURE {
+        return Ok(false);
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap()) as usize;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    let Some(total) = entry_array_len(entry_count, entry_size, block_size) else {
+        return Ok(false);
+    };
+
+    let mut entries = vec![0u8; total];
+    block.read_blocks(media_id, entries_lba, &mut entries)?;
+    for entry in entries.chunks_exact(entry_size).take(entry_count) {
+        let ty = Guid::from_bytes(entry[..16].try_into().unwrap());
+        let id = Guid::from_bytes(entry[16..32].try_into().unwrap());
+        if id == unique {
+            return Ok(ty == XBOOTLDR_GUID);
+        }
+    }
+    Ok(false)
+}
+
+/// Finds the XBOOTLDR partition on the disk `esp` lives on and returns the
+/// handle of a partition whose file system we can read.
+pub fn find_xbootldr(esp: Handle) -> Result<Option<Handle>> {
+    let esp_path = open_shared::<DevicePath>(esp)?;
+    let Some((disk_len, _)) = split_partition_path(&esp_path) else {
+        println!("ESP is not a hard drive partition, skipping XBOOTLDR lookup.");
+        return Ok(None);
+    };
+    let disk_prefix: Vec<u8> = esp_path.as_bytes()[..disk_len].to_vec();
+
+    let mut disk_handle = None;
+    let mut candidates = Vec::new();
+    for handle in boot::find_handles::<BlockIO>()? {
+        if handle == esp {
+            continue;
+        }
+        let Ok(path) = open_shared::<DevicePath>(handle) else {
+            continue;
+        };
+        let bytes = path_bytes(&path);
+        if bytes == disk_prefix.as_slice() {
+            disk_handle = Some(handle);
+            continue;
+        }
+        if !bytes.starts_with(&disk_prefix) {
+            continue;
+        }
+        // Only direct children of the disk, not nested partitions.
+        if let Some((len, unique)) = split_partition_path(&path)
+            && len == disk_len
+        {
+            candidates.push((handle, unique));
+        }
+    }
+
+    for (handle, unique) in candidates {
+        let is_xbootldr = match partition_info_is_xbootldr(handle) {
+            Some(found) => found,
+            None => match (disk_handle, unique) {
+                (Some(disk), Some(unique)) => gpt_is_xbootldr(disk, unique).unwrap_or(false),
+                _ => false,
+            },
+        };
+        if !is_xbootldr {
+            continue;
+        }
+
+        // The file system driver may not have bound to it yet.
+        let _ = boot::connect_controller(handle, None, None, true);
+        // Volume falls back to the built-in ext4 reader.
+        if Volume::open(handle).is_ok() {
+            println!("Found XBOOTLDR partition.");
+            return Ok(Some(handle));
+        }
+        match luks2::unlock_partition(handle, "XBOOTLDR partition") {
+            Ok(Some(unlocked)) if Volume::open(unlocked).is_ok() => {
+                println!("Unlocked encrypted XBOOTLDR partition.");
+                return Ok(Some(unlocked));
+            }
+            Ok(Some(_)) => println!("Unlocked XBOOTLDR partition, but its file system is not supported."),
+            Ok(None) => println!("XBOOTLDR partition found, but its file system is not supported."),
+            Err(e) if e.status() == Status::ACCESS_DENIED => {
+                println!("Failed to unlock XBOOTLDR partition: too many wrong passphrases.")
+            }
+            Err(e) => println!("Failed to unlock XBOOTLDR partition: {:?}", e.status()),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_entry_arrays() {
+        assert_eq!(entry_array_len(128, 128, 512), Some(16384));
+        assert_eq!(entry_array_len(3, 128, 512), Some(512));
+        assert_eq!(entry_array_len(5, 256, 4096), Some(4096));
+        assert_eq!(entry_array_len(1024, 512, 512), Some(524288));
+    }
+
+    #[test]
+    fn rejects_bad_entry_arrays() {
+        assert_eq!(entry_array_len(128, 0, 512), None);
+        assert_eq!(entry_array_len(128, 64, 512), None);
+        assert_eq!(entry_array_len(128, 1024, 512), None);
+        assert_eq!(entry_array_len(128, 130, 512), None);
+        assert_eq!(entry_array_len(0, 128, 512), None);
+        assert_eq!(entry_array_len(1025, 128, 512), None);
+        assert_eq!(entry_array_len(2, usize::MAX - 7, usize::MAX), None);
+    }
+}
@@ -0,0 +1,29 @@
+#!/bin/sh
+# Regenerates the ext4 and ext2 images of the ext4 reader's unit tests
+# (needs e2fsprogs and python3)
+set -e
+cd "$(dirname "$0")"
+root=$(mktemp -d)
+trap 'rm -rf "$root"' EXIT
+
+mkdir -p "$root/loader/entries" "$root/many"
+printf 'title Arch Linux\nlinux /vmlinuz-linux\n' > "$root/loader/entries/arch.conf"
+# Past the double indirect blocks of 1 KiB block maps
+python3 -c 'import sys; sys.stdout.buffer.write(bytes((i * 7 + i // 251) % 256 for i in range(300000)))' > "$root/vmlinuz-linux"
+printf 'head' > "$root/sparse"
+printf 'tail' | dd of="$root/sparse" bs=1024 seek=20 conv=notrunc status=none
+ln -s vmlinuz-linux "$root/vmlinuz"
+ln -s /loader "$root/boot"
+ln -s loader/entries/../entries/../entries/../entries/../entries/arch.conf "$root/long"
+for i in $(seq 1 300); do : > "$root/many/file-$i"; done
+
+export E2FSPROGS_FAKE_TIME=1700000000
+uuid=6f1c2d3e-4b5a-4978-8a9b-0c1d2e3f4a5b
+rm -f ext4.img ext2.img
+mke2fs -q -F -t ext4 -b 1024 -I 256 -g 1024 -N 512 -U $uuid -E hash_seed=$uuid,root_owner=0:0 -L boot -d "$root" ext4.img 2M
+e2fsck -fyD ext4.img > /dev/null 2>&1 || [ $? -eq 1 ]
+mke2fs -q -F -t ext2 -b 1024 -I 128 -N 512 -U $uuid -E hash_seed=$uuid,root_owner=0:0 -d "$root" ext2.img 2M
+for image in ext4.img ext2.img; do
+    python3 -c 'import sys, zlib; sys.stdout.buffer.write(zlib.compress(open(sys.argv[1], "rb").read(), 9))' $image > $image.zz
+    rm $image
+done